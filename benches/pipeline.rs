@@ -0,0 +1,110 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use image_crate::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+
+use pixvert_rs::cache::{NoCacheEngine, shared, SharedCache};
+use pixvert_rs::config::Config;
+use pixvert_rs::decoder::{CachedImageDecoder, ImageDecoder};
+use pixvert_rs::encoder::{AllInOneCachedImageEncoder, ImageEncoder, JpegEncoder, OutputFormat};
+use pixvert_rs::fetcher::Resource;
+use pixvert_rs::metrics::{Metrics, NoMetrics};
+use pixvert_rs::output_dimensions::OutputDimensions;
+use pixvert_rs::resizer::{CachedResizer, Resizer};
+
+fn no_cache() -> SharedCache {
+    shared(Box::new(NoCacheEngine {}))
+}
+
+fn no_metrics() -> Arc<dyn Metrics + Send + Sync> {
+    Arc::new(NoMetrics {})
+}
+
+/// Full HD image with smooth gradients and deterministic grain, close to what photos look like for codecs.
+fn photo() -> DynamicImage {
+    let mut seed: u32 = 0x9e3779b9;
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(1920, 1080, |x, y| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let grain = (seed % 16) as u8;
+        Rgba([
+            ((x * 255 / 1920) as u8).saturating_add(grain),
+            ((y * 255 / 1080) as u8).saturating_add(grain),
+            (((x + y) * 255 / 3000) as u8).saturating_add(grain),
+            255,
+        ])
+    }))
+}
+
+/// Small image with flat colors and transparency, like logos and icons.
+fn icon() -> DynamicImage {
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(256, 256, |x, y| {
+        let inside = (x as i32 - 128).pow(2) + (y as i32 - 128).pow(2) < 100 * 100;
+        if inside { Rgba([30, 120, 220, 255]) } else { Rgba([0, 0, 0, 0]) }
+    }))
+}
+
+fn fixtures() -> Vec<(&'static str, DynamicImage)> {
+    vec![("photo", photo()), ("icon", icon())]
+}
+
+fn resource(image: &DynamicImage, format: ImageOutputFormat, content_type: &str) -> Resource {
+    let mut resource = Resource::default();
+    image.write_to(&mut Cursor::new(&mut resource.content), format).unwrap();
+    resource.response_data.content_type = content_type.to_string();
+    resource
+}
+
+fn decode(c: &mut Criterion) {
+    let decoder = CachedImageDecoder { cache: no_cache(), metrics: no_metrics(), auto_orient: true, maximum_image_size: usize::MAX };
+    let mut group = c.benchmark_group("decode");
+    for (name, image) in fixtures() {
+        for (format, content_type) in [(ImageOutputFormat::Jpeg(90), "image/jpeg"), (ImageOutputFormat::Png, "image/png")] {
+            let resource = resource(&image, format, content_type);
+            group.throughput(Throughput::Bytes(resource.content.len() as u64));
+            group.bench_with_input(BenchmarkId::new(content_type, name), &resource, |b, resource| {
+                b.iter(|| decoder.decode(&resource.response_data.id, resource).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn resize(c: &mut Criterion) {
+    let resizer = CachedResizer { cache: no_cache(), config: Config::default(), metrics: no_metrics() };
+    let mut group = c.benchmark_group("resize");
+    for (name, image) in fixtures() {
+        let dimensions = (image.width() as usize / 3, image.height() as usize / 3);
+        group.bench_with_input(BenchmarkId::new("keep-ratio", name), &image, |b, image| {
+            b.iter(|| resizer.resize("bench", image.clone(), dimensions).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("exact", name), &image, |b, image| {
+            b.iter(|| resizer.resize_exact("bench", image.clone(), dimensions).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let encoder = AllInOneCachedImageEncoder { cache: no_cache(), metrics: no_metrics(), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+    let tag = String::from("bench");
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(20);
+    let formats = vec!["jpeg", "jpeg60", "png", "bmp"];
+    #[cfg(feature = "webp")]
+    let formats = [formats, vec!["webp", "webp80"]].concat();
+    for (name, image) in fixtures() {
+        for &format in &formats {
+            let output_format: OutputFormat = format.parse().unwrap();
+            group.bench_with_input(BenchmarkId::new(format, name), &image, |b, image| {
+                b.iter(|| encoder.encode(&tag, image.clone(), &OutputDimensions::Original, output_format.clone(), None).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode, resize, encode);
+criterion_main!(benches);
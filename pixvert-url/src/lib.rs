@@ -0,0 +1,362 @@
+//! Builds, and signs, URLs of images served by pixvert from typed parameters, in the grammar the
+//! server parses. The server signs and verifies with the same code, so a signed URL built here
+//! is accepted by any pixvert with the same key.
+//!
+//! ```
+//! use pixvert_url::{Format, ImageUrl, Operation, Size};
+//!
+//! let url = ImageUrl::new("https://images.example.com", "https://example.com/cat.png")
+//!     .size(Size::KeepRatio(400, 300))
+//!     .format(Format::Webp { quality: 80, method: None })
+//!     .operation(Operation::Round { radius: 16, color: None })
+//!     .signed("secret");
+//! assert!(url.starts_with("https://images.example.com/400_300/keep-ratio/webp80/https%3A%2F%2Fexample.com%2Fcat.png?ops=round:16&s="));
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Query parameter of the operations, separated by commas.
+pub const OPERATIONS_QUERY_KEY: &str = "ops";
+/// Query parameter of the signature, it signs the path and every other parameter.
+pub const SIGNATURE_QUERY_KEY: &str = "s";
+/// Query parameter of the claims, JSON encoded with unpadded URL-safe base64.
+pub const CLAIMS_QUERY_KEY: &str = "claims";
+/// Query parameter of the Unix time in seconds the link stops working at.
+pub const EXPIRES_QUERY_KEY: &str = "expires";
+
+/// Signature of the path with its query, to be appended as the `s` parameter.
+pub fn sign(key: &str, path_and_query: &str) -> String {
+    base64::encode_config(mac(key, path_and_query).finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Whether the signature, as in the `s` parameter, signs the path with its query.
+pub fn verify_signature(key: &str, path_and_query: &str, signature: &str) -> bool {
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => mac(key, path_and_query).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn mac(key: &str, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Dimensions of the image, in the path before the format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    /// Dimensions of the source.
+    Original,
+    /// Stretched to the dimensions.
+    Exact(u32, u32),
+    /// Largest size keeping the ratio of the source that fits in the dimensions.
+    KeepRatio(u32, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsampling {
+    Chroma444,
+    Chroma422,
+    Chroma420,
+}
+
+/// Format the image is served in. Parameters left as `None` take the defaults of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    /// Negotiated by the `Accept` header of the client.
+    Auto,
+    Jpeg { quality: Option<u8>, progressive: bool, subsampling: Option<Subsampling> },
+    /// Deflate level from 1 to 9.
+    Png { level: Option<u8> },
+    PngFast,
+    Png8,
+    Apng,
+    /// Method from 0 (fastest) to 6.
+    Webp { quality: u8, method: Option<u8> },
+    WebpLossless { method: Option<u8> },
+    WebpNearLossless { level: u8, method: Option<u8> },
+    /// Speed from 1 (slowest) to 10.
+    Avif { quality: Option<u8>, speed: Option<u8> },
+    Heic { quality: Option<u8>, speed: Option<u8> },
+    Gif,
+    Bmp,
+    Tiff { lzw: bool },
+    Ico,
+    /// Lossy format at the highest quality whose output fits in the size in bytes.
+    MaxSize(Box<Format>, u32),
+    Grayscale(Box<Format>),
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let number = |value: Option<u8>| value.map(|value| value.to_string()).unwrap_or_default();
+        let method = |method: Option<u8>| method.map(|method| format!("m{}", method)).unwrap_or_default();
+        let speed = |speed: Option<u8>| speed.map(|speed| format!("s{}", speed)).unwrap_or_default();
+        match self {
+            Format::Auto => write!(f, "auto"),
+            Format::Jpeg { quality, progressive, subsampling } => {
+                let subsampling = match subsampling {
+                    None => "",
+                    Some(Subsampling::Chroma444) => "c444",
+                    Some(Subsampling::Chroma422) => "c422",
+                    Some(Subsampling::Chroma420) => "c420",
+                };
+                write!(f, "jpeg{}{}{}", if *progressive { "p" } else { "" }, number(*quality), subsampling)
+            }
+            Format::Png { level } => write!(f, "png{}", number(*level)),
+            Format::PngFast => write!(f, "png-fast"),
+            Format::Png8 => write!(f, "png8"),
+            Format::Apng => write!(f, "apng"),
+            Format::Webp { quality, method: m } => write!(f, "webp{}{}", quality, method(*m)),
+            Format::WebpLossless { method: m } => write!(f, "webp{}", method(*m)),
+            Format::WebpNearLossless { level, method: m } => write!(f, "webpnl{}{}", level, method(*m)),
+            Format::Avif { quality, speed: s } => write!(f, "avif{}{}", number(*quality), speed(*s)),
+            Format::Heic { quality, speed: s } => write!(f, "heic{}{}", number(*quality), speed(*s)),
+            Format::Gif => write!(f, "gif"),
+            Format::Bmp => write!(f, "bmp"),
+            Format::Tiff { lzw } => write!(f, "tiff{}", if *lzw { "lzw" } else { "" }),
+            Format::Ico => write!(f, "ico"),
+            Format::MaxSize(format, size) if size % 1000 == 0 => write!(f, "{}@{}kb", format, size / 1000),
+            Format::MaxSize(format, size) => write!(f, "{}@{}b", format, size),
+            Format::Grayscale(format) => write!(f, "{}:gray", format),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelMode {
+    Luminance,
+    PerChannel,
+}
+
+/// RGB color with an alpha, written as hex.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.red, self.green, self.blue)?;
+        if self.alpha != 255 {
+            write!(f, "{:02x}", self.alpha)?;
+        }
+        Ok(())
+    }
+}
+
+/// Operation of the `ops` parameter. Strengths left as `None` take the defaults of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// Resized with seam carving instead of stretching.
+    FitSeam,
+    AutoContrast(ChannelMode),
+    Equalize(ChannelMode),
+    /// Strength from 1 to 100.
+    Denoise(Option<u8>),
+    /// Transparent corners without a color.
+    Round { radius: u32, color: Option<Color> },
+    /// Strength from 1 to 100.
+    Vignette(Option<u8>),
+    /// Inside borders keep the image within the requested dimensions, outside ones grow it.
+    Border { width: u32, color: Color, inside: bool },
+    MaxWidth(u32),
+    MaxHeight(u32),
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let channels = |mode: &ChannelMode| match mode {
+            ChannelMode::Luminance => "luma",
+            ChannelMode::PerChannel => "rgb",
+        };
+        let strength = |strength: &Option<u8>| strength.map(|strength| format!(":{}", strength)).unwrap_or_default();
+        match self {
+            Operation::FitSeam => write!(f, "fit:seam"),
+            Operation::AutoContrast(mode) => write!(f, "autocontrast:{}", channels(mode)),
+            Operation::Equalize(mode) => write!(f, "equalize:{}", channels(mode)),
+            Operation::Denoise(value) => write!(f, "denoise{}", strength(value)),
+            Operation::Round { radius, color: None } => write!(f, "round:{}", radius),
+            Operation::Round { radius, color: Some(color) } => write!(f, "round:{}:{}", radius, color),
+            Operation::Vignette(value) => write!(f, "vignette{}", strength(value)),
+            Operation::Border { width, color, inside } => write!(f, "border:{}:{}:{}", width, color, if *inside { "inside" } else { "outside" }),
+            Operation::MaxWidth(width) => write!(f, "maxw:{}", width),
+            Operation::MaxHeight(height) => write!(f, "maxh:{}", height),
+        }
+    }
+}
+
+/// Restrictions of a signed URL, so links handed out cannot be changed into more expensive
+/// renditions or used forever.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Claims {
+    /// Lossy formats are encoded at this quality at most.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_quality: Option<u8>,
+    /// Names of the formats the image may be served in, e.g. `webp` or `webp-lossless`. Empty allows all.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub formats: Vec<String>,
+    /// Unix time in seconds the link stops working at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+/// URL of an image, built up from the pixvert instance and the URL of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageUrl {
+    base: String,
+    source: String,
+    size: Size,
+    format: Option<Format>,
+    preset: Option<String>,
+    operations: Vec<Operation>,
+    expires: Option<i64>,
+    claims: Option<Claims>,
+}
+
+impl ImageUrl {
+    /// `base` is the pixvert instance, with the path prefix of a tenant if any, e.g. `https://images.example.com/shop`.
+    pub fn new(base: &str, source: &str) -> Self {
+        ImageUrl {
+            base: base.trim_end_matches('/').to_string(),
+            source: source.to_string(),
+            size: Size::Original,
+            format: None,
+            preset: None,
+            operations: vec![],
+            expires: None,
+            claims: None,
+        }
+    }
+
+    pub fn size(self, size: Size) -> Self {
+        ImageUrl { size, ..self }
+    }
+
+    /// Without a format the server negotiates one, or serves its configured default.
+    pub fn format(self, format: Format) -> Self {
+        ImageUrl { format: Some(format), ..self }
+    }
+
+    /// Preset configured on the server, it replaces the size and the format.
+    pub fn preset(self, preset: &str) -> Self {
+        ImageUrl { preset: Some(preset.to_string()), ..self }
+    }
+
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Unix time in seconds a signed link stops working at.
+    pub fn expires(self, expires: i64) -> Self {
+        ImageUrl { expires: Some(expires), ..self }
+    }
+
+    pub fn claims(self, claims: Claims) -> Self {
+        ImageUrl { claims: Some(claims), ..self }
+    }
+
+    /// Path of the image on the instance, with the path prefix of the base, and its query.
+    pub fn path_and_query(&self) -> String {
+        let mut path = base_path(&self.base).to_string();
+        match &self.preset {
+            Some(preset) => path.push_str(&format!("/preset/{}", urlencoding::encode(preset))),
+            None => {
+                match self.size {
+                    Size::Original => {}
+                    Size::Exact(width, height) => path.push_str(&format!("/{}_{}", width, height)),
+                    Size::KeepRatio(width, height) => path.push_str(&format!("/{}_{}/keep-ratio", width, height)),
+                }
+                if let Some(format) = &self.format {
+                    path.push_str(&format!("/{}", format));
+                }
+            }
+        }
+        path.push_str(&format!("/{}", urlencoding::encode(&self.source)));
+
+        let mut parameters = vec![];
+        if !self.operations.is_empty() {
+            let operations: Vec<String> = self.operations.iter().map(Operation::to_string).collect();
+            parameters.push(format!("{}={}", OPERATIONS_QUERY_KEY, operations.join(",")));
+        }
+        if let Some(expires) = self.expires {
+            parameters.push(format!("{}={}", EXPIRES_QUERY_KEY, expires));
+        }
+        if let Some(claims) = &self.claims {
+            let json = serde_json::to_vec(claims).expect("claims serialize to JSON");
+            parameters.push(format!("{}={}", CLAIMS_QUERY_KEY, base64::encode_config(json, base64::URL_SAFE_NO_PAD)));
+        }
+        match parameters.is_empty() {
+            true => path,
+            false => format!("{}?{}", path, parameters.join("&")),
+        }
+    }
+
+    /// URL with the signature of its path and query appended.
+    pub fn signed(&self, key: &str) -> String {
+        let path_and_query = self.path_and_query();
+        let signature = sign(key, &path_and_query);
+        let separator = if path_and_query.contains('?') { '&' } else { '?' };
+        format!("{}{}{}{}={}", origin(&self.base), path_and_query, separator, SIGNATURE_QUERY_KEY, signature)
+    }
+}
+
+impl Display for ImageUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", origin(&self.base), self.path_and_query())
+    }
+}
+
+/// Scheme and host of the base, without its path.
+fn origin(base: &str) -> &str {
+    let host_start = base.find("://").map_or(0, |position| position + 3);
+    match base[host_start..].find('/') {
+        Some(position) => &base[..host_start + position],
+        None => base,
+    }
+}
+
+fn base_path(base: &str) -> &str {
+    &base[origin(base).len()..]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Claims, Color, Format, ImageUrl, Operation, sign, Size, Subsampling, verify_signature};
+
+    #[test]
+    fn build_paths() {
+        let url = ImageUrl::new("https://images.example.com/shop/", "https://example.com/a b.png");
+        assert_eq!(url.to_string(), "https://images.example.com/shop/https%3A%2F%2Fexample.com%2Fa%20b.png");
+        let url = url
+            .size(Size::Exact(100, 50))
+            .format(Format::Grayscale(Box::new(Format::MaxSize(Box::new(Format::Jpeg { quality: Some(80), progressive: true, subsampling: Some(Subsampling::Chroma420) }), 50_000))))
+            .operation(Operation::Border { width: 4, color: Color { red: 255, green: 0, blue: 0, alpha: 255 }, inside: true })
+            .operation(Operation::Denoise(None));
+        assert_eq!(url.path_and_query(), "/shop/100_50/jpegp80c420@50kb:gray/https%3A%2F%2Fexample.com%2Fa%20b.png?ops=border:4:ff0000:inside,denoise");
+        assert_eq!(url.preset("thumb").path_and_query(), "/shop/preset/thumb/https%3A%2F%2Fexample.com%2Fa%20b.png?ops=border:4:ff0000:inside,denoise");
+    }
+
+    #[test]
+    fn sign_path_and_query() {
+        let url = ImageUrl::new("https://images.example.com", "https://example.com/a.png")
+            .format(Format::Webp { quality: 80, method: Some(6) })
+            .expires(1_900_000_000)
+            .claims(Claims { max_quality: Some(70), ..Claims::default() });
+        let path_and_query = "/webp80m6/https%3A%2F%2Fexample.com%2Fa.png?expires=1900000000&claims=eyJtYXhRdWFsaXR5Ijo3MH0";
+        assert_eq!(url.path_and_query(), path_and_query);
+        assert_eq!(url.signed("secret"), format!("https://images.example.com{}&s={}", path_and_query, sign("secret", path_and_query)));
+        assert!(verify_signature("secret", path_and_query, &sign("secret", path_and_query)));
+        assert!(!verify_signature("other", path_and_query, &sign("secret", path_and_query)));
+    }
+}
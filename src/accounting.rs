@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::metrics::{BYTES_SERVED, Metrics, TRANSFORM_SECONDS};
+use crate::recover;
+
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct Usage {
+    pub responses: u64,
+    pub bytes_served: u64,
+    pub transforms: u64,
+    pub transform_seconds: f64,
+}
+
+/// Usage split by source domain and by served content type.
+#[derive(Serialize, Clone, Default)]
+pub struct CostReport {
+    pub origins: BTreeMap<String, Usage>,
+    pub formats: BTreeMap<String, Usage>,
+}
+
+impl CostReport {
+    fn update(&mut self, origin: &str, format: &str, update: impl Fn(&mut Usage)) {
+        update(self.origins.entry(origin.to_string()).or_default());
+        update(self.formats.entry(format.to_string()).or_default());
+    }
+}
+
+/// Attributes time spent on transforms and bytes sent to clients to the origin and the output format.
+/// Totals are kept for the admin report and also sent to the metrics backend.
+pub struct CostAccounting {
+    report: Mutex<CostReport>,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+impl CostAccounting {
+    pub fn new(metrics: Arc<dyn Metrics + Send + Sync>) -> Self {
+        CostAccounting { report: Mutex::default(), metrics }
+    }
+
+    pub fn record_transform(&self, origin: &str, format: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        recover(self.report.lock()).update(origin, format, |usage| {
+            usage.transforms += 1;
+            usage.transform_seconds += seconds;
+        });
+        self.metrics.add(TRANSFORM_SECONDS, seconds, &[("origin", origin), ("format", format)]);
+    }
+
+    pub fn record_served(&self, origin: &str, format: &str, bytes: u64) {
+        recover(self.report.lock()).update(origin, format, |usage| {
+            usage.responses += 1;
+            usage.bytes_served += bytes;
+        });
+        self.metrics.add(BYTES_SERVED, bytes as f64, &[("origin", origin), ("format", format)]);
+    }
+
+    pub fn report(&self) -> CostReport {
+        recover(self.report.lock()).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::accounting::{CostAccounting, Usage};
+    use crate::metrics::NoMetrics;
+
+    #[test]
+    fn usage_is_split_by_origin_and_format() {
+        let accounting = CostAccounting::new(Arc::new(NoMetrics {}));
+        accounting.record_transform("a.com", "image/webp", Duration::from_millis(500));
+        accounting.record_served("a.com", "image/webp", 100);
+        accounting.record_served("b.com", "image/webp", 50);
+
+        let report = accounting.report();
+        assert_eq!(report.origins["a.com"], Usage { responses: 1, bytes_served: 100, transforms: 1, transform_seconds: 0.5 });
+        assert_eq!(report.origins["b.com"].bytes_served, 50);
+        assert_eq!(report.formats["image/webp"], Usage { responses: 2, bytes_served: 150, transforms: 1, transform_seconds: 0.5 });
+    }
+}
@@ -0,0 +1,222 @@
+use std::convert::TryInto;
+
+/// Animated WebP holds every frame in an `ANMF` chunk, which neither libwebp's simple
+/// decoder nor the image crate read. Frames are taken out into still WebPs.
+pub struct AnimatedWebp {
+    pub canvas: (u32, u32),
+    pub frames: Vec<AnimatedWebpFrame>,
+}
+
+/// Frame as stored in the animation, covering only its part of the canvas.
+pub struct AnimatedWebpFrame {
+    pub offset: (u32, u32),
+    pub delay_ms: u32,
+    /// Drawn over the canvas with alpha blending, otherwise it replaces its part of the canvas.
+    pub blend: bool,
+    /// Its part of the canvas is cleared once the frame has been shown.
+    pub dispose: bool,
+    pub still: Vec<u8>,
+}
+
+const ANIMATION_FLAG: u8 = 0x02;
+const ALPHA_FLAG: u8 = 0x10;
+const NO_BLEND_FLAG: u8 = 0x02;
+const DISPOSE_FLAG: u8 = 0x01;
+const FRAME_HEADER_LENGTH: usize = 16;
+
+/// Chunks of a RIFF container as four character code and payload.
+pub(crate) fn chunks(mut data: &[u8]) -> impl Iterator<Item=(&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let fourcc = data.get(..4)?;
+        let length = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+        let payload = data.get(8..8 + length)?;
+        data = data.get(8 + length + length % 2..).unwrap_or_default();
+        Some((fourcc, payload))
+    })
+}
+
+fn u24(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16
+}
+
+fn push_u24(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+pub(crate) fn push_chunk(buffer: &mut Vec<u8>, fourcc: &[u8], payload: &[u8]) {
+    buffer.extend_from_slice(fourcc);
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        buffer.push(0);
+    }
+}
+
+pub(crate) fn riff(body: &[u8]) -> Vec<u8> {
+    let mut webp = b"RIFF".to_vec();
+    webp.extend_from_slice(&(4 + body.len() as u32).to_le_bytes());
+    webp.extend_from_slice(b"WEBP");
+    webp.extend_from_slice(body);
+    webp
+}
+
+/// Image chunks of a still WebP or of a frame, without the extended header.
+fn image_chunks(data: &[u8]) -> impl Iterator<Item=(&[u8], &[u8])> {
+    chunks(data).filter(|(fourcc, _)| [&b"ALPH"[..], b"VP8 ", b"VP8L"].contains(fourcc))
+}
+
+/// Frames of an animated WebP, `None` for anything else.
+pub fn frames(content: &[u8]) -> Option<AnimatedWebp> {
+    if content.get(..4)? != b"RIFF" || content.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut container = chunks(&content[12..]);
+    let (fourcc, header) = container.next()?;
+    if fourcc != b"VP8X" || header.len() < 10 || header[0] & ANIMATION_FLAG == 0 {
+        return None;
+    }
+    let canvas = (u24(&header[4..7]) + 1, u24(&header[7..10]) + 1);
+    let frames = container
+        .filter(|(fourcc, frame)| *fourcc == b"ANMF" && frame.len() >= FRAME_HEADER_LENGTH)
+        .map(|(_, frame)| {
+            let (width, height) = (u24(&frame[6..9]) + 1, u24(&frame[9..12]) + 1);
+            let frame_chunks: Vec<(&[u8], &[u8])> = image_chunks(&frame[FRAME_HEADER_LENGTH..]).collect();
+            let mut body = Vec::new();
+            if frame_chunks.iter().any(|(fourcc, _)| *fourcc == b"ALPH") {
+                let mut extended = vec![ALPHA_FLAG, 0, 0, 0];
+                push_u24(&mut extended, width - 1);
+                push_u24(&mut extended, height - 1);
+                push_chunk(&mut body, b"VP8X", &extended);
+            }
+            for (fourcc, payload) in frame_chunks {
+                push_chunk(&mut body, fourcc, payload);
+            }
+            AnimatedWebpFrame {
+                offset: (u24(&frame[0..3]) * 2, u24(&frame[3..6]) * 2),
+                delay_ms: u24(&frame[12..15]),
+                blend: frame[15] & NO_BLEND_FLAG == 0,
+                dispose: frame[15] & DISPOSE_FLAG != 0,
+                still: riff(&body),
+            }
+        })
+        .collect::<Vec<AnimatedWebpFrame>>();
+    if frames.is_empty() {
+        return None;
+    }
+    Some(AnimatedWebp { canvas, frames })
+}
+
+/// Muxes still WebPs covering the whole canvas into an animation which loops forever.
+pub fn mux(canvas: (u32, u32), frames: &[(Vec<u8>, u32)]) -> Vec<u8> {
+    let has_alpha = frames.iter().any(|(still, _)| image_chunks(still.get(12..).unwrap_or_default()).any(|(fourcc, payload)| {
+        // Lossless images carry alpha in the bitstream, flagged in its header.
+        fourcc == b"ALPH" || (fourcc == b"VP8L" && payload.len() > 4 && payload[4] & 0x10 != 0)
+    }));
+    let mut body = Vec::new();
+    let mut extended = vec![ANIMATION_FLAG | if has_alpha { ALPHA_FLAG } else { 0 }, 0, 0, 0];
+    push_u24(&mut extended, canvas.0 - 1);
+    push_u24(&mut extended, canvas.1 - 1);
+    push_chunk(&mut body, b"VP8X", &extended);
+    push_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+    for (still, delay_ms) in frames {
+        let mut frame = Vec::new();
+        push_u24(&mut frame, 0);
+        push_u24(&mut frame, 0);
+        push_u24(&mut frame, canvas.0 - 1);
+        push_u24(&mut frame, canvas.1 - 1);
+        push_u24(&mut frame, (*delay_ms).min(0xFF_FFFF));
+        frame.push(NO_BLEND_FLAG);
+        for (fourcc, payload) in image_chunks(still.get(12..).unwrap_or_default()) {
+            push_chunk(&mut frame, fourcc, payload);
+        }
+        push_chunk(&mut body, b"ANMF", &frame);
+    }
+    riff(&body)
+}
+
+#[cfg(all(test, feature = "webp"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use image_crate::{GenericImageView, Rgba};
+
+    use crate::animated_webp::{frames, mux, push_chunk, push_u24, riff};
+    use crate::cache::{NoCacheEngine, shared};
+    use crate::decoder::{CachedImageDecoder, DecodeError, ImageDecoder};
+    use crate::fetcher::{Resource, ResponseData};
+    use crate::metrics::NoMetrics;
+
+    /// Frame of `width`x`height` pixels of the color at the offset, as an `ANMF` payload.
+    fn frame(color: [u8; 4], width: u32, height: u32, offset: (u32, u32), flags: u8) -> Vec<u8> {
+        let pixels: Vec<u8> = color.repeat((width * height) as usize);
+        let still = webp::Encoder::from_rgba(&pixels, width, height).encode_lossless().to_vec();
+        let mut payload = Vec::new();
+        push_u24(&mut payload, offset.0 / 2);
+        push_u24(&mut payload, offset.1 / 2);
+        push_u24(&mut payload, width - 1);
+        push_u24(&mut payload, height - 1);
+        push_u24(&mut payload, 100);
+        payload.push(flags);
+        payload.extend_from_slice(&still[12..]);
+        payload
+    }
+
+    fn animation() -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut extended = vec![0x02, 0, 0, 0];
+        push_u24(&mut extended, 7);
+        push_u24(&mut extended, 3);
+        push_chunk(&mut body, b"VP8X", &extended);
+        push_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+        push_chunk(&mut body, b"ANMF", &frame([255, 0, 0, 255], 4, 2, (2, 0), 0x01));
+        push_chunk(&mut body, b"ANMF", &frame([0, 0, 255, 128], 4, 4, (4, 0), 0));
+        riff(&body)
+    }
+
+    fn decoder() -> CachedImageDecoder {
+        CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX }
+    }
+
+    fn resource(content: Vec<u8>) -> Resource {
+        Resource {
+            response_data: ResponseData { id: String::from("animation"), content_type: String::from("image/webp"), additional_data: HashMap::default() },
+            content,
+        }
+    }
+
+    #[test]
+    fn decode_first_frame_of_animation() {
+        let parsed = frames(&animation()).unwrap();
+        assert_eq!((parsed.canvas, parsed.frames[0].offset), ((8, 4), (2, 0)));
+        let still = webp::Encoder::from_rgba(&[0; 16], 2, 2).encode_lossless().to_vec();
+        assert!(frames(&still).is_none());
+
+        let image = decoder().decode("animation", &resource(animation())).unwrap();
+        assert_eq!(image.dimensions(), (8, 4));
+        assert_eq!(image.get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(2, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(5, 1), Rgba([255, 0, 0, 255]));
+        let limited = CachedImageDecoder { maximum_image_size: 31, ..decoder() };
+        assert!(matches!(limited.decode("animation", &resource(animation())), Err(DecodeError::ExceedsMaximumSize(31, 32))));
+    }
+
+    #[test]
+    fn compose_and_mux_every_frame() {
+        let decoded = decoder().decode_animation("animation", &resource(animation())).unwrap().unwrap();
+        assert_eq!(decoded.frames.iter().map(|frame| frame.delay_ms).collect::<Vec<u32>>(), vec![100, 100]);
+        let second: image_crate::DynamicImage = decoded.frames[1].image.clone().into();
+        // First frame is disposed, the second one is blended over the cleared canvas.
+        assert_eq!(second.get_pixel(2, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(second.get_pixel(5, 3), Rgba([0, 0, 255, 128]));
+        let limited = CachedImageDecoder { maximum_image_size: 63, ..decoder() };
+        assert!(matches!(limited.decode_animation("animation", &resource(animation())), Err(DecodeError::ExceedsMaximumSize(63, 64))));
+
+        let stills: Vec<(Vec<u8>, u32)> = decoded.frames.iter()
+            .map(|frame| (webp::Encoder::from_rgba(&frame.image.image, 8, 4).encode_lossless().to_vec(), frame.delay_ms * 2))
+            .collect();
+        let muxed = frames(&mux((8, 4), &stills)).unwrap();
+        assert_eq!(muxed.canvas, (8, 4));
+        assert_eq!(muxed.frames.iter().map(|frame| (frame.offset, frame.delay_ms, frame.blend)).collect::<Vec<_>>(), vec![((0, 0), 200, false), ((0, 0), 200, false)]);
+    }
+}
@@ -1,33 +1,142 @@
-use std::collections::HashMap;
+use std::future::Future;
 use std::io::Error;
-use std::sync::Mutex;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use dashmap::DashMap;
 
 pub mod file_cache;
+pub mod index;
+pub mod layers;
+pub mod namespace;
+pub mod typed;
+pub mod write_behind;
+
+/// Backend the stages cache their results in. Engines synchronize access on their own, so every
+/// request and stage calls them at the same time through a [`SharedCache`]. Backends talking to remote storage implement it
+/// directly, the ones kept in memory or on the local disk implement [`SyncCacheEngine`] instead.
+#[async_trait]
+pub trait CacheEngine: Send + Sync {
+    async fn get(&self, name: &str) -> Option<Vec<u8>>;
+    async fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error>;
+    /// Removes the entry, returns whether it was stored.
+    async fn remove(&self, name: &str) -> Result<bool, Error>;
+    /// Prepares the backend before the first request, e.g. connects a pool. Errors stop the startup.
+    async fn open(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Writes out entries the backend still holds back.
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Releases the backend on shutdown. Entries held back are flushed first.
+    async fn close(&self) -> Result<(), Error> {
+        self.flush().await
+    }
+}
 
-pub trait CacheEngine {
+/// Backend which never has to wait for I/O long enough to be worth yielding, adapted to
+/// [`CacheEngine`] by running every call in place.
+pub trait SyncCacheEngine: Send + Sync {
     fn get(&self, name: &str) -> Option<Vec<u8>>;
-    fn set(&self, name: &str, data: &Vec<u8>) -> Result<bool, Error>;
+    fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error>;
+    /// Removes the entry, returns whether it was stored.
+    fn remove(&self, name: &str) -> Result<bool, Error>;
+    fn open(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn close(&self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+#[async_trait]
+impl<T: SyncCacheEngine> CacheEngine for T {
+    async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        SyncCacheEngine::get(self, name)
+    }
+
+    async fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        SyncCacheEngine::set(self, name, data)
+    }
+
+    async fn remove(&self, name: &str) -> Result<bool, Error> {
+        SyncCacheEngine::remove(self, name)
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        SyncCacheEngine::open(self)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        SyncCacheEngine::flush(self)
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        SyncCacheEngine::close(self)
+    }
+}
+
+/// Cache shared by the stages and the routes.
+pub type SharedCache = Arc<dyn CacheEngine>;
+
+pub fn shared(engine: Box<dyn CacheEngine>) -> SharedCache {
+    Arc::from(engine)
+}
+
+/// Waits for the future on the calling thread. Stages run on worker threads outside of the
+/// runtime and reach the cache through it.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    futures_executor::block_on(future)
+}
+
+/// Shared cache as the stages use it, every call blocks the worker thread until the backend answers.
+pub trait BlockingCache {
+    fn get_blocking(&self, name: &str) -> Option<Vec<u8>>;
+    fn set_blocking(&self, name: &str, data: &[u8]) -> Result<bool, Error>;
+    fn remove_blocking(&self, name: &str) -> Result<bool, Error>;
+}
+
+impl<T: CacheEngine + ?Sized> BlockingCache for T {
+    fn get_blocking(&self, name: &str) -> Option<Vec<u8>> {
+        block_on(self.get(name))
+    }
+
+    fn set_blocking(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        block_on(self.set(name, data))
+    }
+
+    fn remove_blocking(&self, name: &str) -> Result<bool, Error> {
+        block_on(self.remove(name))
+    }
 }
 
 pub struct NoCacheEngine {}
 
-impl CacheEngine for NoCacheEngine {
+impl SyncCacheEngine for NoCacheEngine {
     fn get(&self, _: &str) -> Option<Vec<u8>> {
         Option::None
     }
-    fn set(&self, _: &str, _: &Vec<u8>) -> Result<bool, Error> {
+    fn set(&self, _: &str, _: &[u8]) -> Result<bool, Error> {
         Result::Ok(true)
     }
+    fn remove(&self, _: &str) -> Result<bool, Error> {
+        Result::Ok(false)
+    }
 }
 
+/// Entries are spread over shards with a lock each, so requests rarely wait for one another.
 pub struct HashMapCacheEngine {
-    hashmap: Mutex<HashMap<String, Vec<u8>>>,
+    hashmap: DashMap<String, Vec<u8>>,
 }
 
 impl HashMapCacheEngine {
     pub fn new() -> Self {
         HashMapCacheEngine {
-            hashmap: Mutex::from(HashMap::default())
+            hashmap: DashMap::new()
         }
     }
 }
@@ -38,13 +147,17 @@ impl Default for HashMapCacheEngine {
     }
 }
 
-impl CacheEngine for HashMapCacheEngine {
+impl SyncCacheEngine for HashMapCacheEngine {
     fn get(&self, name: &str) -> Option<Vec<u8>> {
-        return self.hashmap.lock().unwrap().get(name).cloned();
+        self.hashmap.get(name).map(|data| data.value().clone())
     }
 
-    fn set(&self, name: &str, data: &Vec<u8>) -> Result<bool, Error> {
-        self.hashmap.lock().unwrap().insert(name.to_string(), data.clone());
+    fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        self.hashmap.insert(name.to_string(), data.to_vec());
         Ok(true)
     }
+
+    fn remove(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.hashmap.remove(name).is_some())
+    }
 }
@@ -1,16 +1,28 @@
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Error, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock};
 
-use log::debug;
+use log::{debug, info, warn};
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
 
-use crate::cache::CacheEngine;
+use crate::cache::SyncCacheEngine;
+use crate::cache::index::CacheIndex;
+use crate::recover;
 
+/// Number of locks the files are spread over.
+const FILE_LOCKS: usize = 64;
+
+/// Files are guarded by locks picked by their name, so a file is never read while it is being
+/// written and files under different locks are read and written at the same time.
 pub struct FileCache {
     dir: PathBuf,
+    index: RwLock<CacheIndex>,
+    locks: Vec<Mutex<()>>,
+    /// Catalog the cache created its directory in, removed on close.
+    catalog: Option<PathBuf>,
 }
 
 impl FileCache {
@@ -23,19 +35,44 @@ impl FileCache {
         let path = Path::new(catalog).join(rand_string);
         fs::create_dir_all(String::from(path.to_string_lossy())).unwrap();
         debug!("Created path {:#?}", path);
+        FileCache { catalog: Some(PathBuf::from(catalog)), ..FileCache::open(path) }
+    }
+
+    /// Uses an existing catalog, indexing the files already stored in it.
+    pub fn open(dir: PathBuf) -> FileCache {
+        let mut index = CacheIndex::new();
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            if let Ok(metadata) = entry.metadata() {
+                index.insert(&entry.file_name().to_string_lossy(), metadata.len());
+            }
+        }
+        info!("Indexed {} cached files taking {} bytes in {}.", index.len(), index.total_size(), dir.to_string_lossy());
         FileCache {
-            dir: path
+            dir,
+            index: RwLock::new(index),
+            locks: (0..FILE_LOCKS).map(|_| Mutex::new(())).collect(),
+            catalog: None,
         }
     }
 
     pub fn generate_file_name(name: &str) -> String {
         format!("{:x}", md5::compute(name))
     }
+
+    fn lock(&self, file_name: &str) -> MutexGuard<'_, ()> {
+        let lock = file_name.get(..2).and_then(|prefix| usize::from_str_radix(prefix, 16).ok()).unwrap_or_default();
+        recover(self.locks[lock % FILE_LOCKS].lock())
+    }
 }
 
-impl CacheEngine for FileCache {
+impl SyncCacheEngine for FileCache {
     fn get(&self, name: &str) -> Option<Vec<u8>> {
-        let path = self.dir.join(FileCache::generate_file_name(name));
+        let file_name = FileCache::generate_file_name(name);
+        if !recover(self.index.read()).might_contain(&file_name) {
+            return Option::None;
+        }
+        let path = self.dir.join(&file_name);
+        let _lock = self.lock(&file_name);
         return match File::open(&path) {
             Ok(mut file) => {
                 debug!("Found file {} under: {}", name, path.to_string_lossy());
@@ -49,34 +86,63 @@ impl CacheEngine for FileCache {
         };
     }
 
-    fn set(&self, name: &str, data: &Vec<u8>) -> Result<bool, Error> {
-        let file_path = self.dir.join(FileCache::generate_file_name(name));
+    fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        let file_name = FileCache::generate_file_name(name);
+        let file_path = self.dir.join(&file_name);
 
-        let mut file = OpenOptions::new().create(true).write(true).read(true).open(
+        let _lock = self.lock(&file_name);
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).read(true).open(
             &file_path
         )?;
         debug!("Created file at {}", file_path.to_string_lossy());
         file.write_all(data).unwrap();
+        recover(self.index.write()).insert(&file_name, data.len() as u64);
         return Result::Ok(true);
     }
+
+    fn remove(&self, name: &str) -> Result<bool, Error> {
+        let file_name = FileCache::generate_file_name(name);
+        recover(self.index.write()).remove(&file_name);
+        let _lock = self.lock(&file_name);
+        match fs::remove_file(self.dir.join(&file_name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Only a catalog in the temp dir is removed, anywhere else it could hold more than the cache.
+    fn close(&self) -> Result<(), Error> {
+        let catalog = match &self.catalog {
+            Some(catalog) => catalog,
+            None => return Ok(()),
+        };
+        if catalog.starts_with(std::env::temp_dir()) {
+            info!("Cleaning temp dir: {}", catalog.to_string_lossy());
+            fs::remove_dir_all(catalog).unwrap_or_default();
+        } else {
+            warn!("Unable to delete file cache catalog. Temp is set to {} and it is outside of system's tmp dir: {}. Please remove the cache dir.", catalog.to_string_lossy(), std::env::temp_dir().to_string_lossy());
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::sync::Arc;
+    use std::thread;
 
     use tempfile;
 
-    use crate::cache::CacheEngine;
+    use crate::cache::SyncCacheEngine;
     use crate::cache::file_cache::FileCache;
 
     #[test]
     fn file_cache_set() {
         let temp_path = tempfile::TempDir::new().unwrap().into_path();
         let cache_name = "unit-test";
-        let file_cache = FileCache {
-            dir: temp_path.clone(),
-        };
+        let file_cache = FileCache::open(temp_path.clone());
         let data: Vec<u8> = Vec::from([0, 0, 0, 8]);
         file_cache.set(cache_name, &data).unwrap();
         let content = fs::read(temp_path.join(FileCache::generate_file_name(cache_name))).unwrap();
@@ -92,11 +158,39 @@ mod tests {
         let file_name = FileCache::generate_file_name(cache_name);
         fs::write(temp_path.join(file_name), &data).unwrap();
 
-        let file_cache = FileCache {
-            dir: temp_path.clone(),
-        };
+        let file_cache = FileCache::open(temp_path.clone());
         let content = file_cache.get(cache_name).unwrap();
         assert_eq!(data, content);
+        assert!(file_cache.get("missing").is_none());
+        fs::remove_dir_all(temp_path).unwrap();
+    }
+
+    #[test]
+    fn read_whole_entries_while_written() {
+        let temp_path = tempfile::TempDir::new().unwrap().into_path();
+        let file_cache = Arc::new(FileCache::open(temp_path.clone()));
+        let writers: Vec<_> = vec![vec![1; 100_000], vec![2; 10]].into_iter().map(|data| {
+            let file_cache = file_cache.clone();
+            thread::spawn(move || (0..50).for_each(|_| { file_cache.set("unit-test", &data).unwrap(); }))
+        }).collect();
+        for _ in 0..50 {
+            if let Some(content) = file_cache.get("unit-test") {
+                assert!(content == vec![1; 100_000] || content == vec![2; 10]);
+            }
+        }
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        fs::remove_dir_all(temp_path).unwrap();
+    }
+
+    #[test]
+    fn file_cache_remove() {
+        let temp_path = tempfile::TempDir::new().unwrap().into_path();
+        let file_cache = FileCache::open(temp_path.clone());
+        file_cache.set("unit-test", &Vec::from([1, 2, 3])).unwrap();
+
+        assert!(file_cache.remove("unit-test").unwrap());
+        assert!(file_cache.get("unit-test").is_none());
+        assert!(!file_cache.remove("unit-test").unwrap());
         fs::remove_dir_all(temp_path).unwrap();
     }
 }
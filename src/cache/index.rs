@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const BLOOM_BITS: usize = 1 << 23;
+const BLOOM_HASHES: u64 = 7;
+
+/// In-memory index of entries kept by a cache engine on slow storage. The bloom filter answers
+/// most misses without touching the storage, sizes track what is stored and how much space it takes.
+pub struct CacheIndex {
+    bloom: Vec<u64>,
+    sizes: HashMap<String, u64>,
+}
+
+impl CacheIndex {
+    pub fn new() -> Self {
+        CacheIndex {
+            bloom: vec![0; BLOOM_BITS / 64],
+            sizes: HashMap::default(),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, size: u64) {
+        for position in positions(name) {
+            self.bloom[position / 64] |= 1 << (position % 64);
+        }
+        self.sizes.insert(name.to_string(), size);
+    }
+
+    /// Forgets the entry. Its bits stay in the bloom filter, so lookups of it still reach the storage.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.sizes.remove(name).is_some()
+    }
+
+    /// `false` means the entry is surely not stored, `true` is wrong only for a small fraction of misses.
+    pub fn might_contain(&self, name: &str) -> bool {
+        positions(name).all(|position| self.bloom[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.sizes.values().sum()
+    }
+}
+
+impl Default for CacheIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn positions(name: &str) -> impl Iterator<Item = usize> {
+    let digest = md5::compute(name).0;
+    let first = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    let second = u64::from_le_bytes(digest[8..].try_into().unwrap());
+    (0..BLOOM_HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % BLOOM_BITS as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::index::CacheIndex;
+
+    #[test]
+    fn stored_entries_are_always_found() {
+        let mut index = CacheIndex::new();
+        for i in 0..1000 {
+            index.insert(&format!("entry {}", i), i);
+        }
+
+        assert!((0..1000).all(|i| index.might_contain(&format!("entry {}", i))));
+        assert!((0..1000).filter(|i| index.might_contain(&format!("missing {}", i))).count() < 10);
+        assert_eq!(index.len(), 1000);
+        assert_eq!(index.total_size(), 999 * 1000 / 2);
+    }
+}
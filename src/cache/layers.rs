@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+
+use crate::cache::{CacheEngine, shared, SharedCache};
+
+/// Stages of the pipeline which cache their results, named like the `stage` label of cache metrics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheLayer {
+    Fetch,
+    Decode,
+    Resize,
+    Filter,
+    Encode,
+}
+
+impl CacheLayer {
+    pub const ALL: [CacheLayer; 5] = [CacheLayer::Fetch, CacheLayer::Decode, CacheLayer::Resize, CacheLayer::Filter, CacheLayer::Encode];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheLayer::Fetch => "fetch",
+            CacheLayer::Decode => "decode",
+            CacheLayer::Resize => "resize",
+            CacheLayer::Filter => "filter",
+            CacheLayer::Encode => "encode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CacheLayer> {
+        CacheLayer::ALL.iter().copied().find(|layer| layer.name() == name)
+    }
+}
+
+/// Layers switched on and off at runtime, all of them are enabled at start.
+pub struct CacheLayers {
+    enabled: [AtomicBool; 5],
+}
+
+impl Default for CacheLayers {
+    fn default() -> Self {
+        CacheLayers { enabled: CacheLayer::ALL.map(|_| AtomicBool::new(true)) }
+    }
+}
+
+impl CacheLayers {
+    pub fn is_enabled(&self, layer: CacheLayer) -> bool {
+        self.enabled[layer as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, layer: CacheLayer, enabled: bool) {
+        self.enabled[layer as usize].store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> BTreeMap<&'static str, bool> {
+        CacheLayer::ALL.iter().map(|layer| (layer.name(), self.is_enabled(*layer))).collect()
+    }
+}
+
+/// View of the shared cache for a single stage. While the layer is disabled the stage misses
+/// every lookup and stores nothing, entries stored before stay in the cache.
+/// Opening and closing the shared cache is left to its owner.
+pub struct LayerCache {
+    inner: SharedCache,
+    layers: Arc<CacheLayers>,
+    layer: CacheLayer,
+}
+
+impl LayerCache {
+    pub fn new(inner: SharedCache, layers: Arc<CacheLayers>, layer: CacheLayer) -> Self {
+        LayerCache { inner, layers, layer }
+    }
+
+    /// Shared handle as the stages take it.
+    pub fn shared(inner: &SharedCache, layers: &Arc<CacheLayers>, layer: CacheLayer) -> SharedCache {
+        shared(Box::new(LayerCache::new(inner.clone(), layers.clone(), layer)))
+    }
+}
+
+#[async_trait]
+impl CacheEngine for LayerCache {
+    async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.layers.is_enabled(self.layer) {
+            return None;
+        }
+        self.inner.get(name).await
+    }
+
+    async fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        if !self.layers.is_enabled(self.layer) {
+            return Ok(false);
+        }
+        self.inner.set(name, data).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<bool, Error> {
+        self.inner.remove(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cache::{BlockingCache, HashMapCacheEngine, shared};
+    use crate::cache::layers::{CacheLayer, CacheLayers, LayerCache};
+
+    #[test]
+    fn skip_disabled_layer() {
+        let inner = shared(Box::new(HashMapCacheEngine::new()));
+        let layers = Arc::new(CacheLayers::default());
+        let encode = LayerCache::shared(&inner, &layers, CacheLayer::Encode);
+        encode.set_blocking("a", &[1]).unwrap();
+
+        layers.set(CacheLayer::Encode, false);
+        assert_eq!(encode.get_blocking("a"), None);
+        encode.set_blocking("b", &[2]).unwrap();
+        assert_eq!(inner.get_blocking("b"), None);
+        assert_eq!(LayerCache::shared(&inner, &layers, CacheLayer::Decode).get_blocking("a"), Some(vec![1]));
+
+        layers.set(CacheLayer::Encode, true);
+        assert_eq!(encode.get_blocking("a"), Some(vec![1]));
+        assert!(layers.report().values().all(|enabled| *enabled));
+    }
+}
@@ -0,0 +1,63 @@
+use std::io::Error;
+
+use async_trait::async_trait;
+
+use crate::cache::{CacheEngine, shared, SharedCache};
+
+/// View of the shared cache keeping the entries of a tenant apart from the others, every name
+/// is prefixed with the namespace. Opening and closing the shared cache is left to its owner.
+pub struct NamespacedCache {
+    inner: SharedCache,
+    namespace: String,
+}
+
+impl NamespacedCache {
+    pub fn new(inner: SharedCache, namespace: &str) -> Self {
+        NamespacedCache { inner, namespace: namespace.to_string() }
+    }
+
+    /// Shared handle as the stages take it.
+    pub fn shared(inner: &SharedCache, namespace: &str) -> SharedCache {
+        shared(Box::new(NamespacedCache::new(inner.clone(), namespace)))
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}/{}", self.namespace, name)
+    }
+}
+
+#[async_trait]
+impl CacheEngine for NamespacedCache {
+    async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        self.inner.get(&self.key(name)).await
+    }
+
+    async fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        self.inner.set(&self.key(name), data).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<bool, Error> {
+        self.inner.remove(&self.key(name)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{BlockingCache, HashMapCacheEngine, shared};
+    use crate::cache::namespace::NamespacedCache;
+
+    #[test]
+    fn keep_tenants_apart() {
+        let inner = shared(Box::new(HashMapCacheEngine::new()));
+        let acme = NamespacedCache::shared(&inner, "acme");
+        let globex = NamespacedCache::shared(&inner, "globex");
+        acme.set_blocking("a", &[1]).unwrap();
+
+        assert_eq!(acme.get_blocking("a"), Some(vec![1]));
+        assert_eq!(globex.get_blocking("a"), None);
+        assert_eq!(inner.get_blocking("a"), None);
+        assert_eq!(inner.get_blocking("acme/a"), Some(vec![1]));
+        assert!(!globex.remove_blocking("a").unwrap());
+        assert!(acme.remove_blocking("a").unwrap());
+    }
+}
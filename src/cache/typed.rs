@@ -0,0 +1,149 @@
+use std::io::{Error, Read, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use flate2::Compression as DeflateLevel;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cache::{block_on, SharedCache};
+
+/// Version of the entry layout, written in front of every entry. Entries of other versions,
+/// e.g. stored by an older release in a file cache, are misses and get written again.
+/// Bump it whenever a cached type changes its fields.
+pub const ENTRY_VERSION: u8 = 1;
+const PLAIN: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Compresses entries on their way to the engine.
+pub trait Compression: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Deflate, worth it for decoded images, which are raw pixels.
+pub struct Deflate;
+
+impl Compression for Deflate {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::fast());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(data).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// Values of a single type kept in the shared cache. Entries are written with bincode behind a
+/// header of the layout version and the compression. Entries which cannot be read are misses.
+pub struct Cache<T> {
+    engine: SharedCache,
+    compression: Option<Arc<dyn Compression>>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Cache<T> {
+    pub fn new(engine: &SharedCache) -> Self {
+        Cache { engine: engine.clone(), compression: None, value: PhantomData }
+    }
+
+    pub fn with_compression(self, compression: Arc<dyn Compression>) -> Self {
+        Cache { compression: Some(compression), ..self }
+    }
+
+    pub async fn get(&self, name: &str) -> Option<T> {
+        let entry = self.engine.get(name).await?;
+        match self.read(&entry) {
+            Ok(value) => value,
+            Err(e) => {
+                debug!("Unable to read cached {}, treated as a miss. Reason: {}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Stores the value, returns whether the engine kept it.
+    pub async fn set(&self, name: &str, value: &T) -> Result<bool, Error> {
+        let entry = self.write(value)?;
+        self.engine.set(name, &entry).await
+    }
+
+    pub fn get_blocking(&self, name: &str) -> Option<T> {
+        block_on(self.get(name))
+    }
+
+    /// Stores the value for stages, which go on without the cache when it fails. Failures are logged.
+    pub fn set_blocking(&self, name: &str, value: &T) -> bool {
+        match block_on(self.set(name, value)) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("Unable to write {} to cache. Reason: {}", name, e);
+                false
+            }
+        }
+    }
+
+    /// `None` for entries of another layout version.
+    fn read(&self, entry: &[u8]) -> Result<Option<T>, Error> {
+        let (header, payload) = match entry {
+            [version, compression, payload @ ..] if *version == ENTRY_VERSION => ([*compression], payload),
+            _ => return Ok(None),
+        };
+        let decompressed;
+        let payload = match (header, &self.compression) {
+            ([PLAIN], _) => payload,
+            ([COMPRESSED], Some(compression)) => {
+                decompressed = compression.decompress(payload)?;
+                &decompressed
+            }
+            _ => return Ok(None),
+        };
+        bincode::deserialize(payload).map(Some).map_err(Error::other)
+    }
+
+    fn write(&self, value: &T) -> Result<Vec<u8>, Error> {
+        let serialized = bincode::serialize(value).map_err(Error::other)?;
+        let (compression, payload) = match &self.compression {
+            Some(compression) => (COMPRESSED, compression.compress(&serialized)?),
+            None => (PLAIN, serialized),
+        };
+        Ok([&[ENTRY_VERSION, compression][..], &payload].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cache::{BlockingCache, HashMapCacheEngine, shared};
+    use crate::cache::typed::{Cache, Deflate, ENTRY_VERSION};
+
+    #[test]
+    fn read_only_entries_of_current_layout() {
+        let engine = shared(Box::new(HashMapCacheEngine::new()));
+        let cache: Cache<Vec<u32>> = Cache::new(&engine);
+        assert!(cache.set_blocking("a", &vec![1, 2, 3]));
+        assert_eq!(cache.get_blocking("a"), Some(vec![1, 2, 3]));
+        assert_eq!(engine.get_blocking("a").unwrap()[..2], [ENTRY_VERSION, 0]);
+
+        engine.set_blocking("old", &bincode::serialize(&vec![1u32]).unwrap()).unwrap();
+        assert_eq!(cache.get_blocking("old"), None);
+        engine.set_blocking("broken", &[ENTRY_VERSION, 0, 1]).unwrap();
+        assert_eq!(cache.get_blocking("broken"), None);
+
+        let compressed: Cache<Vec<u32>> = Cache::new(&engine).with_compression(Arc::new(Deflate));
+        assert!(compressed.set_blocking("b", &vec![7; 1000]));
+        assert!(engine.get_blocking("b").unwrap().len() < 100);
+        assert_eq!(compressed.get_blocking("b"), Some(vec![7; 1000]));
+        // Plain entries stay readable once compression is on.
+        assert_eq!(compressed.get_blocking("a"), Some(vec![1, 2, 3]));
+        assert_eq!(cache.get_blocking("b"), None);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use tokio::sync::oneshot;
+
+use crate::cache::{block_on, CacheEngine};
+use crate::metrics::{CACHE_WRITES_DROPPED, Metrics};
+use crate::recover;
+
+#[derive(Default)]
+struct Pending {
+    order: VecDeque<String>,
+    entries: HashMap<String, Vec<u8>>,
+    /// Entries taken off the queue and not written yet. They are served from `entries` until written.
+    writing: usize,
+}
+
+impl Pending {
+    /// Queues the entry, returns the name of the oldest entry dropped to make room for it.
+    /// An entry set again while it is written is queued once more.
+    fn push(&mut self, name: &str, data: &[u8], capacity: usize) -> Option<String> {
+        if self.entries.insert(name.to_string(), data.to_vec()).is_some() && self.order.iter().any(|queued| queued == name) {
+            return None;
+        }
+        self.order.push_back(name.to_string());
+        if self.order.len() <= capacity {
+            return None;
+        }
+        let dropped = self.order.pop_front()?;
+        self.entries.remove(&dropped);
+        Some(dropped)
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        self.order.retain(|queued| queued != name);
+        self.entries.remove(name).is_some()
+    }
+
+    fn pop(&mut self) -> Option<(String, Vec<u8>)> {
+        let name = self.order.pop_front()?;
+        let data = self.entries.get(&name)?.clone();
+        self.writing += 1;
+        Some((name, data))
+    }
+
+    /// Forgets the written entry, unless it was set again meanwhile.
+    fn finish(&mut self, name: &str, data: &[u8]) {
+        self.writing -= 1;
+        let replaced = self.entries.get(name).is_some_and(|pending| pending != data);
+        if !replaced && !self.order.iter().any(|queued| queued == name) {
+            self.entries.remove(name);
+        }
+    }
+}
+
+/// Writes to the wrapped engine on a background thread, so requests do not wait for slow storage.
+/// Entries waiting for the write are served from memory. When the queue is full the oldest
+/// waiting entry is dropped, which costs only a later cache miss.
+pub struct WriteBehindCache {
+    inner: Arc<dyn CacheEngine>,
+    pending: Arc<(Mutex<Pending>, Condvar)>,
+    capacity: usize,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+impl WriteBehindCache {
+    pub fn new(inner: Box<dyn CacheEngine>, capacity: usize, metrics: Arc<dyn Metrics + Send + Sync>) -> Self {
+        let inner: Arc<dyn CacheEngine> = Arc::from(inner);
+        let pending: Arc<(Mutex<Pending>, Condvar)> = Arc::default();
+        {
+            let inner = inner.clone();
+            let pending = pending.clone();
+            thread::Builder::new()
+                .name(String::from("pixvert-cache-writer"))
+                .spawn(move || loop {
+                    let (lock, changed) = &*pending;
+                    let (name, data) = {
+                        let mut waiting = recover(lock.lock());
+                        loop {
+                            match waiting.pop() {
+                                Some(entry) => break entry,
+                                None => waiting = recover(changed.wait(waiting)),
+                            }
+                        }
+                    };
+                    if let Err(e) = block_on(inner.set(&name, &data)) {
+                        error!("Unable to write {} to cache. Reason: {}", name, e);
+                    }
+                    recover(lock.lock()).finish(&name, &data);
+                    changed.notify_all();
+                })
+                .unwrap();
+        }
+        WriteBehindCache { inner, pending, capacity, metrics }
+    }
+}
+
+/// Writes the queued entries on the calling thread until none is waiting or being written.
+fn write_pending(inner: &dyn CacheEngine, pending: &(Mutex<Pending>, Condvar)) -> Result<(), Error> {
+    let (lock, changed) = pending;
+    loop {
+        let next = {
+            let mut waiting = recover(lock.lock());
+            while waiting.order.is_empty() && waiting.writing > 0 {
+                waiting = recover(changed.wait(waiting));
+            }
+            waiting.pop()
+        };
+        let (name, data) = match next {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let written = block_on(inner.set(&name, &data));
+        recover(lock.lock()).finish(&name, &data);
+        changed.notify_all();
+        written?;
+    }
+}
+
+#[async_trait]
+impl CacheEngine for WriteBehindCache {
+    async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        let pending = recover(self.pending.0.lock()).entries.get(name).cloned();
+        match pending {
+            Some(data) => Some(data),
+            None => self.inner.get(name).await,
+        }
+    }
+
+    async fn set(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        let (lock, changed) = &*self.pending;
+        let dropped = recover(lock.lock()).push(name, data, self.capacity);
+        changed.notify_all();
+        if let Some(dropped) = dropped {
+            debug!("Cache write queue is full, dropped {}.", dropped);
+            self.metrics.increment(CACHE_WRITES_DROPPED, &[]);
+        }
+        Ok(true)
+    }
+
+    async fn remove(&self, name: &str) -> Result<bool, Error> {
+        let pending = recover(self.pending.0.lock()).remove(name);
+        Ok(self.inner.remove(name).await? || pending)
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        self.inner.open().await
+    }
+
+    /// Writes the queued entries and waits for the one the writer is on. Waiting blocks, so it
+    /// happens on a thread of its own and the caller awaits it, on a runtime or not.
+    async fn flush(&self) -> Result<(), Error> {
+        let (inner, pending) = (self.inner.clone(), self.pending.clone());
+        let (sender, receiver) = oneshot::channel();
+        thread::Builder::new()
+            .name(String::from("pixvert-cache-flush"))
+            .spawn(move || sender.send(write_pending(inner.as_ref(), &pending)))?;
+        receiver.await.map_err(Error::other)??;
+        self.inner.flush().await
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cache::{block_on, CacheEngine, HashMapCacheEngine};
+    use crate::cache::write_behind::{Pending, WriteBehindCache};
+    use crate::metrics::NoMetrics;
+
+    #[test]
+    fn drop_oldest_entry_when_full() {
+        let mut pending = Pending::default();
+        assert_eq!(pending.push("a", &[1], 2), None);
+        assert_eq!(pending.push("b", &[2], 2), None);
+        assert_eq!(pending.push("a", &[3], 2), None);
+        assert_eq!(pending.push("c", &[4], 2), Some(String::from("a")));
+
+        assert_eq!(pending.pop(), Some((String::from("b"), vec![2])));
+        assert_eq!(pending.pop(), Some((String::from("c"), vec![4])));
+        assert_eq!(pending.pop(), None);
+    }
+
+    #[test]
+    fn serve_entries_until_written() {
+        let mut pending = Pending::default();
+        pending.push("a", &[1], 2);
+        let (name, data) = pending.pop().unwrap();
+        assert_eq!(pending.entries.get("a"), Some(&vec![1]));
+
+        assert_eq!(pending.push("a", &[2], 2), None);
+        pending.finish(&name, &data);
+        assert_eq!(pending.entries.get("a"), Some(&vec![2]));
+        assert_eq!(pending.pop(), Some((String::from("a"), vec![2])));
+        pending.finish("a", &[2]);
+        assert!(pending.entries.is_empty());
+    }
+
+    #[test]
+    fn flush_pending_writes() {
+        let cache = WriteBehindCache::new(Box::new(HashMapCacheEngine::new()), 100, Arc::new(NoMetrics {}));
+        for index in 0..50u8 {
+            block_on(cache.set(&index.to_string(), &[index])).unwrap();
+        }
+        block_on(cache.flush()).unwrap();
+        assert!(cache.pending.0.lock().unwrap().order.is_empty());
+        assert!((0..50u8).all(|index| block_on(cache.inner.get(&index.to_string())) == Some(vec![index])));
+    }
+
+    #[actix_web::test]
+    async fn flush_on_the_runtime() {
+        let cache = WriteBehindCache::new(Box::new(HashMapCacheEngine::new()), 100, Arc::new(NoMetrics {}));
+        for index in 0..50u8 {
+            cache.set(&index.to_string(), &[index]).await.unwrap();
+        }
+        cache.flush().await.unwrap();
+        assert!(cache.pending.0.lock().unwrap().order.is_empty());
+        for index in 0..50u8 {
+            assert_eq!(cache.inner.get(&index.to_string()).await, Some(vec![index]));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, OperationLimits, QualityPolicy};
+use crate::encoder::OutputFormat;
+use crate::selftest;
+
+/// What this deployment serves, for clients to detect instead of assuming it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub version: String,
+    /// Content types of sources which can be decoded.
+    pub input_formats: Vec<String>,
+    /// Names of the formats images are served in, see `OutputFormat::name`, limited to the allowed ones.
+    pub output_formats: Vec<String>,
+    pub features: Features,
+    pub limits: Limits,
+    pub presets: Vec<String>,
+    pub default_output_format: Option<String>,
+    pub quality_policy: QualityPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Features {
+    pub webp: bool,
+    pub avif: bool,
+    pub heic: bool,
+    /// CR2, NEF and DNG sources are developed into previews.
+    pub raw: bool,
+    /// Animated GIF, WebP and PNG sources keep their frames.
+    pub animated: bool,
+    pub svg: bool,
+    pub simd: bool,
+    pub libvips: bool,
+    pub wgpu: bool,
+    pub mozjpeg: bool,
+    pub upload: bool,
+    pub signing: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// Largest number of pixels of a resized image.
+    pub maximum_image_size: usize,
+    /// Largest upload in bytes, none when uploads are off.
+    pub maximum_upload_size: Option<usize>,
+    pub operations: OperationLimits,
+}
+
+pub fn capabilities(config: &Config) -> Capabilities {
+    let output_formats = output_formats().iter()
+        .map(OutputFormat::name)
+        .filter(|name| config.allows_output_format(name))
+        .map(str::to_string)
+        .collect();
+    let mut presets: Vec<String> = config.presets.keys().cloned().collect();
+    presets.sort();
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        input_formats: input_formats().into_iter().map(str::to_string).collect(),
+        output_formats,
+        features: Features {
+            webp: cfg!(feature = "webp"),
+            avif: cfg!(feature = "avif"),
+            heic: cfg!(feature = "heic"),
+            raw: cfg!(feature = "raw"),
+            animated: true,
+            svg: false,
+            simd: cfg!(feature = "simd"),
+            libvips: cfg!(feature = "libvips"),
+            wgpu: cfg!(feature = "wgpu"),
+            mozjpeg: cfg!(feature = "mozjpeg"),
+            upload: config.upload.enabled,
+            signing: !config.signing.key.is_empty(),
+        },
+        limits: Limits {
+            maximum_image_size: config.maximum_image_size,
+            maximum_upload_size: config.upload.enabled.then_some(config.upload.maximum_size),
+            operations: config.operation_limits.clone(),
+        },
+        presets,
+        default_output_format: config.default_output_format.clone(),
+        quality_policy: config.quality_policy.clone(),
+    }
+}
+
+fn input_formats() -> Vec<&'static str> {
+    let formats = vec!["image/jpeg", "image/png", "image/gif", "image/webp", "image/bmp", "image/tiff", "image/x-tga"];
+    #[cfg(feature = "heic")]
+    let formats = [formats, vec!["image/heic", "image/heif"]].concat();
+    #[cfg(feature = "avif-decoder")]
+    let formats = [formats, vec!["image/avif"]].concat();
+    #[cfg(feature = "raw")]
+    let formats = [formats, vec!["image/x-canon-cr2", "image/x-nikon-nef", "image/x-adobe-dng"]].concat();
+    formats
+}
+
+/// Every output format of this build, including the ones the self-test leaves out.
+fn output_formats() -> Vec<OutputFormat> {
+    let formats = selftest::output_formats();
+    let formats = [formats, vec![OutputFormat::Png8, OutputFormat::Apng]].concat();
+    #[cfg(feature = "webp")]
+    let formats = [formats, vec![OutputFormat::WebpNearLossless(60, crate::encoder::WebpOptions::default())]].concat();
+    formats
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capabilities::capabilities;
+    use crate::config::{Config, UploadSettings};
+
+    #[test]
+    fn capabilities_follow_config() {
+        let config = Config {
+            allowed_output_formats: vec![String::from("jpeg"), String::from("png8"), String::from("svg")],
+            upload: UploadSettings { enabled: true, maximum_size: 1024, ..UploadSettings::default() },
+            ..Config::default()
+        };
+        let restricted = capabilities(&config);
+        assert_eq!(restricted.output_formats, vec!["jpeg", "png8"]);
+        assert_eq!(restricted.limits.maximum_upload_size, Some(1024));
+        assert!(restricted.input_formats.contains(&String::from("image/jpeg")));
+
+        let unrestricted = capabilities(&Config::default());
+        assert!(unrestricted.output_formats.contains(&String::from("gif")));
+        assert_eq!(unrestricted.limits.maximum_upload_size, None);
+    }
+}
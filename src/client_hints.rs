@@ -0,0 +1,80 @@
+use actix_web::http::header::HeaderMap;
+
+use crate::config::ClientHintSettings;
+
+pub const ACCEPT_CH: &str = "accept-ch";
+pub const CONTENT_DPR: &str = "content-dpr";
+/// Hints requested from browsers with `Accept-CH` and varied on, legacy names are still read.
+pub const ACCEPTED_HINTS: &str = "Sec-CH-Width, Sec-CH-Viewport-Width, Sec-CH-DPR";
+pub const VARY_HINTS: &str = "Sec-CH-Width, Width, Sec-CH-Viewport-Width, Viewport-Width, Sec-CH-DPR, DPR";
+
+fn hint(headers: &HeaderMap, names: &[&str]) -> Option<f64> {
+    names.iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|value| value.is_finite() && *value > 0.0)
+}
+
+fn hinted_dpr(headers: &HeaderMap, settings: &ClientHintSettings) -> Option<f64> {
+    hint(headers, &["sec-ch-dpr", "dpr"]).map(|dpr| dpr.min(settings.maximum_dpr))
+}
+
+fn physical_width(headers: &HeaderMap, settings: &ClientHintSettings) -> Option<f64> {
+    let dpr = hinted_dpr(headers, settings).unwrap_or(1.0);
+    hint(headers, &["sec-ch-width", "width"])
+        .or_else(|| hint(headers, &["sec-ch-viewport-width", "viewport-width"]).map(|width| width * dpr))
+}
+
+/// Width in physical pixels the client will display the image at. `Width` hints already are
+/// in physical pixels, `Viewport-Width` is in CSS pixels and gets multiplied by `DPR`.
+/// Without any hint, or with a hint above the limit, the configured maximum is used.
+pub fn hinted_width(headers: &HeaderMap, settings: &ClientHintSettings) -> u32 {
+    match physical_width(headers, settings) {
+        Some(width) => (width.ceil() as u32).clamp(1, settings.maximum_width),
+        None => settings.maximum_width,
+    }
+}
+
+/// Density of the served image: pixels of its width per CSS pixel the client lays it out at.
+/// Lower than `DPR` when the source was narrower than the hinted width.
+pub fn content_dpr(headers: &HeaderMap, settings: &ClientHintSettings, served_width: u32) -> Option<f64> {
+    let dpr = hinted_dpr(headers, settings)?;
+    let css_width = physical_width(headers, settings)? / dpr;
+    Some(f64::min(served_width as f64 / css_width, dpr))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use crate::client_hints::{content_dpr, hinted_width};
+    use crate::config::ClientHintSettings;
+
+    fn headers(hints: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in hints {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn width_from_hints() {
+        let settings = ClientHintSettings { maximum_width: 2000, maximum_dpr: 2.0 };
+        assert_eq!(hinted_width(&headers(&[("sec-ch-width", "640")]), &settings), 640);
+        assert_eq!(hinted_width(&headers(&[("sec-ch-viewport-width", "400"), ("sec-ch-dpr", "1.5")]), &settings), 600);
+        assert_eq!(hinted_width(&headers(&[("viewport-width", "400"), ("dpr", "4")]), &settings), 800);
+        assert_eq!(hinted_width(&headers(&[("sec-ch-width", "9000")]), &settings), 2000);
+        assert_eq!(hinted_width(&headers(&[("sec-ch-width", "wide")]), &settings), 2000);
+    }
+
+    #[test]
+    fn content_dpr_of_served_width() {
+        let settings = ClientHintSettings::default();
+        let hints = headers(&[("sec-ch-viewport-width", "500"), ("sec-ch-dpr", "2")]);
+        assert_eq!(content_dpr(&hints, &settings, 1000), Some(2.0));
+        assert_eq!(content_dpr(&hints, &settings, 300), Some(0.6));
+        assert_eq!(content_dpr(&headers(&[("sec-ch-dpr", "2")]), &settings, 300), None);
+    }
+}
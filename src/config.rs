@@ -1,4 +1,11 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::encoder::{ChromaSubsampling, JpegEncoder};
+use crate::mode::ServiceMode;
+use crate::rendition::Rendition;
 
 #[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -7,17 +14,40 @@ pub enum CacheType {
     File(String),
 }
 
-#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+/// Cache-Control of sources from matching domains. `domain` with `*` or `?` is a glob matched
+/// against the host, e.g. `*.example.com`, otherwise every source URL containing it matches.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct OverriddenCache {
     pub domain: String,
+    /// Replaces the header of the origin, empty keeps it.
+    #[serde(default)]
     pub cache_control: String,
+    /// Replaces only the directives of the origin with the same name, e.g. to add `s-maxage`.
+    #[serde(default)]
+    pub merge: bool,
+    /// Takes the place of `cacheControl` in deciding how long the source is cached.
+    #[serde(default)]
+    pub internal_cache_control: Option<String>,
+    /// Takes the place of `cacheControl` in responses to clients.
+    #[serde(default)]
+    pub client_cache_control: Option<String>,
 }
 
 #[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ApplicationCache {
     pub cache_type: CacheType,
+    /// Entries waiting to be written in the background, `0` writes while serving the request.
+    #[serde(default)]
+    pub write_buffer: usize,
+    /// How eagerly sources are revalidated before they expire, `0` waits for the expiry.
+    #[serde(default = "default_early_expiration_beta")]
+    pub early_expiration_beta: f64,
+}
+
+fn default_early_expiration_beta() -> f64 {
+    1.0
 }
 
 #[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
@@ -26,6 +56,561 @@ pub struct CorsSettings {
     pub origin: String,
 }
 
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MetricsBackend {
+    #[default]
+    None,
+    Prometheus,
+    Statsd(String),
+    Dogstatsd(String),
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSettings {
+    pub backend: MetricsBackend,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExperimentalSettings {
+    pub seam_carving: bool,
+}
+
+/// SIMD extension used by the resizer. `auto` picks the best one supported by the CPU at runtime.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum CpuExtension {
+    #[default]
+    Auto,
+    None,
+    Sse4_1,
+    Avx2,
+    Neon,
+    Simd128,
+}
+
+/// Library resizing images. `fast_image_resize` is the fastest with SIMD, `image` needs no extra
+/// dependencies and `libvips` keeps the least memory around for large images. `wgpu` is an
+/// experiment resizing on the GPU, for deployments which have one.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizerBackend {
+    Image,
+    #[default]
+    FastImageResize,
+    Libvips,
+    Wgpu,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResizerSettings {
+    pub backend: ResizerBackend,
+    pub cpu_extension: CpuExtension,
+}
+
+/// Pipeline decoding, resizing and encoding images. `libvips` streams still JPEG, PNG and WebP
+/// sources resized into JPEG, PNG or WebP without operations, which keeps far less memory around
+/// for large images. Everything else goes through the `native` pipeline.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineBackend {
+    #[default]
+    Native,
+    Libvips,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PipelineSettings {
+    pub backend: PipelineBackend,
+}
+
+/// Number of threads dedicated to a stage, `0` runs the stage on the thread handling the request.
+/// Transforms estimated to cost more than `lowPriorityCost` (source pixels times codec weight)
+/// wait behind cheaper ones in the transform pool.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WorkerSettings {
+    pub decode: usize,
+    pub encode: usize,
+    pub transform: usize,
+    pub low_priority_cost: u64,
+}
+
+impl Default for WorkerSettings {
+    fn default() -> Self {
+        WorkerSettings {
+            decode: 0,
+            encode: 0,
+            transform: 0,
+            low_priority_cost: 3840 * 2160 * 2, // 4K JPEG
+        }
+    }
+}
+
+/// Daily limits of an API key, missing limit means unlimited.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyQuota {
+    pub key: String,
+    #[serde(default)]
+    pub requests_per_day: Option<u64>,
+    #[serde(default)]
+    pub transform_seconds_per_day: Option<f64>,
+    #[serde(default)]
+    pub bytes_per_day: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QuotaSettings {
+    pub header: String,
+    pub require_key: bool,
+    pub keys: Vec<ApiKeyQuota>,
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        QuotaSettings {
+            header: String::from("X-Api-Key"),
+            require_key: false,
+            keys: vec![],
+        }
+    }
+}
+
+/// gRPC server started next to HTTP, available in builds with the `grpc` feature.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GrpcSettings {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for GrpcSettings {
+    fn default() -> Self {
+        GrpcSettings {
+            enabled: false,
+            address: String::from("0.0.0.0:50051"),
+        }
+    }
+}
+
+/// HTTP/3 listener started next to HTTP, available in builds with the `http3` feature.
+/// QUIC always runs over TLS, so it needs a PEM certificate chain and private key.
+/// HTTP responses advertise it in `Alt-Svc`, clients remember it for `maxAgeSeconds`.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Http3Settings {
+    pub enabled: bool,
+    pub address: String,
+    pub certificate: String,
+    pub private_key: String,
+    pub max_age_seconds: u64,
+    /// Largest request body passed on, in bytes.
+    pub maximum_body_size: usize,
+}
+
+impl Http3Settings {
+    /// `Alt-Svc` value pointing clients to the port of the listener.
+    pub fn alt_svc(&self) -> Option<String> {
+        let port = self.address.rsplit(':').next()?.parse::<u16>().ok()?;
+        Some(format!("h3=\":{}\"; ma={}", port, self.max_age_seconds))
+    }
+}
+
+impl Default for Http3Settings {
+    fn default() -> Self {
+        Http3Settings {
+            enabled: false,
+            address: String::from("0.0.0.0:443"),
+            certificate: String::new(),
+            private_key: String::new(),
+            max_age_seconds: 86400,
+            maximum_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Draining waits up to `timeoutSeconds` for transforms in flight, shutting down on a signal
+/// waits as long for the requests in flight.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DrainSettings {
+    pub timeout_seconds: u64,
+}
+
+impl Default for DrainSettings {
+    fn default() -> Self {
+        DrainSettings { timeout_seconds: 30 }
+    }
+}
+
+/// EXIF, XMP and IPTC metadata of sources served as they are is stripped unless turned off here
+/// or kept with `?metadata=keep`. ICC profiles are kept with `keepColorProfile`, without them
+/// wide-gamut images show desaturated. Decoded sources are turned upright as their EXIF orientation
+/// describes unless `autoOrient` is off.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MetadataSettings {
+    pub strip: bool,
+    pub keep_color_profile: bool,
+    pub auto_orient: bool,
+}
+
+impl Default for MetadataSettings {
+    fn default() -> Self {
+        MetadataSettings { strip: true, keep_color_profile: false, auto_orient: true }
+    }
+}
+
+/// Image URLs must carry a signature made with `key` once it is set, see `signing::sign`.
+/// Expiring links stay valid `clockSkewSeconds` longer, as the clocks of the servers issuing
+/// them may run behind.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SigningSettings {
+    pub key: String,
+    pub clock_skew_seconds: i64,
+}
+
+impl Default for SigningSettings {
+    fn default() -> Self {
+        SigningSettings { key: String::new(), clock_skew_seconds: 60 }
+    }
+}
+
+/// Site served by the same process, picked by any of `hosts` or by `pathPrefix`, e.g. `/acme`.
+/// Settings left out are taken from the top level of the config. Cache entries of the tenant
+/// are kept under its `name`, apart from the ones of other tenants.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TenantSettings {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub path_prefix: String,
+    pub allow_from: Option<Vec<String>>,
+    pub allowed_output_formats: Option<Vec<String>>,
+    pub signing: Option<SigningSettings>,
+    pub presets: Option<HashMap<String, String>>,
+    pub quality_policy: Option<QualityPolicy>,
+    pub default_output_format: Option<String>,
+}
+
+impl TenantSettings {
+    /// Config the requests of the tenant are served with.
+    pub fn apply(&self, config: &Config) -> Config {
+        let config = config.clone();
+        Config {
+            allow_from: self.allow_from.clone().unwrap_or(config.allow_from),
+            allowed_output_formats: self.allowed_output_formats.clone().unwrap_or(config.allowed_output_formats),
+            signing: self.signing.clone().unwrap_or(config.signing),
+            presets: self.presets.clone().unwrap_or(config.presets),
+            quality_policy: self.quality_policy.clone().unwrap_or(config.quality_policy),
+            default_output_format: self.default_output_format.clone().or(config.default_output_format),
+            tenants: vec![],
+            ..config
+        }
+    }
+}
+
+/// Broker events about served images are published to. Kafka is available in builds with the `kafka` feature.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum EventsBackend {
+    #[default]
+    None,
+    Nats(String),
+    Kafka(String),
+}
+
+/// Events are sent in batches of up to `batchSize`, at least every `flushIntervalMs`.
+/// Up to `buffer` events, at least one, wait for the broker, the ones above are dropped.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EventsSettings {
+    pub backend: EventsBackend,
+    pub topic: String,
+    pub batch_size: usize,
+    pub flush_interval_ms: u64,
+    pub buffer: usize,
+}
+
+impl Default for EventsSettings {
+    fn default() -> Self {
+        EventsSettings {
+            backend: EventsBackend::None,
+            topic: String::from("pixvert.transforms"),
+            batch_size: 100,
+            flush_interval_ms: 1000,
+            buffer: 10000,
+        }
+    }
+}
+
+/// Local `directory` served by an origin under `baseUrl`. Files appearing in it are fetched
+/// from the origin and rendered to `renditions` right away. Watching is off while `directory` is empty.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WatchSettings {
+    pub directory: String,
+    pub base_url: String,
+    pub interval_ms: u64,
+    pub renditions: Vec<Rendition>,
+}
+
+impl Default for WatchSettings {
+    fn default() -> Self {
+        WatchSettings {
+            directory: String::new(),
+            base_url: String::new(),
+            interval_ms: 2000,
+            renditions: vec![],
+        }
+    }
+}
+
+/// Requests sent to origins. `accept` is sent as `Accept`, e.g. `image/webp,image/*;q=0.8`, so origins
+/// which negotiate formats may answer with a modern one. Empty keeps the default of the HTTP client.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OriginSettings {
+    pub accept: String,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EncodingSettings {
+    /// Transformed images which keep the dimensions of the source but come out larger than it,
+    /// e.g. a JPEG converted to lossless WebP, are served as the original.
+    pub keep_smaller_original: bool,
+    /// Chroma subsampling of JPEGs which do not ask for one in the format.
+    pub jpeg_subsampling: ChromaSubsampling,
+    /// `mozjpeg` needs a build with the mozjpeg feature, others write JPEGs with image.
+    pub jpeg_encoder: JpegEncoder,
+}
+
+impl Default for EncodingSettings {
+    fn default() -> Self {
+        EncodingSettings {
+            keep_smaller_original: true,
+            jpeg_subsampling: ChromaSubsampling::default(),
+            jpeg_encoder: JpegEncoder::default(),
+        }
+    }
+}
+
+/// Cache-Control sent to clients for each kind of image response, in place of the one of the origin
+/// or of pixvert. Kinds left out keep the header they are sent with now.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CacheControlPolicy {
+    /// Source served as it is.
+    pub original: Option<String>,
+    /// Transformed image in the dimensions of the source.
+    pub rendition: Option<String>,
+    /// Transformed image sized by the path or by client hints.
+    pub thumbnail: Option<String>,
+    pub error: Option<String>,
+    /// Image served in maintenance.
+    pub placeholder: Option<String>,
+}
+
+/// Policy of image routes, with overrides for paths starting with a prefix, e.g. `/auto/`.
+/// The first matching route wins, kinds it leaves out fall back to the policy of every route.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResponseCacheControlSettings {
+    #[serde(flatten)]
+    pub policy: CacheControlPolicy,
+    pub routes: Vec<RouteCacheControl>,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RouteCacheControl {
+    pub prefix: String,
+    #[serde(flatten)]
+    pub policy: CacheControlPolicy,
+}
+
+/// Most requested renditions, counted over the last `windowSeconds` in buckets of `bucketSeconds`
+/// and listed at `/admin/hot-renditions`. With `file` set, buckets are written there as they close
+/// and read back on start.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HotRenditionSettings {
+    pub enabled: bool,
+    pub window_seconds: u64,
+    pub bucket_seconds: u64,
+    /// Distinct renditions counted in a bucket, the ones requested after it fills up are not.
+    pub maximum_renditions: usize,
+    pub file: String,
+}
+
+impl Default for HotRenditionSettings {
+    fn default() -> Self {
+        HotRenditionSettings {
+            enabled: false,
+            window_seconds: 24 * 60 * 60,
+            bucket_seconds: 10 * 60,
+            maximum_renditions: 10_000,
+            file: String::new(),
+        }
+    }
+}
+
+/// `record` fetches sources from origins and stores their responses in `directory`, `replay` serves
+/// sources from there and never contacts origins, e.g. in end-to-end tests or offline development.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FixtureSettings {
+    pub mode: FixtureMode,
+    pub directory: String,
+}
+
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum FixtureMode {
+    #[default]
+    Off,
+    Record,
+    Replay,
+}
+
+/// Mode the instance starts in, it can be switched later through `/admin/mode`.
+/// In maintenance the image at `placeholder` is served instead of every requested one.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ModeSettings {
+    pub initial: ServiceMode,
+    pub placeholder: String,
+}
+
+/// Other instances with their own caches, e.g. `http://pixvert-2:8080`. Purges are forwarded to them.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClusterSettings {
+    pub peers: Vec<String>,
+    pub timeout_ms: u64,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        ClusterSettings {
+            peers: vec![],
+            timeout_ms: 2000,
+        }
+    }
+}
+
+/// Credential of the admin routes which change the instance, sent as `Authorization: Bearer {token}`.
+/// Without a token they are disabled.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AdminSettings {
+    pub token: String,
+}
+
+/// Images sent to `POST /upload` by holders of API keys listed in quotas. Size is in bytes.
+/// Stored results are served from `GET /r/{id}` until retention passes.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UploadSettings {
+    pub enabled: bool,
+    pub maximum_size: usize,
+    pub store_results: bool,
+    pub retention_seconds: u64,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        UploadSettings {
+            enabled: false,
+            maximum_size: 10 * 1024 * 1024,
+            store_results: false,
+            retention_seconds: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// What happens to a quality out of the range of the codec, e.g. `jpeg0` or `webp150`.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum QualityPolicy {
+    #[default]
+    Reject,
+    Clamp,
+}
+
+/// Limits of images sized by client hints, the width is never above `maximumWidth`.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClientHintSettings {
+    pub maximum_width: u32,
+    pub maximum_dpr: f64,
+}
+
+impl Default for ClientHintSettings {
+    fn default() -> Self {
+        ClientHintSettings {
+            maximum_width: 3840,
+            maximum_dpr: 3.0,
+        }
+    }
+}
+
+/// Placeholders of the LQIP workflow requested with `?preload={width}_{height}` link to the full
+/// rendition of that size in a `Link: rel=preload` header, so browsers fetch it while they show
+/// the placeholder.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EarlyHintsSettings {
+    pub enabled: bool,
+}
+
+/// Requests with `Save-Data: on` get quality lowered by `qualityDelta`, lossless formats are served as lossy WebP.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SaveDataSettings {
+    pub enabled: bool,
+    pub quality_delta: u8,
+}
+
+impl Default for SaveDataSettings {
+    fn default() -> Self {
+        SaveDataSettings {
+            enabled: true,
+            quality_delta: 20,
+        }
+    }
+}
+
+/// Bounds of the `ops` chain of a request, so a crafted chain cannot keep a worker busy.
+/// Weights of operations are listed in the README, e.g. `fit:seam` weighs 8 and `denoise` 4.
+#[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OperationLimits {
+    pub maximum_operations: usize,
+    /// Sum of the weights of the chain.
+    pub maximum_cost: u32,
+    /// Times an operation, by name, can appear in a chain. Operations left out are not limited.
+    pub maximum_repeats: HashMap<String, usize>,
+}
+
+impl Default for OperationLimits {
+    fn default() -> Self {
+        OperationLimits {
+            maximum_operations: 8,
+            maximum_cost: 16,
+            maximum_repeats: HashMap::from([(String::from("fit"), 1), (String::from("denoise"), 1)]),
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
@@ -34,7 +619,74 @@ pub struct Config {
     pub overridden_cache: Vec<OverriddenCache>,
     pub maximum_image_size: usize,
     pub cache: ApplicationCache,
-
+    #[serde(default)]
+    pub experimental: ExperimentalSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub resizer: ResizerSettings,
+    #[serde(default)]
+    pub pipeline: PipelineSettings,
+    #[serde(default)]
+    pub workers: WorkerSettings,
+    #[serde(default)]
+    pub quotas: QuotaSettings,
+    #[serde(default)]
+    pub save_data: SaveDataSettings,
+    #[serde(default)]
+    pub client_hints: ClientHintSettings,
+    #[serde(default)]
+    pub early_hints: EarlyHintsSettings,
+    #[serde(default)]
+    pub quality_policy: QualityPolicy,
+    /// Format of requests without one in the path, e.g. `webp80`, in place of negotiating it.
+    #[serde(default)]
+    pub default_output_format: Option<String>,
+    /// Names of the formats images are served in, see `OutputFormat::name`. Empty allows all.
+    #[serde(default)]
+    pub allowed_output_formats: Vec<String>,
+    /// Formats served under `/preset/{name}/{url}`, e.g. `thumb: webp60`.
+    #[serde(default)]
+    pub presets: HashMap<String, String>,
+    #[serde(default)]
+    pub grpc: GrpcSettings,
+    #[serde(default)]
+    pub http3: Http3Settings,
+    #[serde(default)]
+    pub drain: DrainSettings,
+    #[serde(default)]
+    pub metadata: MetadataSettings,
+    #[serde(default)]
+    pub signing: SigningSettings,
+    #[serde(default)]
+    pub tenants: Vec<TenantSettings>,
+    #[serde(default)]
+    pub events: EventsSettings,
+    #[serde(default)]
+    pub watch: WatchSettings,
+    #[serde(default)]
+    pub mode: ModeSettings,
+    #[serde(default)]
+    pub cluster: ClusterSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub upload: UploadSettings,
+    #[serde(default)]
+    pub origin: OriginSettings,
+    #[serde(default)]
+    pub encoding: EncodingSettings,
+    #[serde(default)]
+    pub response_cache_control: ResponseCacheControlSettings,
+    #[serde(default)]
+    pub hot_renditions: HotRenditionSettings,
+    #[serde(default)]
+    pub fixtures: FixtureSettings,
+    #[serde(default)]
+    pub operation_limits: OperationLimits,
+    /// Image requests taking longer are logged with the timings of their stages, `None` logs none.
+    #[serde(default)]
+    pub slow_request_threshold_ms: Option<u64>,
 }
 
 impl Default for Config {
@@ -49,9 +701,114 @@ impl Default for Config {
                 OverriddenCache {
                     domain: String::from("localhost"),
                     cache_control: String::from("immutable"),
+                    ..OverriddenCache::default()
                 }
             ],
-            cache: ApplicationCache { cache_type: CacheType::InMemory },
+            cache: ApplicationCache {
+                cache_type: CacheType::InMemory,
+                write_buffer: 0,
+                early_expiration_beta: default_early_expiration_beta(),
+            },
+            experimental: ExperimentalSettings::default(),
+            metrics: MetricsSettings::default(),
+            resizer: ResizerSettings::default(),
+            pipeline: PipelineSettings::default(),
+            workers: WorkerSettings::default(),
+            quotas: QuotaSettings::default(),
+            save_data: SaveDataSettings::default(),
+            client_hints: ClientHintSettings::default(),
+            early_hints: EarlyHintsSettings::default(),
+            quality_policy: QualityPolicy::default(),
+            default_output_format: None,
+            allowed_output_formats: vec![],
+            presets: HashMap::new(),
+            grpc: GrpcSettings::default(),
+            http3: Http3Settings::default(),
+            drain: DrainSettings::default(),
+            metadata: MetadataSettings::default(),
+            signing: SigningSettings::default(),
+            tenants: vec![],
+            events: EventsSettings::default(),
+            watch: WatchSettings::default(),
+            mode: ModeSettings::default(),
+            cluster: ClusterSettings::default(),
+            admin: AdminSettings::default(),
+            upload: UploadSettings::default(),
+            origin: OriginSettings::default(),
+            encoding: EncodingSettings::default(),
+            response_cache_control: ResponseCacheControlSettings::default(),
+            hot_renditions: HotRenditionSettings::default(),
+            fixtures: FixtureSettings::default(),
+            operation_limits: OperationLimits::default(),
+            slow_request_threshold_ms: None,
+        }
+    }
+}
+
+impl Config {
+    /// Whether sources may be fetched from the URL, its host is checked against `allowFrom` like by the fetcher.
+    pub fn allows_source(&self, url: &str) -> bool {
+        if self.allow_from.is_empty() {
+            return true;
         }
+        match Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)) {
+            Some(host) => self.allow_from.iter().any(|allowed_host| host.ends_with(allowed_host.as_str())),
+            None => false,
+        }
+    }
+
+    /// Whether images may be served in the format of the name, see `OutputFormat::name`.
+    pub fn allows_output_format(&self, name: &str) -> bool {
+        self.allowed_output_formats.is_empty() || self.allowed_output_formats.iter().any(|allowed| allowed == name)
+    }
+
+    /// Tenants are told apart by their names, and need a host or a path prefix to be picked by.
+    pub fn check_tenants(&self) -> Result<(), TenantError> {
+        let mut names: Vec<&str> = vec![];
+        for tenant in &self.tenants {
+            if tenant.name.is_empty() {
+                return Err(TenantError::MissingName);
+            }
+            if names.contains(&tenant.name.as_str()) {
+                return Err(TenantError::DuplicateName(tenant.name.clone()));
+            }
+            if tenant.hosts.is_empty() && tenant.path_prefix.trim_end_matches('/').is_empty() {
+                return Err(TenantError::Unreachable(tenant.name.clone()));
+            }
+            names.push(&tenant.name);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TenantError {
+    MissingName,
+    DuplicateName(String),
+    /// Neither hosts nor a path prefix, the tenant would take every request.
+    Unreachable(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, TenantError, TenantSettings};
+
+    #[test]
+    fn check_tenants() {
+        let tenant = |name: &str, hosts: &[&str], path_prefix: &str| TenantSettings {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            path_prefix: path_prefix.to_string(),
+            ..TenantSettings::default()
+        };
+        let check = |tenants| Config { tenants, ..Config::default() }.check_tenants();
+        assert_eq!(check(vec![]), Ok(()));
+        assert_eq!(check(vec![tenant("acme", &["acme.example.com"], ""), tenant("globex", &[], "/globex")]), Ok(()));
+        assert_eq!(check(vec![tenant("", &["acme.example.com"], "")]), Err(TenantError::MissingName));
+        assert_eq!(check(vec![tenant("acme", &[], "/")]), Err(TenantError::Unreachable(String::from("acme"))));
+        assert_eq!(
+            check(vec![tenant("acme", &[], "/a"), tenant("acme", &[], "/b")]),
+            Err(TenantError::DuplicateName(String::from("acme"))),
+        );
     }
 }
@@ -0,0 +1,237 @@
+use image_crate::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use image_crate::imageops::overlay;
+use serde::Deserialize;
+
+use crate::operations::Color;
+
+/// Sources of a single sheet, more are refused.
+pub const MAXIMUM_SOURCES: usize = 100;
+pub const MAXIMUM_TILE_SIZE: u32 = 1024;
+const MAXIMUM_COLUMNS: usize = 20;
+
+/// Glyphs are drawn in 3x5 cells scaled up, with a column and a row of spacing.
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_WIDTH: u32 = 4 * GLYPH_SCALE;
+const LABEL_HEIGHT: u32 = 7 * GLYPH_SCALE;
+
+/// Grid of thumbnails of the sources, in the order they are listed.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ContactSheetRequest {
+    pub urls: Vec<String>,
+    pub columns: usize,
+    /// Thumbnails fit into a square of this size.
+    pub tile_size: u32,
+    pub gap: u32,
+    /// Draws the file name of the source under its thumbnail.
+    pub labels: bool,
+    /// Hex color, e.g. `ffffff`.
+    pub background: String,
+    pub format: String,
+}
+
+impl Default for ContactSheetRequest {
+    fn default() -> Self {
+        ContactSheetRequest {
+            urls: vec![],
+            columns: 4,
+            tile_size: 200,
+            gap: 8,
+            labels: false,
+            background: String::from("ffffff"),
+            format: String::from("jpeg"),
+        }
+    }
+}
+
+impl ContactSheetRequest {
+    pub fn validate(&self) -> Result<Color, String> {
+        if self.urls.is_empty() || self.urls.len() > MAXIMUM_SOURCES {
+            return Err(format!("Between 1 and {} URLs are required.", MAXIMUM_SOURCES));
+        }
+        if self.columns == 0 || self.columns > MAXIMUM_COLUMNS {
+            return Err(format!("Columns have to be between 1 and {}.", MAXIMUM_COLUMNS));
+        }
+        if self.tile_size == 0 || self.tile_size > MAXIMUM_TILE_SIZE {
+            return Err(format!("Tile size has to be between 1 and {}.", MAXIMUM_TILE_SIZE));
+        }
+        if self.gap > self.tile_size {
+            return Err(String::from("Gap cannot be larger than the tile size."));
+        }
+        self.background.parse().map_err(|e| format!("{}", e))
+    }
+
+    fn cell_height(&self) -> u32 {
+        self.tile_size + if self.labels { LABEL_HEIGHT } else { 0 }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        let columns = self.columns.min(self.urls.len()) as u32;
+        let rows = self.urls.len().div_ceil(self.columns) as u32;
+        (
+            columns * self.tile_size + (columns + 1) * self.gap,
+            rows * self.cell_height() + (rows + 1) * self.gap,
+        )
+    }
+}
+
+/// Composes the sheet. Sources without a thumbnail leave an empty tile crossed out.
+pub fn compose(request: &ContactSheetRequest, background: &Color, thumbnails: &[Option<DynamicImage>]) -> RgbaImage {
+    let (width, height) = request.dimensions();
+    let mut sheet = RgbaImage::from_pixel(width, height, Rgba([background.red, background.green, background.blue, background.alpha]));
+    let ink = ink(background);
+    for (index, (url, thumbnail)) in request.urls.iter().zip(thumbnails).enumerate() {
+        let x = request.gap + (index % request.columns) as u32 * (request.tile_size + request.gap);
+        let y = request.gap + (index / request.columns) as u32 * (request.cell_height() + request.gap);
+        match thumbnail {
+            Some(thumbnail) => {
+                let (thumbnail_width, thumbnail_height) = thumbnail.dimensions();
+                let offset_x = request.tile_size.saturating_sub(thumbnail_width) / 2;
+                let offset_y = request.tile_size.saturating_sub(thumbnail_height) / 2;
+                overlay(&mut sheet, &thumbnail.to_rgba8(), (x + offset_x) as i64, (y + offset_y) as i64);
+            }
+            None => cross_out(&mut sheet, x, y, request.tile_size, ink),
+        }
+        if request.labels {
+            let label_width = (request.tile_size / GLYPH_WIDTH) as usize;
+            draw_text(&mut sheet, &label(url, label_width), x, y + request.tile_size + GLYPH_SCALE, ink);
+        }
+    }
+    sheet
+}
+
+/// Black on light backgrounds, white on dark ones.
+fn ink(background: &Color) -> Rgba<u8> {
+    let luma = 0.299 * background.red as f32 + 0.587 * background.green as f32 + 0.114 * background.blue as f32;
+    if background.alpha < 128 || luma > 127.0 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    }
+}
+
+fn cross_out(sheet: &mut RgbaImage, x: u32, y: u32, size: u32, ink: Rgba<u8>) {
+    for offset in 0..size {
+        sheet.put_pixel(x + offset, y + offset, ink);
+        sheet.put_pixel(x + size - 1 - offset, y + offset, ink);
+    }
+}
+
+/// File name of the source, shortened to the characters which fit under the tile.
+fn label(url: &str, maximum_length: usize) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    let name = urlencoding::decode(name).map(|name| name.into_owned()).unwrap_or_else(|_| name.to_string());
+    let characters: Vec<char> = name.chars().collect();
+    if characters.len() <= maximum_length {
+        return name;
+    }
+    let kept = maximum_length.saturating_sub(2);
+    characters[..kept].iter().collect::<String>() + ".."
+}
+
+fn draw_text(sheet: &mut RgbaImage, text: &str, x: u32, y: u32, ink: Rgba<u8>) {
+    for (index, character) in text.chars().enumerate() {
+        let rows = glyph(character);
+        let left = x + index as u32 * GLYPH_WIDTH;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..GLYPH_SCALE {
+                    for dx in 0..GLYPH_SCALE {
+                        let (px, py) = (left + column * GLYPH_SCALE + dx, y + row as u32 * GLYPH_SCALE + dy);
+                        if px < sheet.width() && py < sheet.height() {
+                            sheet.put_pixel(px, py, ink);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a 3x5 glyph, the highest of three bits is the leftmost pixel. Letters are drawn as capitals.
+fn glyph(character: char) -> [u8; 5] {
+    match character.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ' ' => [0b000; 5],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image_crate::{DynamicImage, Rgba, RgbaImage};
+
+    use crate::contact_sheet::{compose, ContactSheetRequest, label};
+
+    #[test]
+    fn compose_grid_of_thumbnails() {
+        let request = ContactSheetRequest {
+            urls: vec![String::from("https://example.com/a.png"), String::from("https://example.com/b.png"), String::from("https://example.com/c.png")],
+            columns: 2,
+            tile_size: 40,
+            gap: 4,
+            labels: true,
+            ..ContactSheetRequest::default()
+        };
+        let background = request.validate().unwrap();
+        let red = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 255])));
+        let sheet = compose(&request, &background, &[Some(red.clone()), None, Some(red)]);
+
+        assert_eq!(sheet.dimensions(), (2 * 40 + 3 * 4, 2 * (40 + 14) + 3 * 4));
+        // Thumbnails are centered in their tiles.
+        assert_eq!(*sheet.get_pixel(4, 4), Rgba([255, 255, 255, 255]));
+        assert_eq!(*sheet.get_pixel(4, 4 + 10), Rgba([255, 0, 0, 255]));
+        // Missing thumbnail is crossed out.
+        assert_eq!(*sheet.get_pixel(48, 4), Rgba([0, 0, 0, 255]));
+        // Label starts with the top bar of `A`.
+        assert_eq!(*sheet.get_pixel(4 + 2, 4 + 40 + 2), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn shorten_labels() {
+        assert_eq!(label("https://example.com/photos/a%20b.jpg?v=2", 20), "a b.jpg");
+        assert_eq!(label("https://example.com/photos/long-file-name.jpg", 8), "long-f..");
+    }
+}
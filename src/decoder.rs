@@ -1,73 +1,676 @@
 use std::io::Cursor;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::Instant;
 
-use image_crate::{DynamicImage, ImageFormat};
+use image_crate::{AnimationDecoder, DynamicImage, Frame, Frames, ImageDecoder as _, ImageFormat, RgbaImage, RgbImage};
+use image_crate::codecs::gif::GifDecoder;
+use image_crate::codecs::png::PngDecoder;
+use image_crate::imageops::{overlay, replace};
 use image_crate::io::Reader as ImageReader;
+use log::debug;
 
-use crate::cache::CacheEngine;
-use crate::fetcher::{generate_resource_tag, Resource};
-use crate::image::Image;
+use crate::animated_webp;
+use crate::animated_webp::AnimatedWebpFrame;
+use crate::cache::SharedCache;
+use crate::cache::typed::Cache;
+use crate::fetcher::{generate_resource_tag, Resource, ResponseData};
+use crate::image::{Animation, AnimationFrame, Image};
+use crate::metadata;
+use crate::metadata::CmykEncoding;
+use crate::metrics::{Metrics, observe_stage, record_cache_lookup};
 
 pub trait ImageDecoder {
+    fn serve_cache(&self, tag: &str) -> Option<DynamicImage>;
     fn decode(&self, tag: &str, resource: &Resource) -> Result<DynamicImage, DecodeError>;
+    /// Every frame of an animated source, `None` when the source is a still image.
+    fn decode_animation(&self, tag: &str, resource: &Resource) -> Result<Option<Animation>, DecodeError>;
 }
 
 #[derive(Debug)]
 pub enum DecodeError {
     UnknownFormat(String),
     MismatchedFormat,
+    /// Pixels of the source, every frame of an animation counted, are more than the maximum (maximum, pixels).
+    ExceedsMaximumSize(usize, usize),
 }
 
 pub struct CachedImageDecoder {
-    pub cache: Arc<RwLock<Box<dyn CacheEngine + Send + Sync>>>,
+    pub cache: SharedCache,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
+    /// Turns still images upright as their EXIF orientation describes.
+    pub auto_orient: bool,
+    /// Sources are refused before more pixels than this are allocated for them.
+    pub maximum_image_size: usize,
+}
+
+impl CachedImageDecoder {
+    /// Upright and stored images are cached apart, switching `auto_orient` never serves the other one.
+    fn decoded_tag(&self, tag: &str) -> String {
+        match self.auto_orient {
+            true => generate_resource_tag(&format!("Image Decoder {} upright", tag)),
+            false => generate_resource_tag(&format!("Image Decoder {}", tag)),
+        }
+    }
 }
 
 impl ImageDecoder for CachedImageDecoder {
+    fn serve_cache(&self, tag: &str) -> Option<DynamicImage> {
+        let tag = self.decoded_tag(tag);
+        let image: Option<DynamicImage> = Cache::<Image>::new(&self.cache).get_blocking(&tag).map(Image::into);
+        record_cache_lookup(self.metrics.as_ref(), "decode", image.is_some());
+        image
+    }
+
     fn decode(&self, tag: &str, resource: &Resource) -> Result<DynamicImage, DecodeError> {
-        let tag = generate_resource_tag(&format!("Image Decoder {}", tag));
+        if let Some(image) = self.serve_cache(tag) {
+            return Ok(image);
+        }
+        let tag = self.decoded_tag(tag);
+        let started = Instant::now();
+
+        let img = match animated_webp::frames(&resource.content) {
+            Some(animation) => decode_first_frame(resource, animation.canvas, &animation.frames[0], self.maximum_image_size)?,
+            #[cfg(feature = "raw")]
+            None if is_camera_raw_source(resource) => decode_camera_raw(resource, self.maximum_image_size)?,
+            None => decode_sniffed(resource)?,
+        };
+        let img = match self.auto_orient {
+            true => orient(img, metadata::orientation(&resource.content)),
+            false => img,
+        };
 
-        if let Some(dynamic_image_bytes) = self.cache.read().unwrap().get(&tag) {
-            return Ok(bincode::deserialize::<Image>(&dynamic_image_bytes).unwrap().into());
+        observe_stage(self.metrics.as_ref(), "decode", started);
+        Cache::<Image>::new(&self.cache).set_blocking(&tag, &img.clone().into());
+        Ok(img)
+    }
+
+    fn decode_animation(&self, tag: &str, resource: &Resource) -> Result<Option<Animation>, DecodeError> {
+        let animated_webp = animated_webp::frames(&resource.content);
+        let apng = is_apng(&resource.content);
+        if !resource.content.starts_with(b"GIF8") && animated_webp.is_none() && !apng {
+            return Ok(None);
+        }
+        let tag = generate_resource_tag(&format!("Image Decoder Animation {}", tag));
+        let cache = Cache::<Animation>::new(&self.cache);
+        let cached_animation = cache.get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "decode", cached_animation.is_some());
+        if let Some(animation) = cached_animation {
+            return Ok(Some(animation));
         }
+        let started = Instant::now();
+        let animation = match animated_webp {
+            Some(animated_webp) => compose_webp_frames(resource, animated_webp.canvas, &animated_webp.frames, self.maximum_image_size)?,
+            None if apng => decode_apng_frames(resource, self.maximum_image_size)?,
+            None => decode_gif_frames(resource, self.maximum_image_size)?,
+        };
+        if animation.frames.len() < 2 {
+            return Ok(None);
+        }
+        observe_stage(self.metrics.as_ref(), "decode", started);
+        cache.set_blocking(&tag, &animation);
+        Ok(Some(animation))
+    }
+}
 
-        let img: DynamicImage;
+/// Turns the image upright, orientations are the ones of EXIF from 1 (upright) to 8.
+fn orient(image: DynamicImage, orientation: Option<u16>) -> DynamicImage {
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
 
-        if resource.response_data.content_type.as_str() == "image/webp" {
-            let decoder = webp::Decoder::new(resource.content.as_slice());
-            img = match decoder.decode() {
-                Some(image) => image.to_image(),
-                None => return Err(DecodeError::MismatchedFormat),
-            };
-        } else {
-            let mut reader = ImageReader::new(Cursor::new(
-                resource.content.clone()
-            ));
-            match resource.response_data.content_type.as_str() {
-                "image/jpeg" => {
-                    reader.set_format(ImageFormat::Jpeg);
-                }
-                "image/png" => {
-                    reader.set_format(ImageFormat::Png);
-                }
-                "image/bmp" => {
-                    reader.set_format(ImageFormat::Bmp);
-                }
-                "image/x-tga" | "image/x-targa" => {
-                    reader.set_format(ImageFormat::Tga);
-                }
-                _ => {
-                    reader = reader.with_guessed_format().unwrap();
-                }
+/// PNG with an `acTL` chunk before its image data is animated, other PNGs are still images.
+pub fn is_apng(content: &[u8]) -> bool {
+    content.starts_with(metadata::PNG_SIGNATURE) && metadata::png_chunks(content).is_some_and(|chunks| chunks.iter()
+        .take_while(|(chunk_type, _)| *chunk_type != b"IDAT")
+        .any(|(chunk_type, _)| *chunk_type == b"acTL"))
+}
+
+fn decode_gif_frames(resource: &Resource, maximum_size: usize) -> Result<Animation, DecodeError> {
+    let decoder = GifDecoder::new(Cursor::new(resource.content.as_slice()))
+        .map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))?;
+    let canvas = decoder.dimensions();
+    Ok(frames_to_animation(collect_frames(resource, decoder.into_frames(), canvas, maximum_size)?))
+}
+
+/// Every frame is as large as the canvas, so the frames are refused as soon as their pixels add up
+/// to more than the maximum, before the rest of a long animation is decoded.
+fn collect_frames(resource: &Resource, frames: Frames, canvas: (u32, u32), maximum_size: usize) -> Result<Vec<Frame>, DecodeError> {
+    let frame_size = canvas.0 as usize * canvas.1 as usize;
+    let mut collected = vec![];
+    for frame in frames {
+        let size = frame_size.saturating_mul(collected.len() + 1);
+        if size > maximum_size {
+            return Err(DecodeError::ExceedsMaximumSize(maximum_size, size));
+        }
+        collected.push(frame.map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))?);
+    }
+    Ok(collected)
+}
+
+/// Frames of an APNG are composed onto the canvas by the decoder, like the ones of a GIF.
+fn decode_apng_frames(resource: &Resource, maximum_size: usize) -> Result<Animation, DecodeError> {
+    let decoder = PngDecoder::new(Cursor::new(resource.content.as_slice()))
+        .map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))?;
+    let canvas = decoder.dimensions();
+    Ok(frames_to_animation(collect_frames(resource, decoder.apng().into_frames(), canvas, maximum_size)?))
+}
+
+fn frames_to_animation(frames: Vec<Frame>) -> Animation {
+    Animation {
+        frames: frames.into_iter().map(|frame| {
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            AnimationFrame {
+                delay_ms: numerator / denominator.max(1),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()).into(),
             }
+        }).collect(),
+    }
+}
 
-            if let Ok(image) = reader.decode() {
-                img = image;
-            } else {
-                return Err(DecodeError::UnknownFormat(resource.response_data.content_type.clone()));
+/// Frames of an animated WebP cover only the part of the canvas which changes, every frame
+/// is drawn onto the canvas left by the previous ones.
+fn compose_webp_frames(resource: &Resource, canvas_size: (u32, u32), frames: &[AnimatedWebpFrame], maximum_size: usize) -> Result<Animation, DecodeError> {
+    // The container tells the canvas and the number of frames, the animation is refused before anything is composed.
+    let size = (canvas_size.0 as usize * canvas_size.1 as usize).saturating_mul(frames.len());
+    if size > maximum_size {
+        return Err(DecodeError::ExceedsMaximumSize(maximum_size, size));
+    }
+    let mut canvas = RgbaImage::new(canvas_size.0, canvas_size.1);
+    let mut composed = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let image = decode_webp_frame(resource, frame)?.to_rgba8();
+        let (x, y) = (frame.offset.0 as i64, frame.offset.1 as i64);
+        match frame.blend {
+            true => overlay(&mut canvas, &image, x, y),
+            false => replace(&mut canvas, &image, x, y),
+        }
+        composed.push(AnimationFrame { image: DynamicImage::ImageRgba8(canvas.clone()).into(), delay_ms: frame.delay_ms });
+        if frame.dispose {
+            replace(&mut canvas, &RgbaImage::new(image.width(), image.height()), x, y);
+        }
+    }
+    Ok(Animation { frames: composed })
+}
+
+fn decode_webp_frame(resource: &Resource, frame: &AnimatedWebpFrame) -> Result<DynamicImage, DecodeError> {
+    let still = Resource {
+        response_data: ResponseData { content_type: String::from("image/webp"), ..resource.response_data.clone() },
+        content: frame.still.clone(),
+    };
+    decode_still(&still, "image/webp")
+}
+
+/// Decodes the source as the format its content is in. Upstreams get the type wrong, e.g. CDNs
+/// serving WebP as `image/jpeg` or anything as `application/octet-stream`, so the signature of the
+/// content takes precedence and the declared type only picks formats without one. Sources which
+/// still do not match the format they were decoded as are given to image to guess.
+fn decode_sniffed(resource: &Resource) -> Result<DynamicImage, DecodeError> {
+    let declared = resource.response_data.content_type.as_str();
+    let content_type = match sniff_content_type(&resource.content) {
+        Some(sniffed) if sniffed != declared => {
+            debug!("Source {} served as {} is {}.", resource.response_data.id, declared, sniffed);
+            sniffed
+        }
+        _ => declared,
+    };
+    match decode_still(resource, content_type) {
+        Err(DecodeError::MismatchedFormat) => decode_with_reader(resource, ""),
+        result => result,
+    }
+}
+
+/// Content type told by the magic bytes of the content, `None` for formats without a signature such as TGA.
+pub fn sniff_content_type(content: &[u8]) -> Option<&'static str> {
+    match content {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => Some("image/tiff"),
+        [0, 0, 1, 0, ..] => Some("image/x-icon"),
+        _ if matches!(content.get(4..12), Some(b"ftypavif" | b"ftypavis")) => Some("image/avif"),
+        _ if is_heif(content) => Some("image/heic"),
+        _ => None,
+    }
+}
+
+fn decode_still(resource: &Resource, content_type: &str) -> Result<DynamicImage, DecodeError> {
+    match content_type {
+        #[cfg(feature = "webp")]
+        "image/webp" => match webp::Decoder::new(resource.content.as_slice()).decode() {
+            Some(image) => Ok(image.to_image()),
+            None => Err(DecodeError::MismatchedFormat),
+        },
+        #[cfg(feature = "heic")]
+        "image/heic" | "image/heif" => decode_heif(resource),
+        #[cfg(feature = "heic")]
+        _ if is_heif(&resource.content) => decode_heif(resource),
+        _ if metadata::jpeg_cmyk(&resource.content).is_some() => decode_cmyk_jpeg(resource),
+        _ => decode_with_reader(resource, content_type),
+    }
+}
+
+/// JPEGs of print workflows hold ink amounts, which image either refuses or turns into inverted
+/// colors for some encodings. They are converted to RGB the way browsers do, without the CMYK
+/// profile, so colors are close but not exact.
+fn decode_cmyk_jpeg(resource: &Resource) -> Result<DynamicImage, DecodeError> {
+    let encoding = metadata::jpeg_cmyk(&resource.content).ok_or(DecodeError::MismatchedFormat)?;
+    let mut decoder = jpeg_decoder::Decoder::new(resource.content.as_slice());
+    let pixels = decoder.decode().map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))?;
+    let info = decoder.info().ok_or(DecodeError::MismatchedFormat)?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Err(DecodeError::MismatchedFormat);
+    }
+    let rgb = pixels.chunks_exact(4).flat_map(|pixel| cmyk_to_rgb(pixel, encoding)).collect();
+    RgbImage::from_raw(info.width as u32, info.height as u32, rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or(DecodeError::MismatchedFormat)
+}
+
+/// jpeg-decoder takes every CMYK JPEG for an inverted one, as Adobe writes them, and converts YCCK,
+/// so only amounts stored as they are come out inverted.
+fn cmyk_to_rgb(pixel: &[u8], encoding: CmykEncoding) -> [u8; 3] {
+    let amount = |value: u8| match encoding {
+        CmykEncoding::Plain => 255 - value,
+        CmykEncoding::Inverted | CmykEncoding::Ycck => value,
+    };
+    let black = amount(pixel[3]) as u16;
+    let channel = |ink: u8| ((255 - amount(ink) as u16) * (255 - black) / 255) as u8;
+    [channel(pixel[0]), channel(pixel[1]), channel(pixel[2])]
+}
+
+/// HEIF brands of HEVC coded images, photos of iPhones are `heic`. AVIF is HEIF too, under its own brands.
+pub fn is_heif(content: &[u8]) -> bool {
+    content.get(4..8) == Some(b"ftyp")
+        && matches!(content.get(8..12), Some(b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1"))
+}
+
+/// Primary image of the HEIF file. libheif applies the rotation and mirroring stored in the
+/// container, the image comes out upright.
+#[cfg(feature = "heic")]
+fn decode_heif(resource: &Resource) -> Result<DynamicImage, DecodeError> {
+    use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
+
+    let unknown_format = |_| DecodeError::UnknownFormat(resource.response_data.content_type.clone());
+    let context = HeifContext::read_from_bytes(&resource.content).map_err(unknown_format)?;
+    let handle = context.primary_image_handle().map_err(unknown_format)?;
+    let (chroma, channels) = match handle.has_alpha_channel() {
+        true => (RgbChroma::Rgba, 4),
+        false => (RgbChroma::Rgb, 3),
+    };
+    let image = handle.decode(ColorSpace::Rgb(chroma), None).map_err(unknown_format)?;
+    let plane = image.planes().interleaved.ok_or(DecodeError::MismatchedFormat)?;
+    // Rows of the plane may be padded past the pixels.
+    let row_length = plane.width as usize * channels;
+    let pixels: Vec<u8> = plane.data.chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_length])
+        .copied()
+        .collect();
+    let image = match channels {
+        4 => RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8),
+        _ => RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8),
+    };
+    image.ok_or(DecodeError::MismatchedFormat)
+}
+
+/// Linear sRGB of CIE XYZ under D65.
+#[cfg(feature = "raw")]
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.041556],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+/// Sensor data of a CR2, NEF or DNG file developed into a preview: levels, white balance as shot
+/// and the color matrix of the camera, without any of the tone curves raw converters apply.
+/// Bayer sensors are developed at half the resolution, a 2×2 block of photosites into one pixel,
+/// still more than web renditions need. The orientation is left to the EXIF of the file.
+/// Sensors developed into more pixels than the maximum size are refused.
+#[cfg(feature = "raw")]
+fn decode_camera_raw(resource: &Resource, maximum_size: usize) -> Result<DynamicImage, DecodeError> {
+    let raw = rawloader::decode(&mut Cursor::new(resource.content.as_slice()))
+        .map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))?;
+    if !matches!(raw.cpp, 1 | 3) {
+        return Err(DecodeError::UnknownFormat(resource.response_data.content_type.clone()));
+    }
+    develop_camera_raw(&raw, maximum_size)
+}
+
+/// Sources served as a camera raw format without the signature of another, or told apart by their content.
+#[cfg(feature = "raw")]
+fn is_camera_raw_source(resource: &Resource) -> bool {
+    let declared = matches!(resource.response_data.content_type.as_str(), "image/x-canon-cr2" | "image/x-nikon-nef" | "image/x-adobe-dng" | "image/dng");
+    (declared && sniff_content_type(&resource.content).is_none()) || metadata::is_camera_raw(&resource.content)
+}
+
+#[cfg(feature = "raw")]
+fn develop_camera_raw(raw: &rawloader::RawImage, maximum_size: usize) -> Result<DynamicImage, DecodeError> {
+    let [top, right, bottom, left] = raw.crops;
+    let raw_width = raw.width;
+    let (columns, rows) = (raw.width.saturating_sub(left + right), raw.height.saturating_sub(top + bottom));
+    // Checked before the sensor data is copied.
+    let size = match raw.cpp {
+        3 => columns * rows,
+        _ => (columns / 2) * (rows / 2),
+    };
+    if size > maximum_size {
+        return Err(DecodeError::ExceedsMaximumSize(maximum_size, size));
+    }
+    let samples = match &raw.data {
+        rawloader::RawImageData::Integer(data) => data.len(),
+        rawloader::RawImageData::Float(data) => data.len(),
+    };
+    if samples < raw.width.saturating_mul(raw.height).saturating_mul(raw.cpp) {
+        return Err(DecodeError::MismatchedFormat);
+    }
+    let data: Vec<f32> = match &raw.data {
+        rawloader::RawImageData::Integer(data) => data.iter().map(|value| *value as f32).collect(),
+        rawloader::RawImageData::Float(data) => data.clone(),
+    };
+    let level = |value: f32, color: usize| {
+        let black = raw.blacklevels[color] as f32;
+        ((value - black) / (raw.whitelevels[color] as f32 - black).max(1.0)).max(0.0)
+    };
+    // Colors of photosites, the second green of some sensors is taken as green.
+    let color_at = |row: usize, column: usize| raw.cfa.color_at(row, column).min(2);
+
+    let (width, height, camera_pixels): (usize, usize, Vec<[f32; 3]>) = match raw.cpp {
+        3 => {
+            let pixels = (top..top + rows)
+                .flat_map(|row| (left..left + columns).map(move |column| (row * raw_width + column) * 3))
+                .map(|index| [level(data[index], 0), level(data[index + 1], 1), level(data[index + 2], 2)])
+                .collect();
+            (columns, rows, pixels)
+        }
+        _ => {
+            let pixels = (0..rows / 2)
+                .flat_map(|row| (0..columns / 2).map(move |column| (top + row * 2, left + column * 2)))
+                .map(|(row, column)| {
+                    let (mut sums, mut counts) = ([0.0; 3], [0.0; 3]);
+                    for (row, column) in [(row, column), (row, column + 1), (row + 1, column), (row + 1, column + 1)] {
+                        let color = color_at(row, column);
+                        sums[color] += level(data[row * raw_width + column], color);
+                        counts[color] += 1.0;
+                    }
+                    [0, 1, 2].map(|color| if counts[color] > 0.0 { sums[color] / counts[color] } else { 0.0 })
+                })
+                .collect();
+            (columns / 2, rows / 2, pixels)
+        }
+    };
+
+    // Files of cameras rawloader has no coefficients or matrix for are developed as they are.
+    let white_balance = match raw.wb_coeffs {
+        [red, green, blue, _] if [red, green, blue].iter().all(|coefficient| coefficient.is_finite() && *coefficient > 0.0) => {
+            [red / green, 1.0, blue / green]
+        }
+        _ => [1.0; 3],
+    };
+    let camera_to_xyz = raw.cam_to_xyz_normalized();
+    let camera_to_srgb: [[f32; 3]; 3] = match camera_to_xyz.iter().flatten().all(|value| value.is_finite()) {
+        true => [0, 1, 2].map(|row| [0, 1, 2].map(|column| (0..3).map(|index| XYZ_TO_SRGB[row][index] * camera_to_xyz[index][column]).sum())),
+        false => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+    let gamma = |linear: f32| {
+        let linear = linear.clamp(0.0, 1.0);
+        let encoded = if linear <= 0.0031308 { linear * 12.92 } else { 1.055 * linear.powf(1.0 / 2.4) - 0.055 };
+        (encoded * 255.0).round() as u8
+    };
+    let pixels: Vec<u8> = camera_pixels.into_iter()
+        .flat_map(|pixel| {
+            let balanced = [0, 1, 2].map(|color| (pixel[color] * white_balance[color]).min(1.0));
+            camera_to_srgb.map(|row| gamma(row.iter().zip(balanced).map(|(factor, value)| factor * value).sum()))
+        })
+        .collect();
+    RgbImage::from_raw(width as u32, height as u32, pixels)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or(DecodeError::MismatchedFormat)
+}
+
+/// Animated sources are served as their first frame, placed on the canvas of the animation.
+fn decode_first_frame(resource: &Resource, canvas: (u32, u32), frame: &AnimatedWebpFrame, maximum_size: usize) -> Result<DynamicImage, DecodeError> {
+    // The canvas is told by the container, it can be far larger than the frames drawn onto it.
+    let size = canvas.0 as usize * canvas.1 as usize;
+    if size > maximum_size {
+        return Err(DecodeError::ExceedsMaximumSize(maximum_size, size));
+    }
+    let image = decode_webp_frame(resource, frame)?;
+    if frame.offset == (0, 0) && (image.width(), image.height()) == canvas {
+        return Ok(image);
+    }
+    let mut canvas = RgbaImage::new(canvas.0, canvas.1);
+    overlay(&mut canvas, &image.to_rgba8(), frame.offset.0 as i64, frame.offset.1 as i64);
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Decodes with image as the content type, or as the format image guesses for other types.
+fn decode_with_reader(resource: &Resource, content_type: &str) -> Result<DynamicImage, DecodeError> {
+    let mut reader = ImageReader::new(Cursor::new(
+        resource.content.clone()
+    ));
+    match content_type {
+        "image/jpeg" => {
+            reader.set_format(ImageFormat::Jpeg);
+        }
+        "image/png" => {
+            reader.set_format(ImageFormat::Png);
+        }
+        "image/bmp" => {
+            reader.set_format(ImageFormat::Bmp);
+        }
+        "image/webp" => {
+            reader.set_format(ImageFormat::WebP);
+        }
+        "image/avif" => {
+            reader.set_format(ImageFormat::Avif);
+        }
+        _ if matches!(resource.content.get(4..12), Some(b"ftypavif" | b"ftypavis")) => {
+            reader.set_format(ImageFormat::Avif);
+        }
+        "image/x-tga" | "image/x-targa" => {
+            reader.set_format(ImageFormat::Tga);
+        }
+        _ => {
+            reader = reader.with_guessed_format().unwrap();
+        }
+    }
+
+    reader.decode().map_err(|_| DecodeError::UnknownFormat(resource.response_data.content_type.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use image_crate::{DynamicImage, GenericImageView, ImageOutputFormat, Rgb, RgbImage};
+
+    use crate::cache::{NoCacheEngine, shared};
+    use crate::decoder::{CachedImageDecoder, ImageDecoder, is_heif, sniff_content_type};
+    use crate::fetcher::{Resource, ResponseData};
+    use crate::metrics::NoMetrics;
+
+    #[test]
+    fn decode_upright() {
+        // Left half red, right half blue.
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8, |x, _| if x < 8 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }));
+        let mut jpeg = Vec::new();
+        image.write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(95)).unwrap();
+        // Little-endian EXIF whose only entry is orientation 6, stored rotated 90° counterclockwise.
+        let exif = [&b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0"[..], &[0; 4]].concat();
+        let segment = [&[0xFF, 0xE1][..], &((exif.len() + 2) as u16).to_be_bytes(), &exif].concat();
+        let resource = Resource {
+            response_data: ResponseData { id: String::from("rotated"), content_type: String::from("image/jpeg"), additional_data: HashMap::default() },
+            content: [&jpeg[..2], &segment, &jpeg[2..]].concat(),
+        };
+
+        let decoder = |auto_orient| CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient, maximum_image_size: usize::MAX };
+        let upright = decoder(true).decode("rotated", &resource).unwrap();
+        assert_eq!(upright.dimensions(), (8, 16));
+        // Turned clockwise, the left half ends up on top.
+        assert!(upright.get_pixel(4, 2)[0] > 200 && upright.get_pixel(4, 13)[2] > 200);
+        assert_eq!(decoder(false).decode("rotated", &resource).unwrap().dimensions(), (16, 8));
+    }
+
+    #[test]
+    fn decode_as_sniffed_format() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(6, 4, Rgb([40, 160, 220])));
+        let decoder = CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        for format in [ImageOutputFormat::Png, ImageOutputFormat::Gif, ImageOutputFormat::Bmp, ImageOutputFormat::Jpeg(90)] {
+            let mut content = Vec::new();
+            image.write_to(&mut Cursor::new(&mut content), format).unwrap();
+            for content_type in ["image/jpeg", "image/webp", "application/octet-stream", ""] {
+                let resource = Resource {
+                    response_data: ResponseData { id: String::from("mislabeled"), content_type: content_type.to_string(), additional_data: HashMap::default() },
+                    content: content.clone(),
+                };
+                assert_eq!(decoder.decode("mislabeled", &resource).unwrap().dimensions(), (6, 4));
             }
         }
 
-        self.cache.write().unwrap().set(&tag, &bincode::serialize::<Image>(&img.clone().into()).unwrap()).unwrap();
-        Ok(img)
+        assert_eq!(sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_content_type(b"MM\0*\0\0\0\x08"), Some("image/tiff"));
+        assert_eq!(sniff_content_type(b"\0\0\0\x1cftypavif\0\0\0\0"), Some("image/avif"));
+        assert_eq!(sniff_content_type(b"\0\0\0\x18ftypheic\0\0\0\0"), Some("image/heic"));
+        assert_eq!(sniff_content_type(b"<svg"), None);
+    }
+
+    #[test]
+    fn recognize_heif_brands() {
+        assert!(is_heif(b"\0\0\0\x18ftypheic\0\0\0\0"));
+        assert!(is_heif(b"\0\0\0\x18ftypmif1\0\0\0\0"));
+        assert!(!is_heif(b"\0\0\0\x1cftypavif\0\0\0\0"));
+        assert!(!is_heif(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[cfg(all(feature = "heic", feature = "avif", feature = "avif-decoder"))]
+    #[test]
+    fn decode_heic_and_avif() {
+        use image_crate::{Rgba, RgbaImage};
+        use crate::encoder::{AllInOneCachedImageEncoder, ImageEncoder, JpegEncoder, OutputFormat};
+        use crate::output_dimensions::OutputDimensions;
+
+        let cache = shared(Box::new(NoCacheEngine {}));
+        let encoder = AllInOneCachedImageEncoder { cache: cache.clone(), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::Image, keep_color_profile: true };
+        let decoder = CachedImageDecoder { cache, metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(24, 16, Rgba([40, 160, 220, 255])));
+        for (output_format, content_type) in [(OutputFormat::Heic(90, 10), "image/heic"), (OutputFormat::Avif(90, 10), "image/avif")] {
+            let encoded = encoder.encode(&String::from("sample"), image.clone(), &OutputDimensions::Original, output_format, None).unwrap();
+            // Origins serving them as binary data get them sniffed.
+            for served_as in [content_type, "application/octet-stream"] {
+                let resource = Resource {
+                    response_data: ResponseData { id: String::from(served_as), content_type: String::from(served_as), additional_data: HashMap::default() },
+                    content: encoded.image.clone(),
+                };
+                let decoded = decoder.decode(served_as, &resource).unwrap();
+                assert_eq!(decoded.dimensions(), (24, 16), "{} as {}", content_type, served_as);
+                let pixel = decoded.to_rgba8().get_pixel(12, 8).0;
+                assert!(pixel[2] > 190 && pixel[0] < 70, "{} as {}: {:?}", content_type, served_as, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_cmyk_jpeg() {
+        use jpeg_encoder::{ColorType, Encoder};
+
+        // Cyan on the left, 50% black on the right.
+        let cmyk: Vec<u8> = (0..16 * 8).flat_map(|index| if index % 16 < 8 { [255, 0, 0, 0] } else { [0, 0, 0, 128] }).collect();
+        let encode = |pixels: &[u8], color_type| {
+            let mut jpeg = Vec::new();
+            Encoder::new(&mut jpeg, 100).encode(pixels, 16, 8, color_type).unwrap();
+            jpeg
+        };
+        // Written inverted with an Adobe segment, the segment is dropped to store the amounts as they are.
+        let inverted: Vec<u8> = cmyk.iter().map(|amount| 255 - amount).collect();
+        let plain = encode(&inverted, ColorType::Cmyk);
+        let adobe = plain.windows(7).position(|window| window == b"\xFF\xEE\0\x0EAdo").unwrap();
+        let plain = [&plain[..adobe], &plain[adobe + 16..]].concat();
+
+        let decoder = CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        for (name, jpeg) in [("cmyk", encode(&cmyk, ColorType::Cmyk)), ("ycck", encode(&cmyk, ColorType::CmykAsYcck)), ("plain", plain)] {
+            let resource = Resource {
+                response_data: ResponseData { id: String::from(name), content_type: String::from("image/jpeg"), additional_data: HashMap::default() },
+                content: jpeg,
+            };
+            let image = decoder.decode(name, &resource).unwrap().to_rgb8();
+            assert_eq!(image.dimensions(), (16, 8), "{}", name);
+            let close = |pixel: &Rgb<u8>, expected: [u8; 3]| pixel.0.iter().zip(expected).all(|(channel, expected)| channel.abs_diff(expected) <= 8);
+            assert!(close(image.get_pixel(3, 4), [0, 255, 255]), "{}: {:?}", name, image.get_pixel(3, 4));
+            assert!(close(image.get_pixel(12, 4), [127, 127, 127]), "{}: {:?}", name, image.get_pixel(12, 4));
+        }
+    }
+
+    /// Sensor of a camera without white balance coefficients or color matrix, levels from 100 to 1100.
+    #[cfg(feature = "raw")]
+    fn sensor(width: usize, height: usize, cpp: usize, cfa: &str, data: Vec<u16>) -> rawloader::RawImage {
+        rawloader::RawImage {
+            make: String::new(),
+            model: String::new(),
+            clean_make: String::new(),
+            clean_model: String::new(),
+            width,
+            height,
+            cpp,
+            wb_coeffs: [f32::NAN; 4],
+            whitelevels: [1100; 4],
+            blacklevels: [100; 4],
+            xyz_to_cam: [[0.0; 3]; 4],
+            cfa: rawloader::CFA::new(cfa),
+            crops: [0; 4],
+            blackareas: Vec::new(),
+            orientation: rawloader::Orientation::Normal,
+            data: rawloader::RawImageData::Integer(data),
+        }
+    }
+
+    #[cfg(feature = "raw")]
+    #[test]
+    fn develop_rgb_sensor_data() {
+        use crate::decoder::develop_camera_raw;
+
+        // Cropped down to the middle pixel, half red, full green and no blue.
+        let mut raw = sensor(3, 1, 3, "", vec![0, 0, 0, 600, 1100, 100, 0, 0, 0]);
+        raw.crops = [0, 1, 0, 1];
+        let developed = |raw: &rawloader::RawImage| develop_camera_raw(raw, usize::MAX).unwrap().to_rgb8();
+        assert_eq!(developed(&raw).dimensions(), (1, 1));
+        assert_eq!(developed(&raw).get_pixel(0, 0), &Rgb([188, 255, 0]));
+
+        // Red doubled by the white balance, relative to green.
+        raw.wb_coeffs = [4.0, 2.0, 2.0, f32::NAN];
+        assert_eq!(developed(&raw).get_pixel(0, 0), &Rgb([255, 255, 0]));
+
+        // Camera recording XYZ, turned into sRGB.
+        raw.wb_coeffs = [f32::NAN; 4];
+        raw.xyz_to_cam = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, 0.0]];
+        assert_eq!(developed(&raw).get_pixel(0, 0), &Rgb([81, 255, 0]));
+    }
+
+    #[cfg(feature = "raw")]
+    #[test]
+    fn develop_bayer_sensor_data() {
+        use crate::decoder::{DecodeError, develop_camera_raw};
+
+        // Red with half green, then blue, one 2×2 block of photosites each.
+        let raw = sensor(4, 2, 1, "RGGB", vec![1100, 600, 100, 100, 600, 100, 100, 1100]);
+        let developed = develop_camera_raw(&raw, 2).unwrap().to_rgb8();
+        assert_eq!(developed.dimensions(), (2, 1));
+        assert_eq!(developed.get_pixel(0, 0), &Rgb([255, 188, 0]));
+        assert_eq!(developed.get_pixel(1, 0), &Rgb([0, 0, 255]));
+
+        assert!(matches!(develop_camera_raw(&raw, 1), Err(DecodeError::ExceedsMaximumSize(1, 2))));
+        let truncated = sensor(4, 2, 1, "RGGB", vec![100; 7]);
+        assert!(matches!(develop_camera_raw(&truncated, usize::MAX), Err(DecodeError::MismatchedFormat)));
     }
 }
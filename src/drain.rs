@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServerHandle;
+use serde::Serialize;
+
+use crate::recover;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Takes the instance out of service without failing requests, e.g. from a Kubernetes `preStop`
+/// hook. Once draining, health checks fail so no new traffic is routed here, while requests which
+/// still arrive are served as usual.
+#[derive(Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    server: Mutex<Option<ServerHandle>>,
+}
+
+/// Transform counted as in flight until dropped.
+pub struct InFlight<'a>(&'a Drain);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DrainReport {
+    /// Transforms still running when the timeout ran out.
+    pub in_flight: usize,
+    pub waited_ms: u64,
+}
+
+impl Drain {
+    pub fn set_server(&self, server: ServerHandle) {
+        *recover(self.server.lock()) = Some(server);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// Stops reporting ready and waits up to `timeout` for the transforms in flight.
+    pub async fn drain(&self, timeout: Duration) -> DrainReport {
+        self.draining.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        while self.in_flight() > 0 && started.elapsed() < timeout {
+            actix_rt::time::sleep(POLL_INTERVAL).await;
+        }
+        DrainReport { in_flight: self.in_flight(), waited_ms: started.elapsed().as_millis() as u64 }
+    }
+
+    /// Stops the HTTP server once the requests it is handling are answered, the process exits
+    /// after closing the cache.
+    pub fn stop_server(&self) {
+        if let Some(server) = recover(self.server.lock()).take() {
+            actix_rt::spawn(async move { server.stop(true).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::drain::Drain;
+
+    #[test]
+    fn wait_for_transforms_in_flight() {
+        actix_rt::System::new().block_on(async {
+            let drain = Drain::default();
+            let finished = drain.track();
+            let stuck = drain.track();
+            assert_eq!(drain.in_flight(), 2);
+            assert!(!drain.is_draining());
+            drop(finished);
+
+            let report = drain.drain(Duration::from_millis(100)).await;
+            assert!(drain.is_draining());
+            assert_eq!(report.in_flight, 1);
+            assert!(report.waited_ms >= 100);
+            drop(stuck);
+            assert_eq!(drain.drain(Duration::from_secs(10)).await.in_flight, 0);
+        });
+    }
+}
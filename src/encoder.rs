@@ -1,83 +1,697 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use std::io::Cursor;
 use std::num::{ParseFloatError, ParseIntError};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::Instant;
 
-use image_crate::{DynamicImage, ImageOutputFormat};
+use color_quant::NeuQuant;
+use image_crate::{ColorType, Delay, DynamicImage, Frame, GenericImageView, ImageError, ImageOutputFormat, RgbaImage};
+use image_crate::codecs::gif::{GifEncoder, Repeat};
+use image_crate::codecs::ico::{IcoEncoder, IcoFrame};
+use image_crate::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image_crate::imageops::{FilterType, overlay};
 use log::info;
 use serde::{Deserialize, Serialize};
+use jpeg_encoder::SamplingFactor;
+use tiff::encoder::colortype;
+use tiff::encoder::compression::Lzw;
 
-use crate::cache::CacheEngine;
+#[cfg(feature = "webp")]
+use crate::animated_webp;
+use crate::cache::{block_on, SharedCache};
+use crate::cache::typed::Cache;
+use crate::config::{EncodingSettings, QualityPolicy};
 use crate::fetcher::generate_resource_tag;
+use crate::image::Animation;
+use crate::metadata;
+use crate::metrics::{Metrics, observe_stage, record_cache_lookup};
 use crate::output_dimensions::OutputDimensions;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum OutputFormat {
-    Jpeg(u8),
-    Png,
-    WebpLoseless,
-    Webp(f32),
+    Jpeg(u8, JpegOptions),
+    Png(PngCompression),
+    /// PNG with a palette of up to 256 colors, truecolor images are quantized with dithering.
+    Png8,
+    /// Animated PNG, keeps every frame of animated sources. Served as `image/png`, still images
+    /// are plain PNGs.
+    Apng,
+    #[cfg(feature = "webp")]
+    WebpLoseless(WebpOptions),
+    #[cfg(feature = "webp")]
+    Webp(f32, WebpOptions),
+    /// Lossless WebP with pixels adjusted for better compression, from 0 (most) to 100 (none).
+    #[cfg(feature = "webp")]
+    WebpNearLossless(u8, WebpOptions),
     Bmp,
+    /// Keeps every frame of animated sources.
+    Gif,
+    Tiff(TiffCompression),
+    /// Icons of all `ICO_SIZES` in a single file.
+    Ico,
+    /// Quality 0-100 and speed of the encoder from 1 (slowest, smallest) to 10.
+    #[cfg(feature = "avif")]
+    Avif(u8, u8),
+    /// HEVC in a HEIF container, quality 0-100 and speed of the encoder from 1 (slowest, smallest) to 10.
+    #[cfg(feature = "heic")]
+    Heic(u8, u8),
+    /// Lossy format at the highest quality, up to its own, whose output fits in the size in bytes,
+    /// e.g. `jpeg@50kb`.
+    MaxSize(Box<OutputFormat>, u32),
+    /// Format written as 8-bit grayscale, alpha is kept by formats which hold it, e.g. `jpeg80:gray`.
+    Grayscale(Box<OutputFormat>),
+}
+
+/// Flags written between `jpeg` and the quality, and the chroma subsampling after it,
+/// e.g. `jpegp80c420`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct JpegOptions {
+    /// `p`, scans of increasing detail which render early on slow connections.
+    pub progressive: bool,
+    /// `None` takes the one configured in `encoding.jpegSubsampling`.
+    pub subsampling: Option<ChromaSubsampling>,
 }
 
+impl JpegOptions {
+    fn flags(&self) -> &'static str {
+        if self.progressive { "p" } else { "" }
+    }
+
+    /// Full resolution chroma is what image writes, only other subsamplings are spelled out.
+    fn subsampling_suffix(&self) -> String {
+        match self.subsampling {
+            Some(subsampling) if subsampling != ChromaSubsampling::Chroma444 => format!("c{}", subsampling),
+            _ => String::new(),
+        }
+    }
+
+    /// Options image writes on its own, the rest need jpeg-encoder.
+    fn is_baseline(&self) -> bool {
+        !self.progressive && self.subsampling.unwrap_or_default() == ChromaSubsampling::Chroma444
+    }
+}
+
+/// Resolution of the color channels relative to luma. Lower ones make smaller files but
+/// smear saturated edges, e.g. red text.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    #[default]
+    #[serde(rename = "444")]
+    Chroma444,
+    #[serde(rename = "422")]
+    Chroma422,
+    #[serde(rename = "420")]
+    Chroma420,
+}
+
+impl Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChromaSubsampling::Chroma444 => write!(f, "444"),
+            ChromaSubsampling::Chroma422 => write!(f, "422"),
+            ChromaSubsampling::Chroma420 => write!(f, "420"),
+        }
+    }
+}
+
+/// Library writing JPEGs. MozJPEG's trellis quantization makes files about 15% smaller at the
+/// same quality, at a few times the encoding time.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JpegEncoder {
+    #[default]
+    Image,
+    Mozjpeg,
+}
+
+/// Method written after the quality, e.g. `webp80m6`. Trades encoding time for smaller files,
+/// from `m0` (fastest) to `m6`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct WebpOptions {
+    /// `None` is libwebp's default, `4`.
+    pub method: Option<u8>,
+}
+
+#[cfg(feature = "webp")]
+impl WebpOptions {
+    fn method_suffix(&self) -> String {
+        self.method.map(|method| format!("m{}", method)).unwrap_or_default()
+    }
+}
+
+/// `png`, a level from `png1` to `png9`, or `png-fast`. The encoder has three levels of deflate,
+/// so `1` to `3` are fast, `4` to `6` default and `7` to `9` best. `png` is written with the
+/// defaults of the encoders, as it was before levels could be picked.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PngCompression {
+    Default,
+    Level(u8),
+    /// Fastest deflate without filtering rows, for sources where latency matters more than size.
+    Fast,
+}
+
+impl PngCompression {
+    /// `None` leaves the compression and filter to the encoder.
+    fn strategy(&self) -> Option<(CompressionType, PngFilterType)> {
+        match self {
+            PngCompression::Default => None,
+            PngCompression::Level(1..=3) => Some((CompressionType::Fast, PngFilterType::Adaptive)),
+            PngCompression::Level(4..=6) => Some((CompressionType::Default, PngFilterType::Adaptive)),
+            PngCompression::Level(_) => Some((CompressionType::Best, PngFilterType::Adaptive)),
+            PngCompression::Fast => Some((CompressionType::Fast, PngFilterType::NoFilter)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TiffCompression {
+    Uncompressed,
+    Lzw,
+}
+
+#[cfg(feature = "avif")]
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+#[cfg(feature = "avif")]
+const DEFAULT_AVIF_SPEED: u8 = 6;
+#[cfg(feature = "heic")]
+const DEFAULT_HEIC_QUALITY: u8 = 80;
+#[cfg(feature = "heic")]
+const DEFAULT_HEIC_SPEED: u8 = 6;
+/// Presets of x265 from the slowest to the fastest, picked by the HEIC speed.
+#[cfg(feature = "heic")]
+const HEIC_PRESETS: [&str; 10] = ["placebo", "veryslow", "slower", "slow", "medium", "fast", "faster", "veryfast", "superfast", "ultrafast"];
+/// Sizes of the icons in an ICO, the image is fitted into squares of them.
+const ICO_SIZES: [u32; 4] = [16, 32, 48, 64];
+/// Speed of the color quantization, 1 gives the best palette and 30 is the fastest.
+const GIF_SPEED: i32 = 10;
+/// Share of pixels NeuQuant learns the `png8` palette from, 1 is every pixel and 30 the fewest.
+const QUANTIZATION_SAMPLE_FACTOR: i32 = 10;
+/// Modifier after any format which writes it in grayscale.
+const GRAYSCALE_SUFFIX: &str = ":gray";
+
 
 impl FromStr for OutputFormat {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("png") { return Ok(OutputFormat::Png); }
+        if let Some(format) = s.strip_suffix(GRAYSCALE_SUFFIX) {
+            return parse_grayscale(format);
+        }
+        if let Some((format, size)) = s.split_once('@') {
+            return parse_max_size(format, size);
+        }
+        if s == "png8" { return Ok(OutputFormat::Png8); }
+        if s == "apng" || s == "image/apng" { return Ok(OutputFormat::Apng); }
+        if let Some(compression) = s.strip_prefix("png") {
+            return match compression {
+                "" => Ok(OutputFormat::Png(PngCompression::Default)),
+                "-fast" => Ok(OutputFormat::Png(PngCompression::Fast)),
+                level => match level.parse() {
+                    Ok(level @ 1..=9) => Ok(OutputFormat::Png(PngCompression::Level(level))),
+                    _ => Err(ParseError::InvalidFormat(s.to_string())),
+                },
+            };
+        }
         if s.starts_with("bmp") { return Ok(OutputFormat::Bmp); }
-        if s.starts_with("jpeg") {
-            let (_, quality) = s.split_at(4);
-            return if quality != "" {
-                let quality_u8: u8 = quality.parse()?;
-                if quality_u8 > 100 {
-                    return Err(ParseError::QualityOutOfRange(String::from("JpegXL must be between 0 (worst) to 100 (best)")));
-                }
-                Ok(OutputFormat::Jpeg(quality_u8))
-            } else {
-                Ok(OutputFormat::Jpeg(90))
+        if s.starts_with("gif") { return Ok(OutputFormat::Gif); }
+        if s.starts_with("ico") { return Ok(OutputFormat::Ico); }
+        if let Some(compression) = s.strip_prefix("tiff") {
+            return match compression {
+                "" => Ok(OutputFormat::Tiff(TiffCompression::Uncompressed)),
+                "lzw" => Ok(OutputFormat::Tiff(TiffCompression::Lzw)),
+                _ => Err(ParseError::InvalidFormat(s.to_string())),
             };
         }
-        if s.starts_with("webp") {
+        if let Some(options) = s.strip_prefix("jpeg") {
+            return parse_jpeg(options);
+        }
+        if let Some(options) = s.strip_prefix("webp") {
+            return parse_webp(options);
+        }
+        if s.starts_with("avif") {
             let (_, quality) = s.split_at(4);
-            return if quality != "" {
-                let quality_f32: f32 = quality.parse()?;
-                if quality_f32 > 100.0 {
-                    return Err(ParseError::QualityOutOfRange(String::from("WebP must be between 0 (best) to 100 (best)")));
-                }
-                Ok(OutputFormat::Webp(quality_f32))
-            } else {
-                Ok(OutputFormat::WebpLoseless)
-            };
+            return parse_avif(quality);
+        }
+        if let Some(options) = s.strip_prefix("heic") {
+            return parse_heic(options);
         }
-        if s == "image/webp" { return Ok(OutputFormat::WebpLoseless); }
-        if s == "image/png" { return Ok(OutputFormat::Png); }
+        if s == "image/webp" { return parse_webp(""); }
+        if s == "image/avif" { return parse_avif(""); }
+        if s == "image/heic" { return parse_heic(""); }
+        if s == "image/png" { return Ok(OutputFormat::Png(PngCompression::Default)); }
         if s == "image/bmp" { return Ok(OutputFormat::Bmp); }
-        if s == "image/jpeg" { return Ok(OutputFormat::Jpeg(90)); }
+        if s == "image/gif" { return Ok(OutputFormat::Gif); }
+        if s == "image/x-icon" || s == "image/vnd.microsoft.icon" { return Ok(OutputFormat::Ico); }
+        if s == "image/tiff" { return Ok(OutputFormat::Tiff(TiffCompression::Uncompressed)); }
+        if s == "image/jpeg" { return Ok(OutputFormat::Jpeg(90, JpegOptions::default())); }
         return Err(ParseError::InvalidFormat(s.to_string()));
     }
 }
 
+/// Any other format, once, e.g. `png:gray` or `webp80@50kb:gray`.
+fn parse_grayscale(format: &str) -> Result<OutputFormat, ParseError> {
+    match format.parse::<OutputFormat>() {
+        Ok(OutputFormat::Grayscale(_)) => Err(ParseError::InvalidFormat(format!("{}{}", format, GRAYSCALE_SUFFIX))),
+        Ok(format) => Ok(OutputFormat::Grayscale(Box::new(format))),
+        Err(ParseError::QualityOutOfRange(range)) => Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            clamped: OutputFormat::Grayscale(Box::new(range.clamped.clone())),
+            ..range
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lossy format followed by a size in kilobytes of 1000 bytes or in bytes, e.g. `webp80@50kb` or `jpeg@4096b`.
+fn parse_max_size(format: &str, size: &str) -> Result<OutputFormat, ParseError> {
+    let invalid_format = || ParseError::InvalidFormat(format!("{}@{}", format, size));
+    let max_size = match (size.strip_suffix("kb"), size.strip_suffix('b')) {
+        (Some(kilobytes), _) => kilobytes.parse::<u32>().ok().and_then(|kilobytes| kilobytes.checked_mul(1000)),
+        (None, Some(bytes)) => bytes.parse::<u32>().ok(),
+        _ => None,
+    };
+    let max_size = match max_size {
+        Some(max_size) if max_size > 0 => max_size,
+        _ => return Err(invalid_format()),
+    };
+    match format.parse::<OutputFormat>() {
+        Ok(format) if format.quality().is_some() => Ok(OutputFormat::MaxSize(Box::new(format), max_size)),
+        Ok(_) => Err(invalid_format()),
+        Err(ParseError::QualityOutOfRange(range)) => Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            clamped: OutputFormat::MaxSize(Box::new(range.clamped.clone()), max_size),
+            ..range
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+/// Flags, quality and subsampling, all optional, e.g. `p80c420`.
+fn parse_jpeg(options: &str) -> Result<OutputFormat, ParseError> {
+    let invalid_format = || ParseError::InvalidFormat(format!("jpeg{}", options));
+    let (options_and_quality, subsampling) = options.split_once('c').unwrap_or((options, ""));
+    let quality = options_and_quality.trim_start_matches(|flag: char| flag.is_ascii_alphabetic());
+    let mut jpeg_options = JpegOptions::default();
+    for flag in options_and_quality[..options_and_quality.len() - quality.len()].chars() {
+        match flag {
+            'p' => jpeg_options.progressive = true,
+            _ => return Err(invalid_format()),
+        }
+    }
+    jpeg_options.subsampling = match subsampling {
+        "" => None,
+        "444" => Some(ChromaSubsampling::Chroma444),
+        "422" => Some(ChromaSubsampling::Chroma422),
+        "420" => Some(ChromaSubsampling::Chroma420),
+        _ => return Err(invalid_format()),
+    };
+    if quality.is_empty() {
+        return Ok(OutputFormat::Jpeg(90, jpeg_options));
+    }
+    let quality: i64 = quality.parse()?;
+    if !(1..=100).contains(&quality) {
+        return Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            format: "jpeg",
+            requested: quality as f64,
+            minimum: 1.0,
+            maximum: 100.0,
+            clamped: OutputFormat::Jpeg(quality.clamp(1, 100) as u8, jpeg_options),
+        }));
+    }
+    Ok(OutputFormat::Jpeg(quality as u8, jpeg_options))
+}
+
+/// Quality or near-lossless level, then the method, all optional, e.g. `80m6` or `nl60`.
+#[cfg(feature = "webp")]
+fn parse_webp(options: &str) -> Result<OutputFormat, ParseError> {
+    let invalid_format = || ParseError::InvalidFormat(format!("webp{}", options));
+    let (quality, method) = options.split_once('m').unwrap_or((options, ""));
+    let webp_options = WebpOptions {
+        method: match method {
+            "" if !options.ends_with('m') => None,
+            method => match method.parse::<u8>() {
+                Ok(method) if method <= 6 => Some(method),
+                _ => return Err(invalid_format()),
+            },
+        },
+    };
+    if let Some(level) = quality.strip_prefix("nl") {
+        return match level.parse::<u8>() {
+            Ok(level) if level <= 100 => Ok(OutputFormat::WebpNearLossless(level, webp_options)),
+            _ => Err(invalid_format()),
+        };
+    }
+    if quality.is_empty() {
+        return Ok(OutputFormat::WebpLoseless(webp_options));
+    }
+    let quality_f32: f32 = quality.parse()?;
+    if !quality_f32.is_finite() {
+        return Err(invalid_format());
+    }
+    if !(0.0..=100.0).contains(&quality_f32) {
+        return Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            format: "webp",
+            requested: quality_f32 as f64,
+            minimum: 0.0,
+            maximum: 100.0,
+            clamped: OutputFormat::Webp(quality_f32.clamp(0.0, 100.0), webp_options),
+        }));
+    }
+    Ok(OutputFormat::Webp(quality_f32, webp_options))
+}
+
+#[cfg(not(feature = "webp"))]
+fn parse_webp(_quality: &str) -> Result<OutputFormat, ParseError> {
+    Err(ParseError::FormatNotEnabled(String::from("webp")))
+}
+
+/// `avif`, `avif{quality}` or `avif{quality}s{speed}`, e.g. `avif70s4`.
+#[cfg(feature = "avif")]
+fn parse_avif(options: &str) -> Result<OutputFormat, ParseError> {
+    let (quality, speed) = options.split_once('s').unwrap_or((options, ""));
+    let speed = match speed {
+        "" => DEFAULT_AVIF_SPEED,
+        speed => match speed.parse::<u8>()? {
+            speed @ 1..=10 => speed,
+            _ => return Err(ParseError::InvalidFormat(format!("avif{}", options))),
+        },
+    };
+    let quality = match quality {
+        "" => DEFAULT_AVIF_QUALITY as i64,
+        quality => quality.parse()?,
+    };
+    if !(0..=100).contains(&quality) {
+        return Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            format: "avif",
+            requested: quality as f64,
+            minimum: 0.0,
+            maximum: 100.0,
+            clamped: OutputFormat::Avif(quality.clamp(0, 100) as u8, speed),
+        }));
+    }
+    Ok(OutputFormat::Avif(quality as u8, speed))
+}
+
+#[cfg(not(feature = "avif"))]
+fn parse_avif(_options: &str) -> Result<OutputFormat, ParseError> {
+    Err(ParseError::FormatNotEnabled(String::from("avif")))
+}
+
+/// `heic`, `heic{quality}` or `heic{quality}s{speed}`, e.g. `heic70s4`.
+#[cfg(feature = "heic")]
+fn parse_heic(options: &str) -> Result<OutputFormat, ParseError> {
+    let (quality, speed) = options.split_once('s').unwrap_or((options, ""));
+    let speed = match speed {
+        "" => DEFAULT_HEIC_SPEED,
+        speed => match speed.parse::<u8>()? {
+            speed @ 1..=10 => speed,
+            _ => return Err(ParseError::InvalidFormat(format!("heic{}", options))),
+        },
+    };
+    let quality = match quality {
+        "" => DEFAULT_HEIC_QUALITY as i64,
+        quality => quality.parse()?,
+    };
+    if !(0..=100).contains(&quality) {
+        return Err(ParseError::QualityOutOfRange(QualityOutOfRange {
+            format: "heic",
+            requested: quality as f64,
+            minimum: 0.0,
+            maximum: 100.0,
+            clamped: OutputFormat::Heic(quality.clamp(0, 100) as u8, speed),
+        }));
+    }
+    Ok(OutputFormat::Heic(quality as u8, speed))
+}
+
+#[cfg(not(feature = "heic"))]
+fn parse_heic(_options: &str) -> Result<OutputFormat, ParseError> {
+    Err(ParseError::FormatNotEnabled(String::from("heic")))
+}
+
+impl OutputFormat {
+    /// Parses the format, applying the policy to a quality out of the range of the codec.
+    /// Clamped format is returned along with the range it was clamped to.
+    pub fn parse_with_policy(s: &str, policy: &QualityPolicy) -> Result<(OutputFormat, Option<QualityOutOfRange>), ParseError> {
+        match s.parse() {
+            Ok(format) => Ok((format, None)),
+            Err(ParseError::QualityOutOfRange(range)) if *policy == QualityPolicy::Clamp => Ok((range.clamped.clone(), Some(range))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fills in what the request left to the configuration.
+    pub fn with_defaults(self, settings: &EncodingSettings) -> OutputFormat {
+        match self {
+            OutputFormat::Jpeg(quality, options) => OutputFormat::Jpeg(quality, JpegOptions {
+                subsampling: options.subsampling.or(Some(settings.jpeg_subsampling)),
+                ..options
+            }),
+            OutputFormat::MaxSize(format, max_size) => OutputFormat::MaxSize(Box::new(format.with_defaults(settings)), max_size),
+            OutputFormat::Grayscale(format) => OutputFormat::Grayscale(Box::new(format.with_defaults(settings))),
+            format => format,
+        }
+    }
+
+    /// Quality of lossy formats, the one searched down from for a size.
+    fn quality(&self) -> Option<u8> {
+        match self {
+            OutputFormat::Jpeg(quality, _) => Some(*quality),
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(quality, _) => Some(*quality as u8),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(quality, _) => Some(*quality),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(quality, _) => Some(*quality),
+            _ => None,
+        }
+    }
+
+    fn with_quality(&self, quality: u8) -> OutputFormat {
+        match self {
+            OutputFormat::Jpeg(_, options) => OutputFormat::Jpeg(quality, *options),
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(_, options) => OutputFormat::Webp(quality as f32, *options),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(_, speed) => OutputFormat::Avif(quality, *speed),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(_, speed) => OutputFormat::Heic(quality, *speed),
+            format => format.clone(),
+        }
+    }
+
+    /// Format the grayscale image is written in, the format itself for others.
+    fn without_grayscale(&self) -> &OutputFormat {
+        match self {
+            OutputFormat::Grayscale(format) => format,
+            format => format,
+        }
+    }
+
+    /// Format as written in URL paths, e.g. `webp80`.
+    pub fn path_segment(&self) -> String {
+        match self {
+            OutputFormat::Png(PngCompression::Default) => String::from("png"),
+            OutputFormat::Png(PngCompression::Level(level)) => format!("png{}", level),
+            OutputFormat::Png(PngCompression::Fast) => String::from("png-fast"),
+            OutputFormat::Png8 => String::from("png8"),
+            OutputFormat::Apng => String::from("apng"),
+            OutputFormat::Bmp => String::from("bmp"),
+            OutputFormat::Gif => String::from("gif"),
+            OutputFormat::Ico => String::from("ico"),
+            OutputFormat::Tiff(TiffCompression::Uncompressed) => String::from("tiff"),
+            OutputFormat::Tiff(TiffCompression::Lzw) => String::from("tifflzw"),
+            OutputFormat::Jpeg(quality, options) => format!("jpeg{}{}{}", options.flags(), quality, options.subsampling_suffix()),
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(quality, options) => format!("webp{}{}", quality, options.method_suffix()),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(options) => format!("webp{}", options.method_suffix()),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpNearLossless(level, options) => format!("webpnl{}{}", level, options.method_suffix()),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(quality, speed) => format!("avif{}s{}", quality, speed),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(quality, speed) => format!("heic{}s{}", quality, speed),
+            OutputFormat::MaxSize(format, max_size) if max_size % 1000 == 0 => format!("{}@{}kb", format.path_segment(), max_size / 1000),
+            OutputFormat::MaxSize(format, max_size) => format!("{}@{}b", format.path_segment(), max_size),
+            OutputFormat::Grayscale(format) => format!("{}{}", format.path_segment(), GRAYSCALE_SUFFIX),
+        }
+    }
+
+    /// Format without its parameters, e.g. `webp` or `webp-lossless`, as named in allow-lists.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg(_, _) => "jpeg",
+            OutputFormat::Png(_) => "png",
+            OutputFormat::Png8 => "png8",
+            OutputFormat::Apng => "apng",
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(_, _) => "webp",
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(_) => "webp-lossless",
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpNearLossless(_, _) => "webp-near-lossless",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Tiff(_) => "tiff",
+            OutputFormat::Ico => "ico",
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(_, _) => "avif",
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(_, _) => "heic",
+            OutputFormat::MaxSize(format, _) | OutputFormat::Grayscale(format) => format.name(),
+        }
+    }
+
+    /// Lossy formats above the quality are lowered to it, others stay as they are.
+    pub fn with_quality_cap(self, cap: u8) -> OutputFormat {
+        match self {
+            OutputFormat::MaxSize(format, max_size) => OutputFormat::MaxSize(Box::new(format.with_quality_cap(cap)), max_size),
+            OutputFormat::Grayscale(format) => OutputFormat::Grayscale(Box::new(format.with_quality_cap(cap))),
+            format => match format.quality() {
+                Some(quality) if quality > cap => format.with_quality(cap),
+                _ => format,
+            },
+        }
+    }
+
+    /// Formats which keep every frame of animated sources.
+    pub fn is_animated(&self) -> bool {
+        match self {
+            OutputFormat::Gif | OutputFormat::Apng => true,
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(_, _) | OutputFormat::WebpLoseless(_) | OutputFormat::WebpNearLossless(_, _) => true,
+            OutputFormat::Grayscale(format) => format.is_animated(),
+            _ => false,
+        }
+    }
+
+    /// Relative cost of encoding a single pixel, used to estimate how expensive a transform is.
+    pub fn cost_weight(&self) -> u64 {
+        match self {
+            OutputFormat::Bmp | OutputFormat::Tiff(TiffCompression::Uncompressed) => 1,
+            OutputFormat::Tiff(TiffCompression::Lzw) => 2,
+            OutputFormat::Jpeg(_, options) if !options.is_baseline() => 3,
+            OutputFormat::Jpeg(_, _) => 2,
+            OutputFormat::Png(PngCompression::Fast) => 2,
+            OutputFormat::Png(PngCompression::Level(7..=9)) => 8,
+            OutputFormat::Png(_) => 4,
+            OutputFormat::Png8 => 8,
+            OutputFormat::Gif | OutputFormat::Apng => 8,
+            // Icons are small, the cost is in scaling the image down for each of them.
+            OutputFormat::Ico => 1,
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(_, _) => 4,
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(_) | OutputFormat::WebpNearLossless(_, _) => 8,
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(_, speed) => 4 * (11 - *speed as u64),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(_, speed) => 4 * (11 - *speed as u64),
+            // Binary search over 100 qualities encodes the image up to 7 times.
+            OutputFormat::MaxSize(format, _) => 7 * format.cost_weight(),
+            OutputFormat::Grayscale(format) => format.cost_weight(),
+        }
+    }
+
+    /// Format served to clients asking to save data: lossy formats lose `quality_delta` of quality,
+    /// lossless ones become lossy WebP. GIF is kept, so animations keep moving, and so is ICO.
+    pub fn save_data(self, quality_delta: u8) -> OutputFormat {
+        match self {
+            format @ (OutputFormat::Gif | OutputFormat::Ico) => format,
+            OutputFormat::MaxSize(format, max_size) => OutputFormat::MaxSize(Box::new(format.save_data(quality_delta)), max_size),
+            OutputFormat::Grayscale(format) => OutputFormat::Grayscale(Box::new(format.save_data(quality_delta))),
+            OutputFormat::Jpeg(quality, options) => OutputFormat::Jpeg(quality.saturating_sub(quality_delta).max(1), options),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(quality, speed) => OutputFormat::Avif(quality.saturating_sub(quality_delta), speed),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(quality, speed) => OutputFormat::Heic(quality.saturating_sub(quality_delta), speed),
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(quality, options) => OutputFormat::Webp((quality - quality_delta as f32).max(0.0), options),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(options) | OutputFormat::WebpNearLossless(_, options) => OutputFormat::Webp((100.0 - quality_delta as f32).max(0.0), options),
+            #[cfg(feature = "webp")]
+            OutputFormat::Png(_) | OutputFormat::Png8 | OutputFormat::Apng | OutputFormat::Bmp | OutputFormat::Tiff(_) => OutputFormat::Webp((100.0 - quality_delta as f32).max(0.0), WebpOptions::default()),
+            #[cfg(not(feature = "webp"))]
+            format => format,
+        }
+    }
+}
+
 impl Display for OutputFormat {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            OutputFormat::Png => write!(f, "image/png"),
-            OutputFormat::WebpLoseless => write!(f, "image/webp - loseless"),
-            OutputFormat::Jpeg(q) => write!(f, "image/jpeg - quality: {}", q),
-            OutputFormat::Webp(q) => write!(f, "image/webp - quality: {}", q),
+            OutputFormat::Png(PngCompression::Default) => write!(f, "image/png"),
+            OutputFormat::Png(PngCompression::Level(level)) => write!(f, "image/png - level: {}", level),
+            OutputFormat::Png(PngCompression::Fast) => write!(f, "image/png - fast"),
+            OutputFormat::Png8 => write!(f, "image/png - palette"),
+            OutputFormat::Apng => write!(f, "image/png - animated"),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(options) => write!(f, "image/webp - loseless{}", options),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpNearLossless(level, options) => write!(f, "image/webp - near-lossless: {}{}", level, options),
+            OutputFormat::Jpeg(q, options) => {
+                write!(f, "image/jpeg - quality: {}", q)?;
+                if options.progressive {
+                    write!(f, " progressive")?;
+                }
+                match options.subsampling_suffix().as_str() {
+                    "" => Ok(()),
+                    _ => write!(f, " subsampling: {}", options.subsampling.unwrap_or_default()),
+                }
+            }
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(q, options) => write!(f, "image/webp - quality: {}{}", q, options),
             OutputFormat::Bmp => write!(f, "image/bmp"),
+            OutputFormat::Gif => write!(f, "image/gif"),
+            OutputFormat::Ico => write!(f, "image/x-icon"),
+            OutputFormat::Tiff(TiffCompression::Uncompressed) => write!(f, "image/tiff"),
+            OutputFormat::Tiff(TiffCompression::Lzw) => write!(f, "image/tiff - lzw"),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(q, speed) => write!(f, "image/avif - quality: {} speed: {}", q, speed),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(q, speed) => write!(f, "image/heic - quality: {} speed: {}", q, speed),
+            OutputFormat::MaxSize(format, max_size) => write!(f, "{} max size: {}", format, max_size),
+            OutputFormat::Grayscale(format) => write!(f, "{} grayscale", format),
         }
     }
 }
 
+/// Only a method which was asked for is written, so keys of WebPs without one stay the same.
+impl Display for WebpOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.method {
+            Some(method) => write!(f, " method: {}", method),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct QualityOutOfRange {
+    pub format: &'static str,
+    pub requested: f64,
+    pub minimum: f64,
+    pub maximum: f64,
+    /// Format with the quality clamped into the range, served under the clamp policy.
+    #[serde(skip)]
+    pub clamped: OutputFormat,
+}
+
+impl QualityOutOfRange {
+    pub fn clamped_quality(&self) -> f64 {
+        self.requested.clamp(self.minimum, self.maximum)
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseError {
     InvalidIntQuality(ParseIntError),
     InvalidFloatQuality(ParseFloatError),
-    QualityOutOfRange(String),
+    QualityOutOfRange(QualityOutOfRange),
     InvalidFormat(String),
+    FormatNotEnabled(String),
 }
 
 impl From<ParseIntError> for ParseError {
@@ -94,76 +708,1005 @@ impl From<ParseFloatError> for ParseError {
 
 #[derive(Debug)]
 pub enum EncodingError {
+    /// Color type of the image the format cannot hold, e.g. a 16-bit image written as BMP.
+    UnsupportedColorType(ColorType),
+    EncoderFailure(String),
+    /// Image was encoded, but could not be written to the cache.
+    CacheWriteFailure(String),
+}
+
+impl EncodingError {
+    /// Errors image reports as unsupported are caused by the color type of the written image.
+    fn from_image(e: ImageError, color: ColorType) -> Self {
+        match e {
+            ImageError::Unsupported(_) => EncodingError::UnsupportedColorType(color),
+            e => EncodingError::EncoderFailure(e.to_string()),
+        }
+    }
+
+    fn failure(e: impl Display) -> Self {
+        EncodingError::EncoderFailure(e.to_string())
+    }
 }
 
+/// Version of the encoding pipeline, part of every ETag. Output of the same source can change
+/// between releases, so the ETag changes with it.
+pub const CODEC_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct EncodedImage {
     pub content_type: String,
     pub image: Vec<u8>,
+    pub etag: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Cache key of the image encoded from the tagged image. Images carrying the color profile of
+/// the source are kept apart from the ones without.
+pub fn encoded_tag(tag: &str, dimensions: &OutputDimensions, output_format: &OutputFormat, keep_color_profile: bool) -> String {
+    match keep_color_profile {
+        true => generate_resource_tag(&format!("{} - {} {} with color profile", tag, output_format, dimensions)),
+        false => generate_resource_tag(&format!("{} - {} {}", tag, output_format, dimensions)),
+    }
+}
+
+/// ETag of the image cached under the key, see `encoded_tag`.
+pub fn encoded_etag(encoded_tag: &str) -> String {
+    format!("\"{}\"", generate_resource_tag(&format!("{} - {}", encoded_tag, CODEC_VERSION)))
 }
 
 pub trait ImageEncoder {
     fn serve_cache(&self, tag: &String, dimensions: &OutputDimensions, output_format: OutputFormat) -> Option<EncodedImage>;
-    fn encode(&self, tag: &String, resource: DynamicImage, dimensions: &OutputDimensions, output_format: OutputFormat) -> Result<EncodedImage, EncodingError>;
+    /// The ICC profile of the source is embedded in JPEG, PNG and WebP output when color profiles are kept.
+    fn encode(&self, tag: &String, resource: DynamicImage, dimensions: &OutputDimensions, output_format: OutputFormat, color_profile: Option<&[u8]>) -> Result<EncodedImage, EncodingError>;
+    /// Encodes every frame of the animation, cached under the same key as a still image.
+    /// Formats which do not animate, see `OutputFormat::is_animated`, are written as GIF.
+    fn encode_animation(&self, tag: &String, animation: Animation, dimensions: &OutputDimensions, output_format: OutputFormat) -> Result<EncodedImage, EncodingError>;
+    /// Replaces the cached image encoded from the tagged image, e.g. with the source itself.
+    fn store(&self, tag: &str, dimensions: &OutputDimensions, output_format: &OutputFormat, encoded_image: &EncodedImage) -> Result<(), EncodingError>;
 }
 
 pub struct AllInOneCachedImageEncoder {
-    pub cache: Arc<RwLock<Box<dyn CacheEngine + Send + Sync>>>,
+    pub cache: SharedCache,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
+    pub jpeg_encoder: JpegEncoder,
+    pub keep_color_profile: bool,
+}
+
+impl AllInOneCachedImageEncoder {
+    /// Encoded image and its content type.
+    fn encode_image(&self, resource: &DynamicImage, output_format: &OutputFormat) -> Result<(Vec<u8>, String), EncodingError> {
+        let write_image = |format: ImageOutputFormat| {
+            let mut image = Vec::new();
+            resource.write_to(&mut Cursor::new(&mut image), format)
+                .map_err(|e| EncodingError::from_image(e, resource.color()))?;
+            Ok(image)
+        };
+        Ok(match output_format.clone() {
+            #[cfg(feature = "mozjpeg")]
+            OutputFormat::Jpeg(quality, options) if self.jpeg_encoder == JpegEncoder::Mozjpeg => (encode_mozjpeg(resource, quality, options)?, mime::IMAGE_JPEG.to_string()),
+            OutputFormat::Jpeg(quality, options) if !options.is_baseline() => (encode_jpeg(resource, quality, options)?, mime::IMAGE_JPEG.to_string()),
+            OutputFormat::Jpeg(quality, _) => (write_jpeg(resource, quality)?, mime::IMAGE_JPEG.to_string()),
+            OutputFormat::Png(compression) => (encode_png(resource, compression)?, mime::IMAGE_PNG.to_string()),
+            OutputFormat::Png8 => (encode_png8(resource)?, mime::IMAGE_PNG.to_string()),
+            OutputFormat::Apng => (encode_png(resource, PngCompression::Default)?, mime::IMAGE_PNG.to_string()),
+            OutputFormat::Bmp => (write_image(ImageOutputFormat::Bmp)?, mime::IMAGE_BMP.to_string()),
+            OutputFormat::Gif => (encode_gif(vec![(resource.to_rgba8(), 0)], false)?, mime::IMAGE_GIF.to_string()),
+            OutputFormat::Tiff(compression) => (encode_tiff(resource, compression)?, String::from("image/tiff")),
+            OutputFormat::Ico => (encode_ico(resource)?, String::from("image/x-icon")),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(options) => (encode_webp(resource, None, 100, options)?, String::from("image/webp")),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpNearLossless(level, options) => (encode_webp(resource, None, level, options)?, String::from("image/webp")),
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(quality, options) => (encode_webp(resource, Some(quality), 100, options)?, String::from("image/webp")),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif(quality, speed) => (encode_avif(resource, quality, speed)?, String::from("image/avif")),
+            #[cfg(feature = "heic")]
+            OutputFormat::Heic(quality, speed) => (encode_heic(resource, quality, speed)?, String::from("image/heic")),
+            OutputFormat::MaxSize(format, max_size) => self.encode_max_size(resource, &format, max_size)?,
+            OutputFormat::Grayscale(format) => self.encode_image(&grayscale(resource, &format), &format)?,
+        })
+    }
+
+    /// Binary search for the highest quality whose output fits. When none does, the image is
+    /// written at the lowest quality, which is as close as the format gets.
+    fn encode_max_size(&self, resource: &DynamicImage, output_format: &OutputFormat, max_size: u32) -> Result<(Vec<u8>, String), EncodingError> {
+        let (mut lowest, mut highest) = (1, output_format.quality().unwrap_or(100));
+        let mut fitting = None;
+        while lowest <= highest {
+            let quality = lowest + (highest - lowest) / 2;
+            let encoded = self.encode_image(resource, &output_format.with_quality(quality))?;
+            if encoded.0.len() <= max_size as usize {
+                fitting = Some(encoded);
+                lowest = quality + 1;
+            } else {
+                highest = quality - 1;
+            }
+        }
+        match fitting {
+            Some(fitting) => Ok(fitting),
+            None => {
+                info!("No quality of {} fits in {} bytes, writing the lowest one.", output_format, max_size);
+                self.encode_image(resource, &output_format.with_quality(1))
+            }
+        }
+    }
 }
 
 impl ImageEncoder for AllInOneCachedImageEncoder {
     fn serve_cache(&self, tag: &String, dimensions: &OutputDimensions, output_format: OutputFormat) -> Option<EncodedImage> {
-        let tag = generate_resource_tag(&format!("{} - {} {}", tag, output_format, dimensions));
-        if let Some(cached_encoded_image) = self.cache.read().unwrap().get(&tag) {
+        let tag = encoded_tag(tag, dimensions, &output_format, self.keep_color_profile);
+        let cached_encoded_image = Cache::<EncodedImage>::new(&self.cache).get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "encode", cached_encoded_image.is_some());
+        if cached_encoded_image.is_some() {
             info!("Serving {} {} from cache.", tag, output_format);
-            return Option::Some(bincode::deserialize(cached_encoded_image.as_slice()).unwrap());
         }
-        Option::None
+        cached_encoded_image
     }
 
 
-    fn encode(&self, tag: &String, resource: DynamicImage, dimensions: &OutputDimensions, output_format: OutputFormat) -> Result<EncodedImage, EncodingError> {
-        let mut image: Vec<u8> = Vec::default();
-        let content_type: String;
-
-        let tag = generate_resource_tag(&format!("{} - {} {}", tag, output_format, dimensions));
-        if let Some(cached_encoded_image) = self.cache.read().unwrap().get(&tag) {
+    fn encode(&self, tag: &String, resource: DynamicImage, dimensions: &OutputDimensions, output_format: OutputFormat, color_profile: Option<&[u8]>) -> Result<EncodedImage, EncodingError> {
+        let tag = encoded_tag(tag, dimensions, &output_format, self.keep_color_profile);
+        let cache = Cache::<EncodedImage>::new(&self.cache);
+        let cached_encoded_image = cache.get_blocking(&tag);
+        if let Some(cached_encoded_image) = cached_encoded_image {
             info!("Serving {} {} from cache.", tag, output_format);
-            return Ok(bincode::deserialize(cached_encoded_image.as_slice()).unwrap());
+            return Ok(cached_encoded_image);
         }
 
-        match output_format {
-            OutputFormat::Jpeg(quality) => {
-                resource.write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Jpeg(quality)).unwrap();
-                content_type = mime::IMAGE_JPEG.to_string();
-            }
-            OutputFormat::Png => {
-                resource.write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Png).unwrap();
-                content_type = mime::IMAGE_PNG.to_string();
-            }
-            OutputFormat::Bmp => {
-                resource.write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Bmp).unwrap();
-                content_type = mime::IMAGE_BMP.to_string();
-            }
-            OutputFormat::WebpLoseless => {
-                let encoder = webp::Encoder::from_image(&resource).unwrap();
-                image = encoder.encode_lossless().to_vec();
-                content_type = String::from("image/webp")
-            }
-            OutputFormat::Webp(quality) => {
-                let encoder = webp::Encoder::from_image(&resource).unwrap();
-                image = encoder.encode(quality).to_vec();
-                content_type = String::from("image/webp")
+        let (mut width, mut height) = resource.dimensions();
+        if output_format == OutputFormat::Ico {
+            // Largest of the icons is reported, it is the one decoders pick.
+            width = ICO_SIZES[ICO_SIZES.len() - 1];
+            height = width;
+        }
+        let started = Instant::now();
+        let (mut image, content_type) = self.encode_image(&resource, &output_format)?;
+        // Profiles describe colors, grayscale images have none left to describe.
+        let has_color = resource.color().has_color() && !matches!(output_format, OutputFormat::Grayscale(_));
+        if let Some(profile) = color_profile.filter(|profile| self.keep_color_profile && has_color && metadata::is_rgb_profile(profile)) {
+            let embedded = metadata::embed_color_profile(&image, profile, resource.dimensions());
+            match (embedded, &output_format) {
+                (Some(embedded), OutputFormat::MaxSize(_, max_size)) if embedded.len() > *max_size as usize => {
+                    info!("Leaving the color profile out of {}, it does not fit in {} bytes.", tag, max_size);
+                }
+                (Some(embedded), _) => image = embedded,
+                (None, _) => {}
             }
         }
+        observe_stage(self.metrics.as_ref(), "encode", started);
         let encoded_image = EncodedImage {
             image,
             content_type,
+            etag: encoded_etag(&tag),
+            width,
+            height,
         };
 
         info!("Saving {} {} to cache.", tag, output_format);
-        self.cache.write().unwrap().set(&tag, &bincode::serialize(&encoded_image.clone()).unwrap()).unwrap();
+        cache.set_blocking(&tag, &encoded_image);
 
         Ok(encoded_image)
     }
+
+    fn encode_animation(&self, tag: &String, animation: Animation, dimensions: &OutputDimensions, output_format: OutputFormat) -> Result<EncodedImage, EncodingError> {
+        let output_format = match (output_format.is_animated(), output_format) {
+            (true, output_format) => output_format,
+            (false, OutputFormat::Grayscale(_)) => OutputFormat::Grayscale(Box::new(OutputFormat::Gif)),
+            (false, _) => OutputFormat::Gif,
+        };
+        let tag = encoded_tag(tag, dimensions, &output_format, self.keep_color_profile);
+        let cache = Cache::<EncodedImage>::new(&self.cache);
+        let cached_encoded_image = cache.get_blocking(&tag);
+        if let Some(cached_encoded_image) = cached_encoded_image {
+            info!("Serving {} {} from cache.", tag, output_format);
+            return Ok(cached_encoded_image);
+        }
+
+        let (width, height) = animation.frames.first()
+            .map(|frame| (frame.image.width, frame.image.height))
+            .unwrap_or_default();
+        let started = Instant::now();
+        let frames = animation.frames.into_iter()
+            .map(|frame| match &output_format {
+                OutputFormat::Grayscale(format) => (grayscale(&DynamicImage::from(frame.image), format).to_rgba8(), frame.delay_ms),
+                _ => (DynamicImage::from(frame.image).to_rgba8(), frame.delay_ms),
+            })
+            .collect();
+        let (image, content_type) = match output_format.without_grayscale().clone() {
+            #[cfg(feature = "webp")]
+            OutputFormat::Webp(quality, options) => (encode_animated_webp(frames, Some(quality), 100, options)?, String::from("image/webp")),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpLoseless(options) => (encode_animated_webp(frames, None, 100, options)?, String::from("image/webp")),
+            #[cfg(feature = "webp")]
+            OutputFormat::WebpNearLossless(level, options) => (encode_animated_webp(frames, None, level, options)?, String::from("image/webp")),
+            OutputFormat::Apng => (encode_apng(frames)?, mime::IMAGE_PNG.to_string()),
+            _ => (encode_gif(frames, true)?, mime::IMAGE_GIF.to_string()),
+        };
+        observe_stage(self.metrics.as_ref(), "encode", started);
+        let encoded_image = EncodedImage {
+            image,
+            content_type,
+            etag: encoded_etag(&tag),
+            width,
+            height,
+        };
+
+        info!("Saving {} {} to cache.", tag, output_format);
+        cache.set_blocking(&tag, &encoded_image);
+
+        Ok(encoded_image)
+    }
+
+    fn store(&self, tag: &str, dimensions: &OutputDimensions, output_format: &OutputFormat, encoded_image: &EncodedImage) -> Result<(), EncodingError> {
+        let tag = encoded_tag(tag, dimensions, output_format, self.keep_color_profile);
+        info!("Saving {} {} to cache.", tag, output_format);
+        block_on(Cache::<EncodedImage>::new(&self.cache).set(&tag, encoded_image))
+            .map(|_| ())
+            .map_err(|e| EncodingError::CacheWriteFailure(e.to_string()))
+    }
+}
+
+/// 8-bit grayscale of the image. Alpha is dropped for JPEG, which cannot hold it.
+fn grayscale(resource: &DynamicImage, output_format: &OutputFormat) -> DynamicImage {
+    match resource.color().has_alpha() && output_format.name() != "jpeg" {
+        true => DynamicImage::ImageLumaA8(resource.to_luma_alpha8()),
+        false => DynamicImage::ImageLuma8(resource.to_luma8()),
+    }
+}
+
+/// Images of up to 256 colors are written with a palette, e.g. palette sources which were not
+/// resized. Other images keep their color type, so grayscale stays grayscale.
+fn encode_png(resource: &DynamicImage, compression: PngCompression) -> Result<Vec<u8>, EncodingError> {
+    let strategy = compression.strategy();
+    let indexed = match resource {
+        DynamicImage::ImageRgba8(_) | DynamicImage::ImageRgb8(_) => index_colors(&resource.to_rgba8()),
+        _ => None,
+    };
+    match indexed {
+        Some((palette, indices)) => encode_indexed_png(resource.dimensions(), &palette, &indices, strategy),
+        None => {
+            let mut image = Vec::new();
+            let encoder = match strategy {
+                Some((compression, filter)) => PngEncoder::new_with_quality(&mut image, compression, filter),
+                None => PngEncoder::new(&mut image),
+            };
+            image_crate::ImageEncoder::write_image(encoder, resource.as_bytes(), resource.width(), resource.height(), resource.color())
+                .map_err(|e| EncodingError::from_image(e, resource.color()))?;
+            Ok(image)
+        }
+    }
+}
+
+fn encode_png8(resource: &DynamicImage) -> Result<Vec<u8>, EncodingError> {
+    let rgba = resource.to_rgba8();
+    let (palette, indices) = index_colors(&rgba).unwrap_or_else(|| quantize(&rgba));
+    encode_indexed_png(rgba.dimensions(), &palette, &indices, Some((CompressionType::Best, PngFilterType::Adaptive)))
+}
+
+fn encode_indexed_png((width, height): (u32, u32), palette: &[[u8; 4]], indices: &[u8], strategy: Option<(CompressionType, PngFilterType)>) -> Result<Vec<u8>, EncodingError> {
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    if let Some((compression, filter)) = strategy {
+        encoder.set_compression(match compression {
+            CompressionType::Best => png::Compression::Best,
+            CompressionType::Default => png::Compression::Default,
+            _ => png::Compression::Fast,
+        });
+        if filter == PngFilterType::NoFilter {
+            encoder.set_filter(png::FilterType::NoFilter);
+        } else {
+            encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+        }
+    }
+    encoder.set_palette(palette.iter().flat_map(|color| color[..3].to_vec()).collect::<Vec<u8>>());
+    if palette.iter().any(|color| color[3] != 255) {
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect::<Vec<u8>>());
+    }
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(indices))
+        .map_err(EncodingError::failure)?;
+    Ok(image)
+}
+
+/// Frames with their delays in milliseconds, played forever. The first frame is the default
+/// image, which decoders without APNG support show.
+fn encode_apng(frames: Vec<(RgbaImage, u32)>) -> Result<Vec<u8>, EncodingError> {
+    let (width, height) = frames.first().map(|(frame, _)| frame.dimensions()).unwrap_or((1, 1));
+    let mut image = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut image, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames.len() as u32, 0).map_err(EncodingError::failure)?;
+        let mut writer = encoder.write_header().map_err(EncodingError::failure)?;
+        for (frame, delay_ms) in frames {
+            writer.set_frame_delay(delay_ms.min(u16::MAX as u32) as u16, 1000).map_err(EncodingError::failure)?;
+            writer.write_image_data(frame.as_raw()).map_err(EncodingError::failure)?;
+        }
+    }
+    Ok(image)
+}
+
+/// Palette of 256 colors picked by NeuQuant, with every pixel mapped to the closest one. The error
+/// of each pixel is spread over the following ones (Floyd-Steinberg), so gradients do not band.
+fn quantize(rgba: &RgbaImage) -> (Vec<[u8; 4]>, Vec<u8>) {
+    let quantizer = NeuQuant::new(QUANTIZATION_SAMPLE_FACTOR, 256, rgba.as_raw());
+    let palette: Vec<[u8; 4]> = quantizer.color_map_rgba().chunks_exact(4)
+        .map(|color| [color[0], color[1], color[2], color[3]])
+        .collect();
+    let width = rgba.width() as usize;
+    let mut errors = vec![[0f32; 4]; width];
+    let mut next_errors = vec![[0f32; 4]; width];
+    let mut indices = Vec::with_capacity(width * rgba.height() as usize);
+    for row in rgba.rows() {
+        for (x, pixel) in row.enumerate() {
+            let mut wanted = [0u8; 4];
+            for channel in 0..4 {
+                wanted[channel] = (pixel[channel] as f32 + errors[x][channel]).round().clamp(0.0, 255.0) as u8;
+            }
+            let index = quantizer.index_of(&wanted);
+            indices.push(index as u8);
+            for channel in 0..4 {
+                let error = wanted[channel] as f32 - palette[index][channel] as f32;
+                if x + 1 < width {
+                    errors[x + 1][channel] += error * 7.0 / 16.0;
+                    next_errors[x + 1][channel] += error / 16.0;
+                }
+                if x > 0 {
+                    next_errors[x - 1][channel] += error * 3.0 / 16.0;
+                }
+                next_errors[x][channel] += error * 5.0 / 16.0;
+            }
+        }
+        errors = std::mem::replace(&mut next_errors, vec![[0f32; 4]; width]);
+    }
+    (palette, indices)
+}
+
+/// Palette and the index of every pixel, `None` when the image has more than 256 colors.
+fn index_colors(rgba: &RgbaImage) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(rgba.width() as usize * rgba.height() as usize);
+    for pixel in rgba.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(index) => *index,
+            None if palette.len() < 256 => {
+                let index = palette.len() as u8;
+                palette.push(pixel.0);
+                lookup.insert(pixel.0, index);
+                index
+            }
+            None => return None,
+        };
+        indices.push(index);
+    }
+    Some((palette, indices))
+}
+
+/// Lossy with `quality`, lossless otherwise. Settings left alone are the ones of libwebp's simple
+/// API, which lossless images are encoded with at quality 70.
+#[cfg(feature = "webp")]
+fn encode_webp(resource: &DynamicImage, quality: Option<f32>, near_lossless: u8, options: WebpOptions) -> Result<Vec<u8>, EncodingError> {
+    use libwebp_sys::*;
+
+    let (width, height) = resource.dimensions();
+    let (pixels, has_alpha) = match resource.color().has_alpha() {
+        true => (resource.to_rgba8().into_raw(), true),
+        false => (resource.to_rgb8().into_raw(), false),
+    };
+    unsafe {
+        let mut config: WebPConfig = std::mem::zeroed();
+        if WebPConfigInitInternal(&mut config, WebPPreset::WEBP_PRESET_DEFAULT, quality.unwrap_or(70.0), WEBP_ENCODER_ABI_VERSION) == 0 {
+            return Err(EncodingError::failure("Unable to configure WebP encoder, libwebp version mismatch."));
+        }
+        config.lossless = quality.is_none() as i32;
+        config.near_lossless = near_lossless as i32;
+        if let Some(method) = options.method {
+            config.method = method as i32;
+        }
+        let mut picture: WebPPicture = std::mem::zeroed();
+        if WebPPictureInitInternal(&mut picture, WEBP_ENCODER_ABI_VERSION) == 0 {
+            return Err(EncodingError::failure("Unable to configure WebP picture, libwebp version mismatch."));
+        }
+        picture.use_argb = config.lossless;
+        picture.width = width as i32;
+        picture.height = height as i32;
+        let imported = match has_alpha {
+            true => WebPPictureImportRGBA(&mut picture, pixels.as_ptr(), width as i32 * 4),
+            false => WebPPictureImportRGB(&mut picture, pixels.as_ptr(), width as i32 * 3),
+        };
+        let mut writer: WebPMemoryWriter = std::mem::zeroed();
+        WebPMemoryWriterInit(&mut writer);
+        picture.writer = Some(WebPMemoryWrite);
+        picture.custom_ptr = &mut writer as *mut WebPMemoryWriter as *mut std::ffi::c_void;
+        let encoded = imported != 0 && WebPEncode(&config, &mut picture) != 0;
+        let error = picture.error_code;
+        WebPPictureFree(&mut picture);
+        let image = std::slice::from_raw_parts(writer.mem, writer.size).to_vec();
+        WebPMemoryWriterClear(&mut writer);
+        if !encoded {
+            return Err(EncodingError::EncoderFailure(format!("Unable to encode WebP: {:?}", error)));
+        }
+        Ok(image)
+    }
+}
+
+/// Frames are written one by one like still WebPs and muxed into an animation.
+#[cfg(feature = "webp")]
+fn encode_animated_webp(frames: Vec<(RgbaImage, u32)>, quality: Option<f32>, near_lossless: u8, options: WebpOptions) -> Result<Vec<u8>, EncodingError> {
+    let canvas = frames.first().map(|(frame, _)| frame.dimensions()).unwrap_or((1, 1));
+    let stills = frames.into_iter()
+        .map(|(frame, delay_ms)| Ok((encode_webp(&DynamicImage::ImageRgba8(frame), quality, near_lossless, options)?, delay_ms)))
+        .collect::<Result<Vec<(Vec<u8>, u32)>, EncodingError>>()?;
+    Ok(animated_webp::mux(canvas, &stills))
+}
+
+/// Baseline JPEGs written by image, which only takes 8-bit samples, so gray sources stay gray
+/// and other sources are written as RGB.
+fn write_jpeg(resource: &DynamicImage, quality: u8) -> Result<Vec<u8>, EncodingError> {
+    let eight_bit = match resource.color().has_color() {
+        true => DynamicImage::ImageRgb8(resource.to_rgb8()),
+        false => DynamicImage::ImageLuma8(resource.to_luma8()),
+    };
+    let mut image = Vec::new();
+    eight_bit.write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Jpeg(quality))
+        .map_err(|e| EncodingError::from_image(e, eight_bit.color()))?;
+    Ok(image)
+}
+
+/// JPEGs with options image does not support are written by jpeg-encoder.
+fn encode_jpeg(resource: &DynamicImage, quality: u8, options: JpegOptions) -> Result<Vec<u8>, EncodingError> {
+    // The frame header holds the dimensions in 16 bits.
+    let (width, height) = match (u16::try_from(resource.width()), u16::try_from(resource.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(EncodingError::failure(format!("JPEG cannot hold {}x{} pixels, 65535 is the largest side.", resource.width(), resource.height()))),
+    };
+    let mut image = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut image, quality);
+    encoder.set_progressive(options.progressive);
+    if resource.color() == ColorType::L8 {
+        let luma = resource.to_luma8();
+        encoder.encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma)
+            .map_err(EncodingError::failure)?;
+        return Ok(image);
+    }
+    let rgb = resource.to_rgb8();
+    encoder.set_sampling_factor(match options.subsampling.unwrap_or_default() {
+        ChromaSubsampling::Chroma444 => SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Chroma422 => SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Chroma420 => SamplingFactor::R_4_2_0,
+    });
+    encoder.encode(rgb.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+        .map_err(EncodingError::failure)?;
+    Ok(image)
+}
+
+#[cfg(feature = "mozjpeg")]
+fn encode_mozjpeg(resource: &DynamicImage, quality: u8, options: JpegOptions) -> Result<Vec<u8>, EncodingError> {
+    // Grayscale images are written with a single channel.
+    let (pixels, color_space) = match resource.color() {
+        ColorType::L8 => (resource.to_luma8().into_raw(), mozjpeg::ColorSpace::JCS_GRAYSCALE),
+        _ => (resource.to_rgb8().into_raw(), mozjpeg::ColorSpace::JCS_RGB),
+    };
+    let mut compress = mozjpeg::Compress::new(color_space);
+    compress.set_size(resource.width() as usize, resource.height() as usize);
+    compress.set_quality(quality as f32);
+    // MozJPEG writes progressive scans unless told otherwise.
+    if options.progressive {
+        compress.set_progressive_mode();
+    } else {
+        compress.set_optimize_scans(false);
+    }
+    let chroma = match options.subsampling.unwrap_or_default() {
+        ChromaSubsampling::Chroma444 => (1, 1),
+        ChromaSubsampling::Chroma422 => (2, 1),
+        ChromaSubsampling::Chroma420 => (2, 2),
+    };
+    compress.set_chroma_sampling_pixel_sizes(chroma, chroma);
+    let mut started = compress.start_compress(Vec::new()).map_err(EncodingError::failure)?;
+    started.write_scanlines(&pixels).map_err(EncodingError::failure)?;
+    started.finish().map_err(EncodingError::failure)
+}
+
+fn encode_ico(resource: &DynamicImage) -> Result<Vec<u8>, EncodingError> {
+    let icons: Vec<RgbaImage> = ICO_SIZES.iter().map(|size| {
+        let scaled = resource.resize(*size, *size, FilterType::Lanczos3).to_rgba8();
+        let mut icon = RgbaImage::new(*size, *size);
+        overlay(&mut icon, &scaled, ((size - scaled.width()) / 2) as i64, ((size - scaled.height()) / 2) as i64);
+        icon
+    }).collect();
+    let frames = icons.iter()
+        .map(|icon| IcoFrame::as_png(icon.as_raw(), icon.width(), icon.height(), ColorType::Rgba8))
+        .collect::<Result<Vec<IcoFrame>, ImageError>>()
+        .map_err(|e| EncodingError::from_image(e, ColorType::Rgba8))?;
+    let mut image = Vec::new();
+    IcoEncoder::new(&mut image).encode_images(&frames).map_err(|e| EncodingError::from_image(e, ColorType::Rgba8))?;
+    Ok(image)
+}
+
+/// RGB, or RGBA when the image has an alpha channel.
+fn encode_tiff(resource: &DynamicImage, compression: TiffCompression) -> Result<Vec<u8>, EncodingError> {
+    let mut image = Vec::new();
+    let mut encoder = tiff::encoder::TiffEncoder::new(Cursor::new(&mut image)).map_err(EncodingError::failure)?;
+    let (width, height) = resource.dimensions();
+    match (resource.color().has_alpha(), compression) {
+        (true, TiffCompression::Uncompressed) => encoder.write_image::<colortype::RGBA8>(width, height, &resource.to_rgba8()),
+        (true, TiffCompression::Lzw) => encoder.write_image_with_compression::<colortype::RGBA8, _>(width, height, Lzw, &resource.to_rgba8()),
+        (false, TiffCompression::Uncompressed) => encoder.write_image::<colortype::RGB8>(width, height, &resource.to_rgb8()),
+        (false, TiffCompression::Lzw) => encoder.write_image_with_compression::<colortype::RGB8, _>(width, height, Lzw, &resource.to_rgb8()),
+    }.map_err(EncodingError::failure)?;
+    Ok(image)
+}
+
+/// Frames with their delays in milliseconds, looped forever when `repeat` is set.
+fn encode_gif(frames: Vec<(RgbaImage, u32)>, repeat: bool) -> Result<Vec<u8>, EncodingError> {
+    let mut image = Vec::new();
+    {
+        let gif_error = |e| EncodingError::from_image(e, ColorType::Rgba8);
+        let mut encoder = GifEncoder::new_with_speed(&mut image, GIF_SPEED);
+        if repeat {
+            encoder.set_repeat(Repeat::Infinite).map_err(gif_error)?;
+        }
+        encoder.encode_frames(frames.into_iter().map(|(buffer, delay)| {
+            Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay, 1))
+        })).map_err(gif_error)?;
+    }
+    Ok(image)
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(resource: &DynamicImage, quality: u8, speed: u8) -> Result<Vec<u8>, EncodingError> {
+    let rgba = resource.to_rgba8();
+    let pixels: Vec<ravif::RGBA8> = rgba.pixels().map(|pixel| ravif::RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3])).collect();
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(quality as f32)
+        .with_speed(speed);
+    encoder.encode_rgba(ravif::Img::new(pixels.as_slice(), rgba.width() as usize, rgba.height() as usize))
+        .map(|encoded| encoded.avif_file)
+        .map_err(EncodingError::failure)
+}
+
+/// 8-bit RGB, or RGBA when the image has an alpha channel, encoded with x265.
+#[cfg(feature = "heic")]
+fn encode_heic(resource: &DynamicImage, quality: u8, speed: u8) -> Result<Vec<u8>, EncodingError> {
+    use libheif_rs::{Channel, ColorSpace, CompressionFormat, EncoderParameterValue, EncoderQuality, HeifContext, Image, RgbChroma};
+
+    let (width, height) = resource.dimensions();
+    let (pixels, chroma, channels) = match resource.color().has_alpha() {
+        true => (resource.to_rgba8().into_raw(), RgbChroma::Rgba, 4),
+        false => (resource.to_rgb8().into_raw(), RgbChroma::Rgb, 3),
+    };
+    let mut image = Image::new(width, height, ColorSpace::Rgb(chroma)).map_err(EncodingError::failure)?;
+    image.create_plane(Channel::Interleaved, width, height, 8).map_err(EncodingError::failure)?;
+    {
+        let planes = image.planes_mut();
+        let plane = planes.interleaved.ok_or_else(|| EncodingError::failure("HEIF image has no interleaved plane."))?;
+        // Rows of the plane may be padded past the pixels.
+        let row_length = width as usize * channels;
+        for (row, pixels) in pixels.chunks_exact(row_length).enumerate() {
+            plane.data[row * plane.stride..row * plane.stride + row_length].copy_from_slice(pixels);
+        }
+    }
+    let mut context = HeifContext::new().map_err(EncodingError::failure)?;
+    let mut encoder = context.encoder_for_format(CompressionFormat::Hevc).map_err(EncodingError::failure)?;
+    encoder.set_quality(EncoderQuality::Lossy(quality)).map_err(EncodingError::failure)?;
+    let preset = HEIC_PRESETS[(speed.clamp(1, 10) - 1) as usize];
+    encoder.set_parameter_value("preset", EncoderParameterValue::String(preset.to_string())).map_err(EncodingError::failure)?;
+    context.encode_image(&image, &mut encoder, None).map_err(EncodingError::failure)?;
+    context.write_to_bytes().map_err(EncodingError::failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use image_crate::{ColorType, DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    use crate::cache::{NoCacheEngine, shared};
+    use crate::config::{EncodingSettings, QualityPolicy};
+    use crate::decoder::{CachedImageDecoder, DecodeError, ImageDecoder};
+    use crate::encoder::{AllInOneCachedImageEncoder, ChromaSubsampling, encode_ico, encode_jpeg, encode_png, encode_png8, encode_tiff, EncodingError, ImageEncoder, JpegEncoder, JpegOptions, OutputFormat, ParseError, PngCompression, TiffCompression, WebpOptions};
+    use crate::fetcher::{Resource, ResponseData};
+    use crate::image::{Animation, AnimationFrame};
+    use crate::metrics::NoMetrics;
+    use crate::output_dimensions::OutputDimensions;
+
+    #[test]
+    fn save_data_lowers_quality() {
+        assert_eq!(OutputFormat::Jpeg(90, JpegOptions::default()).save_data(20), OutputFormat::Jpeg(70, JpegOptions::default()));
+        assert_eq!(OutputFormat::Jpeg(10, JpegOptions::default()).save_data(20), OutputFormat::Jpeg(1, JpegOptions::default()));
+        #[cfg(feature = "webp")]
+        assert_eq!(OutputFormat::Png(PngCompression::Default).save_data(20), OutputFormat::Webp(80.0, WebpOptions::default()));
+    }
+
+    #[test]
+    fn clamp_or_reject_quality_out_of_range() {
+        assert!(matches!(OutputFormat::parse_with_policy("jpeg0", &QualityPolicy::Reject), Err(ParseError::QualityOutOfRange(_))));
+        let (format, range) = OutputFormat::parse_with_policy("jpeg300", &QualityPolicy::Clamp).unwrap();
+        assert_eq!(format, OutputFormat::Jpeg(100, JpegOptions::default()));
+        assert_eq!(range.unwrap().requested, 300.0);
+        assert_eq!(OutputFormat::parse_with_policy("jpeg80", &QualityPolicy::Clamp).unwrap(), (OutputFormat::Jpeg(80, JpegOptions::default()), None));
+        #[cfg(feature = "webp")]
+        assert_eq!(OutputFormat::parse_with_policy("webp-5", &QualityPolicy::Clamp).unwrap().0, OutputFormat::Webp(0.0, WebpOptions::default()));
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn encode_webp_with_method_and_near_lossless() {
+        use crate::encoder::encode_webp;
+
+        let method = |method| WebpOptions { method: Some(method) };
+        assert_eq!("webp80m6".parse::<OutputFormat>().unwrap(), OutputFormat::Webp(80.0, method(6)));
+        assert_eq!("webpm0".parse::<OutputFormat>().unwrap(), OutputFormat::WebpLoseless(method(0)));
+        assert_eq!("webpnl60".parse::<OutputFormat>().unwrap(), OutputFormat::WebpNearLossless(60, WebpOptions::default()));
+        for invalid in ["webp80m7", "webp80m", "webpnl101", "webpnl"] {
+            assert!(matches!(invalid.parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))), "{}", invalid);
+        }
+        assert_eq!(OutputFormat::WebpNearLossless(60, method(6)).path_segment(), "webpnl60m6");
+        assert_eq!(OutputFormat::Webp(80.0, WebpOptions::default()).to_string(), "image/webp - quality: 80");
+
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(64, 64, |x, y| image_crate::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])));
+        // Without options the output is the one of libwebp's simple API, as before.
+        assert_eq!(encode_webp(&image, None, 100, WebpOptions::default()).unwrap(), webp::Encoder::from_image(&image).unwrap().encode_lossless().to_vec());
+        assert_eq!(encode_webp(&image, Some(80.0), 100, WebpOptions::default()).unwrap(), webp::Encoder::from_image(&image).unwrap().encode(80.0).to_vec());
+        let lossless = encode_webp(&image, None, 100, method(6)).unwrap();
+        assert_eq!(image_crate::load_from_memory(&lossless).unwrap().to_rgb8(), image.to_rgb8());
+        // Grain is what near-lossless smooths out.
+        let mut seed = 1u32;
+        let grainy = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(128, 128, |x, y| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let grain = (seed >> 16) % 12;
+            image_crate::Rgb([(x + grain) as u8, (y + grain) as u8, 100 + grain as u8])
+        }));
+        let near_lossless = encode_webp(&grainy, None, 60, WebpOptions::default()).unwrap();
+        let lossless = encode_webp(&grainy, None, 100, WebpOptions::default()).unwrap();
+        assert!(near_lossless.len() < lossless.len());
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn parse_avif_quality_and_speed() {
+        assert_eq!("avif".parse::<OutputFormat>().unwrap(), OutputFormat::Avif(80, 6));
+        assert_eq!("avif60".parse::<OutputFormat>().unwrap(), OutputFormat::Avif(60, 6));
+        assert_eq!("avif60s3".parse::<OutputFormat>().unwrap(), OutputFormat::Avif(60, 3));
+        assert_eq!("image/avif".parse::<OutputFormat>().unwrap(), OutputFormat::Avif(80, 6));
+        assert!(matches!("avif60s11".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert!(matches!("avif101".parse::<OutputFormat>(), Err(ParseError::QualityOutOfRange(_))));
+        assert_eq!(OutputFormat::Avif(60, 3).path_segment(), "avif60s3");
+    }
+
+    #[cfg(feature = "heic")]
+    #[test]
+    fn encode_heic_with_quality_and_speed() {
+        assert_eq!("heic".parse::<OutputFormat>().unwrap(), OutputFormat::Heic(80, 6));
+        assert_eq!("heic60s3".parse::<OutputFormat>().unwrap(), OutputFormat::Heic(60, 3));
+        assert_eq!("image/heic".parse::<OutputFormat>().unwrap(), OutputFormat::Heic(80, 6));
+        assert!(matches!("heic60s11".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert!(matches!("heic101".parse::<OutputFormat>(), Err(ParseError::QualityOutOfRange(_))));
+        assert_eq!(OutputFormat::Heic(60, 3).path_segment(), "heic60s3");
+
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 8, Rgba([200, 30, 30, 255])));
+        let heic = crate::encoder::encode_heic(&image, 60, 10).unwrap();
+        assert_eq!(&heic[4..12], b"ftypheic");
+    }
+
+    #[test]
+    fn encode_every_frame_of_animation() {
+        assert_eq!("gif".parse::<OutputFormat>().unwrap(), OutputFormat::Gif);
+        assert_eq!("image/gif".parse::<OutputFormat>().unwrap(), OutputFormat::Gif);
+        let frame = |color: [u8; 4], delay_ms: u32| AnimationFrame {
+            image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba(color))).into(),
+            delay_ms,
+        };
+        let animation = Animation { frames: vec![frame([255, 0, 0, 255], 100), frame([0, 0, 255, 255], 250)] };
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let encoded_image = encoder.encode_animation(&String::from("animation"), animation, &OutputDimensions::Original, OutputFormat::Gif).unwrap();
+        assert_eq!((encoded_image.content_type.as_str(), encoded_image.width, encoded_image.height), ("image/gif", 6, 4));
+
+        let decoder = CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        let resource = Resource {
+            response_data: ResponseData { id: String::from("animation"), content_type: String::from("image/gif"), additional_data: HashMap::default() },
+            content: encoded_image.image,
+        };
+        let frames = decoder.decode_animation("animation", &resource).unwrap().unwrap().frames;
+        assert_eq!(frames.iter().map(|frame| frame.delay_ms).collect::<Vec<u32>>(), vec![100, 250]);
+        let last: DynamicImage = frames[1].image.clone().into();
+        assert_eq!(last.get_pixel(3, 2), Rgba([0, 0, 255, 255]));
+        assert_eq!(decoder.decode("animation", &resource).unwrap().get_pixel(3, 2), Rgba([255, 0, 0, 255]));
+        // Both frames cover the 6x4 canvas.
+        let decoder = CachedImageDecoder { maximum_image_size: 47, ..decoder };
+        assert!(matches!(decoder.decode_animation("animation", &resource), Err(DecodeError::ExceedsMaximumSize(47, 48))));
+    }
+
+    #[test]
+    fn encode_animation_to_apng() {
+        assert_eq!("apng".parse::<OutputFormat>().unwrap(), OutputFormat::Apng);
+        assert!(OutputFormat::Apng.is_animated());
+        let frame = |color: [u8; 4], delay_ms: u32| AnimationFrame {
+            image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba(color))).into(),
+            delay_ms,
+        };
+        let animation = Animation { frames: vec![frame([255, 0, 0, 255], 100), frame([0, 0, 255, 255], 250)] };
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let encoded_image = encoder.encode_animation(&String::from("animation"), animation, &OutputDimensions::Original, OutputFormat::Apng).unwrap();
+        assert_eq!((encoded_image.content_type.as_str(), encoded_image.width, encoded_image.height), ("image/png", 6, 4));
+
+        let decoder = CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        let resource = Resource {
+            response_data: ResponseData { id: String::from("animation"), content_type: String::from("image/png"), additional_data: HashMap::default() },
+            content: encoded_image.image,
+        };
+        let frames = decoder.decode_animation("animation", &resource).unwrap().unwrap().frames;
+        assert_eq!(frames.iter().map(|frame| frame.delay_ms).collect::<Vec<u32>>(), vec![100, 250]);
+        let last: DynamicImage = frames[1].image.clone().into();
+        assert_eq!(last.get_pixel(3, 2), Rgba([0, 0, 255, 255]));
+        assert_eq!(decoder.decode("animation", &resource).unwrap().get_pixel(3, 2), Rgba([255, 0, 0, 255]));
+        let decoder = CachedImageDecoder { maximum_image_size: 47, ..decoder };
+        assert!(matches!(decoder.decode_animation("animation", &resource), Err(DecodeError::ExceedsMaximumSize(47, 48))));
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn encode_animation_to_animated_webp() {
+        assert!(OutputFormat::Webp(80.0, WebpOptions::default()).is_animated());
+        assert!(!OutputFormat::Png(PngCompression::Default).is_animated());
+        let frame = |color: [u8; 4], delay_ms: u32| AnimationFrame {
+            image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba(color))).into(),
+            delay_ms,
+        };
+        let animation = Animation { frames: vec![frame([255, 0, 0, 255], 100), frame([0, 0, 255, 255], 250)] };
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let encoded_image = encoder.encode_animation(&String::from("animation"), animation, &OutputDimensions::Original, OutputFormat::WebpLoseless(WebpOptions::default())).unwrap();
+        assert_eq!((encoded_image.content_type.as_str(), encoded_image.width, encoded_image.height), ("image/webp", 6, 4));
+
+        let decoder = CachedImageDecoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), auto_orient: true, maximum_image_size: usize::MAX };
+        let resource = Resource {
+            response_data: ResponseData { id: String::from("animation"), content_type: String::from("image/webp"), additional_data: HashMap::default() },
+            content: encoded_image.image,
+        };
+        let frames = decoder.decode_animation("animation", &resource).unwrap().unwrap().frames;
+        assert_eq!(frames.iter().map(|frame| frame.delay_ms).collect::<Vec<u32>>(), vec![100, 250]);
+        let last: DynamicImage = frames[1].image.clone().into();
+        assert_eq!(last.get_pixel(3, 2), Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn write_few_colors_with_palette() {
+        let mut few_colors = RgbaImage::from_pixel(16, 16, Rgba([255, 0, 0, 255]));
+        few_colors.put_pixel(3, 3, Rgba([0, 0, 255, 128]));
+        let png = encode_png(&DynamicImage::ImageRgba8(few_colors.clone()), PngCompression::Default).unwrap();
+        // Color type is the byte after the bit depth in the IHDR chunk.
+        assert_eq!(png[25], 3);
+        assert_eq!(image_crate::load_from_memory(&png).unwrap().to_rgba8(), few_colors);
+
+        let gradient = RgbaImage::from_fn(32, 32, |x, y| Rgba([(x * 8) as u8, (y * 8) as u8, 0, 255]));
+        assert_eq!(encode_png(&DynamicImage::ImageRgba8(gradient), PngCompression::Default).unwrap()[25], 6);
+    }
+
+    #[test]
+    fn encode_tiff_with_and_without_compression() {
+        assert_eq!("tiff".parse::<OutputFormat>().unwrap(), OutputFormat::Tiff(TiffCompression::Uncompressed));
+        assert_eq!("tifflzw".parse::<OutputFormat>().unwrap(), OutputFormat::Tiff(TiffCompression::Lzw));
+        assert!(matches!("tiffzip".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert_eq!(OutputFormat::Tiff(TiffCompression::Lzw).path_segment(), "tifflzw");
+
+        let stripes = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(64, 64, |x, _| image_crate::Rgb([(x / 8 * 32) as u8, 0, 0])));
+        let uncompressed = encode_tiff(&stripes, TiffCompression::Uncompressed).unwrap();
+        let lzw = encode_tiff(&stripes, TiffCompression::Lzw).unwrap();
+        assert!(lzw.len() < uncompressed.len());
+        assert_eq!(image_crate::load_from_memory(&lzw).unwrap().to_rgb8(), stripes.to_rgb8());
+    }
+
+    #[test]
+    fn encode_icons_of_every_size() {
+        assert_eq!("ico".parse::<OutputFormat>().unwrap(), OutputFormat::Ico);
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_pixel(200, 100, Rgba([0, 128, 255, 255])));
+        let ico = encode_ico(&logo).unwrap();
+        // Directory of the icons follows the 6 byte header, 16 bytes per icon starting with its width and height.
+        assert_eq!(u16::from_le_bytes([ico[4], ico[5]]), 4);
+        let sizes: Vec<(u8, u8)> = (0..4).map(|index| (ico[6 + index * 16], ico[7 + index * 16])).collect();
+        assert_eq!(sizes, vec![(16, 16), (32, 32), (48, 48), (64, 64)]);
+
+        let largest = image_crate::load_from_memory(&ico).unwrap();
+        assert_eq!(largest.dimensions(), (64, 64));
+        // Wide image is centered, leaving the top and bottom transparent.
+        assert_eq!(largest.get_pixel(32, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(largest.get_pixel(32, 32), Rgba([0, 128, 255, 255]));
+    }
+
+    #[test]
+    fn encode_progressive_jpeg() {
+        let progressive = JpegOptions { progressive: true, ..JpegOptions::default() };
+        assert_eq!("jpegp".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg(90, progressive));
+        assert_eq!("jpegp80".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg(80, progressive));
+        assert!(matches!("jpegx80".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert_eq!(OutputFormat::parse_with_policy("jpegp300", &QualityPolicy::Clamp).unwrap().0, OutputFormat::Jpeg(100, progressive));
+        assert_eq!(OutputFormat::Jpeg(80, progressive).path_segment(), "jpegp80");
+
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_pixel(16, 16, image_crate::Rgb([200, 30, 30])));
+        let jpeg = encode_jpeg(&image, 80, progressive).unwrap();
+        // Progressive frames start with SOF2.
+        assert!(jpeg.windows(2).any(|marker| marker == [0xFF, 0xC2]));
+        assert_eq!(image_crate::load_from_memory(&jpeg).unwrap().dimensions(), (16, 16));
+
+        let too_wide = DynamicImage::ImageRgb8(image_crate::RgbImage::new(65536, 1));
+        assert!(matches!(encode_jpeg(&too_wide, 80, progressive), Err(EncodingError::EncoderFailure(_))));
+    }
+
+    #[test]
+    fn encode_jpeg_with_chroma_subsampling() {
+        let subsampled = |subsampling| JpegOptions { subsampling: Some(subsampling), ..JpegOptions::default() };
+        assert_eq!("jpeg80c420".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg(80, subsampled(ChromaSubsampling::Chroma420)));
+        assert_eq!("jpegc422".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg(90, subsampled(ChromaSubsampling::Chroma422)));
+        assert!(matches!("jpeg80c411".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert_eq!(OutputFormat::Jpeg(80, JpegOptions { progressive: true, ..subsampled(ChromaSubsampling::Chroma420) }).path_segment(), "jpegp80c420");
+        // Full resolution chroma is what JPEGs were written with before, so their keys stay the same.
+        assert_eq!(OutputFormat::Jpeg(80, subsampled(ChromaSubsampling::Chroma444)).path_segment(), "jpeg80");
+
+        let settings = EncodingSettings { jpeg_subsampling: ChromaSubsampling::Chroma420, ..EncodingSettings::default() };
+        assert_eq!(OutputFormat::Jpeg(80, JpegOptions::default()).with_defaults(&settings), OutputFormat::Jpeg(80, subsampled(ChromaSubsampling::Chroma420)));
+        assert_eq!(OutputFormat::Jpeg(80, subsampled(ChromaSubsampling::Chroma444)).with_defaults(&settings), OutputFormat::Jpeg(80, subsampled(ChromaSubsampling::Chroma444)));
+
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_pixel(16, 16, image_crate::Rgb([200, 30, 30])));
+        for (subsampling, luma_sampling) in [(ChromaSubsampling::Chroma444, 0x11), (ChromaSubsampling::Chroma422, 0x21), (ChromaSubsampling::Chroma420, 0x22)] {
+            let jpeg = encode_jpeg(&image, 80, subsampled(subsampling)).unwrap();
+            // Sampling factors of the luma component follow its ID in the baseline frame header.
+            let frame = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+            assert_eq!(jpeg[frame + 11], luma_sampling);
+        }
+    }
+
+    #[test]
+    fn encode_grayscale() {
+        let gray = |format| OutputFormat::Grayscale(Box::new(format));
+        assert_eq!("jpeg80:gray".parse::<OutputFormat>().unwrap(), gray(OutputFormat::Jpeg(80, JpegOptions::default())));
+        assert_eq!("jpeg80@50kb:gray".parse::<OutputFormat>().unwrap(), gray(OutputFormat::MaxSize(Box::new(OutputFormat::Jpeg(80, JpegOptions::default())), 50000)));
+        for invalid in ["jpeg80:gray:gray", "jpeg80:gray@50kb", ":gray"] {
+            assert!(invalid.parse::<OutputFormat>().is_err(), "{}", invalid);
+        }
+        assert_eq!(gray(OutputFormat::Png(PngCompression::Default)).path_segment(), "png:gray");
+        assert_eq!(gray(OutputFormat::Png(PngCompression::Default)).name(), "png");
+
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, y| Rgba([(x * 8) as u8, (y * 8) as u8, 90, 255])));
+        for format in [OutputFormat::Jpeg(80, JpegOptions::default()), OutputFormat::Jpeg(80, JpegOptions { progressive: true, ..JpegOptions::default() })] {
+            let color = encoder.encode(&String::from("color"), image.clone(), &OutputDimensions::Original, format.clone(), None).unwrap();
+            let grayscale = encoder.encode(&String::from("gray"), image.clone(), &OutputDimensions::Original, gray(format), None).unwrap();
+            assert_eq!(grayscale.content_type, "image/jpeg");
+            assert!(grayscale.image.len() < color.image.len());
+            assert_eq!(image_crate::load_from_memory(&grayscale.image).unwrap().color(), ColorType::L8);
+        }
+        let png = encoder.encode(&String::from("gray"), image, &OutputDimensions::Original, gray(OutputFormat::Png(PngCompression::Default)), None).unwrap();
+        assert_eq!(image_crate::load_from_memory(&png.image).unwrap().color(), ColorType::La8);
+    }
+
+    #[test]
+    fn encode_sixteen_bit_and_grayscale_sources() {
+        let encoder = |jpeg_encoder| AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder, keep_color_profile: true };
+        let sources = [
+            DynamicImage::ImageLuma8(image_crate::GrayImage::from_fn(16, 8, |x, _| image_crate::Luma([(x * 16) as u8]))),
+            DynamicImage::ImageLumaA16(image_crate::ImageBuffer::from_fn(16, 8, |x, _| image_crate::LumaA([(x * 4096) as u16, u16::MAX]))),
+            DynamicImage::ImageRgb16(image_crate::ImageBuffer::from_fn(16, 8, |x, y| image_crate::Rgb([(x * 4096) as u16, (y * 8192) as u16, 0]))),
+        ];
+        let formats = [
+            (JpegEncoder::Image, OutputFormat::Jpeg(80, JpegOptions::default())),
+            (JpegEncoder::Image, OutputFormat::Png(PngCompression::Default)),
+            (JpegEncoder::Image, OutputFormat::Ico),
+            #[cfg(feature = "mozjpeg")]
+            (JpegEncoder::Mozjpeg, OutputFormat::Jpeg(80, JpegOptions::default())),
+            #[cfg(feature = "webp")]
+            (JpegEncoder::Image, OutputFormat::Webp(80.0, WebpOptions::default())),
+            #[cfg(feature = "avif")]
+            (JpegEncoder::Image, OutputFormat::Avif(80, 10)),
+        ];
+        for source in sources {
+            for (jpeg_encoder, format) in formats.iter() {
+                let encoded = encoder(*jpeg_encoder).encode(&String::from("a"), source.clone(), &OutputDimensions::Original, format.clone(), None);
+                assert!(encoded.is_ok(), "{:?} to {}", source.color(), format);
+            }
+        }
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn encode_jpeg_with_mozjpeg() {
+        let encoder = |jpeg_encoder| AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder, keep_color_profile: true };
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(64, 64, |x, y| image_crate::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])));
+        let baseline = OutputFormat::Jpeg(80, JpegOptions::default());
+        let image_rs = encoder(JpegEncoder::Image).encode(&String::from("a"), image.clone(), &OutputDimensions::Original, baseline.clone(), None).unwrap().image;
+        let mozjpeg = encoder(JpegEncoder::Mozjpeg).encode(&String::from("a"), image.clone(), &OutputDimensions::Original, baseline, None).unwrap().image;
+        assert!(mozjpeg.len() < image_rs.len());
+        assert!(mozjpeg.windows(2).any(|marker| marker == [0xFF, 0xC0]));
+        assert_eq!(image_crate::load_from_memory(&mozjpeg).unwrap().dimensions(), (64, 64));
+
+        let progressive = OutputFormat::Jpeg(80, JpegOptions { progressive: true, subsampling: Some(ChromaSubsampling::Chroma420) });
+        let mozjpeg = encoder(JpegEncoder::Mozjpeg).encode(&String::from("a"), image, &OutputDimensions::Original, progressive, None).unwrap().image;
+        let frame = mozjpeg.windows(2).position(|marker| marker == [0xFF, 0xC2]).unwrap();
+        assert_eq!(mozjpeg[frame + 11], 0x22);
+    }
+
+    #[test]
+    fn encode_jpeg_within_size() {
+        let within = "jpeg80@2kb".parse::<OutputFormat>().unwrap();
+        assert_eq!(within, OutputFormat::MaxSize(Box::new(OutputFormat::Jpeg(80, JpegOptions::default())), 2000));
+        assert_eq!(within.path_segment(), "jpeg80@2kb");
+        assert_eq!("jpeg@2500b".parse::<OutputFormat>().unwrap().path_segment(), "jpeg90@2500b");
+        assert!(matches!("png@3kb".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert!(matches!("jpeg@0kb".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert!(matches!("jpeg@3mb".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(64, 64, |x, y| image_crate::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * 7919 + y * 104_729) ^ (x * y * 31)) as u8])));
+        let full = encoder.encode(&String::from("a"), image.clone(), &OutputDimensions::Original, OutputFormat::Jpeg(80, JpegOptions::default()), None).unwrap();
+        assert!(full.image.len() > 2000);
+        let encoded_image = encoder.encode(&String::from("a"), image.clone(), &OutputDimensions::Original, within, None).unwrap();
+        assert!(encoded_image.image.len() <= 2000);
+        assert_eq!(encoded_image.content_type, "image/jpeg");
+        assert_ne!(encoded_image.etag, full.etag);
+        // Quality of the format is the highest one tried.
+        let roomy = OutputFormat::MaxSize(Box::new(OutputFormat::Jpeg(80, JpegOptions::default())), 1_000_000);
+        assert_eq!(encoder.encode(&String::from("a"), image, &OutputDimensions::Original, roomy, None).unwrap().image, full.image);
+    }
+
+    #[test]
+    fn embed_color_profile_only_when_kept() {
+        let profile: Vec<u8> = [&[0; 16][..], b"RGB "].concat().into_iter().chain(0..100).collect();
+        let image = DynamicImage::ImageRgb8(image_crate::RgbImage::from_pixel(4, 4, image_crate::Rgb([200, 30, 60])));
+        let encode = |keep_color_profile| {
+            let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile };
+            encoder.encode(&String::from("a"), image.clone(), &OutputDimensions::Original, OutputFormat::Png(PngCompression::Default), Some(&profile)).unwrap()
+        };
+        let (kept, dropped) = (encode(true), encode(false));
+        assert_eq!(crate::metadata::color_profile(&kept.image), Some(profile.clone()));
+        assert_eq!(crate::metadata::color_profile(&dropped.image), None);
+        assert_ne!(kept.etag, dropped.etag);
+    }
+
+    #[test]
+    fn encode_png_with_compression_level() {
+        assert_eq!("png9".parse::<OutputFormat>().unwrap(), OutputFormat::Png(PngCompression::Level(9)));
+        assert_eq!("png-fast".parse::<OutputFormat>().unwrap(), OutputFormat::Png(PngCompression::Fast));
+        assert!(matches!("png0".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert!(matches!("png-slow".parse::<OutputFormat>(), Err(ParseError::InvalidFormat(_))));
+        assert_eq!(OutputFormat::Png(PngCompression::Level(9)).path_segment(), "png9");
+        assert_eq!(OutputFormat::Png(PngCompression::Fast).path_segment(), "png-fast");
+
+        let gradient = DynamicImage::ImageRgb8(image_crate::RgbImage::from_fn(64, 64, |x, y| image_crate::Rgb([x as u8 * 4, y as u8 * 4, 128])));
+        let fast = encode_png(&gradient, PngCompression::Fast).unwrap();
+        let best = encode_png(&gradient, PngCompression::Level(9)).unwrap();
+        assert!(best.len() < fast.len());
+        for png in [fast, best] {
+            assert_eq!(image_crate::load_from_memory(&png).unwrap().to_rgb8(), gradient.to_rgb8());
+        }
+
+        // `png` is written as it was before levels, with the defaults of image.
+        let mut default = Vec::new();
+        gradient.write_to(&mut std::io::Cursor::new(&mut default), image_crate::ImageOutputFormat::Png).unwrap();
+        assert_eq!(encode_png(&gradient, PngCompression::Default).unwrap(), default);
+    }
+
+    #[test]
+    fn quantize_truecolor_png8() {
+        assert_eq!("png8".parse::<OutputFormat>().unwrap(), OutputFormat::Png8);
+        assert_eq!(OutputFormat::Png8.path_segment(), "png8");
+
+        let gradient = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 128, 255 - x as u8])));
+        let png8 = encode_png8(&gradient).unwrap();
+        // Color type of the header is indexed.
+        assert_eq!(png8[25], 3);
+        let quantized = image_crate::load_from_memory(&png8).unwrap().to_rgba8();
+        let error: i32 = quantized.as_raw().iter().zip(gradient.as_bytes())
+            .map(|(quantized, original)| (*quantized as i32 - *original as i32).abs())
+            .sum();
+        // Dithering keeps the average of every area close to the original.
+        assert!(error / (64 * 64 * 4) < 8, "{}", error / (64 * 64 * 4));
+    }
 }
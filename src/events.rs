@@ -0,0 +1,216 @@
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, sync_channel, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+#[cfg(not(feature = "kafka"))]
+use log::warn;
+use serde::Serialize;
+
+use crate::config::{EventsBackend, EventsSettings};
+use crate::events::nats::NatsSink;
+use crate::metrics::{EVENTS_DROPPED, Metrics};
+use crate::source_url::SourceUrlEncoding;
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod nats;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum CacheStatus {
+    /// Rendition was already encoded.
+    Hit,
+    /// Rendition was transformed for this request.
+    Miss,
+    /// Source was served as it is.
+    Original,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TransformEvent {
+    pub timestamp: String,
+    pub source_url: String,
+    /// How the source URL was written in the path, `percent` or `verbatim`. None when it could not be decoded.
+    pub source_url_encoding: Option<SourceUrlEncoding>,
+    /// Path and query of the request, they describe the rendition.
+    pub rendition: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bytes: u64,
+    pub cache: Option<CacheStatus>,
+    pub cache_key: Option<String>,
+    pub canonical_url: Option<String>,
+    pub latency_ms: f64,
+    /// Span pixvert served the request in, when the request was traced.
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+    pub parent_span_id: Option<String>,
+}
+
+/// Destination of batches of serialized events.
+pub trait EventSink {
+    fn publish(&mut self, topic: &str, batch: &[Vec<u8>]) -> Result<(), String>;
+}
+
+/// Publishes events from a background thread in batches. Requests never wait for the broker:
+/// events are queued up to `buffer`, events which do not fit and batches the broker refused
+/// are dropped and counted in `pixvert_events_dropped_total`.
+pub struct EventPublisher {
+    sender: Option<SyncSender<Vec<u8>>>,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+impl EventPublisher {
+    pub fn new(settings: &EventsSettings, metrics: Arc<dyn Metrics + Send + Sync>) -> Self {
+        let sink: Box<dyn EventSink + Send> = match &settings.backend {
+            EventsBackend::None => return EventPublisher { sender: None, metrics },
+            EventsBackend::Nats(address) => Box::new(NatsSink::new(address)),
+            #[cfg(feature = "kafka")]
+            EventsBackend::Kafka(brokers) => match kafka::KafkaSink::new(brokers) {
+                Ok(sink) => Box::new(sink),
+                Err(e) => {
+                    error!("Unable to create Kafka producer, events will not be published. Reason: {}", e);
+                    return EventPublisher { sender: None, metrics };
+                }
+            },
+            #[cfg(not(feature = "kafka"))]
+            EventsBackend::Kafka(_) => {
+                warn!("Kafka events are configured, but this build does not include the kafka feature.");
+                return EventPublisher { sender: None, metrics };
+            }
+        };
+        EventPublisher::with_sink(sink, settings, metrics)
+    }
+
+    pub fn with_sink(sink: Box<dyn EventSink + Send>, settings: &EventsSettings, metrics: Arc<dyn Metrics + Send + Sync>) -> Self {
+        // Without room for a single event, every event would be dropped unless the thread is waiting.
+        let (sender, receiver) = sync_channel(settings.buffer.max(1));
+        {
+            let settings = settings.clone();
+            let metrics = metrics.clone();
+            thread::Builder::new()
+                .name(String::from("pixvert-events"))
+                .spawn(move || publish_batches(receiver, sink, &settings, metrics.as_ref()))
+                .unwrap();
+        }
+        EventPublisher { sender: Some(sender), metrics }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    pub fn publish(&self, event: &TransformEvent) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        let payload = match serde_json::to_vec(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Unable to serialize event. Reason: {}", e);
+                return;
+            }
+        };
+        if sender.try_send(payload).is_err() {
+            self.metrics.increment(EVENTS_DROPPED, &[("reason", "full")]);
+        }
+    }
+}
+
+/// Sends a batch once it has `batchSize` events or `flushInterval` passed since its first event.
+fn publish_batches(receiver: Receiver<Vec<u8>>, mut sink: Box<dyn EventSink + Send>, settings: &EventsSettings, metrics: &dyn Metrics) {
+    let flush_interval = Duration::from_millis(settings.flush_interval_ms);
+    let mut batch = Vec::with_capacity(settings.batch_size);
+    while let Ok(event) = receiver.recv() {
+        batch.push(event);
+        let deadline = Instant::now() + flush_interval;
+        while batch.len() < settings.batch_size {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => batch.push(event),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        if let Err(e) = sink.publish(&settings.topic, &batch) {
+            error!("Unable to publish {} events. Reason: {}", batch.len(), e);
+            metrics.add(EVENTS_DROPPED, batch.len() as f64, &[("reason", "error")]);
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+
+    use crate::config::EventsSettings;
+    use crate::events::{EventPublisher, EventSink, TransformEvent};
+    use crate::metrics::NoMetrics;
+    use crate::source_url::SourceUrlEncoding;
+
+    /// Sizes of the published batches. The sink is dropped, and `stopped` with it, once the
+    /// publisher has sent every event and its thread ended.
+    struct RecordingSink {
+        batches: Arc<Mutex<Vec<usize>>>,
+        _stopped: Sender<()>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn publish(&mut self, _: &str, batch: &[Vec<u8>]) -> Result<(), String> {
+            self.batches.lock().unwrap().push(batch.len());
+            Ok(())
+        }
+    }
+
+    /// Publishes the events and waits for the publisher to send them, returns the sizes of the batches.
+    fn publish_all(settings: &EventsSettings, count: usize) -> Vec<usize> {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let (stopped, wait) = channel();
+        let publisher = EventPublisher::with_sink(Box::new(RecordingSink { batches: batches.clone(), _stopped: stopped }), settings, Arc::new(NoMetrics {}));
+        let event = TransformEvent {
+            timestamp: String::new(),
+            source_url: String::from("http://localhost/a.png"),
+            source_url_encoding: Some(SourceUrlEncoding::Percent),
+            rendition: String::from("/100_100/webp/http%3A%2F%2Flocalhost%2Fa.png"),
+            status: 200,
+            content_type: Some(String::from("image/webp")),
+            width: Some(100),
+            height: Some(67),
+            bytes: 1000,
+            cache: None,
+            cache_key: None,
+            canonical_url: None,
+            latency_ms: 1.0,
+            trace_id: None,
+            span_id: None,
+            parent_span_id: None,
+        };
+        for _ in 0..count {
+            publisher.publish(&event);
+        }
+        drop(publisher);
+        assert!(wait.recv().is_err());
+        let batches = batches.lock().unwrap().clone();
+        batches
+    }
+
+    #[test]
+    fn publish_in_batches() {
+        let settings = EventsSettings { batch_size: 2, flush_interval_ms: 50, ..EventsSettings::default() };
+        let batches = publish_all(&settings, 5);
+        assert_eq!(batches.iter().sum::<usize>(), 5);
+        assert!(batches.iter().all(|batch| *batch <= 2));
+    }
+
+    #[test]
+    fn keep_room_for_an_event() {
+        let settings = EventsSettings { buffer: 0, ..EventsSettings::default() };
+        assert_eq!(publish_all(&settings, 1), vec![1]);
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use rdkafka::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+
+use crate::events::EventSink;
+
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct KafkaSink {
+    producer: BaseProducer,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str) -> Result<Self, String> {
+        ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .map(|producer| KafkaSink { producer })
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl EventSink for KafkaSink {
+    fn publish(&mut self, topic: &str, batch: &[Vec<u8>]) -> Result<(), String> {
+        for payload in batch {
+            self.producer.send(BaseRecord::<(), _>::to(topic).payload(payload))
+                .map_err(|(e, _)| e.to_string())?;
+        }
+        self.producer.flush(FLUSH_TIMEOUT);
+        Ok(())
+    }
+}
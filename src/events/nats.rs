@@ -0,0 +1,84 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::events::EventSink;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes to NATS over its text protocol. Connection is opened on the first batch
+/// and opened again after it fails.
+pub struct NatsSink {
+    address: String,
+    connection: Option<TcpStream>,
+}
+
+impl NatsSink {
+    pub fn new(address: &str) -> Self {
+        NatsSink {
+            address: address.trim_start_matches("nats://").to_string(),
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut info = String::new();
+        BufReader::new(&mut stream).read_line(&mut info)?;
+        if !info.starts_with("INFO") {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected greeting: {}", info.trim())));
+        }
+        // Not verbose, so the server does not acknowledge every publish.
+        stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"pixvert\"}\r\n")?;
+        Ok(stream)
+    }
+
+    fn send(&mut self, frames: &[u8]) -> Result<(), Error> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        let stream = self.connection.as_mut().unwrap();
+        answer_pings(stream)?;
+        stream.write_all(frames)
+    }
+}
+
+/// Server drops connections which do not answer its pings, they are answered before each batch.
+fn answer_pings(stream: &mut TcpStream) -> Result<(), Error> {
+    let mut received = Vec::new();
+    stream.set_nonblocking(true)?;
+    let mut buffer = [0; 1024];
+    let read = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break Err(Error::new(ErrorKind::ConnectionAborted, "Connection closed by server.")),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    read?;
+    let pings = String::from_utf8_lossy(&received).matches("PING\r\n").count();
+    stream.write_all(&b"PONG\r\n".repeat(pings))
+}
+
+pub fn frames(subject: &str, batch: &[Vec<u8>]) -> Vec<u8> {
+    let mut frames = Vec::new();
+    for payload in batch {
+        frames.extend_from_slice(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes());
+        frames.extend_from_slice(payload);
+        frames.extend_from_slice(b"\r\n");
+    }
+    frames
+}
+
+impl EventSink for NatsSink {
+    fn publish(&mut self, topic: &str, batch: &[Vec<u8>]) -> Result<(), String> {
+        self.send(&frames(topic, batch)).map_err(|e| {
+            self.connection = None;
+            e.to_string()
+        })
+    }
+}
@@ -1,32 +1,59 @@
 use std::collections::HashMap;
 use std::io::Read;
 use std::ops::Add;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::Instant;
 
 use actix_web::{http, HttpResponse, HttpResponseBuilder};
 use actix_web::http::{header, StatusCode};
 use chrono;
 use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
 use log::{debug, error};
+use rand::{Rng, thread_rng};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use uuid::Uuid;
 
-use crate::cache::CacheEngine;
-use crate::config::Config;
+use crate::cache::SharedCache;
+use crate::cache::typed::Cache;
+use crate::config::{Config, OverriddenCache};
+use crate::fetcher::content_encoding::{ACCEPTED_ENCODINGS, ContentEncodingError};
+use crate::fetcher::download::{DownloadError, read_body};
+use crate::fetcher::integrity::{DIGEST_HEADERS, IntegrityError};
+use crate::metrics::{Metrics, observe_stage, record_cache_lookup};
 use crate::tagged_element::TaggedElement;
+use crate::trace::TraceContext;
+
+pub mod content_encoding;
+pub mod download;
+pub mod fixtures;
+pub mod integrity;
 
 pub(super) const REQUEST_TIME_KEY: &str = "REQUEST_RECEIVED_AT";
+pub(super) const FETCH_DURATION_KEY: &str = "FETCH_DURATION_SECONDS";
 pub(super) const CHRONO_HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
 pub const HTTP_ADDITIONAL_DATA_HEADERS_KEY: &str = "http_headers";
+/// Additional data about the fetch of the source, used to compute `Age` of responses.
+pub const FETCH_ADDITIONAL_DATA_KEY: &str = "fetch";
 
 pub fn generate_resource_tag(tag: &str) -> String {
     return format!("{:x}", md5::compute(tag));
 }
 
+/// Identifies source by its content, so everything derived from it keeps its tags
+/// when the source is fetched again or pixvert restarts.
+pub fn content_tag(content: &[u8]) -> String {
+    format!("{:x}", md5::compute(content))
+}
+
 pub trait Fetcher<T> {
-    fn fetch(&self, resource: &str) -> Result<T, FetchError>;
-    fn serve_cache(&self, resource: &str) -> Option<ResponseData>;
+    /// Requests to the origin carry the trace of the request the resource is fetched for.
+    fn fetch(&self, resource: &str, trace: &TraceContext) -> Result<T, FetchError>;
+    /// Fetches the resource from the origin even when the cached one is still fresh, replacing it.
+    fn refetch(&self, resource: &str, trace: &TraceContext) -> Result<T, FetchError> {
+        self.fetch(resource, trace)
+    }
+    fn serve_cache(&self, resource: &str) -> Option<(ResponseData, CanServeCache)>;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -37,8 +64,9 @@ pub struct ResponseData {
 }
 
 pub struct HttpImageFetcher {
-    pub cache: Arc<RwLock<Box<dyn CacheEngine + Send + Sync>>>,
+    pub cache: SharedCache,
     pub config: Config,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -56,10 +84,23 @@ impl Into<HttpResponseBuilder> for ResponseData {
                 response.insert_header((header_name.clone(), header_value.clone()));
             }
         }
+        if let Some(age) = self.age() {
+            response.insert_header((header::AGE, age.to_string()));
+        }
         return response;
     }
 }
 
+impl ResponseData {
+    /// Seconds since the source was fetched, added to the age the origin reported for it.
+    pub fn age(&self) -> Option<i64> {
+        let fetch_data = self.additional_data.get(FETCH_ADDITIONAL_DATA_KEY)?;
+        let fetched_at = DateTime::parse_from_rfc3339(fetch_data.get(REQUEST_TIME_KEY)?).ok()?;
+        let origin_age: i64 = fetch_data.get(header::AGE.as_str()).and_then(|age| age.parse().ok()).unwrap_or(0);
+        Some(origin_age + Utc::now().signed_duration_since(fetched_at).num_seconds().max(0))
+    }
+}
+
 impl Default for Resource {
     fn default() -> Self {
         Self { content: Vec::default(), response_data: ResponseData{ additional_data: HashMap::default(), id: Uuid::new_v4().to_string(), content_type: String::from("") } }
@@ -75,7 +116,19 @@ pub enum CanServeCache {
 }
 
 impl HttpImageFetcher {
-    pub fn can_serve_cache(resource: &TaggedElement<Resource>) -> CanServeCache {
+    /// Request of the source, asking for the formats configured for origins. Resumed downloads
+    /// ask for the same ones, so the origin keeps serving the same representation.
+    fn get(&self, resource: &str, trace: &TraceContext) -> ureq::Request {
+        let request = trace.propagated_headers().iter()
+            .fold(ureq::get(resource), |request, (name, value)| request.set(name, value));
+        match self.config.origin.accept.as_str() {
+            "" => request,
+            accept => request.set(http::header::ACCEPT.as_str(), accept),
+        }
+    }
+
+    /// Decides how the cached resource may be used at the moment `now`.
+    pub fn can_serve_cache(resource: &TaggedElement<Resource>, now: DateTime<Utc>) -> CanServeCache {
         if let Some(cache_control_header) = resource.cache_data.get(header::CACHE_CONTROL.as_str()) {
             let cc = cache_control::CacheControl::from_value(cache_control_header).unwrap();
             if cc.immutable { return CanServeCache::Yes; }
@@ -83,7 +136,6 @@ impl HttpImageFetcher {
             if let (Some(request_time), Some(duration)) = (resource.cache_data.get(REQUEST_TIME_KEY), cc.max_age) {
                 let request_time: DateTime<Utc> = request_time.parse().unwrap();
                 let expires_at = request_time.add(Duration::from_std(duration).unwrap());
-                let now: DateTime<Utc> = Utc::now();
                 debug!("Current time is {} - expires at {}", now.to_rfc3339(), expires_at.to_rfc3339());
                 if now > expires_at {
                     return match resource.cache_data.get(header::ETAG.as_str()) {
@@ -102,7 +154,6 @@ impl HttpImageFetcher {
         if let Some(expires_header) = resource.cache_data.get(header::EXPIRES.as_str()) {
             let expires_at: DateTime<Utc> = Utc.from_local_datetime(&NaiveDateTime::parse_from_str(expires_header, CHRONO_HTTP_DATE_FORMAT)
                 .unwrap()).unwrap();
-            let now = Utc::now();
 
             debug!("Current time is {} - expires at {}", now.to_rfc3339(), expires_at.to_rfc3339());
 
@@ -118,25 +169,79 @@ impl HttpImageFetcher {
         CanServeCache::No
     }
 
+    /// XFetch: every lookup treats the entry as if it was looked up a bit later, by a random amount
+    /// scaled with the time the fetch took. Entries asked for often get revalidated shortly before
+    /// they expire by a single request, instead of all requests at once right after expiry.
+    fn lookup_time(&self, resource: &TaggedElement<Resource>) -> DateTime<Utc> {
+        let now = Utc::now();
+        let fetch_duration: f64 = match resource.cache_data.get(FETCH_DURATION_KEY).and_then(|duration| duration.parse().ok()) {
+            Some(duration) => duration,
+            None => return now,
+        };
+        let early_seconds = fetch_duration * self.config.cache.early_expiration_beta * -(1.0 - thread_rng().gen::<f64>()).ln();
+        now + Duration::milliseconds((early_seconds * 1000.0) as i64)
+    }
+
     fn insert_request_cache_data(cache_data: &mut HashMap<String, String>, header_name: String, header_value: Option<&str>) {
         if let Some(header_value) = header_value {
             cache_data.insert(header_name, header_value.to_string());
         }
     }
 
-    fn get_cache_control(&self, resource: &str, header: Option<&str>) -> String {
-        for overriden_cache in &self.config.overridden_cache {
-            if resource.contains(&overriden_cache.domain) {
-                return overriden_cache.cache_control.clone();
+    /// Cache-Control deciding how long the source is cached, and the one sent to clients.
+    fn get_cache_control(&self, resource: &str, header: Option<&str>) -> (String, String) {
+        let header = header.unwrap_or_default();
+        let overridden_cache = match self.config.overridden_cache.iter().find(|overridden_cache| matches_domain(overridden_cache, resource)) {
+            Some(overridden_cache) => overridden_cache,
+            None => return (header.to_string(), header.to_string()),
+        };
+        let apply = |side: &Option<String>| {
+            let cache_control = side.as_deref().unwrap_or(&overridden_cache.cache_control);
+            match (cache_control, overridden_cache.merge) {
+                ("", _) => header.to_string(),
+                (cache_control, true) => merge_cache_control(header, cache_control),
+                (cache_control, false) => cache_control.to_string(),
             }
-        }
-        if let Some(header_value) = header {
-            return header_value.to_string()
-        }
-        String::from("")
+        };
+        (apply(&overridden_cache.internal_cache_control), apply(&overridden_cache.client_cache_control))
     }
 }
 
+fn matches_domain(overridden_cache: &OverriddenCache, resource: &str) -> bool {
+    let domain = overridden_cache.domain.as_str();
+    if !domain.contains(['*', '?']) {
+        return resource.contains(domain);
+    }
+    Url::parse(resource).ok()
+        .and_then(|url| url.host_str().map(|host| glob_matches(domain.as_bytes(), host.as_bytes())))
+        .unwrap_or(false)
+}
+
+/// `*` matches any run of characters, `?` a single one. Hosts are compared case-insensitively.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => glob_matches(&pattern[1..], text) || (!text.is_empty() && glob_matches(pattern, &text[1..])),
+        (Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &text[1..]),
+        (Some(expected), Some(character)) if expected.eq_ignore_ascii_case(character) => glob_matches(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Directives of the override replace those of the origin with the same name, the rest of the
+/// origin's are kept, e.g. `s-maxage=600` merged into `public, max-age=60`.
+fn merge_cache_control(origin: &str, overrides: &str) -> String {
+    let name = |directive: &str| directive.split('=').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let overriding: Vec<&str> = overrides.split(',').map(str::trim).filter(|directive| !directive.is_empty()).collect();
+    let overridden: Vec<String> = overriding.iter().map(|directive| name(directive)).collect();
+    origin.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty() && !overridden.contains(&name(directive)))
+        .chain(overriding)
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
 #[derive(Debug)]
 pub enum FetchError {
     NotFound,
@@ -144,6 +249,12 @@ pub enum FetchError {
     NoAccess,
     InvalidResourceTag(String),
     InvalidFormat,
+    /// Body compressed by the origin could not be decompressed.
+    ContentEncoding(ContentEncodingError),
+    /// Body did not arrive whole, even after resuming it.
+    Download(DownloadError),
+    /// Body does not match a digest the origin sent.
+    Integrity(IntegrityError),
     Unknown(String),
 }
 
@@ -153,8 +264,35 @@ impl From<ureq::Error> for FetchError {
     }
 }
 
-impl Fetcher<Resource> for HttpImageFetcher {
-    fn fetch(&self, resource: &str) -> Result<Resource, FetchError> {
+/// Validator for `If-Range`, which takes only strong ETags.
+fn resume_validator(response: &ureq::Response) -> Option<String> {
+    response.header(http::header::ETAG.as_str())
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| response.header(http::header::LAST_MODIFIED.as_str()))
+        .map(str::to_string)
+}
+
+/// Requests the rest of the body from `offset`. The origin answers with the whole body
+/// when the source no longer matches the validator, which cannot be resumed.
+fn resume(request: ureq::Request, resource: &str, offset: u64, validator: &str) -> Result<Box<dyn Read + Send>, String> {
+    let response = request
+        .set(http::header::RANGE.as_str(), &format!("bytes={}-", offset))
+        .set(http::header::IF_RANGE.as_str(), validator)
+        .set(http::header::ACCEPT_ENCODING.as_str(), "identity")
+        .call()
+        .map_err(|e| e.to_string())?;
+    let start = response.header(http::header::CONTENT_RANGE.as_str())
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse::<u64>().ok());
+    match (response.status(), start) {
+        (206, Some(start)) if start == offset => Ok(Box::new(response.into_reader())),
+        (status, _) => Err(format!("Unable to resume {} at {}, origin answered {}.", resource, offset, status)),
+    }
+}
+
+impl HttpImageFetcher {
+    fn fetch_source(&self, resource: &str, trace: &TraceContext, refresh: bool) -> Result<Resource, FetchError> {
         match Url::parse(resource) {
             Ok(url) => {
                 if !self.config.allow_from.is_empty() {
@@ -174,32 +312,36 @@ impl Fetcher<Resource> for HttpImageFetcher {
             Err(parse_error) => return Err(FetchError::InvalidResourceTag(parse_error.to_string()))
         }
         let resource_tag = generate_resource_tag(resource);
-        let cache_element: Option<TaggedElement<Resource>>;
-        {
-            cache_element = self.cache.read()
-                .unwrap()
-                .get(resource_tag.as_str())
-                .map(|data| bincode::deserialize(data.as_slice()).unwrap())
-        }
+        let cache = Cache::<TaggedElement<Resource>>::new(&self.cache);
+        let cache_element = match refresh {
+            true => None,
+            false => cache.get_blocking(&resource_tag),
+        };
         let request_builder: ureq::Request;
-        if let Some(tagged_image) = &cache_element {
-            request_builder = match Self::can_serve_cache(tagged_image) {
+        let can_serve_cache = cache_element.as_ref().map(|element| Self::can_serve_cache(element, self.lookup_time(element)));
+        record_cache_lookup(self.metrics.as_ref(), "fetch", can_serve_cache == Some(CanServeCache::Yes));
+        if let (Some(tagged_image), Some(can_serve_cache)) = (&cache_element, can_serve_cache) {
+            request_builder = match can_serve_cache {
                 CanServeCache::Yes => return Ok(tagged_image.object.clone()),
-                CanServeCache::MustReinvalidateETag(etag) => ureq::get(resource).set(
+                CanServeCache::MustReinvalidateETag(etag) => self.get(resource, trace).set(
                     http::header::IF_NONE_MATCH.as_str(),
                     etag.as_str()
                 ),
-                CanServeCache::MustReinvalidateByRequestTime(time) => ureq::get(resource).set(
+                CanServeCache::MustReinvalidateByRequestTime(time) => self.get(resource, trace).set(
                     http::header::IF_MODIFIED_SINCE.as_str(),
                     time.format(CHRONO_HTTP_DATE_FORMAT).to_string().as_str(),
                 ),
-                CanServeCache::No => ureq::get(resource),
+                CanServeCache::No => self.get(resource, trace),
             };
         } else {
-            request_builder = ureq::get(resource);
+            request_builder = self.get(resource, trace);
         }
+        let request_builder = request_builder.set(http::header::ACCEPT_ENCODING.as_str(), ACCEPTED_ENCODINGS);
         let response_time: String = Utc::now().to_rfc3339();
+        let started = Instant::now();
         let response = request_builder.call().unwrap();
+        let fetch_duration = started.elapsed().as_secs_f64().to_string();
+        observe_stage(self.metrics.as_ref(), "fetch", started);
         match response.status() {
             code if (400..500).contains(&code) => Err(FetchError::NotFound),
             code if (500..600).contains(&code) => Err(FetchError::NotAvailable),
@@ -209,44 +351,64 @@ impl Fetcher<Resource> for HttpImageFetcher {
                     Some(content_type) => content_type,
                     None => mime::OCTET_STREAM.as_str(),
                 }.to_string();
-                let cache_control = self.get_cache_control(resource, response.header(http::header::CACHE_CONTROL.as_str()));
+                let (cache_control, client_cache_control) = self.get_cache_control(resource, response.header(http::header::CACHE_CONTROL.as_str()));
                 Self::insert_request_cache_data(&mut cache_data, REQUEST_TIME_KEY.to_string(), Some(response_time.as_str()));
+                Self::insert_request_cache_data(&mut cache_data, FETCH_DURATION_KEY.to_string(), Some(fetch_duration.as_str()));
                 Self::insert_request_cache_data(&mut cache_data, http::header::ETAG.to_string(), response.header(http::header::ETAG.as_str()));
                 Self::insert_request_cache_data(&mut cache_data, http::header::EXPIRES.to_string(), response.header(http::header::EXPIRES.as_str()));
                 Self::insert_request_cache_data(&mut cache_data, http::header::CACHE_CONTROL.to_string(), Some(cache_control.as_str()));
                 let mut http_hashmap: HashMap<String, String> = HashMap::default();
-                let cache_control_string = String::from(&cache_control);
-                if !cache_control_string.is_empty() {
-                    http_hashmap.insert(header::CACHE_CONTROL.to_string(), cache_control_string);
+                if !client_cache_control.is_empty() {
+                    http_hashmap.insert(header::CACHE_CONTROL.to_string(), client_cache_control);
                 }
                 let expire_string = cache_data.get(http::header::EXPIRES.as_str()).unwrap_or(&String::from("")).to_string();
                 if !expire_string.is_empty() {
                     http_hashmap.insert(header::EXPIRES.to_string(), expire_string);
                 }
-                let mut content = Vec::new();
-                response.into_reader().read_to_end(&mut content).unwrap();
+                if let Some(last_modified) = response.header(http::header::LAST_MODIFIED.as_str()) {
+                    http_hashmap.insert(header::LAST_MODIFIED.to_string(), last_modified.to_string());
+                }
+                let mut fetch_hashmap: HashMap<String, String> = HashMap::from([(REQUEST_TIME_KEY.to_string(), response_time.clone())]);
+                Self::insert_request_cache_data(&mut fetch_hashmap, header::AGE.to_string(), response.header(http::header::AGE.as_str()));
+                let encoding = response.header(http::header::CONTENT_ENCODING.as_str()).map(str::to_string);
+                let digests: Vec<(&str, String)> = DIGEST_HEADERS.iter()
+                    .filter_map(|header| response.header(header).map(|value| (*header, value.to_string())))
+                    .collect();
+                let expected_length = response.header(http::header::CONTENT_LENGTH.as_str()).and_then(|length| length.parse().ok());
+                let validator = resume_validator(&response);
+                let resumable = encoding.is_none() && validator.is_some()
+                    && response.header(http::header::ACCEPT_RANGES.as_str()) == Some("bytes");
+                let content = read_body(response.into_reader(), expected_length, resumable, |offset| {
+                    resume(self.get(resource, trace), resource, offset, validator.as_deref().unwrap_or_default())
+                }).map_err(FetchError::Download)?;
+                integrity::verify(digests.iter().map(|(header, value)| (*header, value.as_str())), &content).map_err(FetchError::Integrity)?;
+                let content = content_encoding::decode(encoding.as_deref(), content)
+                    .map_err(FetchError::ContentEncoding)?;
+                let id = content_tag(&content);
                 let resource = TaggedElement {
                     object: Resource {
                         content,
-                        response_data: ResponseData{ content_type, id: Uuid::new_v4().to_string(), additional_data: HashMap::from([(
-                            String::from(HTTP_ADDITIONAL_DATA_HEADERS_KEY),
-                            http_hashmap
-                        )])},
+                        response_data: ResponseData{ content_type, id, additional_data: HashMap::from([
+                            (String::from(HTTP_ADDITIONAL_DATA_HEADERS_KEY), http_hashmap),
+                            (String::from(FETCH_ADDITIONAL_DATA_KEY), fetch_hashmap),
+                        ])},
                     },
                     cache_data,
                 };
-                {
-                    self.cache.write().unwrap().set(
-                        &resource_tag,
-                        &bincode::serialize(&resource).unwrap(),
-                    ).unwrap();
-                }
+                cache.set_blocking(&resource_tag, &resource);
                 Ok(resource.object)
             }
             code if code == StatusCode::NOT_MODIFIED => {
-                match &cache_element {
-                    Some(cache_resource) => {
-                        Ok((*cache_resource).clone().object)
+                match cache_element {
+                    Some(mut cache_resource) => {
+                        // Revalidated entry is fresh again from now on.
+                        Self::insert_request_cache_data(&mut cache_resource.cache_data, REQUEST_TIME_KEY.to_string(), Some(response_time.as_str()));
+                        Self::insert_request_cache_data(&mut cache_resource.cache_data, FETCH_DURATION_KEY.to_string(), Some(fetch_duration.as_str()));
+                        let mut fetch_hashmap: HashMap<String, String> = HashMap::from([(REQUEST_TIME_KEY.to_string(), response_time.clone())]);
+                        Self::insert_request_cache_data(&mut fetch_hashmap, header::AGE.to_string(), response.header(http::header::AGE.as_str()));
+                        cache_resource.object.response_data.additional_data.insert(String::from(FETCH_ADDITIONAL_DATA_KEY), fetch_hashmap);
+                        cache.set_blocking(&resource_tag, &cache_resource);
+                        Ok(cache_resource.object)
                     }
                     None => Err(FetchError::Unknown("Server returned 'not modified' but the cache value doesn't exist.".to_string()))
                 }
@@ -256,19 +418,23 @@ impl Fetcher<Resource> for HttpImageFetcher {
             }
         }
     }
+}
+
+impl Fetcher<Resource> for HttpImageFetcher {
+    fn fetch(&self, resource: &str, trace: &TraceContext) -> Result<Resource, FetchError> {
+        self.fetch_source(resource, trace, false)
+    }
 
-    fn serve_cache(&self, resource: &str) -> Option<ResponseData> {
+    fn refetch(&self, resource: &str, trace: &TraceContext) -> Result<Resource, FetchError> {
+        self.fetch_source(resource, trace, true)
+    }
+
+    fn serve_cache(&self, resource: &str) -> Option<(ResponseData, CanServeCache)> {
         let resource_tag = generate_resource_tag(resource);
-        let cache_element: Option<TaggedElement<Resource>>;
-        {
-            cache_element = self.cache.read()
-                .unwrap()
-                .get(resource_tag.as_str())
-                .map(|data| bincode::deserialize(data.as_slice()).unwrap());
-        }
+        let cache_element = Cache::<TaggedElement<Resource>>::new(&self.cache).get_blocking(&resource_tag);
         match &cache_element {
             Option::Some(tagged_image) => {
-                Option::Some(tagged_image.object.response_data.clone())
+                Option::Some((tagged_image.object.response_data.clone(), Self::can_serve_cache(tagged_image, self.lookup_time(tagged_image))))
             }
             Option::None => {
                 Option::None
@@ -279,4 +445,135 @@ impl Fetcher<Resource> for HttpImageFetcher {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::http::header;
+    use chrono::{Duration, Utc};
+
+    use std::sync::Arc;
+
+    use httpmock::prelude::*;
+
+    use crate::cache::{HashMapCacheEngine, shared};
+    use crate::config::{Config, OriginSettings, OverriddenCache};
+    use crate::fetcher::{CanServeCache, FETCH_ADDITIONAL_DATA_KEY, Fetcher, HttpImageFetcher, merge_cache_control, REQUEST_TIME_KEY, Resource};
+    use crate::metrics::NoMetrics;
+    use crate::tagged_element::TaggedElement;
+    use crate::trace::TraceContext;
+
+    #[test]
+    fn revalidate_when_looked_up_after_max_age() {
+        let fetched_at = Utc::now();
+        let resource = TaggedElement {
+            object: Resource::default(),
+            cache_data: HashMap::from([
+                (REQUEST_TIME_KEY.to_string(), fetched_at.to_rfc3339()),
+                (header::CACHE_CONTROL.to_string(), String::from("max-age=60")),
+                (header::ETAG.to_string(), String::from("\"v1\"")),
+            ]),
+        };
+
+        assert_eq!(HttpImageFetcher::can_serve_cache(&resource, fetched_at + Duration::seconds(59)), CanServeCache::Yes);
+        assert_eq!(
+            HttpImageFetcher::can_serve_cache(&resource, fetched_at + Duration::seconds(61)),
+            CanServeCache::MustReinvalidateETag(String::from("\"v1\"")),
+        );
+    }
+
+    #[test]
+    fn age_includes_age_reported_by_origin() {
+        let mut resource = Resource::default();
+        assert_eq!(resource.response_data.age(), None);
+
+        resource.response_data.additional_data.insert(String::from(FETCH_ADDITIONAL_DATA_KEY), HashMap::from([
+            (REQUEST_TIME_KEY.to_string(), (Utc::now() - Duration::seconds(30)).to_rfc3339()),
+            (header::AGE.to_string(), String::from("100")),
+        ]));
+        assert!((130..=131).contains(&resource.response_data.age().unwrap()));
+    }
+
+    #[test]
+    fn ask_origin_for_configured_formats() {
+        let origin = MockServer::start();
+        let webp = origin.mock(|when, then| {
+            when.method(GET).path("/a").header("accept", "image/webp,image/*;q=0.8");
+            then.status(200).header("content-type", "image/webp").body("webp");
+        });
+        let config = Config {
+            allow_from: vec![],
+            origin: OriginSettings { accept: String::from("image/webp,image/*;q=0.8") },
+            ..Config::default()
+        };
+        let fetcher = HttpImageFetcher {
+            cache: shared(Box::new(HashMapCacheEngine::new())),
+            config,
+            metrics: Arc::new(NoMetrics {}),
+        };
+
+        let resource = fetcher.fetch(&origin.url("/a"), &TraceContext::default()).unwrap();
+        webp.assert();
+        assert_eq!(resource.response_data.content_type, "image/webp");
+        assert_eq!(resource.content, b"webp");
+    }
+
+    #[test]
+    fn refetch_fresh_source() {
+        let origin = MockServer::start();
+        let mut v1 = origin.mock(|when, then| {
+            when.method(GET).path("/a");
+            then.status(200).header("content-type", "image/png").header("cache-control", "max-age=3600").body("v1");
+        });
+        let fetcher = HttpImageFetcher {
+            cache: shared(Box::new(HashMapCacheEngine::new())),
+            config: Config { allow_from: vec![], ..Config::default() },
+            metrics: Arc::new(NoMetrics {}),
+        };
+        let url = origin.url("/a");
+        fetcher.fetch(&url, &TraceContext::default()).unwrap();
+        v1.delete();
+        origin.mock(|when, then| {
+            when.method(GET).path("/a");
+            then.status(200).header("content-type", "image/png").header("cache-control", "max-age=3600").body("v2");
+        });
+
+        assert_eq!(fetcher.fetch(&url, &TraceContext::default()).unwrap().content, b"v1");
+        assert_eq!(fetcher.refetch(&url, &TraceContext::default()).unwrap().content, b"v2");
+        assert_eq!(fetcher.fetch(&url, &TraceContext::default()).unwrap().content, b"v2");
+    }
+
+    #[test]
+    fn override_cache_control_of_matching_domains() {
+        assert_eq!(merge_cache_control("public, max-age=60, s-maxage=10", "s-maxage=600"), "public, max-age=60, s-maxage=600");
+        let config = Config {
+            overridden_cache: vec![
+                OverriddenCache {
+                    domain: String::from("*.images.example.com"),
+                    cache_control: String::from("s-maxage=600"),
+                    merge: true,
+                    ..OverriddenCache::default()
+                },
+                OverriddenCache {
+                    domain: String::from("static.example.com"),
+                    internal_cache_control: Some(String::from("immutable")),
+                    client_cache_control: Some(String::from("no-cache")),
+                    ..OverriddenCache::default()
+                },
+            ],
+            ..Config::default()
+        };
+        let fetcher = HttpImageFetcher {
+            cache: shared(Box::new(HashMapCacheEngine::new())),
+            config,
+            metrics: Arc::new(NoMetrics {}),
+        };
+
+        let merged = String::from("max-age=60, s-maxage=600");
+        assert_eq!(fetcher.get_cache_control("https://eu.Images.example.com/a.png", Some("max-age=60")), (merged.clone(), merged));
+        let origin = String::from("max-age=60");
+        assert_eq!(fetcher.get_cache_control("https://images.example.com/a.png", Some("max-age=60")), (origin.clone(), origin));
+        assert_eq!(
+            fetcher.get_cache_control("https://static.example.com/a.png", Some("max-age=60")),
+            (String::from("immutable"), String::from("no-cache")),
+        );
+    }
 }
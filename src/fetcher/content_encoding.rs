@@ -0,0 +1,81 @@
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+/// Encodings requested from origins. Some origins compress every response, images included.
+pub const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br";
+/// Bodies which inflate above this size are refused rather than decompressed into memory.
+const MAXIMUM_DECODED_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum ContentEncodingError {
+    Unsupported(String),
+    Corrupted(String),
+    TooLarge,
+}
+
+fn read_limited(reader: impl Read) -> Result<Vec<u8>, std::io::Error> {
+    let mut decoded = Vec::new();
+    reader.take(MAXIMUM_DECODED_SIZE + 1).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+fn decode_one(encoding: &str, body: Vec<u8>) -> Result<Vec<u8>, ContentEncodingError> {
+    let corrupted = |e: std::io::Error| ContentEncodingError::Corrupted(format!("{}: {}", encoding, e));
+    let decoded = match encoding {
+        "" | "identity" => return Ok(body),
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(body.as_slice())).map_err(corrupted)?,
+        // `deflate` should be zlib wrapped, but some servers send raw deflate streams.
+        "deflate" => read_limited(ZlibDecoder::new(body.as_slice()))
+            .or_else(|_| read_limited(DeflateDecoder::new(body.as_slice())))
+            .map_err(corrupted)?,
+        "br" => read_limited(brotli_decompressor::Decompressor::new(body.as_slice(), 4096)).map_err(corrupted)?,
+        _ => return Err(ContentEncodingError::Unsupported(encoding.to_string())),
+    };
+    if decoded.len() as u64 > MAXIMUM_DECODED_SIZE {
+        return Err(ContentEncodingError::TooLarge);
+    }
+    Ok(decoded)
+}
+
+/// Undoes the codings listed in `Content-Encoding`, which were applied in the listed order.
+pub fn decode(content_encoding: Option<&str>, body: Vec<u8>) -> Result<Vec<u8>, ContentEncodingError> {
+    let content_encoding = content_encoding.unwrap_or_default().to_ascii_lowercase();
+    content_encoding.split(',')
+        .map(str::trim)
+        .rev()
+        .try_fold(body, |body, encoding| decode_one(encoding, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+
+    use crate::fetcher::content_encoding::{ContentEncodingError, decode};
+
+    fn compress<W: Write>(mut encoder: W, finish: impl FnOnce(W) -> std::io::Result<Vec<u8>>, body: &[u8]) -> Vec<u8> {
+        encoder.write_all(body).unwrap();
+        finish(encoder).unwrap()
+    }
+
+    #[test]
+    fn decode_compressed_bodies() {
+        let body = b"\x89PNG not really".to_vec();
+        let gzip = compress(GzEncoder::new(Vec::new(), Compression::default()), GzEncoder::finish, &body);
+        let zlib = compress(ZlibEncoder::new(Vec::new(), Compression::default()), ZlibEncoder::finish, &body);
+        let raw = compress(DeflateEncoder::new(Vec::new(), Compression::default()), DeflateEncoder::finish, &body);
+        let gzip_zlib = compress(ZlibEncoder::new(Vec::new(), Compression::default()), ZlibEncoder::finish, &gzip);
+
+        assert_eq!(decode(None, body.clone()), Ok(body.clone()));
+        assert_eq!(decode(Some("identity"), body.clone()), Ok(body.clone()));
+        assert_eq!(decode(Some("GZIP"), gzip), Ok(body.clone()));
+        assert_eq!(decode(Some("deflate"), zlib), Ok(body.clone()));
+        assert_eq!(decode(Some("deflate"), raw), Ok(body.clone()));
+        assert_eq!(decode(Some("gzip, deflate"), gzip_zlib), Ok(body.clone()));
+        assert_eq!(decode(Some("zstd"), body.clone()), Err(ContentEncodingError::Unsupported(String::from("zstd"))));
+        assert!(matches!(decode(Some("br"), body), Err(ContentEncodingError::Corrupted(_))));
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::Read;
+
+/// Ranged requests made to complete a single body before the download is given up.
+pub const MAXIMUM_RESUMES: usize = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum DownloadError {
+    /// Body ended before `Content-Length` bytes were received.
+    Incomplete { expected: Option<u64>, received: u64 },
+    /// Body is longer than `Content-Length`.
+    Overlong { expected: u64, received: u64 },
+}
+
+/// Reads the body, resuming it with ranged requests when the connection drops before
+/// the whole body arrives. `resume` continues the body from the given offset. Bodies which
+/// do not match `Content-Length` are refused, so a truncated source never ends up in the cache.
+pub fn read_body(
+    body: impl Read + Send + 'static,
+    expected_length: Option<u64>,
+    resumable: bool,
+    mut resume: impl FnMut(u64) -> Result<Box<dyn Read + Send>, String>,
+) -> Result<Vec<u8>, DownloadError> {
+    let mut content = Vec::new();
+    let mut reader: Box<dyn Read + Send> = Box::new(body);
+    let mut resumes = 0;
+    loop {
+        let interrupted = reader.read_to_end(&mut content).is_err();
+        let received = content.len() as u64;
+        match expected_length {
+            Some(expected) if received > expected => return Err(DownloadError::Overlong { expected, received }),
+            Some(expected) if received == expected => return Ok(content),
+            None if !interrupted => return Ok(content),
+            _ => {}
+        }
+        // Offsets are only known when the length is, and only for origins supporting ranges.
+        if !resumable || expected_length.is_none() || resumes == MAXIMUM_RESUMES {
+            return Err(DownloadError::Incomplete { expected: expected_length, received });
+        }
+        resumes += 1;
+        reader = match resume(received) {
+            Ok(reader) => reader,
+            Err(_) => return Err(DownloadError::Incomplete { expected: expected_length, received }),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind, Read};
+
+    use crate::fetcher::download::{DownloadError, MAXIMUM_RESUMES, read_body};
+
+    /// Serves `data`, then fails as a dropped connection would.
+    struct Flaky {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Flaky {
+        fn new(data: &[u8]) -> Self {
+            Flaky { data: data.to_vec(), position: 0 }
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.position == self.data.len() {
+                return Err(Error::new(ErrorKind::ConnectionReset, "connection reset"));
+            }
+            let read = (&self.data[self.position..]).read(buf)?;
+            self.position += read;
+            Ok(read)
+        }
+    }
+
+    const BODY: &[u8] = b"0123456789";
+
+    #[test]
+    fn resume_interrupted_body() {
+        let mut offsets = vec![];
+        let content = read_body(Flaky::new(&BODY[..4]), Some(10), true, |offset| {
+            offsets.push(offset);
+            let end = (offset as usize + 3).min(BODY.len());
+            Ok(Box::new(Flaky::new(&BODY[offset as usize..end])))
+        });
+        assert_eq!(content, Ok(BODY.to_vec()));
+        assert_eq!(offsets, vec![4, 7]);
+    }
+
+    #[test]
+    fn refuse_incomplete_body() {
+        let not_resumable = read_body(Flaky::new(&BODY[..4]), Some(10), false, |_| unreachable!());
+        assert_eq!(not_resumable, Err(DownloadError::Incomplete { expected: Some(10), received: 4 }));
+
+        let mut resumes = 0;
+        let stalled = read_body(Flaky::new(&BODY[..4]), Some(10), true, |_| {
+            resumes += 1;
+            Ok(Box::new(Flaky::new(b"")))
+        });
+        assert_eq!(stalled, Err(DownloadError::Incomplete { expected: Some(10), received: 4 }));
+        assert_eq!(resumes, MAXIMUM_RESUMES);
+
+        let changed = read_body(Flaky::new(&BODY[..4]), Some(10), true, |_| Err(String::from("source changed")));
+        assert_eq!(changed, Err(DownloadError::Incomplete { expected: Some(10), received: 4 }));
+
+        let unknown_length = read_body(Flaky::new(&BODY[..4]), None, true, |_| unreachable!());
+        assert_eq!(unknown_length, Err(DownloadError::Incomplete { expected: None, received: 4 }));
+
+        assert_eq!(read_body(BODY, Some(4), true, |_| unreachable!()), Err(DownloadError::Overlong { expected: 4, received: 10 }));
+        assert_eq!(read_body(BODY, None, false, |_| unreachable!()), Ok(BODY.to_vec()));
+    }
+}
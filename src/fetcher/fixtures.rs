@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::fetcher::{CanServeCache, content_tag, FETCH_ADDITIONAL_DATA_KEY, FetchError, Fetcher, generate_resource_tag, HTTP_ADDITIONAL_DATA_HEADERS_KEY, REQUEST_TIME_KEY, Resource, ResponseData};
+use crate::trace::TraceContext;
+
+/// Response of the origin to a source URL. Stored as `{tag}.json`, with the body next to it
+/// in `{tag}.body`, so fixtures can be inspected and edited by hand.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Fixture {
+    url: String,
+    status: u16,
+    content_type: String,
+    /// Headers passed on to clients.
+    headers: HashMap<String, String>,
+}
+
+fn fixture_path(directory: &Path, url: &str, extension: &str) -> PathBuf {
+    directory.join(format!("{}.{}", generate_resource_tag(url), extension))
+}
+
+/// Stores a source as if the origin had answered with it, for tests of the routes.
+#[cfg(test)]
+pub(crate) fn write_fixture(directory: &Path, url: &str, content_type: &str, content: &[u8]) {
+    let fixture = Fixture { url: url.to_string(), status: 200, content_type: content_type.to_string(), headers: HashMap::new() };
+    std::fs::write(fixture_path(directory, url, "body"), content).unwrap();
+    std::fs::write(fixture_path(directory, url, "json"), serde_json::to_vec_pretty(&fixture).unwrap()).unwrap();
+}
+
+/// Fetches sources from the origin and stores every response as a fixture, errors the origin
+/// answered with included.
+pub struct RecordingFetcher {
+    pub fetcher: Box<dyn Fetcher<Resource> + Send>,
+    pub directory: PathBuf,
+}
+
+impl RecordingFetcher {
+    fn record(&self, url: &str, result: &Result<Resource, FetchError>) -> std::io::Result<()> {
+        let (status, content_type, headers, content): (u16, &str, HashMap<String, String>, &[u8]) = match result {
+            Ok(resource) => (
+                200,
+                &resource.response_data.content_type,
+                resource.response_data.additional_data.get(HTTP_ADDITIONAL_DATA_HEADERS_KEY).cloned().unwrap_or_default(),
+                &resource.content,
+            ),
+            Err(FetchError::NotFound) => (404, "", HashMap::new(), &[]),
+            Err(FetchError::NotAvailable) => (503, "", HashMap::new(), &[]),
+            Err(_) => return Ok(()),
+        };
+        let fixture = Fixture { url: url.to_string(), status, content_type: content_type.to_string(), headers };
+        std::fs::create_dir_all(&self.directory)?;
+        std::fs::write(fixture_path(&self.directory, url, "body"), content)?;
+        std::fs::write(fixture_path(&self.directory, url, "json"), serde_json::to_vec_pretty(&fixture)?)
+    }
+
+    fn recorded(&self, url: &str, result: Result<Resource, FetchError>) -> Result<Resource, FetchError> {
+        if let Err(e) = self.record(url, &result) {
+            error!("Unable to record a fixture of {} in {}. Reason: {}", url, self.directory.display(), e);
+        }
+        result
+    }
+}
+
+impl Fetcher<Resource> for RecordingFetcher {
+    fn fetch(&self, resource: &str, trace: &TraceContext) -> Result<Resource, FetchError> {
+        self.recorded(resource, self.fetcher.fetch(resource, trace))
+    }
+
+    fn refetch(&self, resource: &str, trace: &TraceContext) -> Result<Resource, FetchError> {
+        self.recorded(resource, self.fetcher.refetch(resource, trace))
+    }
+
+    fn serve_cache(&self, resource: &str) -> Option<(ResponseData, CanServeCache)> {
+        self.fetcher.serve_cache(resource)
+    }
+}
+
+/// Serves sources from recorded fixtures and never contacts origins. Sources without one are not found.
+pub struct ReplayFetcher {
+    pub directory: PathBuf,
+}
+
+impl Fetcher<Resource> for ReplayFetcher {
+    fn fetch(&self, resource: &str, _trace: &TraceContext) -> Result<Resource, FetchError> {
+        let fixture: Fixture = match std::fs::read(fixture_path(&self.directory, resource, "json")) {
+            Ok(fixture) => serde_json::from_slice(&fixture).map_err(|e| FetchError::Unknown(format!("Invalid fixture of {}. {}", resource, e)))?,
+            Err(_) => {
+                warn!("No fixture of {} in {}.", resource, self.directory.display());
+                return Err(FetchError::NotFound);
+            }
+        };
+        match fixture.status {
+            200 => {}
+            500..=599 => return Err(FetchError::NotAvailable),
+            _ => return Err(FetchError::NotFound),
+        }
+        let content = std::fs::read(fixture_path(&self.directory, resource, "body"))
+            .map_err(|e| FetchError::Unknown(format!("Missing body of the fixture of {}. {}", resource, e)))?;
+        Ok(Resource {
+            response_data: ResponseData {
+                id: content_tag(&content),
+                content_type: fixture.content_type,
+                additional_data: HashMap::from([
+                    (String::from(HTTP_ADDITIONAL_DATA_HEADERS_KEY), fixture.headers),
+                    (String::from(FETCH_ADDITIONAL_DATA_KEY), HashMap::from([(REQUEST_TIME_KEY.to_string(), Utc::now().to_rfc3339())])),
+                ]),
+            },
+            content,
+        })
+    }
+
+    /// Fixtures are read on every request, they are as fast as a cache.
+    fn serve_cache(&self, _resource: &str) -> Option<(ResponseData, CanServeCache)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::fetcher::{CanServeCache, content_tag, FetchError, Fetcher, HTTP_ADDITIONAL_DATA_HEADERS_KEY, Resource, ResponseData};
+    use crate::fetcher::fixtures::{RecordingFetcher, ReplayFetcher};
+    use crate::trace::TraceContext;
+
+    struct Origin;
+
+    impl Fetcher<Resource> for Origin {
+        fn fetch(&self, resource: &str, _trace: &TraceContext) -> Result<Resource, FetchError> {
+            if resource.ends_with("missing.png") {
+                return Err(FetchError::NotFound);
+            }
+            let content = vec![1, 2, 3];
+            Ok(Resource {
+                response_data: ResponseData {
+                    id: content_tag(&content),
+                    content_type: String::from("image/png"),
+                    additional_data: HashMap::from([(
+                        String::from(HTTP_ADDITIONAL_DATA_HEADERS_KEY),
+                        HashMap::from([(String::from("cache-control"), String::from("max-age=60"))]),
+                    )]),
+                },
+                content,
+            })
+        }
+
+        fn serve_cache(&self, _resource: &str) -> Option<(ResponseData, CanServeCache)> {
+            None
+        }
+    }
+
+    #[test]
+    fn replay_recorded_responses() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let recording = RecordingFetcher { fetcher: Box::new(Origin), directory: directory.path().to_path_buf() };
+        let recorded = recording.fetch("https://example.com/a.png", &TraceContext::default()).unwrap();
+        assert!(matches!(recording.fetch("https://example.com/missing.png", &TraceContext::default()), Err(FetchError::NotFound)));
+
+        let replay = ReplayFetcher { directory: directory.path().to_path_buf() };
+        let replayed = replay.fetch("https://example.com/a.png", &TraceContext::default()).unwrap();
+        assert_eq!(replayed.content, vec![1, 2, 3]);
+        assert_eq!(replayed.response_data.id, recorded.response_data.id);
+        assert_eq!(replayed.response_data.content_type, "image/png");
+        assert_eq!(replayed.response_data.additional_data[HTTP_ADDITIONAL_DATA_HEADERS_KEY]["cache-control"], "max-age=60");
+        assert!(matches!(replay.fetch("https://example.com/missing.png", &TraceContext::default()), Err(FetchError::NotFound)));
+        assert!(matches!(replay.fetch("https://example.com/never-recorded.png", &TraceContext::default()), Err(FetchError::NotFound)));
+    }
+}
@@ -0,0 +1,102 @@
+use sha2::{Digest, Sha256, Sha512};
+
+/// Headers with digests of the body as sent, before its content coding is undone.
+pub const CONTENT_MD5: &str = "content-md5";
+pub const DIGEST: &str = "digest";
+pub const CONTENT_DIGEST: &str = "content-digest";
+pub const REPR_DIGEST: &str = "repr-digest";
+pub const DIGEST_HEADERS: [&str; 4] = [CONTENT_MD5, DIGEST, CONTENT_DIGEST, REPR_DIGEST];
+
+#[derive(Debug, PartialEq)]
+pub enum IntegrityError {
+    /// Body does not match the digest, it is truncated or was tampered with.
+    Mismatch { header: String, algorithm: String },
+    Malformed { header: String, value: String },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha-256" => Some(Algorithm::Sha256),
+            "sha-512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn digest(&self, content: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Md5 => md5::compute(content).0.to_vec(),
+            Algorithm::Sha256 => Sha256::digest(content).to_vec(),
+            Algorithm::Sha512 => Sha512::digest(content).to_vec(),
+        }
+    }
+}
+
+/// Digests listed in a header. `Content-MD5` holds a single base64 MD5, `Digest` (RFC 3230)
+/// lists `algorithm=base64` and `Content-Digest`/`Repr-Digest` (RFC 9530) list `algorithm=:base64:`.
+/// Algorithms pixvert does not know are skipped.
+fn expected_digests(header: &str, value: &str) -> Result<Vec<(Algorithm, Vec<u8>)>, IntegrityError> {
+    let malformed = || IntegrityError::Malformed { header: header.to_string(), value: value.to_string() };
+    if header == CONTENT_MD5 {
+        return Ok(vec![(Algorithm::Md5, base64::decode(value.trim()).map_err(|_| malformed())?)]);
+    }
+    let mut digests = vec![];
+    for member in value.split(',') {
+        let (name, digest) = member.split_once('=').ok_or_else(malformed)?;
+        let algorithm = match Algorithm::from_name(name.trim()) {
+            Some(algorithm) => algorithm,
+            None => continue,
+        };
+        let digest = digest.trim();
+        let digest = match header {
+            DIGEST => digest,
+            _ => digest.strip_prefix(':').and_then(|digest| digest.strip_suffix(':')).ok_or_else(malformed)?,
+        };
+        digests.push((algorithm, base64::decode(digest).map_err(|_| malformed())?));
+    }
+    Ok(digests)
+}
+
+/// Verifies the body against every digest the origin sent along with it.
+pub fn verify<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>, content: &[u8]) -> Result<(), IntegrityError> {
+    for (header, value) in headers {
+        for (algorithm, expected) in expected_digests(header, value)? {
+            if algorithm.digest(content) != expected {
+                return Err(IntegrityError::Mismatch { header: header.to_string(), algorithm: format!("{:?}", algorithm) });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fetcher::integrity::{CONTENT_DIGEST, CONTENT_MD5, DIGEST, IntegrityError, REPR_DIGEST, verify};
+
+    const BODY: &[u8] = b"hello";
+    const MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+    const SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    #[test]
+    fn verify_digests_sent_by_origin() {
+        assert_eq!(verify([(CONTENT_MD5, MD5)], BODY), Ok(()));
+        assert_eq!(verify([(DIGEST, &*format!("SHA-256={}, UNIXsum=30637", SHA256))], BODY), Ok(()));
+        assert_eq!(verify([(CONTENT_DIGEST, &*format!("sha-256=:{}:", SHA256))], BODY), Ok(()));
+        assert_eq!(verify([(REPR_DIGEST, &*format!("md5=:{}:, sha-256=:{}:", MD5, SHA256))], BODY), Ok(()));
+        assert_eq!(verify([], BODY), Ok(()));
+
+        assert_eq!(
+            verify([(CONTENT_MD5, MD5)], b"hell"),
+            Err(IntegrityError::Mismatch { header: String::from(CONTENT_MD5), algorithm: String::from("Md5") })
+        );
+        assert!(matches!(verify([(CONTENT_DIGEST, &*format!("sha-256={}", SHA256))], BODY), Err(IntegrityError::Malformed { .. })));
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use image_crate::DynamicImage;
+
+use crate::cache::SharedCache;
+use crate::cache::typed::Cache;
+use crate::fetcher::generate_resource_tag;
+use crate::image::Image;
+use crate::metrics::{Metrics, observe_stage, record_cache_lookup};
+use crate::operations::{Operation, OperationChain};
+
+mod contrast;
+mod denoise;
+mod effects;
+
+pub trait ImageFilter {
+    fn filter(&self, tag: &str, resource: DynamicImage, operations: &OperationChain) -> DynamicImage;
+}
+
+pub struct CachedImageFilter {
+    pub cache: SharedCache,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+fn apply(resource: DynamicImage, operation: &Operation) -> DynamicImage {
+    match operation {
+        Operation::Fit(_) | Operation::MaxWidth(_) | Operation::MaxHeight(_) => resource,
+        Operation::AutoContrast(mode) => {
+            let mut image = resource.into_rgba8();
+            contrast::autocontrast(&mut image, mode);
+            DynamicImage::ImageRgba8(image)
+        }
+        Operation::Equalize(mode) => {
+            let mut image = resource.into_rgba8();
+            contrast::equalize(&mut image, mode);
+            DynamicImage::ImageRgba8(image)
+        }
+        Operation::Denoise(strength) => {
+            DynamicImage::ImageRgba8(denoise::bilateral(&resource.into_rgba8(), *strength))
+        }
+        Operation::Round(radius, background) => {
+            let mut image = resource.into_rgba8();
+            effects::round_corners(&mut image, *radius, background);
+            DynamicImage::ImageRgba8(image)
+        }
+        Operation::Vignette(strength) => {
+            let mut image = resource.into_rgba8();
+            effects::vignette(&mut image, *strength);
+            DynamicImage::ImageRgba8(image)
+        }
+        Operation::Border(width, color, _) => {
+            DynamicImage::ImageRgba8(effects::border(&resource.into_rgba8(), *width, color))
+        }
+    }
+}
+
+impl ImageFilter for CachedImageFilter {
+    fn filter(&self, tag: &str, resource: DynamicImage, operations: &OperationChain) -> DynamicImage {
+        if !operations.operations().iter().any(Operation::is_filter) {
+            return resource;
+        }
+        let tag = generate_resource_tag(&format!("Image Filter {} - {}", tag, operations));
+        let cache = Cache::<Image>::new(&self.cache);
+        let cached_image = cache.get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "filter", cached_image.is_some());
+        if let Some(cached_image) = cached_image {
+            return cached_image.into();
+        }
+
+        let started = Instant::now();
+        let image = operations.operations().iter().fold(resource, apply);
+        observe_stage(self.metrics.as_ref(), "filter", started);
+
+        cache.set_blocking(&tag, &image.clone().into());
+        image
+    }
+}
@@ -0,0 +1,128 @@
+use image_crate::RgbaImage;
+
+use crate::operations::ChannelMode;
+
+fn luminance(pixel: &[u8]) -> u8 {
+    (0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32).round() as u8
+}
+
+/// Builds a lookup table stretching the `low..=high` range onto the full `0..=255` range.
+fn stretch_table(low: u8, high: u8) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    for (value, mapped) in table.iter_mut().enumerate() {
+        *mapped = if high <= low {
+            value as u8
+        } else {
+            ((value.clamp(low as usize, high as usize) - low as usize) as f32 * 255.0 / (high - low) as f32).round() as u8
+        };
+    }
+    table
+}
+
+/// Builds a lookup table mapping values by the cumulative distribution of the histogram.
+fn equalize_table(histogram: &[u32; 256]) -> [u8; 256] {
+    let total: u32 = histogram.iter().sum();
+    let first = histogram.iter().find(|count| **count > 0).copied().unwrap_or_default();
+    let mut table = [0u8; 256];
+    let mut cumulative = 0u32;
+    for (value, count) in histogram.iter().enumerate() {
+        cumulative += count;
+        table[value] = if total <= first {
+            value as u8
+        } else {
+            ((cumulative.saturating_sub(first)) as f32 * 255.0 / (total - first) as f32).round() as u8
+        };
+    }
+    table
+}
+
+fn histogram(image: &RgbaImage, channel: Option<usize>) -> [u32; 256] {
+    let mut histogram = [0u32; 256];
+    for pixel in image.pixels() {
+        let value = match channel {
+            Some(channel) => pixel[channel],
+            None => luminance(&pixel.0),
+        };
+        histogram[value as usize] += 1;
+    }
+    histogram
+}
+
+fn range(histogram: &[u32; 256]) -> (u8, u8) {
+    let low = histogram.iter().position(|count| *count > 0).unwrap_or(0);
+    let high = histogram.iter().rposition(|count| *count > 0).unwrap_or(255);
+    (low as u8, high as u8)
+}
+
+fn apply_per_channel(image: &mut RgbaImage, tables: &[[u8; 256]; 3]) {
+    for pixel in image.pixels_mut() {
+        for channel in 0..3 {
+            pixel[channel] = tables[channel][pixel[channel] as usize];
+        }
+    }
+}
+
+/// Scales every color channel by the ratio between the mapped and the original luminance,
+/// which keeps hues close to the source.
+fn apply_to_luminance(image: &mut RgbaImage, table: &[u8; 256]) {
+    for pixel in image.pixels_mut() {
+        let luma = luminance(&pixel.0);
+        if luma == 0 {
+            let mapped = table[0];
+            for channel in 0..3 {
+                pixel[channel] = mapped;
+            }
+            continue;
+        }
+        let ratio = table[luma as usize] as f32 / luma as f32;
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as f32 * ratio).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+pub fn autocontrast(image: &mut RgbaImage, mode: &ChannelMode) {
+    match mode {
+        ChannelMode::Luminance => {
+            let (low, high) = range(&histogram(image, None));
+            apply_to_luminance(image, &stretch_table(low, high));
+        }
+        ChannelMode::PerChannel => {
+            let tables = [0, 1, 2].map(|channel| {
+                let (low, high) = range(&histogram(image, Some(channel)));
+                stretch_table(low, high)
+            });
+            apply_per_channel(image, &tables);
+        }
+    }
+}
+
+pub fn equalize(image: &mut RgbaImage, mode: &ChannelMode) {
+    match mode {
+        ChannelMode::Luminance => {
+            apply_to_luminance(image, &equalize_table(&histogram(image, None)));
+        }
+        ChannelMode::PerChannel => {
+            let tables = [0, 1, 2].map(|channel| equalize_table(&histogram(image, Some(channel))));
+            apply_per_channel(image, &tables);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image_crate::{Rgba, RgbaImage};
+
+    use crate::filter::contrast::autocontrast;
+    use crate::operations::ChannelMode;
+
+    #[test]
+    fn autocontrast_stretches_channels_to_full_range() {
+        let mut image = RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([50, 60, 70, 255]) } else { Rgba([150, 160, 170, 255]) }
+        });
+        autocontrast(&mut image, &ChannelMode::PerChannel);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([255, 255, 255, 255]));
+    }
+}
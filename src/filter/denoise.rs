@@ -0,0 +1,46 @@
+use image_crate::RgbaImage;
+
+const RADIUS: i64 = 2;
+const SPATIAL_SIGMA: f32 = 1.5;
+
+/// Edge preserving bilateral filter. `strength` is the range sigma in 8-bit intensity units,
+/// higher values smooth stronger but also start to blur low contrast edges.
+pub fn bilateral(image: &RgbaImage, strength: u8) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let range_coefficient = -1.0 / (2.0 * (strength as f32).powi(2));
+    let spatial_coefficient = -1.0 / (2.0 * SPATIAL_SIGMA.powi(2));
+    let spatial_weights: Vec<f32> = (-RADIUS..=RADIUS)
+        .flat_map(|dy| (-RADIUS..=RADIUS).map(move |dx| ((dx * dx + dy * dy) as f32 * spatial_coefficient).exp()))
+        .collect();
+    let range_weights: Vec<f32> = (0..=255 * 3)
+        .map(|distance| ((distance as f32 / 3.0).powi(2) * range_coefficient).exp())
+        .collect();
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let center = image.get_pixel(x, y);
+        let mut sum = [0f32; 3];
+        let mut total_weight = 0f32;
+        let mut spatial_index = 0;
+        for dy in -RADIUS..=RADIUS {
+            for dx in -RADIUS..=RADIUS {
+                let sx = (x as i64 + dx).clamp(0, width as i64 - 1) as u32;
+                let sy = (y as i64 + dy).clamp(0, height as i64 - 1) as u32;
+                let sample = image.get_pixel(sx, sy);
+                let distance: usize = (0..3)
+                    .map(|channel| (center[channel] as i32 - sample[channel] as i32).unsigned_abs() as usize)
+                    .sum();
+                let weight = spatial_weights[spatial_index] * range_weights[distance];
+                for channel in 0..3 {
+                    sum[channel] += sample[channel] as f32 * weight;
+                }
+                total_weight += weight;
+                spatial_index += 1;
+            }
+        }
+        let mut pixel = *center;
+        for channel in 0..3 {
+            pixel[channel] = (sum[channel] / total_weight).round().clamp(0.0, 255.0) as u8;
+        }
+        pixel
+    })
+}
@@ -0,0 +1,68 @@
+use image_crate::{Rgba, RgbaImage};
+use image_crate::imageops::overlay;
+
+use crate::operations::Color;
+
+fn blend(foreground: [f32; 4], background: &Color) -> [u8; 4] {
+    let alpha = foreground[3] / 255.0;
+    let background_alpha = background.alpha as f32 / 255.0;
+    let output_alpha = alpha + background_alpha * (1.0 - alpha);
+    if output_alpha <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+    let background = [background.red, background.green, background.blue];
+    let mut blended = [0u8; 4];
+    for channel in 0..3 {
+        blended[channel] = ((foreground[channel] * alpha + background[channel] as f32 * background_alpha * (1.0 - alpha)) / output_alpha)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+    blended[3] = (output_alpha * 255.0).round() as u8;
+    blended
+}
+
+/// Cuts the corners with the given radius. Area outside of the corners is filled with the
+/// background color, fully transparent one by default.
+pub fn round_corners(image: &mut RgbaImage, radius: u32, background: &Color) {
+    let (width, height) = image.dimensions();
+    let radius = radius.min(width / 2).min(height / 2) as f32;
+    if radius <= 0.0 {
+        return;
+    }
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let cx = if px < radius { radius } else if px > width as f32 - radius { width as f32 - radius } else { continue };
+        let cy = if py < radius { radius } else if py > height as f32 - radius { height as f32 - radius } else { continue };
+        let distance = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+        // Anti-aliasing: pixels crossed by the arc are partially covered.
+        let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+        if coverage >= 1.0 {
+            continue;
+        }
+        let foreground = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32, pixel[3] as f32 * coverage];
+        pixel.0 = blend(foreground, background);
+    }
+}
+
+/// Darkens the image towards its edges. `strength` is the darkening of the corners in percent.
+pub fn vignette(image: &mut RgbaImage, strength: u8) {
+    let (width, height) = image.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let maximum_distance = (cx * cx + cy * cy).sqrt();
+    let strength = strength as f32 / 100.0;
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance = ((x as f32 + 0.5 - cx).powi(2) + (y as f32 + 0.5 - cy).powi(2)).sqrt() / maximum_distance;
+        let factor = 1.0 - strength * distance.powi(2);
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as f32 * factor).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Expands the canvas by `width` on every side and fills the new area with the color.
+pub fn border(image: &RgbaImage, width: u32, color: &Color) -> RgbaImage {
+    let fill = Rgba([color.red, color.green, color.blue, color.alpha]);
+    let mut canvas = RgbaImage::from_pixel(image.width() + width * 2, image.height() + width * 2, fill);
+    overlay(&mut canvas, image, width as i64, width as i64);
+    canvas
+}
@@ -0,0 +1,203 @@
+use std::net::SocketAddr;
+use std::thread;
+
+use actix_web::web;
+use log::{error, info};
+use tonic::{Request, Response, Status};
+
+use crate::{AppState, recover};
+use crate::encoder::{EncodingError, ParseError};
+use crate::fetcher::FetchError;
+use crate::purge::purge;
+use crate::quotas::QuotaError;
+use crate::rendition::{fetch, render, Rendition, RenditionError, Requester};
+use crate::routes::admin::{AdminError, check_token};
+use crate::routes::index::{origin, source_dimensions, TransformError};
+
+pub mod proto {
+    tonic::include_proto!("pixvert");
+}
+
+use proto::{InfoRequest, InfoResponse, PurgeRequest, PurgeResponse, TransformRequest, TransformResponse};
+use proto::pixvert_server::{Pixvert, PixvertServer};
+
+pub struct PixvertService {
+    data: web::Data<AppState>,
+}
+
+impl From<FetchError> for Status {
+    fn from(e: FetchError) -> Self {
+        match e {
+            FetchError::NotFound => Status::not_found(format!("{:?}", e)),
+            FetchError::NoAccess => Status::permission_denied(format!("{:?}", e)),
+            FetchError::InvalidFormat | FetchError::InvalidResourceTag(_) => Status::invalid_argument(format!("{:?}", e)),
+            FetchError::NotAvailable => Status::unavailable(format!("{:?}", e)),
+            FetchError::Integrity(_) => Status::data_loss(format!("{:?}", e)),
+            _ => Status::internal(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<TransformError> for Status {
+    fn from(e: TransformError) -> Self {
+        match e {
+            TransformError::Decode(err) => Status::invalid_argument(format!("{:?}", err)),
+            TransformError::Resize(err) => Status::out_of_range(format!("{:?}", err)),
+            TransformError::Encode(err @ EncodingError::UnsupportedColorType(_)) => Status::invalid_argument(format!("{:?}", err)),
+            TransformError::Encode(err) => Status::internal(format!("{:?}", err)),
+            TransformError::Panic(_) => Status::internal("Image processing failed."),
+        }
+    }
+}
+
+impl From<RenditionError> for Status {
+    fn from(e: RenditionError) -> Self {
+        match e {
+            RenditionError::Fetch(err) => err.into(),
+            RenditionError::Format(ParseError::FormatNotEnabled(format)) => Status::unimplemented(format!("Format {} is not enabled in this build.", format)),
+            RenditionError::Format(err) => Status::invalid_argument(format!("{:?}", err)),
+            RenditionError::Operations(err) => Status::invalid_argument(err.to_string()),
+            RenditionError::Transform(err) => err.into(),
+            RenditionError::Cancelled => Status::cancelled("Transform was cancelled."),
+            RenditionError::Unavailable(mode) => Status::unavailable(format!("Service is in {:?} mode.", mode)),
+            RenditionError::NotAllowed(format) => Status::permission_denied(format!("Format {} is not allowed.", format)),
+            RenditionError::SeamCarvingDisabled => Status::permission_denied("Seam carving is disabled."),
+            RenditionError::Quota(QuotaError::MissingKey) => Status::unauthenticated("API key is required."),
+            RenditionError::Quota(QuotaError::UnknownKey) => Status::unauthenticated("Unknown API key."),
+            RenditionError::Quota(QuotaError::Exceeded(limit)) => Status::resource_exhausted(format!("Daily {} quota exceeded.", limit)),
+            RenditionError::SigningRequired => Status::permission_denied("Only signed image URLs are served."),
+        }
+    }
+}
+
+impl From<AdminError> for Status {
+    fn from(e: AdminError) -> Self {
+        match e {
+            AdminError::Disabled => Status::permission_denied("Admin calls are disabled until admin.token is set."),
+            AdminError::MissingToken => Status::unauthenticated("Admin token is required."),
+            AdminError::InvalidToken => Status::unauthenticated("Invalid admin token."),
+            AdminError::UnknownTenant(name) => Status::not_found(format!("Unknown tenant {}.", name)),
+        }
+    }
+}
+
+impl PixvertService {
+    /// Calls are made for the API key in the metadata entry named by `quotas.header`, like requests to the image routes.
+    fn requester<T>(&self, request: &Request<T>) -> Requester {
+        let header = recover(self.data.config.lock()).quotas.header.to_ascii_lowercase();
+        let api_key = request.metadata().get(header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Requester::Client(api_key)
+    }
+
+    /// Calls which change the instance carry the admin token, as on the admin routes.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), AdminError> {
+        let token = recover(self.data.config.lock()).admin.token.clone();
+        check_token(request.metadata().get("authorization").and_then(|value| value.to_str().ok()), &token)
+    }
+}
+
+#[tonic::async_trait]
+impl Pixvert for PixvertService {
+    async fn transform(&self, request: Request<TransformRequest>) -> Result<Response<TransformResponse>, Status> {
+        let requester = self.requester(&request);
+        let request = request.into_inner();
+        let rendition = Rendition {
+            width: request.width as usize,
+            height: request.height as usize,
+            keep_ratio: request.keep_ratio,
+            format: request.format,
+            operations: request.operations,
+        };
+        let encoded_image = render(&self.data, &request.url, &rendition, &requester).await?;
+        self.data.costs.record_served(&origin(&request.url), &encoded_image.content_type, encoded_image.image.len() as u64);
+        if let Requester::Client(Some(api_key)) = &requester {
+            self.data.quotas.record_served(api_key, encoded_image.image.len() as u64);
+        }
+        Ok(Response::new(TransformResponse {
+            image: encoded_image.image,
+            content_type: encoded_image.content_type,
+            etag: encoded_image.etag,
+            width: encoded_image.width,
+            height: encoded_image.height,
+        }))
+    }
+
+    async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        let resource = fetch(&self.data, &request.into_inner().url).await?;
+        let (width, height) = source_dimensions(&resource);
+        Ok(Response::new(InfoResponse {
+            content_type: resource.response_data.content_type,
+            width,
+            height,
+            size: resource.content.len() as u64,
+        }))
+    }
+
+    async fn purge(&self, request: Request<PurgeRequest>) -> Result<Response<PurgeResponse>, Status> {
+        self.authorize(&request)?;
+        let data = self.data.clone();
+        let url = request.into_inner().url;
+        let report = web::block(move || purge(&data, &url, None, true)).await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?
+            .map_err(Status::internal)?;
+        Ok(Response::new(PurgeResponse { purged: report.purged }))
+    }
+}
+
+/// Serves gRPC on its own thread and runtime, next to the HTTP server.
+pub fn spawn(address: SocketAddr, data: web::Data<AppState>) {
+    thread::Builder::new()
+        .name(String::from("pixvert-grpc"))
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Unable to start gRPC runtime. Reason: {}", e);
+                    return;
+                }
+            };
+            info!("Serving gRPC on {}.", address);
+            let server = tonic::transport::Server::builder()
+                .add_service(PixvertServer::new(PixvertService { data }))
+                .serve(address);
+            if let Err(e) = runtime.block_on(server) {
+                error!("gRPC server failed. Reason: {}", e);
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{Code, Request};
+
+    use crate::config::Config;
+    use crate::grpc::PixvertService;
+    use crate::grpc::proto::{PurgeRequest, TransformRequest};
+    use crate::grpc::proto::pixvert_server::Pixvert;
+    use crate::test_state;
+
+    #[actix_web::test]
+    async fn hold_calls_to_the_rules_of_http_requests() {
+        let mut config = Config::default();
+        config.quotas.require_key = true;
+        config.admin.token = String::from("secret");
+        let service = PixvertService { data: test_state(config) };
+
+        let transform = TransformRequest { url: String::from("https://example.com/a.png"), format: String::from("png"), ..Default::default() };
+        assert_eq!(service.transform(Request::new(transform)).await.unwrap_err().code(), Code::Unauthenticated);
+
+        let purge = |authorization: Option<&str>| {
+            let mut request = Request::new(PurgeRequest { url: String::from("https://example.com/a.png") });
+            if let Some(authorization) = authorization {
+                request.metadata_mut().insert("authorization", authorization.parse().unwrap());
+            }
+            request
+        };
+        assert_eq!(service.purge(purge(None)).await.unwrap_err().code(), Code::Unauthenticated);
+        assert_eq!(service.purge(purge(Some("Bearer secrets"))).await.unwrap_err().code(), Code::Unauthenticated);
+        assert!(service.purge(purge(Some("Bearer secret"))).await.is_ok());
+    }
+}
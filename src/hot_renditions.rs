@@ -0,0 +1,169 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::HotRenditionSettings;
+use crate::recover;
+use crate::rendition::Rendition;
+
+/// Rendition of a source with the number of requests for it in the window.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HotRendition {
+    pub url: String,
+    pub rendition: Rendition,
+    pub requests: u64,
+}
+
+/// Requests counted for `bucketSeconds` from `start`, a Unix timestamp in seconds.
+/// Renditions are keyed by their path.
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    start: i64,
+    renditions: HashMap<String, HotRendition>,
+}
+
+/// Counts requests of renditions over a sliding window of buckets, the ones which fall out
+/// of the window are dropped as new ones start. A bucket counts at most `maximumRenditions`
+/// distinct renditions, so crawlers walking through every URL cannot grow it without bound.
+pub struct HotRenditions {
+    settings: HotRenditionSettings,
+    buckets: Mutex<VecDeque<Bucket>>,
+    /// Buckets to store in `file`, written on a thread of their own instead of the request's.
+    store: Option<Sender<Vec<u8>>>,
+}
+
+impl HotRenditions {
+    /// Starts from the buckets stored in `file`, when there are any.
+    pub fn new(settings: HotRenditionSettings) -> Self {
+        let buckets = match settings.file.as_str() {
+            "" => VecDeque::new(),
+            file => std::fs::read(file).ok()
+                .and_then(|stored| serde_json::from_slice(&stored).ok())
+                .unwrap_or_default(),
+        };
+        let store = match settings.enabled && !settings.file.is_empty() {
+            true => spawn_writer(settings.file.clone()),
+            false => None,
+        };
+        HotRenditions { settings, buckets: Mutex::new(buckets), store }
+    }
+
+    pub fn record(&self, url: &str, rendition: &Rendition) {
+        self.record_at(url, rendition, Utc::now().timestamp());
+    }
+
+    /// Most requested renditions first.
+    pub fn top(&self, limit: usize) -> Vec<HotRendition> {
+        self.top_at(limit, Utc::now().timestamp())
+    }
+
+    fn bucket_seconds(&self) -> i64 {
+        self.settings.bucket_seconds.max(1) as i64
+    }
+
+    fn record_at(&self, url: &str, rendition: &Rendition, now: i64) {
+        if !self.settings.enabled {
+            return;
+        }
+        let start = now - now.rem_euclid(self.bucket_seconds());
+        let mut buckets = recover(self.buckets.lock());
+        let mut closed = None;
+        if buckets.back().map(|bucket| bucket.start) != Some(start) {
+            if !buckets.is_empty() && self.store.is_some() {
+                closed = serde_json::to_vec(&*buckets).ok();
+            }
+            let oldest = now - self.settings.window_seconds as i64;
+            while buckets.front().is_some_and(|bucket| bucket.start + self.bucket_seconds() <= oldest) {
+                buckets.pop_front();
+            }
+            buckets.push_back(Bucket { start, renditions: HashMap::new() });
+        }
+        if let Some(bucket) = buckets.back_mut() {
+            let path = rendition.path(url);
+            let full = bucket.renditions.len() >= self.settings.maximum_renditions;
+            match bucket.renditions.get_mut(&path) {
+                Some(hot) => hot.requests += 1,
+                None if !full => {
+                    bucket.renditions.insert(path, HotRendition { url: url.to_string(), rendition: rendition.clone(), requests: 1 });
+                }
+                None => {}
+            }
+        }
+        drop(buckets);
+        if let (Some(stored), Some(store)) = (closed, &self.store) {
+            let _ = store.send(stored);
+        }
+    }
+
+    fn top_at(&self, limit: usize, now: i64) -> Vec<HotRendition> {
+        let oldest = now - self.settings.window_seconds as i64;
+        let mut merged: HashMap<String, HotRendition> = HashMap::new();
+        for bucket in recover(self.buckets.lock()).iter().filter(|bucket| bucket.start + self.bucket_seconds() > oldest) {
+            for (path, hot) in &bucket.renditions {
+                merged.entry(path.clone())
+                    .and_modify(|merged| merged.requests += hot.requests)
+                    .or_insert_with(|| hot.clone());
+            }
+        }
+        let mut top: Vec<HotRendition> = merged.into_values().collect();
+        top.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.url.cmp(&b.url)));
+        top.truncate(limit);
+        top
+    }
+}
+
+/// Writes the buckets sent to it in order, so the file always ends up with the latest ones.
+fn spawn_writer(file: String) -> Option<Sender<Vec<u8>>> {
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let spawned = thread::Builder::new()
+        .name(String::from("pixvert-hot-renditions"))
+        .spawn(move || {
+            for stored in receiver {
+                if let Err(e) = std::fs::write(&file, stored) {
+                    warn!("Unable to store hot renditions in {}. Reason: {}", file, e);
+                }
+            }
+        });
+    match spawned {
+        Ok(_) => Some(sender),
+        Err(e) => {
+            warn!("Unable to start storing hot renditions. Reason: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::HotRenditionSettings;
+    use crate::hot_renditions::HotRenditions;
+    use crate::rendition::Rendition;
+
+    #[test]
+    fn rank_renditions_in_window() {
+        let settings = HotRenditionSettings { enabled: true, window_seconds: 300, bucket_seconds: 60, maximum_renditions: 2, ..HotRenditionSettings::default() };
+        let hot = HotRenditions::new(settings);
+        let thumbnail = Rendition { width: 100, height: 100, format: String::from("webp80"), ..Rendition::default() };
+        let original = Rendition::default();
+        hot.record_at("https://example.com/a.png", &thumbnail, 0);
+        hot.record_at("https://example.com/a.png", &thumbnail, 10);
+        hot.record_at("https://example.com/b.png", &thumbnail, 10);
+        // Third distinct rendition of the bucket is not counted.
+        hot.record_at("https://example.com/c.png", &original, 10);
+        hot.record_at("https://example.com/b.png", &thumbnail, 120);
+        hot.record_at("https://example.com/b.png", &thumbnail, 130);
+
+        let top = hot.top_at(10, 130);
+        assert_eq!(top.iter().map(|hot| (hot.url.as_str(), hot.requests)).collect::<Vec<_>>(), vec![("https://example.com/b.png", 3), ("https://example.com/a.png", 2)]);
+        assert_eq!(top[0].rendition, thumbnail);
+        assert_eq!(hot.top_at(1, 130).len(), 1);
+        // First bucket falls out of the window.
+        assert_eq!(hot.top_at(10, 360).iter().map(|hot| hot.requests).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::quic::BidiStream;
+use h3::server::{RequestResolver, RequestStream};
+use http::header::{CONTENT_LENGTH, HOST};
+use http::{Request, Response, StatusCode};
+use log::{info, warn};
+use quinn::crypto::rustls::QuicServerConfig;
+
+use crate::config::Http3Settings;
+
+/// Headers of a single connection, which are not passed between HTTP/3 and HTTP/1.1.
+const HOP_BY_HOP_HEADERS: [&str; 5] = ["connection", "keep-alive", "transfer-encoding", "upgrade", "content-length"];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Serves HTTP/3 on its own thread and runtime. Requests are passed on to the HTTP listener at
+/// `upstream`, so they go through the same routes, quotas and caches as HTTP/1.1 ones, with the
+/// address of the client in `X-Forwarded-For`. Fails when the certificate cannot be read or the address cannot be bound.
+pub fn spawn(settings: &Http3Settings, upstream: String) -> Result<(), Error> {
+    let address: SocketAddr = settings.address.parse().map_err(Error::other)?;
+    let server_config = server_config(settings)?;
+    let maximum_body_size = settings.maximum_body_size;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let endpoint = {
+        let _runtime = runtime.enter();
+        quinn::Endpoint::server(server_config, address)?
+    };
+    thread::Builder::new()
+        .name(String::from("pixvert-http3"))
+        .spawn(move || {
+            info!("Serving HTTP/3 on {}.", address);
+            runtime.block_on(serve(endpoint, upstream, maximum_body_size));
+        })?;
+    Ok(())
+}
+
+fn server_config(settings: &Http3Settings) -> Result<quinn::ServerConfig, Error> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(&settings.certificate)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&settings.private_key)?))?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No private key in {}.", settings.private_key)))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)
+        .map_err(Error::other)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(Error::other)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+async fn serve(endpoint: quinn::Endpoint, upstream: String, maximum_body_size: usize) {
+    let agent = ureq::AgentBuilder::new().redirects(0).build();
+    while let Some(incoming) = endpoint.accept().await {
+        let (agent, upstream) = (agent.clone(), upstream.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, agent, upstream, maximum_body_size).await {
+                warn!("HTTP/3 connection failed. Reason: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, agent: ureq::Agent, upstream: String, maximum_body_size: usize) -> Result<(), BoxError> {
+    let connection = incoming.await?;
+    let client = connection.remote_address();
+    let connection = h3_quinn::Connection::new(connection);
+    let mut connection = h3::server::Connection::<_, Bytes>::new(connection).await?;
+    loop {
+        let resolver = match connection.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_h3_no_error() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let (agent, upstream) = (agent.clone(), upstream.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(resolver, agent, upstream, client, maximum_body_size).await {
+                warn!("HTTP/3 request failed. Reason: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    agent: ureq::Agent,
+    upstream: String,
+    client: SocketAddr,
+    maximum_body_size: usize,
+) -> Result<(), BoxError> {
+    let (request, mut stream) = resolver.resolve_request().await?;
+    let body = match receive_body(&mut stream, &request, maximum_body_size).await? {
+        Some(body) => body,
+        None => {
+            stream.send_response(Response::builder().status(StatusCode::PAYLOAD_TOO_LARGE).body(())?).await?;
+            stream.finish().await?;
+            return Ok(());
+        }
+    };
+    let headers = upstream_headers(&request, client);
+    let (response, content) = tokio::task::spawn_blocking(move || forward(&agent, &upstream, &request, &headers, &body)).await??;
+    stream.send_response(response).await?;
+    if !content.is_empty() {
+        stream.send_data(Bytes::from(content)).await?;
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Whole body of the request, `None` once it is larger than the maximum size.
+async fn receive_body<S: BidiStream<Bytes>>(stream: &mut RequestStream<S, Bytes>, request: &Request<()>, maximum_size: usize) -> Result<Option<Vec<u8>>, BoxError> {
+    let declared_size = request.headers().get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared_size.is_some_and(|size| size > maximum_size) {
+        return Ok(None);
+    }
+    let mut body = BytesMut::new();
+    while let Some(chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > maximum_size {
+            return Ok(None);
+        }
+        body.put(chunk);
+    }
+    Ok(Some(body.to_vec()))
+}
+
+/// Headers passed on to the HTTP listener. HTTP/3 carries the host in the `:authority` of the request.
+fn upstream_headers(request: &Request<()>, client: SocketAddr) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = request.uri().authority()
+        .map(|authority| (HOST.to_string(), authority.to_string()))
+        .into_iter()
+        .collect();
+    for (name, value) in request.headers() {
+        if let (false, Ok(value)) = (HOP_BY_HOP_HEADERS.contains(&name.as_str()) || name == X_FORWARDED_FOR, value.to_str()) {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    let client = client.ip().to_string();
+    let forwarded_for = request.headers().get_all(X_FORWARDED_FOR).iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain([client.as_str()])
+        .collect::<Vec<&str>>()
+        .join(", ");
+    headers.push((String::from(X_FORWARDED_FOR), forwarded_for));
+    headers
+}
+
+/// Passes the request on to the HTTP listener and reads its whole response.
+fn forward(agent: &ureq::Agent, upstream: &str, request: &Request<()>, headers: &[(String, String)], body: &[u8]) -> Result<(Response<()>, Vec<u8>), Error> {
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    let mut upstream_request = agent.request(request.method().as_str(), &format!("http://{}{}", upstream, path));
+    for (name, value) in headers {
+        upstream_request = upstream_request.set(name, value);
+    }
+    let upstream_response = match upstream_request.send_bytes(body) {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(Error::other(e.to_string())),
+    };
+    let mut response = Response::builder().status(upstream_response.status());
+    for name in upstream_response.headers_names() {
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        for value in upstream_response.all(&name) {
+            response = response.header(name.as_str(), value);
+        }
+    }
+    let mut content = Vec::new();
+    upstream_response.into_reader().read_to_end(&mut content)?;
+    Ok((response.body(()).map_err(Error::other)?, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+
+    use crate::http3::upstream_headers;
+
+    #[test]
+    fn pass_the_host_and_client_on() {
+        let request = Request::get("https://images.example.com/image.jpg")
+            .header("accept", "image/webp")
+            .header("connection", "close")
+            .header("x-forwarded-for", "203.0.113.7")
+            .body(())
+            .unwrap();
+        let headers = upstream_headers(&request, "198.51.100.2:4433".parse().unwrap());
+        let header = |name: &str| headers.iter().filter(|(key, _)| key == name).map(|(_, value)| value.as_str()).collect::<Vec<&str>>();
+        assert_eq!(header("host"), ["images.example.com"]);
+        assert_eq!(header("accept"), ["image/webp"]);
+        assert_eq!(header("connection"), Vec::<&str>::new());
+        assert_eq!(header("x-forwarded-for"), ["203.0.113.7, 198.51.100.2"]);
+    }
+}
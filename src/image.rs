@@ -27,6 +27,19 @@ impl From<Image> for DynamicImage {
     }
 }
 
+/// Frame of an animation, composed onto the full canvas, so frames can be resized and
+/// filtered one by one like still images.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AnimationFrame {
+    pub image: Image,
+    pub delay_ms: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+}
+
 #[cfg(test)]
 mod tests {
     use image_crate::{DynamicImage, GenericImage, Rgba};
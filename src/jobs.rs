@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::recover;
+
+/// Finished jobs kept for reporting, the oldest ones are forgotten first.
+const RETAINED_JOBS: usize = 100;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobReport {
+    pub id: u64,
+    pub kind: &'static str,
+    /// What the job works on, e.g. URL of a sitemap.
+    pub source: String,
+    pub state: JobState,
+    /// Items discovered so far, `done` out of them are processed.
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Progress of background jobs started through the admin API.
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<BTreeMap<u64, JobReport>>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl Jobs {
+    pub fn start(&self, kind: &'static str, source: &str) -> JobReport {
+        self.start_limited(kind, source, usize::MAX).unwrap()
+    }
+
+    /// Starts the job unless `limit` jobs of the kind are running already.
+    pub fn start_limited(&self, kind: &'static str, source: &str, limit: usize) -> Option<JobReport> {
+        let mut jobs = recover(self.jobs.lock());
+        if jobs.values().filter(|job| job.kind == kind && job.state == JobState::Running).count() >= limit {
+            return None;
+        }
+        let id = jobs.keys().next_back().map_or(1, |id| id + 1);
+        let report = JobReport {
+            id,
+            kind,
+            source: source.to_string(),
+            state: JobState::Running,
+            total: 0,
+            done: 0,
+            failed: 0,
+            started_at: now(),
+            finished_at: None,
+            error: None,
+        };
+        jobs.insert(id, report.clone());
+        let finished: Vec<u64> = jobs.values()
+            .filter(|job| job.state != JobState::Running)
+            .map(|job| job.id)
+            .collect();
+        for id in finished.iter().take(finished.len().saturating_sub(RETAINED_JOBS)) {
+            jobs.remove(id);
+        }
+        Some(report)
+    }
+
+    pub fn update(&self, id: u64, update: impl FnOnce(&mut JobReport)) {
+        if let Some(job) = recover(self.jobs.lock()).get_mut(&id) {
+            update(job);
+        }
+    }
+
+    pub fn finish(&self, id: u64, error: Option<String>) {
+        self.update(id, |job| {
+            job.state = if error.is_some() { JobState::Failed } else { JobState::Finished };
+            job.finished_at = Some(now());
+            job.error = error;
+        });
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobReport> {
+        recover(self.jobs.lock()).get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<JobReport> {
+        recover(self.jobs.lock()).values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jobs::{JobState, Jobs, RETAINED_JOBS};
+
+    #[test]
+    fn forget_oldest_finished_jobs() {
+        let jobs = Jobs::default();
+        let running = jobs.start("prewarm", "first").id;
+        for _ in 0..RETAINED_JOBS + 5 {
+            let id = jobs.start("prewarm", "next").id;
+            jobs.finish(id, None);
+        }
+        jobs.start("prewarm", "last");
+        let list = jobs.list();
+        assert_eq!(list.len(), RETAINED_JOBS + 2);
+        assert_eq!(jobs.get(running).unwrap().state, JobState::Running);
+        assert!(jobs.get(running + 1).is_none());
+        assert_eq!(list.last().unwrap().id, RETAINED_JOBS as u64 + 7);
+    }
+
+    #[test]
+    fn limit_running_jobs() {
+        let jobs = Jobs::default();
+        let first = jobs.start_limited("prewarm", "first", 2).unwrap().id;
+        assert!(jobs.start_limited("prewarm", "second", 2).is_some());
+        assert!(jobs.start_limited("prewarm", "third", 2).is_none());
+        assert!(jobs.start_limited("verify", "other", 2).is_some());
+        jobs.finish(first, None);
+        assert!(jobs.start_limited("prewarm", "third", 2).is_some());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LockResult, Mutex, PoisonError, RwLock};
+
+use actix_web::web;
+
+use crate::accounting::CostAccounting;
+use crate::cache::SharedCache;
+use crate::cache::layers::CacheLayers;
+use crate::config::Config;
+use crate::decoder::ImageDecoder;
+use crate::drain::Drain;
+use crate::events::EventPublisher;
+use crate::encoder::ImageEncoder;
+use crate::fetcher::{Fetcher, Resource};
+use crate::filter::ImageFilter;
+use crate::hot_renditions::HotRenditions;
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::mode::RuntimeMode;
+use crate::pools::StagePools;
+use crate::quotas::Quotas;
+use crate::resizer::Resizer;
+use crate::selftest::SelfTestReport;
+
+pub mod image;
+pub mod cache;
+pub mod fetcher;
+pub mod tagged_element;
+pub mod config;
+pub mod routes;
+pub mod resizer;
+pub mod encoder;
+pub mod decoder;
+pub mod output_dimensions;
+pub mod operations;
+pub mod filter;
+pub mod metrics;
+pub mod selftest;
+pub mod pools;
+pub mod accounting;
+pub mod quotas;
+pub mod client_hints;
+pub mod events;
+pub mod jobs;
+pub mod mode;
+pub mod prewarm;
+pub mod purge;
+pub mod rendition;
+pub mod results;
+pub mod contact_sheet;
+pub mod watch;
+pub mod hot_renditions;
+pub mod animated_webp;
+pub mod drain;
+pub mod metadata;
+pub mod trace;
+pub mod signing;
+pub mod source_url;
+pub mod verify;
+pub mod capabilities;
+#[cfg(feature = "libvips")]
+pub mod vips;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http3")]
+pub mod http3;
+
+/// Takes over a lock poisoned by a panicking request. Stages and cache engines keep no state
+/// a panic could leave half-updated, so the lock must not fail every following request.
+pub fn recover<T>(result: LockResult<T>) -> T {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+pub struct AppState {
+    pub config: Mutex<Config>,
+    pub fetcher: Mutex<Box<dyn Fetcher<Resource> + Send>>,
+    pub decoder: Mutex<Box<dyn ImageDecoder + Send>>,
+    pub resizer: Mutex<Box<dyn Resizer + Send>>,
+    pub filter: Mutex<Box<dyn ImageFilter + Send>>,
+    pub encoder: Mutex<Box<dyn ImageEncoder + Send>>,
+    pub cache: SharedCache,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
+    pub self_test: Arc<RwLock<SelfTestReport>>,
+    pub pools: Arc<StagePools>,
+    pub costs: Arc<CostAccounting>,
+    pub quotas: Arc<Quotas>,
+    pub events: Arc<EventPublisher>,
+    pub jobs: Arc<Jobs>,
+    pub mode: Arc<RuntimeMode>,
+    pub cache_layers: Arc<CacheLayers>,
+    pub hot_renditions: Arc<HotRenditions>,
+    pub drain: Arc<Drain>,
+    /// States of the tenants by name, so the admin routes can act on their caches.
+    pub tenants: HashMap<String, web::Data<AppState>>,
+}
+
+/// State of an instance with an in-memory cache, serving sources from the fixtures in `fixtures.directory`.
+#[cfg(test)]
+pub(crate) fn test_state(config: Config) -> web::Data<AppState> {
+    use std::path::PathBuf;
+
+    use crate::cache::layers::{CacheLayer, LayerCache};
+    use crate::cache::{HashMapCacheEngine, shared};
+    use crate::decoder::CachedImageDecoder;
+    use crate::encoder::AllInOneCachedImageEncoder;
+    use crate::fetcher::fixtures::ReplayFetcher;
+    use crate::filter::CachedImageFilter;
+    use crate::metrics::create_metrics;
+    use crate::resizer::CachedResizer;
+
+    let metrics = create_metrics(&config.metrics.backend);
+    let cache = shared(Box::new(HashMapCacheEngine::new()));
+    let cache_layers = Arc::new(CacheLayers::default());
+    web::Data::new(AppState {
+        fetcher: Mutex::new(Box::new(ReplayFetcher { directory: PathBuf::from(&config.fixtures.directory) })),
+        decoder: Mutex::new(Box::new(CachedImageDecoder { cache: LayerCache::shared(&cache, &cache_layers, CacheLayer::Decode), metrics: metrics.clone(), auto_orient: config.metadata.auto_orient, maximum_image_size: config.maximum_image_size })),
+        resizer: Mutex::new(Box::new(CachedResizer { cache: LayerCache::shared(&cache, &cache_layers, CacheLayer::Resize), config: config.clone(), metrics: metrics.clone() })),
+        filter: Mutex::new(Box::new(CachedImageFilter { cache: LayerCache::shared(&cache, &cache_layers, CacheLayer::Filter), metrics: metrics.clone() })),
+        encoder: Mutex::new(Box::new(AllInOneCachedImageEncoder { cache: LayerCache::shared(&cache, &cache_layers, CacheLayer::Encode), metrics: metrics.clone(), jpeg_encoder: config.encoding.jpeg_encoder, keep_color_profile: config.metadata.keep_color_profile })),
+        cache,
+        self_test: Arc::new(RwLock::new(SelfTestReport::default())),
+        pools: Arc::new(StagePools::new(&config.workers)),
+        costs: Arc::new(CostAccounting::new(metrics.clone())),
+        quotas: Arc::new(Quotas::new(config.quotas.clone())),
+        events: Arc::new(EventPublisher::new(&config.events, metrics.clone())),
+        jobs: Arc::new(Jobs::default()),
+        mode: Arc::new(RuntimeMode::new(config.mode.initial)),
+        cache_layers,
+        hot_renditions: Arc::new(HotRenditions::new(config.hot_renditions.clone())),
+        drain: Arc::new(Drain::default()),
+        tenants: HashMap::new(),
+        metrics,
+        config: Mutex::new(config),
+    })
+}
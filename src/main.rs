@@ -1,41 +1,90 @@
+use std::collections::HashMap;
 use std::fs::OpenOptions;
-use std::io::{LineWriter, Write};
+use std::io::{Error, ErrorKind, LineWriter, Write};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 use actix_cors::Cors;
 
-use actix_web::{App, HttpServer, web};
+use actix_web::{App, guard, HttpServer, Scope, web};
+use actix_web::http::header::ALT_SVC;
+use actix_web::middleware::{Condition, DefaultHeaders};
 use figment::Figment;
 use figment::providers::{Format, Yaml};
 use log::{error, info, warn};
 
-use crate::cache::{CacheEngine, HashMapCacheEngine};
-use crate::cache::file_cache::FileCache;
-use crate::config::{CacheType, Config};
-use crate::decoder::{CachedImageDecoder, ImageDecoder};
-use crate::encoder::{AllInOneCachedImageEncoder, ImageEncoder};
-use crate::fetcher::{Fetcher, HttpImageFetcher, Resource};
-use crate::resizer::{CachedResizer, Resizer};
-use crate::routes::health::health;
-use crate::routes::index::{index, index_with_ratio};
+use pixvert_rs::accounting::CostAccounting;
+use pixvert_rs::cache::{CacheEngine, HashMapCacheEngine, shared};
+use pixvert_rs::cache::file_cache::FileCache;
+use pixvert_rs::cache::layers::{CacheLayer, CacheLayers, LayerCache};
+use pixvert_rs::cache::namespace::NamespacedCache;
+use pixvert_rs::cache::write_behind::WriteBehindCache;
+use pixvert_rs::config::{CacheType, Config, FixtureMode, Http3Settings, TenantSettings};
+use pixvert_rs::decoder::CachedImageDecoder;
+use pixvert_rs::drain::Drain;
+use pixvert_rs::encoder::AllInOneCachedImageEncoder;
+use pixvert_rs::events::EventPublisher;
+use pixvert_rs::fetcher::{Fetcher, HttpImageFetcher, Resource};
+use pixvert_rs::fetcher::fixtures::{RecordingFetcher, ReplayFetcher};
+use pixvert_rs::hot_renditions::HotRenditions;
+use pixvert_rs::metrics::create_metrics;
+use pixvert_rs::pools::StagePools;
+use pixvert_rs::quotas::Quotas;
+use pixvert_rs::filter::CachedImageFilter;
+use pixvert_rs::resizer::{CachedResizer, describe_backend};
+use pixvert_rs::selftest;
+use pixvert_rs::jobs::Jobs;
+use pixvert_rs::mode::RuntimeMode;
+use pixvert_rs::routes::admin::{cache_layers as cache_layers_route, costs as costs_route, drain as drain_route, hot_renditions as hot_renditions_route, job as job_route, jobs as jobs_route, mode as mode_route, quotas as quotas_route, self_test, start_prewarm, start_purge, switch_cache_layers, switch_mode, verify_source};
+use pixvert_rs::routes::health::health;
+use pixvert_rs::routes::capabilities::capabilities;
+use pixvert_rs::routes::index::{index, index_auto, index_with_ratio};
+use pixvert_rs::routes::metrics::metrics as metrics_route;
+use pixvert_rs::routes::contact_sheet::contact_sheet;
+use pixvert_rs::routes::upload::{stored_result, upload};
+use pixvert_rs::AppState;
 
-mod image;
-mod cache;
-mod fetcher;
-mod tagged_element;
-mod config;
-mod routes;
-mod resizer;
-mod encoder;
-mod decoder;
-mod output_dimensions;
+const HTTP_PORT: u16 = 8080;
 
-pub struct AppState {
-    config: Mutex<Config>,
-    fetcher: Mutex<Box<dyn Fetcher<Resource> + Send>>,
-    decoder: Mutex<Box<dyn ImageDecoder + Send>>,
-    resizer: Mutex<Box<dyn Resizer + Send>>,
-    encoder: Mutex<Box<dyn ImageEncoder + Send>>,
-    cache: Arc<RwLock<Box<dyn CacheEngine + Send + Sync>>>,
+/// Starts the HTTP/3 listener, returns the `Alt-Svc` value advertising it.
+#[cfg(feature = "http3")]
+fn serve_http3(settings: &Http3Settings) -> Option<String> {
+    match pixvert_rs::http3::spawn(settings, format!("127.0.0.1:{}", HTTP_PORT)) {
+        Ok(()) => settings.alt_svc(),
+        Err(e) => {
+            error!("Unable to serve HTTP/3 on {}. Reason: {}", settings.address, e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "http3"))]
+fn serve_http3(_settings: &Http3Settings) -> Option<String> {
+    warn!("HTTP/3 is enabled in the config, but this build does not include the http3 feature.");
+    None
+}
+
+/// Routes of images, served for every tenant.
+fn image_routes(config: &mut web::ServiceConfig) {
+    config
+        .route("/auto/{format}/{tail:.*}", web::get().to(index_auto))
+        .route("/auto/{tail:.*}", web::get().to(index_auto))
+        .route("/{width}_{height}/keep-ratio/{format}/{tail:.*}", web::get().to(index_with_ratio))
+        .route("/{width}_{height}/keep-ratio/{tail:.*}", web::get().to(index_with_ratio))
+        .route("/{width}_{height}/{format}/{tail:.*}", web::get().to(index))
+        .route("/{width}_{height}/{tail:.*}", web::get().to(index))
+        .route("/preset/{preset}/{tail:.*}", web::get().to(index))
+        .route("/{format}/{tail:.*}", web::get().to(index))
+        .route("/{tail:.*}", web::get().to(index));
+}
+
+/// Image routes of the tenant under its path prefix, limited to its hosts when it has any.
+fn tenant_scope(tenant: &TenantSettings, app_state: web::Data<AppState>) -> Scope {
+    let scope = web::scope(tenant.path_prefix.trim_end_matches('/')).app_data(app_state);
+    let scope = match tenant.hosts.split_first() {
+        Some((host, hosts)) => scope.guard(hosts.iter().fold(guard::Any(guard::Host(host)), |any, host| any.or(guard::Host(host)))),
+        None => scope,
+    };
+    scope.configure(image_routes)
 }
 
 #[actix_web::main]
@@ -63,60 +112,165 @@ async fn main() -> std::io::Result<()> {
             return Result::Ok(());
         }
     };
-    let cache_engine: Box<dyn CacheEngine + Send + Sync> = match &config.cache.cache_type {
-        CacheType::InMemory => Box::from(HashMapCacheEngine::default()) as Box<dyn CacheEngine + Send + Sync>,
-        CacheType::File(path) => Box::from(FileCache::new(path)) as Box<dyn CacheEngine + Send + Sync>,
+    if let Err(e) = config.check_tenants() {
+        error!("Invalid tenants in the config. Reason: {:?}", e);
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{:?}", e)));
+    }
+    let cache_engine: Box<dyn CacheEngine> = match &config.cache.cache_type {
+        CacheType::InMemory => Box::from(HashMapCacheEngine::default()) as Box<dyn CacheEngine>,
+        CacheType::File(path) => Box::from(FileCache::new(path)) as Box<dyn CacheEngine>,
+    };
+    let metrics = create_metrics(&config.metrics.backend);
+    let cache_engine: Box<dyn CacheEngine> = match config.cache.write_buffer {
+        0 => cache_engine,
+        capacity => Box::new(WriteBehindCache::new(cache_engine, capacity, metrics.clone())),
     };
-    let mutex_cache_engine = RwLock::from(cache_engine);
-    let arc_cache = Arc::new(mutex_cache_engine);
+    if let Err(e) = cache_engine.open().await {
+        error!("Unable to open the cache. Reason: {}", e);
+        return Err(e);
+    }
+    let arc_cache = shared(cache_engine);
+    let shutdown_cache = arc_cache.clone();
     let config_clone = config.clone();
+    info!("Resizing with {}.", describe_backend(&config.resizer));
+    #[cfg(not(feature = "mozjpeg"))]
+    if config.encoding.jpeg_encoder == pixvert_rs::encoder::JpegEncoder::Mozjpeg {
+        warn!("MozJPEG is selected in the config, but this build does not include the mozjpeg feature. JPEGs are written with image.");
+    }
+    #[cfg(not(feature = "libvips"))]
+    if config.pipeline.backend == pixvert_rs::config::PipelineBackend::Libvips {
+        warn!("The libvips pipeline is selected in the config, but this build does not include the libvips feature. Images are processed by the native pipeline.");
+    }
+    match config.fixtures.mode {
+        FixtureMode::Off => {}
+        FixtureMode::Record => warn!("Responses of origins are recorded in {}.", config.fixtures.directory),
+        FixtureMode::Replay => warn!("Sources are replayed from {}, origins are not contacted.", config.fixtures.directory),
+    }
+    let self_test_report = selftest::run(&config);
+    if !self_test_report.passed() {
+        error!("Self-test failed. Instance will report itself as not ready.");
+    }
+    let self_test_report = Arc::new(RwLock::new(self_test_report));
+    let pools = Arc::new(StagePools::new(&config.workers));
+    let costs = Arc::new(CostAccounting::new(metrics.clone()));
+    let quotas = Arc::new(Quotas::new(config.quotas.clone()));
+    let events = Arc::new(EventPublisher::new(&config.events, metrics.clone()));
+    let jobs = Arc::new(Jobs::default());
+    let mode = Arc::new(RuntimeMode::new(config.mode.initial));
+    let cache_layers = Arc::new(CacheLayers::default());
+    let hot_renditions = Arc::new(HotRenditions::new(config.hot_renditions.clone()));
+    let drain = Arc::new(Drain::default());
+    let shutdown_drain = drain.clone();
 
-    HttpServer::new(move || {
-        let c_arc_cache = arc_cache.clone();
+    let new_app_state = move |tenant: Option<&TenantSettings>, tenants: HashMap<String, web::Data<AppState>>| {
+        let (config_clone, c_arc_cache) = match tenant {
+            Some(tenant) => (tenant.apply(&config_clone), NamespacedCache::shared(&arc_cache, &tenant.name)),
+            None => (config_clone.clone(), arc_cache.clone()),
+        };
         let fetcher = HttpImageFetcher {
-            cache: c_arc_cache.clone(),
+            cache: LayerCache::shared(&c_arc_cache, &cache_layers, CacheLayer::Fetch),
             config: config_clone.clone(),
+            metrics: metrics.clone(),
+        };
+        let directory = PathBuf::from(&config_clone.fixtures.directory);
+        let fetcher: Box<dyn Fetcher<Resource> + Send> = match config_clone.fixtures.mode {
+            FixtureMode::Off => Box::new(fetcher),
+            FixtureMode::Record => Box::new(RecordingFetcher { fetcher: Box::new(fetcher), directory }),
+            FixtureMode::Replay => Box::new(ReplayFetcher { directory }),
         };
         let resizer = CachedResizer {
-            cache: c_arc_cache.clone(),
+            cache: LayerCache::shared(&c_arc_cache, &cache_layers, CacheLayer::Resize),
             config: config_clone.clone(),
+            metrics: metrics.clone(),
         };
-        let encoder = AllInOneCachedImageEncoder { cache: c_arc_cache.clone() };
-        let decoder = CachedImageDecoder { cache: c_arc_cache.clone() };
-        let cors = Cors::default()
-            .allowed_methods(vec!["GET"])
-            .allowed_origin(&config_clone.cors.origin);
-
-        let app_state = web::Data::new(AppState {
+        let filter = CachedImageFilter { cache: LayerCache::shared(&c_arc_cache, &cache_layers, CacheLayer::Filter), metrics: metrics.clone() };
+        let encoder = AllInOneCachedImageEncoder { cache: LayerCache::shared(&c_arc_cache, &cache_layers, CacheLayer::Encode), metrics: metrics.clone(), jpeg_encoder: config_clone.encoding.jpeg_encoder, keep_color_profile: config_clone.metadata.keep_color_profile };
+        let decoder = CachedImageDecoder { cache: LayerCache::shared(&c_arc_cache, &cache_layers, CacheLayer::Decode), metrics: metrics.clone(), auto_orient: config_clone.metadata.auto_orient, maximum_image_size: config_clone.maximum_image_size };
+        web::Data::new(AppState {
             config: Mutex::new(config_clone.clone()),
-            fetcher: Mutex::new(Box::new(fetcher)),
+            fetcher: Mutex::new(fetcher),
             resizer: Mutex::new(Box::new(resizer)),
+            filter: Mutex::new(Box::new(filter)),
             encoder: Mutex::new(Box::new(encoder)),
             decoder: Mutex::new(Box::new(decoder)),
             cache: c_arc_cache.clone(),
-        });
-        App::new()
+            metrics: metrics.clone(),
+            self_test: self_test_report.clone(),
+            pools: pools.clone(),
+            costs: costs.clone(),
+            quotas: quotas.clone(),
+            events: events.clone(),
+            jobs: jobs.clone(),
+            mode: mode.clone(),
+            cache_layers: cache_layers.clone(),
+            hot_renditions: hot_renditions.clone(),
+            drain: drain.clone(),
+            tenants,
+        })
+    };
+
+    if config.grpc.enabled {
+        #[cfg(feature = "grpc")]
+        match config.grpc.address.parse() {
+            Ok(address) => pixvert_rs::grpc::spawn(address, new_app_state(None, HashMap::new())),
+            Err(e) => error!("Invalid gRPC address {}. Reason: {}", config.grpc.address, e),
+        }
+        #[cfg(not(feature = "grpc"))]
+        warn!("gRPC is enabled in the config, but this build does not include the grpc feature.");
+    }
+
+    let alt_svc = if config.http3.enabled { serve_http3(&config.http3) } else { None };
+
+    if !config.watch.directory.is_empty() {
+        pixvert_rs::watch::spawn(config.watch.clone(), new_app_state(None, HashMap::new()));
+    }
+
+    let cors_origin = config.cors.origin.clone();
+    let tenants = config.tenants.clone();
+    let server = HttpServer::new(move || {
+        let tenant_states: Vec<_> = tenants.iter()
+            .map(|tenant| (tenant, new_app_state(Some(tenant), HashMap::new())))
+            .collect();
+        let app_state = new_app_state(None, tenant_states.iter().map(|(tenant, state)| (tenant.name.clone(), state.clone())).collect());
+        let cors = Cors::default()
+            .allowed_methods(vec!["GET"])
+            .allowed_origin(&cors_origin);
+        let app = App::new()
             .app_data(app_state)
             .wrap(cors)
+            .wrap(Condition::new(alt_svc.is_some(), DefaultHeaders::new().add((ALT_SVC, alt_svc.clone().unwrap_or_default()))))
             .route("/_health", web::get().to(health))
-            .route("/cache", web::get().to(health))
-            .route("/{width}_{height}/keep-ratio/{format}/{tail:.*}", web::get().to(index_with_ratio))
-            .route("/{width}_{height}/keep-ratio/{tail:.*}", web::get().to(index_with_ratio))
-            .route("/{width}_{height}/{format}/{tail:.*}", web::get().to(index))
-            .route("/{width}_{height}/{tail:.*}", web::get().to(index))
-            .route("/{format}/{tail:.*}", web::get().to(index))
-            .route("/{tail:.*}", web::get().to(index))
+            .route("/metrics", web::get().to(metrics_route))
+            .route("/capabilities", web::get().to(capabilities))
+            .route("/admin/selftest", web::get().to(self_test))
+            .route("/admin/costs", web::get().to(costs_route))
+            .route("/admin/hot-renditions", web::get().to(hot_renditions_route))
+            .route("/admin/quotas", web::get().to(quotas_route))
+            .route("/admin/jobs", web::get().to(jobs_route))
+            .route("/admin/jobs/{id}", web::get().to(job_route))
+            .route("/admin/prewarm", web::post().to(start_prewarm))
+            .route("/admin/mode", web::get().to(mode_route))
+            .route("/admin/mode", web::post().to(switch_mode))
+            .route("/admin/purge", web::post().to(start_purge))
+            .route("/admin/verify", web::post().to(verify_source))
+            .route("/admin/drain", web::post().to(drain_route))
+            .route("/admin/cache/layers", web::get().to(cache_layers_route))
+            .route("/admin/cache/layers", web::post().to(switch_cache_layers))
+            .route("/upload", web::post().to(upload))
+            .route("/r/{id}", web::get().to(stored_result))
+            .route("/contact-sheet", web::post().to(contact_sheet))
+            .route("/cache", web::get().to(health));
+        tenant_states.into_iter()
+            .fold(app, |app, (tenant, state)| app.service(tenant_scope(tenant, state)))
+            .configure(image_routes)
     })
-        .bind("0.0.0.0:8080")?
-        .run()
-        .await?;
-    if let CacheType::File(path) = &config.cache.cache_type {
-        if path.starts_with(&String::from(std::env::temp_dir().to_string_lossy())) {
-            info!("Cleaning temp dir: {}", path);
-            std::fs::remove_dir_all(path).unwrap_or_default();
-        } else {
-            warn!("Unable to delete file cache catalog. Temp is set to {} and it is outside of system's tmp dir: {}. Please remove the cache dir.", path, std::env::temp_dir().to_str().unwrap());
-        }
+        .bind(("0.0.0.0", HTTP_PORT))?
+        .shutdown_timeout(config.drain.timeout_seconds)
+        .run();
+    shutdown_drain.set_server(server.handle());
+    server.await?;
+    if let Err(e) = shutdown_cache.close().await {
+        error!("Unable to close the cache. Reason: {}", e);
     }
     Result::Ok(())
 }
@@ -0,0 +1,460 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::Crc;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::animated_webp::{chunks, push_chunk, riff};
+use crate::encoder::EncodedImage;
+
+/// Query parameter keeping (`keep`) or stripping (`strip`) metadata regardless of the config.
+pub const METADATA_QUERY_KEY: &str = "metadata";
+
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// EXIF or XMP, IPTC.
+const JPEG_METADATA_MARKERS: [u8; 2] = [0xE1, 0xED];
+const JPEG_ICC_MARKER: u8 = 0xE2;
+const JPEG_JFIF_MARKER: u8 = 0xE0;
+const JPEG_START_OF_SCAN: u8 = 0xDA;
+const JPEG_ADOBE_MARKER: u8 = 0xEE;
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+const TIFF_MAKE_TAG: u16 = 0x010F;
+const DNG_VERSION_TAG: u16 = 0xC612;
+const TIFF_SUB_IFDS_TAG: u16 = 0x014A;
+/// Largest part of a profile in a single segment, its length, header and part numbers take the rest.
+const JPEG_ICC_PART: usize = 65535 - 2 - 14;
+const PNG_METADATA_CHUNKS: [&[u8]; 4] = [b"eXIf", b"iTXt", b"tEXt", b"zTXt"];
+const WEBP_METADATA_CHUNKS: [&[u8]; 2] = [b"EXIF", b"XMP "];
+const WEBP_METADATA_FLAGS: u8 = 0x08 | 0x04;
+const WEBP_ICC_FLAG: u8 = 0x20;
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+
+/// Image without its EXIF, XMP and IPTC metadata, and without its ICC profile unless it is kept.
+/// Pixels stay as they are. `None` for formats other than JPEG, PNG and WebP, for broken images
+/// and when there is nothing to remove.
+pub fn strip(image: &[u8], keep_color_profile: bool) -> Option<Vec<u8>> {
+    let stripped = if image.starts_with(&[0xFF, 0xD8]) {
+        let (segments, scan) = jpeg_segments(image)?;
+        let mut stripped = image[..2].to_vec();
+        for (marker, segment) in segments {
+            if !JPEG_METADATA_MARKERS.contains(&marker) && (keep_color_profile || marker != JPEG_ICC_MARKER) {
+                stripped.extend_from_slice(segment);
+            }
+        }
+        stripped.extend_from_slice(scan);
+        stripped
+    } else if image.starts_with(PNG_SIGNATURE) {
+        let mut stripped = PNG_SIGNATURE.to_vec();
+        for (chunk_type, chunk) in png_chunks(image)? {
+            if !PNG_METADATA_CHUNKS.contains(&chunk_type) && (keep_color_profile || chunk_type != b"iCCP") {
+                stripped.extend_from_slice(chunk);
+            }
+        }
+        stripped
+    } else if is_webp(image) {
+        let mut body = Vec::new();
+        for (fourcc, payload) in chunks(&image[12..]) {
+            match fourcc {
+                b"VP8X" if !payload.is_empty() => {
+                    let mut extended = payload.to_vec();
+                    extended[0] &= !(WEBP_METADATA_FLAGS | if keep_color_profile { 0 } else { WEBP_ICC_FLAG });
+                    push_chunk(&mut body, fourcc, &extended);
+                }
+                b"ICCP" if !keep_color_profile => {}
+                fourcc if WEBP_METADATA_CHUNKS.contains(&fourcc) => {}
+                fourcc => push_chunk(&mut body, fourcc, payload),
+            }
+        }
+        riff(&body)
+    } else {
+        return None;
+    };
+    (stripped.len() < image.len()).then_some(stripped)
+}
+
+/// Strips the image in place. Its ETag changes with it, so it never matches the one with metadata.
+pub fn strip_encoded(encoded_image: &mut EncodedImage, keep_color_profile: bool) {
+    if let Some(stripped) = strip(&encoded_image.image, keep_color_profile) {
+        encoded_image.image = stripped;
+        encoded_image.etag = format!("{}-stripped\"", encoded_image.etag.trim_end_matches('"'));
+    }
+}
+
+/// ICC profile embedded in a JPEG, PNG or WebP image.
+pub fn color_profile(image: &[u8]) -> Option<Vec<u8>> {
+    if image.starts_with(&[0xFF, 0xD8]) {
+        // Profiles above 64 KB are split into numbered parts.
+        let mut parts: Vec<(u8, &[u8])> = jpeg_segments(image)?.0.into_iter()
+            .filter(|(marker, segment)| *marker == JPEG_ICC_MARKER && segment.get(4..16) == Some(JPEG_ICC_HEADER))
+            .filter_map(|(_, segment)| Some((*segment.get(16)?, segment.get(18..)?)))
+            .collect();
+        parts.sort_by_key(|(number, _)| *number);
+        let profile = parts.into_iter().flat_map(|(_, part)| part.iter().copied()).collect::<Vec<u8>>();
+        return (!profile.is_empty()).then_some(profile);
+    }
+    if image.starts_with(PNG_SIGNATURE) {
+        let (_, chunk) = png_chunks(image)?.into_iter().find(|(chunk_type, _)| *chunk_type == b"iCCP")?;
+        // Name of the profile, compression method, compressed profile.
+        let data = &chunk[8..chunk.len() - 4];
+        let compressed = data.get(data.iter().position(|byte| *byte == 0)? + 2..)?;
+        let mut profile = Vec::new();
+        ZlibDecoder::new(compressed).read_to_end(&mut profile).ok()?;
+        return Some(profile);
+    }
+    if is_webp(image) {
+        return chunks(&image[12..]).find(|(fourcc, _)| *fourcc == b"ICCP").map(|(_, profile)| profile.to_vec());
+    }
+    None
+}
+
+/// EXIF orientation of a JPEG, PNG, WebP or TIFF image, from 1 (upright) to 8. Camera RAW files
+/// are TIFFs too. `None` without one.
+pub fn orientation(image: &[u8]) -> Option<u16> {
+    let exif = if image.starts_with(&[0xFF, 0xD8]) {
+        let (segments, _) = jpeg_segments(image)?;
+        let (_, segment) = segments.into_iter()
+            .find(|(marker, segment)| *marker == JPEG_METADATA_MARKERS[0] && segment.get(4..10) == Some(EXIF_HEADER))?;
+        &segment[10..]
+    } else if image.starts_with(PNG_SIGNATURE) {
+        let (_, chunk) = png_chunks(image)?.into_iter().find(|(chunk_type, _)| *chunk_type == b"eXIf")?;
+        chunk.get(8..chunk.len() - 4)?
+    } else if is_webp(image) {
+        let (_, payload) = chunks(&image[12..]).find(|(fourcc, _)| *fourcc == b"EXIF")?;
+        // Some writers keep the header JPEGs have.
+        payload.strip_prefix(EXIF_HEADER).unwrap_or(payload)
+    } else if image.starts_with(b"II*\0") || image.starts_with(b"MM\0*") {
+        image
+    } else {
+        return None;
+    };
+    exif_orientation(exif)
+}
+
+/// Orientation tag of the first IFD of EXIF data, a TIFF structure in either byte order.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(tiff)?;
+    let entry = tiff.entry(EXIF_ORIENTATION_TAG)?;
+    tiff.u16_at(entry + 8).filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// CR2, NEF or DNG file of a camera. They are TIFFs, told apart from plain ones by the signature
+/// of CR2, the DNG version tag or the maker of the camera. Nikon scanners write plain TIFFs with the
+/// same maker, NEFs also have the sensor data in a sub IFD.
+pub fn is_camera_raw(image: &[u8]) -> bool {
+    if image.starts_with(b"II*\0") && image.get(8..10) == Some(b"CR") {
+        return true;
+    }
+    let tiff = match Tiff::new(image) {
+        Some(tiff) => tiff,
+        None => return false,
+    };
+    if tiff.entry(DNG_VERSION_TAG).is_some() {
+        return true;
+    }
+    // Makers longer than 4 bytes, as all of them are, are stored at an offset.
+    let maker = tiff.entry(TIFF_MAKE_TAG)
+        .and_then(|entry| tiff.u32_at(entry + 8))
+        .and_then(|offset| image.get(offset as usize..));
+    matches!(maker, Some(maker) if maker.starts_with(b"NIKON")) && tiff.entry(TIFF_SUB_IFDS_TAG).is_some()
+}
+
+/// First IFD of a TIFF structure in either byte order.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    /// Offset of the entry of the tag in the first IFD.
+    fn entry(&self, tag: u16) -> Option<usize> {
+        let ifd = self.u32_at(4)? as usize;
+        (0..self.u16_at(ifd)? as usize)
+            .map(|index| ifd + 2 + index * 12)
+            .find(|entry| self.u16_at(*entry) == Some(tag))
+    }
+}
+
+/// How the four components of a CMYK JPEG are stored.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CmykEncoding {
+    /// Ink amounts as they are, written by tools without an Adobe segment.
+    Plain,
+    /// Inverted ink amounts, written by Photoshop and other tools with an Adobe segment.
+    Inverted,
+    /// Inverted CMY coded as YCbCr, and inverted K.
+    Ycck,
+}
+
+/// Encoding of a JPEG with four color components, `None` for other images.
+pub fn jpeg_cmyk(image: &[u8]) -> Option<CmykEncoding> {
+    if !image.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let (segments, _) = jpeg_segments(image)?;
+    // Start of frame markers, the ones between them are tables.
+    let (_, frame) = segments.iter().find(|(marker, _)| matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC))?;
+    if *frame.get(9)? != 4 {
+        return None;
+    }
+    let adobe_transform = segments.iter()
+        .find(|(marker, segment)| *marker == JPEG_ADOBE_MARKER && segment.get(4..9) == Some(b"Adobe"))
+        .and_then(|(_, segment)| segment.get(15).copied());
+    Some(match adobe_transform {
+        None => CmykEncoding::Plain,
+        Some(2) => CmykEncoding::Ycck,
+        Some(_) => CmykEncoding::Inverted,
+    })
+}
+
+/// Whether the profile describes RGB colors, the only ones decoded images have.
+pub fn is_rgb_profile(profile: &[u8]) -> bool {
+    profile.get(16..20) == Some(b"RGB ")
+}
+
+/// Image with the ICC profile embedded, `None` for formats other than JPEG, PNG and WebP.
+/// WebP without an extended header gets one of the given dimensions.
+pub fn embed_color_profile(image: &[u8], profile: &[u8], dimensions: (u32, u32)) -> Option<Vec<u8>> {
+    if image.starts_with(&[0xFF, 0xD8]) {
+        let (segments, scan) = jpeg_segments(image)?;
+        let mut embedded = image[..2].to_vec();
+        let parts = profile.chunks(JPEG_ICC_PART).collect::<Vec<&[u8]>>();
+        let mut remaining = segments.into_iter().peekable();
+        // ICC segments go after the JFIF header, which must come first.
+        if let Some((_, jfif)) = remaining.next_if(|(marker, _)| *marker == JPEG_JFIF_MARKER) {
+            embedded.extend_from_slice(jfif);
+        }
+        for (number, part) in parts.iter().enumerate() {
+            embedded.extend_from_slice(&[0xFF, JPEG_ICC_MARKER]);
+            embedded.extend_from_slice(&((2 + JPEG_ICC_HEADER.len() + 2 + part.len()) as u16).to_be_bytes());
+            embedded.extend_from_slice(JPEG_ICC_HEADER);
+            embedded.extend_from_slice(&[number as u8 + 1, parts.len() as u8]);
+            embedded.extend_from_slice(part);
+        }
+        remaining.filter(|(marker, _)| *marker != JPEG_ICC_MARKER).for_each(|(_, segment)| embedded.extend_from_slice(segment));
+        embedded.extend_from_slice(scan);
+        return Some(embedded);
+    }
+    if image.starts_with(PNG_SIGNATURE) {
+        let mut compressed = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), Compression::default());
+        compressed.write_all(profile).ok()?;
+        let data = compressed.finish().ok()?;
+        let mut embedded = PNG_SIGNATURE.to_vec();
+        for (chunk_type, chunk) in png_chunks(image)? {
+            match chunk_type {
+                b"iCCP" | b"sRGB" => {}
+                b"IHDR" => {
+                    embedded.extend_from_slice(chunk);
+                    push_png_chunk(&mut embedded, b"iCCP", &data);
+                }
+                _ => embedded.extend_from_slice(chunk),
+            }
+        }
+        return Some(embedded);
+    }
+    if is_webp(image) {
+        let image_chunks = chunks(&image[12..]).filter(|(fourcc, _)| *fourcc != b"ICCP").collect::<Vec<(&[u8], &[u8])>>();
+        let mut body = Vec::new();
+        let extended = match image_chunks.first() {
+            Some((b"VP8X", extended)) if !extended.is_empty() => extended.to_vec(),
+            _ => {
+                let has_alpha = image_chunks.iter().any(|(fourcc, payload)| {
+                    *fourcc == b"ALPH" || (*fourcc == b"VP8L" && payload.len() > 4 && payload[4] & 0x10 != 0)
+                });
+                let mut extended = vec![if has_alpha { WEBP_ALPHA_FLAG } else { 0 }, 0, 0, 0];
+                extended.extend_from_slice(&(dimensions.0 - 1).to_le_bytes()[..3]);
+                extended.extend_from_slice(&(dimensions.1 - 1).to_le_bytes()[..3]);
+                extended
+            }
+        };
+        let mut flags = extended;
+        flags[0] |= WEBP_ICC_FLAG;
+        push_chunk(&mut body, b"VP8X", &flags);
+        push_chunk(&mut body, b"ICCP", profile);
+        for (fourcc, payload) in image_chunks.into_iter().filter(|(fourcc, _)| *fourcc != b"VP8X") {
+            push_chunk(&mut body, fourcc, payload);
+        }
+        return Some(riff(&body));
+    }
+    None
+}
+
+fn is_webp(image: &[u8]) -> bool {
+    image.get(..4) == Some(b"RIFF") && image.get(8..12) == Some(b"WEBP")
+}
+
+/// Marker and bytes of a JPEG segment.
+type Segment<'a> = (u8, &'a [u8]);
+
+/// Every segment up to the start of the scan, and the rest of the image from there, which is
+/// entropy-coded data.
+fn jpeg_segments(image: &[u8]) -> Option<(Vec<Segment<'_>>, &[u8])> {
+    let mut segments = Vec::new();
+    let mut position = 2;
+    loop {
+        let marker = match image.get(position..position + 2)? {
+            [0xFF, 0xFF] => {
+                position += 1;
+                continue;
+            }
+            [0xFF, marker] => *marker,
+            _ => return None,
+        };
+        if marker == JPEG_START_OF_SCAN {
+            return Some((segments, &image[position..]));
+        }
+        let length = u16::from_be_bytes(image.get(position + 2..position + 4)?.try_into().ok()?) as usize;
+        let end = position + 2 + length;
+        segments.push((marker, image.get(position..end)?));
+        position = end;
+    }
+}
+
+/// Type and bytes of every chunk, with its length and CRC.
+pub(crate) fn png_chunks(image: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let mut png_chunks = Vec::new();
+    let mut position = PNG_SIGNATURE.len();
+    while position < image.len() {
+        let length = u32::from_be_bytes(image.get(position..position + 4)?.try_into().ok()?) as usize;
+        let end = position + 12 + length;
+        let chunk = image.get(position..end)?;
+        png_chunks.push((&chunk[4..8], chunk));
+        position = end;
+    }
+    Some(png_chunks)
+}
+
+fn push_png_chunk(buffer: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(chunk_type);
+    buffer.extend_from_slice(data);
+    buffer.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image_crate::{DynamicImage, ImageOutputFormat, RgbImage};
+    use image_crate::io::Reader as ImageReader;
+
+    use crate::metadata::{color_profile, embed_color_profile, is_camera_raw, orientation, PNG_SIGNATURE, strip};
+
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        let mut image = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4)).write_to(&mut Cursor::new(&mut image), format).unwrap();
+        image
+    }
+
+    #[test]
+    fn strip_jpeg_and_png_metadata() {
+        let jpeg = encoded(ImageOutputFormat::Jpeg(80));
+        let exif = [&[0xFF, 0xE1, 0x00, 0x0C][..], b"Exif\0\0GPS!"].concat();
+        let with_exif = [&jpeg[..2], &exif, &jpeg[2..]].concat();
+        assert_eq!(strip(&with_exif, true), Some(jpeg.clone()));
+        assert_eq!(strip(&jpeg, false), None);
+
+        let png = encoded(ImageOutputFormat::Png);
+        let text = [&[0, 0, 0, 4][..], b"tEXtGPS!", &[0, 0, 0, 0]].concat();
+        let with_text = [PNG_SIGNATURE, &text, &png[PNG_SIGNATURE.len()..]].concat();
+        assert_eq!(strip(&with_text, true), Some(png.clone()));
+        assert_eq!(strip(&with_text[..with_text.len() - 1], true), None);
+        assert_eq!(strip(b"GIF89a", true), None);
+    }
+
+    /// EXIF with the orientation as the only entry of the first IFD.
+    fn exif(orientation: u16, big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        [
+            if big_endian { &b"MM\0*"[..] } else { &b"II*\0"[..] },
+            &u32_bytes(8), &u16_bytes(1),
+            &u16_bytes(0x0112), &u16_bytes(3), &u32_bytes(1), &u16_bytes(orientation), &[0, 0],
+            &u32_bytes(0),
+        ].concat()
+    }
+
+    /// JPEG with the EXIF in an APP1 segment right after the start of the image.
+    fn with_exif(jpeg: &[u8], exif: &[u8]) -> Vec<u8> {
+        let segment = [&[0xFF, 0xE1][..], &((exif.len() + 8) as u16).to_be_bytes(), b"Exif\0\0", exif].concat();
+        [&jpeg[..2], &segment, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn read_exif_orientation() {
+        let jpeg = encoded(ImageOutputFormat::Jpeg(80));
+        assert_eq!(orientation(&jpeg), None);
+        assert_eq!(orientation(&with_exif(&jpeg, &exif(6, false))), Some(6));
+        assert_eq!(orientation(&with_exif(&jpeg, &exif(8, true))), Some(8));
+        assert_eq!(orientation(&with_exif(&jpeg, &exif(9, true))), None);
+        assert_eq!(orientation(&with_exif(&jpeg, b"II*\0\xFF")), None);
+        assert_eq!(orientation(&exif(3, true)), Some(3));
+    }
+
+    #[test]
+    fn recognize_camera_raw() {
+        assert!(!is_camera_raw(&exif(1, false)));
+        assert!(is_camera_raw(b"II*\0\x10\0\0\0CR\x02\0"));
+        // First IFD with the entries, their values stored one after the other past it.
+        let tiff = |entries: &[(u16, &[u8])]| {
+            let mut offset = 8 + 2 + entries.len() * 12 + 4;
+            let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+            for (tag, value) in entries {
+                ifd.extend([&tag.to_le_bytes()[..], &2u16.to_le_bytes(), &(value.len() as u32).to_le_bytes(), &(offset as u32).to_le_bytes()].concat());
+                offset += value.len();
+            }
+            let values = entries.iter().flat_map(|(_, value)| value.iter().copied());
+            [&b"II*\0"[..], &8u32.to_le_bytes(), &ifd, &0u32.to_le_bytes()].concat().into_iter().chain(values).collect::<Vec<u8>>()
+        };
+        assert!(is_camera_raw(&tiff(&[(0xC612, &[1, 4, 0, 0])])));
+        assert!(is_camera_raw(&tiff(&[(0x010F, b"NIKON CORPORATION\0"), (0x014A, &[0; 8])])));
+        // Scanned film.
+        assert!(!is_camera_raw(&tiff(&[(0x010F, b"NIKON\0")])));
+        assert!(!is_camera_raw(&tiff(&[(0x010F, b"Canon\0")])));
+        assert!(!is_camera_raw(b"GIF89a"));
+    }
+
+    #[test]
+    fn carry_color_profile_over() {
+        // Large enough to be split into parts in JPEGs.
+        let profile: Vec<u8> = [&[0; 16][..], b"RGB "].concat().into_iter().chain((0..70000).map(|byte| byte as u8)).collect();
+        let mut images = vec![encoded(ImageOutputFormat::Jpeg(80)), encoded(ImageOutputFormat::Png)];
+        #[cfg(feature = "webp")]
+        images.push(webp::Encoder::from_rgb(&[0; 48], 4, 4).encode(80.0).to_vec());
+        for image in images {
+            assert_eq!(color_profile(&image), None);
+            let embedded = embed_color_profile(&image, &profile, (4, 4)).unwrap();
+            assert_eq!(color_profile(&embedded).as_ref(), Some(&profile));
+            let decoded = ImageReader::new(Cursor::new(&embedded)).with_guessed_format().unwrap().decode().unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (4, 4));
+
+            assert_eq!(color_profile(&strip(&embedded, true).unwrap_or_else(|| embedded.clone())).as_ref(), Some(&profile));
+            assert_eq!(color_profile(&strip(&embedded, false).unwrap()), None);
+        }
+    }
+}
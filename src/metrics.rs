@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::MetricsBackend;
+use crate::metrics::prometheus::PrometheusMetrics;
+use crate::metrics::statsd::StatsdMetrics;
+
+pub mod prometheus;
+pub mod statsd;
+
+pub const CACHE_HITS: &str = "pixvert_cache_hits_total";
+pub const CACHE_MISSES: &str = "pixvert_cache_misses_total";
+pub const STAGE_DURATION: &str = "pixvert_stage_duration_seconds";
+pub const RESPONSES: &str = "pixvert_responses_total";
+pub const PANICS: &str = "pixvert_panics_total";
+pub const CACHE_WRITES_DROPPED: &str = "pixvert_cache_writes_dropped_total";
+pub const EVENTS_DROPPED: &str = "pixvert_events_dropped_total";
+pub const PREWARMED: &str = "pixvert_prewarmed_total";
+
+pub const TRANSFORM_SECONDS: &str = "pixvert_transform_seconds_total";
+pub const BYTES_SERVED: &str = "pixvert_bytes_served_total";
+
+pub trait Metrics {
+    fn add(&self, name: &str, value: f64, labels: &[(&str, &str)]);
+    fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, 1.0, labels);
+    }
+    fn observe(&self, name: &str, value: f64, labels: &[(&str, &str)]);
+    /// Text exposition of collected metrics for backends which are scraped instead of pushing.
+    fn render(&self) -> Option<String>;
+}
+
+pub struct NoMetrics {}
+
+impl Metrics for NoMetrics {
+    fn add(&self, _: &str, _: f64, _: &[(&str, &str)]) {}
+    fn observe(&self, _: &str, _: f64, _: &[(&str, &str)]) {}
+    fn render(&self) -> Option<String> {
+        Option::None
+    }
+}
+
+pub fn create_metrics(backend: &MetricsBackend) -> Arc<dyn Metrics + Send + Sync> {
+    match backend {
+        MetricsBackend::None => Arc::new(NoMetrics {}),
+        MetricsBackend::Prometheus => Arc::new(PrometheusMetrics::default()),
+        MetricsBackend::Statsd(address) => Arc::new(StatsdMetrics::new(address, false)),
+        MetricsBackend::Dogstatsd(address) => Arc::new(StatsdMetrics::new(address, true)),
+    }
+}
+
+/// Records duration of a stage since `started`.
+pub fn observe_stage(metrics: &dyn Metrics, stage: &str, started: Instant) {
+    metrics.observe(STAGE_DURATION, started.elapsed().as_secs_f64(), &[("stage", stage)]);
+}
+
+pub fn record_cache_lookup(metrics: &dyn Metrics, stage: &str, hit: bool) {
+    metrics.increment(if hit { CACHE_HITS } else { CACHE_MISSES }, &[("stage", stage)]);
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::metrics::Metrics;
+
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Collects metrics in memory and renders them in the Prometheus text format on scrape.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    counters: Mutex<BTreeMap<String, BTreeMap<String, f64>>>,
+    histograms: Mutex<BTreeMap<String, BTreeMap<String, Histogram>>>,
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn with_label(labels: &str, label: &str) -> String {
+    if labels.is_empty() {
+        return format!("{{{}}}", label);
+    }
+    format!("{{{},{}}}", labels, label)
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels)
+}
+
+impl Metrics for PrometheusMetrics {
+    fn add(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        *self.counters.lock().unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .entry(format_labels(labels))
+            .or_default() += value;
+    }
+
+    fn observe(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry(name.to_string())
+            .or_default()
+            .entry(format_labels(labels))
+            .or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> Option<String> {
+        let mut output = String::new();
+        for (name, series) in self.counters.lock().unwrap().iter() {
+            writeln!(output, "# TYPE {} counter", name).unwrap();
+            for (labels, value) in series {
+                writeln!(output, "{}{} {}", name, braced(labels), value).unwrap();
+            }
+        }
+        for (name, series) in self.histograms.lock().unwrap().iter() {
+            writeln!(output, "# TYPE {} histogram", name).unwrap();
+            for (labels, histogram) in series {
+                for (bucket, bound) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+                    writeln!(output, "{}_bucket{} {}", name, with_label(labels, &format!("le=\"{}\"", bound)), bucket).unwrap();
+                }
+                writeln!(output, "{}_bucket{} {}", name, with_label(labels, "le=\"+Inf\""), histogram.count).unwrap();
+                writeln!(output, "{}_sum{} {}", name, braced(labels), histogram.sum).unwrap();
+                writeln!(output, "{}_count{} {}", name, braced(labels), histogram.count).unwrap();
+            }
+        }
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+    use crate::metrics::prometheus::PrometheusMetrics;
+
+    #[test]
+    fn render_counters_and_histograms() {
+        let metrics = PrometheusMetrics::default();
+        metrics.increment("hits_total", &[("stage", "decode")]);
+        metrics.increment("hits_total", &[("stage", "decode")]);
+        metrics.observe("duration_seconds", 0.3, &[]);
+        metrics.add("bytes_total", 1.5, &[]);
+
+        let output = metrics.render().unwrap();
+        assert!(output.contains("# TYPE hits_total counter\nhits_total{stage=\"decode\"} 2\n"));
+        assert!(output.contains("duration_seconds_bucket{le=\"0.25\"} 0\n"));
+        assert!(output.contains("duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(output.contains("duration_seconds_count 1\n"));
+        assert!(output.contains("bytes_total 1.5\n"));
+    }
+}
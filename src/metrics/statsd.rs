@@ -0,0 +1,65 @@
+use std::net::UdpSocket;
+
+use log::error;
+
+use crate::metrics::Metrics;
+
+/// Pushes metrics over UDP. Plain statsd has no tags, so label values are appended to the metric name,
+/// DogStatsD receives them as `#label:value` tags.
+pub struct StatsdMetrics {
+    socket: Option<UdpSocket>,
+    tagged: bool,
+}
+
+impl StatsdMetrics {
+    pub fn new(address: &str, tagged: bool) -> Self {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(address).map(|_| socket))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket));
+        let socket = match socket {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                error!("Unable to reach statsd at {}, metrics will not be sent. Reason: {}", address, e);
+                None
+            }
+        };
+        StatsdMetrics { socket, tagged }
+    }
+
+    fn line(&self, name: &str, value: &str, kind: &str, labels: &[(&str, &str)]) -> String {
+        if self.tagged {
+            if labels.is_empty() {
+                return format!("{}:{}|{}", name, value, kind);
+            }
+            let tags: Vec<String> = labels.iter().map(|(name, value)| format!("{}:{}", name, value)).collect();
+            return format!("{}:{}|{}|#{}", name, value, kind, tags.join(","));
+        }
+        let mut name = name.to_string();
+        for (_, value) in labels {
+            name.push('.');
+            name.push_str(value);
+        }
+        format!("{}:{}|{}", name, value, kind)
+    }
+
+    fn send(&self, line: String) {
+        if let Some(socket) = &self.socket {
+            // Metrics are best effort, a full buffer or missing agent must not fail the request.
+            socket.send(line.as_bytes()).unwrap_or_default();
+        }
+    }
+}
+
+impl Metrics for StatsdMetrics {
+    fn add(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        self.send(self.line(name, &value.to_string(), "c", labels));
+    }
+
+    fn observe(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        self.send(self.line(name, &value.to_string(), "h", labels));
+    }
+
+    fn render(&self) -> Option<String> {
+        Option::None
+    }
+}
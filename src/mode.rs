@@ -0,0 +1,92 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::recover;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ServiceMode {
+    #[default]
+    Normal,
+    /// Only renditions already in the cache are served, origins are never contacted.
+    ReadOnly,
+    /// Every image request is answered with the placeholder.
+    Maintenance,
+}
+
+/// Mode shared by all workers, switched at runtime through the admin API.
+pub struct RuntimeMode {
+    mode: AtomicU8,
+    /// Placeholder image with the path it was read from.
+    placeholder: Mutex<Option<(String, Bytes)>>,
+}
+
+impl RuntimeMode {
+    pub fn new(mode: ServiceMode) -> Self {
+        RuntimeMode { mode: AtomicU8::new(mode as u8), placeholder: Mutex::new(None) }
+    }
+
+    pub fn get(&self) -> ServiceMode {
+        match self.mode.load(Ordering::Relaxed) {
+            1 => ServiceMode::ReadOnly,
+            2 => ServiceMode::Maintenance,
+            _ => ServiceMode::Normal,
+        }
+    }
+
+    /// Switching the mode forgets the placeholder, so a replaced file is served from the next switch on.
+    pub fn set(&self, mode: ServiceMode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+        *recover(self.placeholder.lock()) = None;
+    }
+
+    /// Placeholder at `path`, read once and again only when the path or the mode changes.
+    /// A placeholder which cannot be read is tried again on the next request.
+    pub fn placeholder(&self, path: &str) -> Option<Bytes> {
+        let mut placeholder = recover(self.placeholder.lock());
+        if let Some((read_from, image)) = placeholder.as_ref() {
+            if read_from == path {
+                return Some(image.clone());
+            }
+        }
+        let image = Bytes::from(std::fs::read(path).ok()?);
+        *placeholder = Some((path.to_string(), image.clone()));
+        Some(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mode::{RuntimeMode, ServiceMode};
+
+    #[test]
+    fn switch_modes() {
+        let mode = RuntimeMode::new(ServiceMode::default());
+        for next in [ServiceMode::ReadOnly, ServiceMode::Maintenance, ServiceMode::Normal] {
+            mode.set(next);
+            assert_eq!(mode.get(), next);
+        }
+    }
+
+    #[test]
+    fn read_placeholder_once_per_path() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let (first, second) = (directory.path().join("first.png"), directory.path().join("second.png"));
+        let mode = RuntimeMode::new(ServiceMode::Maintenance);
+        assert_eq!(mode.placeholder(&first.to_string_lossy()), None);
+        std::fs::write(&first, b"first").unwrap();
+        assert_eq!(mode.placeholder(&first.to_string_lossy()).as_deref(), Some(&b"first"[..]));
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(mode.placeholder(&first.to_string_lossy()).as_deref(), Some(&b"first"[..]));
+        std::fs::write(&second, b"second").unwrap();
+        assert_eq!(mode.placeholder(&second.to_string_lossy()).as_deref(), Some(&b"second"[..]));
+
+        std::fs::write(&second, b"replaced").unwrap();
+        assert_eq!(mode.placeholder(&second.to_string_lossy()).as_deref(), Some(&b"second"[..]));
+        mode.set(ServiceMode::Maintenance);
+        assert_eq!(mode.placeholder(&second.to_string_lossy()).as_deref(), Some(&b"replaced"[..]));
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::config::OperationLimits;
+use crate::fetcher::generate_resource_tag;
+
+pub const OPERATIONS_QUERY_KEY: &str = "ops";
+const DEFAULT_DENOISE_STRENGTH: u8 = 20;
+const DEFAULT_VIGNETTE_STRENGTH: u8 = 50;
+const MAXIMUM_BORDER_WIDTH: u32 = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FitMode {
+    Seam,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelMode {
+    Luminance,
+    PerChannel,
+}
+
+/// Inside border keeps the output within the requested dimensions by scaling the image down,
+/// outside border expands the canvas around the scaled image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BorderPlacement {
+    Inside,
+    Outside,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl Color {
+    pub const TRANSPARENT: Color = Color { red: 0, green: 0, blue: 0, alpha: 0 };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Fit(FitMode),
+    AutoContrast(ChannelMode),
+    Equalize(ChannelMode),
+    Denoise(u8),
+    Round(u32, Color),
+    Vignette(u8),
+    Border(u32, Color, BorderPlacement),
+    MaxWidth(u32),
+    MaxHeight(u32),
+}
+
+#[derive(Debug)]
+pub enum OperationParseError {
+    UnknownOperation(String),
+    InvalidArgument(String, String),
+    /// Chain is valid, but goes beyond `operationLimits`.
+    LimitExceeded(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OperationChain {
+    operations: Vec<Operation>,
+}
+
+impl OperationChain {
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn fit_mode(&self) -> Option<&FitMode> {
+        self.operations.iter().find_map(|operation| match operation {
+            Operation::Fit(mode) => Some(mode),
+            _ => None,
+        })
+    }
+
+    fn filtered(&self, predicate: fn(&Operation) -> bool) -> OperationChain {
+        OperationChain {
+            operations: self.operations.iter().filter(|operation| predicate(operation)).cloned().collect(),
+        }
+    }
+
+    /// Operations which have to see the full resolution source, e.g. noise is gone after downscaling.
+    pub fn before_resize(&self) -> OperationChain {
+        self.filtered(Operation::is_applied_before_resize)
+    }
+
+    pub fn after_resize(&self) -> OperationChain {
+        self.filtered(|operation| !operation.is_applied_before_resize())
+    }
+
+    /// Size by which requested dimensions shrink to make room for the inside borders.
+    pub fn border_inset(&self) -> usize {
+        self.operations.iter().map(|operation| match operation {
+            Operation::Border(width, _, BorderPlacement::Inside) => *width as usize * 2,
+            _ => 0,
+        }).sum()
+    }
+
+    /// Dimensions the image has to be shrunk to, if it exceeds `maxw` or `maxh` bounds.
+    /// Images within the bounds are never enlarged.
+    pub fn maximum_dimensions(&self, dimensions: (u32, u32)) -> Option<(usize, usize)> {
+        let (mut width, mut height) = dimensions;
+        for operation in &self.operations {
+            match operation {
+                Operation::MaxWidth(maximum) => width = width.min(*maximum),
+                Operation::MaxHeight(maximum) => height = height.min(*maximum),
+                _ => {}
+            }
+        }
+        if (width, height) == dimensions {
+            return None;
+        }
+        Some((width as usize, height as usize))
+    }
+
+    pub fn with(mut self, operation: Operation) -> OperationChain {
+        self.operations.push(operation);
+        self
+    }
+
+    pub fn check_limits(&self, limits: &OperationLimits) -> Result<(), OperationParseError> {
+        if self.operations.len() > limits.maximum_operations {
+            return Err(OperationParseError::LimitExceeded(format!("at most {} operations are allowed", limits.maximum_operations)));
+        }
+        let cost: u32 = self.operations.iter().map(Operation::cost).sum();
+        if cost > limits.maximum_cost {
+            return Err(OperationParseError::LimitExceeded(format!("operations weigh {}, at most {} is allowed", cost, limits.maximum_cost)));
+        }
+        for (name, maximum) in &limits.maximum_repeats {
+            if self.operations.iter().filter(|operation| operation.name() == name).count() > *maximum {
+                return Err(OperationParseError::LimitExceeded(format!("{} is allowed at most {} times", name, maximum)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Derives a cache tag for an image produced by applying this chain to the tagged image.
+    pub fn tag(&self, tag: &str) -> String {
+        if self.is_empty() {
+            return tag.to_string();
+        }
+        generate_resource_tag(&format!("{} - {}", tag, self))
+    }
+}
+
+impl Operation {
+    pub fn is_applied_before_resize(&self) -> bool {
+        matches!(self, Operation::Denoise(_))
+    }
+
+    /// Operations which can turn gray pixels into colored or translucent ones. The rest of them
+    /// treat all channels alike.
+    pub fn adds_color(&self) -> bool {
+        match self {
+            Operation::Round(_, color) | Operation::Border(_, color, _) => {
+                color.alpha != 255 || color.red != color.green || color.green != color.blue
+            }
+            _ => false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Fit(_) => "fit",
+            Operation::AutoContrast(_) => "autocontrast",
+            Operation::Equalize(_) => "equalize",
+            Operation::Denoise(_) => "denoise",
+            Operation::Round(_, _) => "round",
+            Operation::Vignette(_) => "vignette",
+            Operation::Border(_, _, _) => "border",
+            Operation::MaxWidth(_) => "maxw",
+            Operation::MaxHeight(_) => "maxh",
+        }
+    }
+
+    /// Rough weight of the operation against the others. Seam carving removes one seam at
+    /// a time and denoising looks at a neighbourhood of every pixel, bounds cost nothing.
+    pub fn cost(&self) -> u32 {
+        match self {
+            Operation::Fit(_) => 8,
+            Operation::Denoise(_) => 4,
+            Operation::AutoContrast(_) | Operation::Equalize(_) | Operation::Vignette(_) => 2,
+            Operation::Round(_, _) | Operation::Border(_, _, _) => 1,
+            Operation::MaxWidth(_) | Operation::MaxHeight(_) => 0,
+        }
+    }
+
+    /// Operations changing the pixels, rest of them only affect dimensions of the output.
+    pub fn is_filter(&self) -> bool {
+        !matches!(self, Operation::Fit(_) | Operation::MaxWidth(_) | Operation::MaxHeight(_))
+    }
+}
+
+fn parse_bound(name: &str, arguments: &[&str]) -> Result<u32, OperationParseError> {
+    match arguments {
+        [bound] => match bound.parse::<u32>() {
+            Ok(bound) if bound > 0 => Ok(bound),
+            _ => Err(OperationParseError::InvalidArgument(name.to_string(), bound.to_string())),
+        },
+        _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+    }
+}
+
+impl FromStr for Color {
+    type Err = OperationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid_color = || OperationParseError::InvalidArgument(String::from("color"), s.to_string());
+        if !(s.len() == 6 || s.len() == 8) || !s.is_ascii() {
+            return Err(invalid_color());
+        }
+        let component = |index: usize| u8::from_str_radix(&s[index..index + 2], 16).map_err(|_| invalid_color());
+        Ok(Color {
+            red: component(0)?,
+            green: component(2)?,
+            blue: component(4)?,
+            alpha: if s.len() == 8 { component(6)? } else { 255 },
+        })
+    }
+}
+
+fn parse_percentage(name: &str, argument: &str) -> Result<u8, OperationParseError> {
+    match argument.parse::<u8>() {
+        Ok(value) if (1..=100).contains(&value) => Ok(value),
+        _ => Err(OperationParseError::InvalidArgument(name.to_string(), argument.to_string())),
+    }
+}
+
+fn parse_channel_mode(name: &str, arguments: &[&str]) -> Result<ChannelMode, OperationParseError> {
+    match arguments {
+        [] | ["luma"] => Ok(ChannelMode::Luminance),
+        ["rgb"] => Ok(ChannelMode::PerChannel),
+        _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+    }
+}
+
+impl FromStr for Operation {
+    type Err = OperationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let arguments: Vec<&str> = parts.collect();
+        match name {
+            "fit" => match arguments.as_slice() {
+                ["seam"] => Ok(Operation::Fit(FitMode::Seam)),
+                _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+            },
+            "autocontrast" => Ok(Operation::AutoContrast(parse_channel_mode(name, &arguments)?)),
+            "equalize" => Ok(Operation::Equalize(parse_channel_mode(name, &arguments)?)),
+            "denoise" => match arguments.as_slice() {
+                [] => Ok(Operation::Denoise(DEFAULT_DENOISE_STRENGTH)),
+                [strength] => Ok(Operation::Denoise(parse_percentage(name, strength)?)),
+                _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+            },
+            "vignette" => match arguments.as_slice() {
+                [] => Ok(Operation::Vignette(DEFAULT_VIGNETTE_STRENGTH)),
+                [strength] => Ok(Operation::Vignette(parse_percentage(name, strength)?)),
+                _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+            },
+            "maxw" => Ok(Operation::MaxWidth(parse_bound(name, &arguments)?)),
+            "maxh" => Ok(Operation::MaxHeight(parse_bound(name, &arguments)?)),
+            "border" => {
+                let width = match arguments.first().map(|width| width.parse::<u32>()) {
+                    Some(Ok(width)) if width <= MAXIMUM_BORDER_WIDTH => width,
+                    _ => return Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+                };
+                match arguments.as_slice() {
+                    [_, color] => Ok(Operation::Border(width, color.parse()?, BorderPlacement::Outside)),
+                    [_, color, "outside"] => Ok(Operation::Border(width, color.parse()?, BorderPlacement::Outside)),
+                    [_, color, "inside"] => Ok(Operation::Border(width, color.parse()?, BorderPlacement::Inside)),
+                    _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+                }
+            }
+            "round" => {
+                let radius = match arguments.first().map(|radius| radius.parse::<u32>()) {
+                    Some(Ok(radius)) => radius,
+                    _ => return Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+                };
+                match arguments.as_slice() {
+                    [_] => Ok(Operation::Round(radius, Color::TRANSPARENT)),
+                    [_, color] => Ok(Operation::Round(radius, color.parse()?)),
+                    _ => Err(OperationParseError::InvalidArgument(name.to_string(), arguments.join(":"))),
+                }
+            }
+            _ => Err(OperationParseError::UnknownOperation(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for OperationChain {
+    type Err = OperationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let operations = s
+            .split(',')
+            .filter(|operation| !operation.is_empty())
+            .map(|operation| operation.parse::<Operation>())
+            .collect::<Result<Vec<Operation>, OperationParseError>>()?;
+        Ok(OperationChain { operations })
+    }
+}
+
+impl Display for OperationParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperationParseError::UnknownOperation(operation) => write!(f, "Unknown operation: {}", operation),
+            OperationParseError::InvalidArgument(operation, argument) => write!(f, "Invalid argument '{}' for operation: {}", argument, operation),
+            OperationParseError::LimitExceeded(limit) => write!(f, "Operations exceed the limits: {}", limit),
+        }
+    }
+}
+
+impl Display for FitMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FitMode::Seam => write!(f, "seam"),
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.red, self.green, self.blue)?;
+        if self.alpha != 255 {
+            write!(f, "{:02x}", self.alpha)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for BorderPlacement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BorderPlacement::Inside => write!(f, "inside"),
+            BorderPlacement::Outside => write!(f, "outside"),
+        }
+    }
+}
+
+impl Display for ChannelMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelMode::Luminance => write!(f, "luma"),
+            ChannelMode::PerChannel => write!(f, "rgb"),
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Fit(mode) => write!(f, "fit:{}", mode),
+            Operation::AutoContrast(mode) => write!(f, "autocontrast:{}", mode),
+            Operation::Equalize(mode) => write!(f, "equalize:{}", mode),
+            Operation::Denoise(strength) => write!(f, "denoise:{}", strength),
+            Operation::Round(radius, color) => write!(f, "round:{}:{}", radius, color),
+            Operation::Vignette(strength) => write!(f, "vignette:{}", strength),
+            Operation::Border(width, color, placement) => write!(f, "border:{}:{}:{}", width, color, placement),
+            Operation::MaxWidth(width) => write!(f, "maxw:{}", width),
+            Operation::MaxHeight(height) => write!(f, "maxh:{}", height),
+        }
+    }
+}
+
+impl Display for OperationChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let operations: Vec<String> = self.operations.iter().map(|operation| operation.to_string()).collect();
+        write!(f, "{}", operations.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::OperationLimits;
+    use crate::operations::{BorderPlacement, ChannelMode, Color, FitMode, Operation, OperationChain, OperationParseError};
+
+    #[test]
+    fn parse_operation_chain() {
+        let chain: OperationChain = "fit:seam".parse().unwrap();
+        assert_eq!(chain.fit_mode(), Some(&FitMode::Seam));
+        assert_eq!(chain.to_string(), "fit:seam");
+    }
+
+    #[test]
+    fn parse_channel_mode_arguments() {
+        let chain: OperationChain = "autocontrast,equalize:rgb".parse().unwrap();
+        assert_eq!(chain.operations(), &[
+            Operation::AutoContrast(ChannelMode::Luminance),
+            Operation::Equalize(ChannelMode::PerChannel),
+        ]);
+        assert_eq!(chain.to_string(), "autocontrast:luma,equalize:rgb");
+    }
+
+    #[test]
+    fn split_operations_around_resize() {
+        let chain: OperationChain = "autocontrast,denoise".parse().unwrap();
+        assert_eq!(chain.before_resize().operations(), &[Operation::Denoise(20)]);
+        assert_eq!(chain.after_resize().operations(), &[Operation::AutoContrast(ChannelMode::Luminance)]);
+        assert!("denoise:0".parse::<OperationChain>().is_err());
+    }
+
+    #[test]
+    fn parse_round_with_background_color() {
+        let chain: OperationChain = "round:12,round:8:ff8000,vignette".parse().unwrap();
+        assert_eq!(chain.operations(), &[
+            Operation::Round(12, Color::TRANSPARENT),
+            Operation::Round(8, Color { red: 255, green: 128, blue: 0, alpha: 255 }),
+            Operation::Vignette(50),
+        ]);
+        assert_eq!(chain.to_string(), "round:12:00000000,round:8:ff8000,vignette:50");
+        assert!("round:8:ff80".parse::<OperationChain>().is_err());
+    }
+
+    #[test]
+    fn parse_border_placement() {
+        let chain: OperationChain = "border:4:000000,border:10:ffffff:inside".parse().unwrap();
+        assert_eq!(chain.operations(), &[
+            Operation::Border(4, Color { red: 0, green: 0, blue: 0, alpha: 255 }, BorderPlacement::Outside),
+            Operation::Border(10, Color { red: 255, green: 255, blue: 255, alpha: 255 }, BorderPlacement::Inside),
+        ]);
+        assert_eq!(chain.border_inset(), 20);
+        assert!("border:4".parse::<OperationChain>().is_err());
+    }
+
+    #[test]
+    fn maximum_dimensions_only_shrink() {
+        let chain: OperationChain = "maxw:800".parse().unwrap();
+        assert_eq!(chain.maximum_dimensions((1600, 1200)), Some((800, 1200)));
+        assert_eq!(chain.maximum_dimensions((640, 480)), None);
+
+        let chain: OperationChain = "maxw:800,maxh:300".parse().unwrap();
+        assert_eq!(chain.maximum_dimensions((640, 480)), Some((640, 300)));
+        assert!("maxw:0".parse::<OperationChain>().is_err());
+    }
+
+    #[test]
+    fn parse_empty_operation_chain() {
+        let chain: OperationChain = "".parse().unwrap();
+        assert!(chain.is_empty());
+        assert_eq!(chain.fit_mode(), None);
+    }
+
+    #[test]
+    fn limit_operation_chains() {
+        let limits = OperationLimits::default();
+        let chain: OperationChain = "fit:seam,denoise,autocontrast,round:8".parse().unwrap();
+        assert!(chain.check_limits(&limits).is_ok());
+        let chain: OperationChain = "denoise,denoise".parse().unwrap();
+        assert!(matches!(chain.check_limits(&limits), Err(OperationParseError::LimitExceeded(_))));
+        let chain: OperationChain = "fit:seam,denoise,equalize,autocontrast,vignette".parse().unwrap();
+        assert!(matches!(chain.check_limits(&limits), Err(OperationParseError::LimitExceeded(_))));
+        let chain: OperationChain = "maxw:800,".repeat(9).parse().unwrap();
+        assert!(matches!(chain.check_limits(&limits), Err(OperationParseError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn reject_unknown_operation() {
+        assert!("fit:seam,unknown".parse::<OperationChain>().is_err());
+        assert!("fit:stretch".parse::<OperationChain>().is_err());
+    }
+}
@@ -1,9 +1,13 @@
 use std::fmt::{Display, Formatter};
+
+use crate::operations::FitMode;
+
 #[derive(Debug, Clone)]
 pub enum OutputDimensions {
     Original,
     ScaledWithRatio(usize, usize),
     ScaledExact(usize, usize),
+    SeamCarved(usize, usize),
 }
 
 impl Display for OutputDimensions {
@@ -18,6 +22,41 @@ impl Display for OutputDimensions {
             OutputDimensions::ScaledWithRatio(x,y) => {
                 write!(f, "{}x{} keep ratio", x, y)
             }
+            OutputDimensions::SeamCarved(x,y) => {
+                write!(f, "{}x{} seam", x, y)
+            }
+        }
+    }
+}
+
+impl OutputDimensions {
+    pub fn with_fit(self, fit: &FitMode) -> Self {
+        match (self, fit) {
+            (OutputDimensions::ScaledExact(x, y), FitMode::Seam) |
+            (OutputDimensions::ScaledWithRatio(x, y), FitMode::Seam) => OutputDimensions::SeamCarved(x, y),
+            (dimensions, _) => dimensions,
+        }
+    }
+
+    /// Whether an image of the given dimensions already is the requested output.
+    pub fn matches(&self, dimensions: (u32, u32)) -> bool {
+        let dimensions = (dimensions.0 as usize, dimensions.1 as usize);
+        match self {
+            OutputDimensions::Original => true,
+            OutputDimensions::ScaledWithRatio(x, y) |
+            OutputDimensions::ScaledExact(x, y) |
+            OutputDimensions::SeamCarved(x, y) => (*x, *y) == dimensions,
+        }
+    }
+
+    /// Shrinks requested dimensions by the inset, original dimensions are left as they are.
+    pub fn inset(self, inset: usize) -> Self {
+        let shrink = |value: usize| usize::max(value.saturating_sub(inset), 1);
+        match self {
+            OutputDimensions::Original => OutputDimensions::Original,
+            OutputDimensions::ScaledWithRatio(x, y) => OutputDimensions::ScaledWithRatio(shrink(x), shrink(y)),
+            OutputDimensions::ScaledExact(x, y) => OutputDimensions::ScaledExact(shrink(x), shrink(y)),
+            OutputDimensions::SeamCarved(x, y) => OutputDimensions::SeamCarved(shrink(x), shrink(y)),
         }
     }
 }
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use actix_web::web;
+use log::{error, info};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tokio::sync::oneshot;
+
+use crate::config::WorkerSettings;
+use crate::recover;
+
+/// Number of cheap transforms taken in a row before a waiting expensive one gets its turn.
+const HIGH_PRIORITY_BURST: usize = 4;
+
+/// Dedicated worker pools of the CPU heavy stages. Stage without a pool runs on the thread handling the request.
+#[derive(Default)]
+pub struct StagePools {
+    decode: Option<ThreadPool>,
+    encode: Option<ThreadPool>,
+    transform: Option<TransformPool>,
+    low_priority_cost: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+impl StagePools {
+    pub fn new(settings: &WorkerSettings) -> Self {
+        StagePools {
+            decode: build_pool("decode", settings.decode),
+            encode: build_pool("encode", settings.encode),
+            transform: TransformPool::new(settings.transform),
+            low_priority_cost: settings.low_priority_cost,
+        }
+    }
+
+    pub fn priority(&self, cost: u64) -> Priority {
+        if cost > self.low_priority_cost {
+            Priority::Low
+        } else {
+            Priority::High
+        }
+    }
+
+    /// Runs the whole transform. Without a dedicated pool it goes to the blocking pool of the runtime,
+    /// where priority does not apply. Returns `None` when the job was dropped without finishing.
+    pub async fn transform<T: Send + 'static>(&self, priority: Priority, f: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+        match &self.transform {
+            Some(pool) => {
+                let (sender, receiver) = oneshot::channel();
+                pool.submit(priority, Box::new(move || {
+                    let _ = sender.send(f());
+                }));
+                receiver.await.ok()
+            }
+            None => web::block(f).await.ok(),
+        }
+    }
+
+    pub fn decode<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        run(&self.decode, f)
+    }
+
+    pub fn encode<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        run(&self.encode, f)
+    }
+}
+
+fn build_pool(stage: &'static str, threads: usize) -> Option<ThreadPool> {
+    if threads == 0 {
+        return None;
+    }
+    info!("Starting {} {} workers.", threads, stage);
+    Some(
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(move |index| format!("pixvert-{}-{}", stage, index))
+            .build()
+            .unwrap()
+    )
+}
+
+fn run<T: Send>(pool: &Option<ThreadPool>, f: impl FnOnce() -> T + Send) -> T {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queues {
+    high: VecDeque<Job>,
+    low: VecDeque<Job>,
+    high_in_row: usize,
+}
+
+impl Queues {
+    fn next(&mut self) -> Option<Job> {
+        if self.high_in_row >= HIGH_PRIORITY_BURST || self.high.is_empty() {
+            self.high_in_row = 0;
+            if let Some(job) = self.low.pop_front() {
+                return Some(job);
+            }
+        }
+        let job = self.high.pop_front()?;
+        self.high_in_row += 1;
+        Some(job)
+    }
+}
+
+struct TransformPool {
+    queues: Arc<(Mutex<Queues>, Condvar)>,
+}
+
+impl TransformPool {
+    fn new(threads: usize) -> Option<Self> {
+        if threads == 0 {
+            return None;
+        }
+        info!("Starting {} transform workers.", threads);
+        let queues: Arc<(Mutex<Queues>, Condvar)> = Arc::default();
+        for index in 0..threads {
+            let queues = queues.clone();
+            thread::Builder::new()
+                .name(format!("pixvert-transform-{}", index))
+                .spawn(move || loop {
+                    let job = {
+                        let (lock, available) = &*queues;
+                        let mut waiting = recover(lock.lock());
+                        loop {
+                            match waiting.next() {
+                                Some(job) => break job,
+                                None => waiting = recover(available.wait(waiting)),
+                            }
+                        }
+                    };
+                    if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("Transform job panicked.");
+                    }
+                })
+                .unwrap();
+        }
+        Some(TransformPool { queues })
+    }
+
+    fn submit(&self, priority: Priority, job: Job) {
+        let (lock, available) = &*self.queues;
+        let mut queues = recover(lock.lock());
+        match priority {
+            Priority::High => queues.high.push_back(job),
+            Priority::Low => queues.low.push_back(job),
+        }
+        available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::pools::{HIGH_PRIORITY_BURST, Queues};
+
+    #[test]
+    fn cheap_jobs_go_first_without_starving_expensive_ones() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut queues = Queues::default();
+        for (queue, name) in [(&mut queues.low, "low"), (&mut queues.high, "high")] {
+            for _ in 0..HIGH_PRIORITY_BURST + 1 {
+                let order = order.clone();
+                queue.push_back(Box::new(move || order.lock().unwrap().push(name)));
+            }
+        }
+        while let Some(job) = queues.next() {
+            job();
+        }
+
+        let order = order.lock().unwrap();
+        assert_eq!(order[..HIGH_PRIORITY_BURST], vec!["high"; HIGH_PRIORITY_BURST]);
+        assert_eq!(order[HIGH_PRIORITY_BURST], "low");
+        assert_eq!(order[HIGH_PRIORITY_BURST + 1], "high");
+    }
+}
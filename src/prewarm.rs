@@ -0,0 +1,225 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use log::{error, info, warn};
+use serde::Deserialize;
+
+use crate::{AppState, recover};
+use crate::config::Config;
+use crate::jobs::JobReport;
+use crate::rendition::{prewarm, Rendition};
+
+/// Prewarm jobs which may run at once, each one keeps a thread and fetches from the origin.
+const MAXIMUM_RUNNING_JOBS: usize = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum PrewarmError {
+    /// The sitemap or manifest is not on a host of `allowFrom`.
+    SourceNotAllowed,
+    TooManyJobs,
+}
+
+/// Bulk prewarm of sources listed in an XML sitemap or a JSON manifest (an array of URLs).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PrewarmRequest {
+    pub sitemap: Option<String>,
+    pub manifest: Option<String>,
+    pub renditions: Vec<Rendition>,
+    /// Sources fetched per second, the origin is not asked more often.
+    #[serde(default = "default_requests_per_second")]
+    pub requests_per_second: f64,
+}
+
+fn default_requests_per_second() -> f64 {
+    10.0
+}
+
+impl PrewarmRequest {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.sitemap.is_some() == self.manifest.is_some() {
+            return Err("Either sitemap or manifest is required.");
+        }
+        if self.renditions.is_empty() {
+            return Err("At least one rendition is required.");
+        }
+        if self.interval().is_none() {
+            return Err("requestsPerSecond has to be positive.");
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        Duration::try_from_secs_f64(1.0 / self.requests_per_second).ok()
+    }
+
+    fn source(&self) -> &str {
+        self.sitemap.as_deref().or(self.manifest.as_deref()).unwrap_or_default()
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let text = text.trim();
+    let text = text.strip_prefix("<![CDATA[").and_then(|text| text.strip_suffix("]]>")).unwrap_or(text);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn elements(xml: &str, name: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|element| element.split(close.as_str()).next())
+        .map(unescape)
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Sitemap {
+    /// A sitemap index which lists further sitemaps.
+    Index(Vec<String>),
+    Images(Vec<String>),
+}
+
+/// Image sitemaps list pages in `<loc>` and their images in `<image:loc>`, plain sitemaps
+/// are expected to list images directly.
+fn parse_sitemap(xml: &str) -> Sitemap {
+    if xml.contains("<sitemapindex") {
+        return Sitemap::Index(elements(xml, "loc"));
+    }
+    let images = elements(xml, "image:loc");
+    if images.is_empty() {
+        Sitemap::Images(elements(xml, "loc"))
+    } else {
+        Sitemap::Images(images)
+    }
+}
+
+/// Sitemaps and manifests are only downloaded from the origins sources may be fetched from.
+fn download(url: &str, config: &Config) -> Result<String, String> {
+    if !config.allows_source(url) {
+        return Err(format!("Host of {} is not allowed.", url));
+    }
+    ureq::get(url).call()
+        .map_err(|e| format!("Unable to download {}. Reason: {}", url, e))?
+        .into_string()
+        .map_err(|e| format!("Unable to read {}. Reason: {}", url, e))
+}
+
+fn discover(request: &PrewarmRequest, config: &Config) -> Result<Vec<String>, String> {
+    let urls: Vec<String> = if let Some(manifest) = &request.manifest {
+        serde_json::from_str(&download(manifest, config)?)
+            .map_err(|e| format!("Manifest is not an array of URLs. Reason: {}", e))?
+    } else {
+        match parse_sitemap(&download(request.source(), config)?) {
+            Sitemap::Images(images) => images,
+            Sitemap::Index(sitemaps) => {
+                let mut images = vec![];
+                for sitemap in sitemaps {
+                    match parse_sitemap(&download(&sitemap, config)?) {
+                        Sitemap::Images(listed) => images.extend(listed),
+                        Sitemap::Index(_) => return Err(format!("Sitemap index {} is nested in another index.", sitemap)),
+                    }
+                }
+                images
+            }
+        }
+    };
+    let (allowed, refused): (Vec<String>, Vec<String>) = urls.into_iter().partition(|url| config.allows_source(url));
+    if !refused.is_empty() {
+        warn!("Skipping {} sources of {} which are not on allowed hosts.", refused.len(), request.source());
+    }
+    Ok(allowed)
+}
+
+/// Starts the job on its own thread, progress is reported through `data.jobs`.
+pub fn spawn(data: web::Data<AppState>, request: PrewarmRequest) -> Result<JobReport, PrewarmError> {
+    let config = recover(data.config.lock()).clone();
+    if !config.allows_source(request.source()) {
+        return Err(PrewarmError::SourceNotAllowed);
+    }
+    let job = data.jobs.start_limited("prewarm", request.source(), MAXIMUM_RUNNING_JOBS).ok_or(PrewarmError::TooManyJobs)?;
+    let id = job.id;
+    thread::Builder::new()
+        .name(format!("pixvert-prewarm-{}", id))
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => return data.jobs.finish(id, Some(format!("Unable to start runtime. Reason: {}", e))),
+            };
+            let urls = match discover(&request, &config) {
+                Ok(urls) => urls,
+                Err(e) => {
+                    error!("Prewarm job {} failed. {}", id, e);
+                    return data.jobs.finish(id, Some(e));
+                }
+            };
+            data.jobs.update(id, |job| job.total = urls.len());
+            info!("Prewarm job {} found {} sources in {}.", id, urls.len(), request.source());
+            let interval = request.interval().unwrap_or_default();
+            let mut next = Instant::now();
+            for url in urls {
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+                next = Instant::now() + interval;
+                let failed = runtime.block_on(prewarm(&data, &url, &request.renditions));
+                data.jobs.update(id, |job| {
+                    job.done += 1;
+                    job.failed += failed;
+                });
+            }
+            data.jobs.finish(id, None);
+            info!("Prewarm job {} finished.", id);
+        })
+        .unwrap();
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::Method::GET;
+    use httpmock::MockServer;
+
+    use crate::config::Config;
+    use crate::prewarm::{discover, parse_sitemap, PrewarmRequest, Sitemap};
+
+    #[test]
+    fn read_image_and_index_sitemaps() {
+        let images = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:image="http://www.google.com/schemas/sitemap-image/1.1">
+              <url>
+                <loc>https://example.com/gallery</loc>
+                <image:image><image:loc>https://example.com/a.jpg?size=large&amp;v=2</image:loc></image:image>
+                <image:image><image:loc><![CDATA[https://example.com/b.png]]></image:loc></image:image>
+              </url>
+            </urlset>"#;
+        assert_eq!(parse_sitemap(images), Sitemap::Images(vec![
+            String::from("https://example.com/a.jpg?size=large&v=2"),
+            String::from("https://example.com/b.png"),
+        ]));
+
+        let plain = "<urlset><url><loc> https://example.com/c.jpg </loc></url></urlset>";
+        assert_eq!(parse_sitemap(plain), Sitemap::Images(vec![String::from("https://example.com/c.jpg")]));
+
+        let index = "<sitemapindex><sitemap><loc>https://example.com/images.xml</loc></sitemap></sitemapindex>";
+        assert_eq!(parse_sitemap(index), Sitemap::Index(vec![String::from("https://example.com/images.xml")]));
+    }
+
+    #[test]
+    fn discover_sources_on_allowed_hosts() {
+        let origin = MockServer::start();
+        origin.mock(|when, then| {
+            when.method(GET).path("/manifest.json");
+            then.status(200).body(format!(r#"["{}", "https://example.com/b.png"]"#, origin.url("/a.png")));
+        });
+        let request = PrewarmRequest { sitemap: None, manifest: Some(origin.url("/manifest.json")), renditions: vec![], requests_per_second: 1.0 };
+        let config = Config { allow_from: vec![String::from("127.0.0.1")], ..Config::default() };
+        assert_eq!(discover(&request, &config), Ok(vec![origin.url("/a.png")]));
+
+        let config = Config { allow_from: vec![String::from("example.com")], ..Config::default() };
+        assert!(discover(&request, &config).is_err());
+    }
+}
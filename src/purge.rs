@@ -0,0 +1,87 @@
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, recover};
+use crate::cache::BlockingCache;
+use crate::fetcher::generate_resource_tag;
+
+/// Marks purges forwarded by a peer, they are not forwarded again.
+pub const PROPAGATED: &str = "x-pixvert-propagated";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeRequest {
+    pub url: String,
+    /// Name of the tenant whose cache is purged, the top-level cache when none.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerPurge {
+    pub peer: String,
+    pub purged: Option<bool>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeReport {
+    pub purged: bool,
+    pub peers: Vec<PeerPurge>,
+}
+
+/// Forgets the cached source. Renditions are tagged by the source content, so they are
+/// reused only when the origin still serves the same bytes.
+pub fn purge_local(data: &AppState, url: &str) -> Result<bool, String> {
+    data.cache.remove_blocking(&generate_resource_tag(url)).map_err(|e| e.to_string())
+}
+
+fn purge_peer(peer: &str, url: &str, tenant: Option<&str>, timeout: Duration, token: &str) -> PeerPurge {
+    let mut request = ureq::post(&format!("{}/admin/purge", peer.trim_end_matches('/')))
+        .timeout(timeout)
+        .set(PROPAGATED, "1")
+        .set("content-type", "application/json");
+    if !token.is_empty() {
+        request = request.set("authorization", &format!("Bearer {}", token));
+    }
+    let response = request.send_string(&serde_json::json!({ "url": url, "tenant": tenant }).to_string());
+    let report = response.map_err(|e| e.to_string())
+        .and_then(|response| serde_json::from_reader::<_, PurgeReport>(response.into_reader()).map_err(|e| e.to_string()));
+    match report {
+        Ok(report) => PeerPurge { peer: peer.to_string(), purged: Some(report.purged), error: None },
+        Err(e) => PeerPurge { peer: peer.to_string(), purged: None, error: Some(e) },
+    }
+}
+
+/// Purges the local cache and, unless the purge came from a peer, the caches of all
+/// configured peers in parallel. Blocks until every peer answers or times out.
+/// `data` is the state of the `tenant`, which is passed on to the peers.
+pub fn purge(data: &AppState, url: &str, tenant: Option<&str>, propagate: bool) -> Result<PurgeReport, String> {
+    let purged = purge_local(data, url)?;
+    let (cluster, token) = {
+        let config = recover(data.config.lock());
+        (config.cluster.clone(), config.admin.token.clone())
+    };
+    if !propagate || cluster.peers.is_empty() {
+        return Ok(PurgeReport { purged, peers: vec![] });
+    }
+    let timeout = Duration::from_millis(cluster.timeout_ms);
+    let token = token.as_str();
+    let peers = thread::scope(|scope| {
+        let requests: Vec<_> = cluster.peers.iter()
+            .map(|peer| scope.spawn(move || purge_peer(peer, url, tenant, timeout, token)))
+            .collect();
+        requests.into_iter()
+            .zip(&cluster.peers)
+            .map(|(request, peer)| request.join().unwrap_or_else(|_| PeerPurge {
+                peer: peer.clone(),
+                purged: None,
+                error: Some(String::from("Purge panicked.")),
+            }))
+            .collect()
+    });
+    Ok(PurgeReport { purged, peers })
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use chrono::{NaiveDate, Timelike, Utc};
+use serde::Serialize;
+
+use crate::config::{ApiKeyQuota, QuotaSettings};
+use crate::recover;
+
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct KeyUsage {
+    pub requests: u64,
+    pub transform_seconds: f64,
+    pub bytes_served: u64,
+}
+
+/// Daily limits, `None` is unlimited.
+#[derive(Serialize, Clone)]
+pub struct KeyLimits {
+    pub requests: Option<u64>,
+    pub transform_seconds: Option<f64>,
+    pub bytes_served: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct KeyReport {
+    pub limits: KeyLimits,
+    pub usage: KeyUsage,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QuotaError {
+    MissingKey,
+    UnknownKey,
+    Exceeded(&'static str),
+}
+
+struct DailyUsage {
+    day: NaiveDate,
+    usage: KeyUsage,
+}
+
+/// Daily quotas of API keys. Usage resets at midnight UTC. Transform time and bytes are known only
+/// after the request is served, so the request which crosses a limit completes and the following ones are refused.
+pub struct Quotas {
+    settings: QuotaSettings,
+    usage: Mutex<HashMap<String, DailyUsage>>,
+}
+
+impl Quotas {
+    pub fn new(settings: QuotaSettings) -> Self {
+        Quotas { settings, usage: Mutex::default() }
+    }
+
+    pub fn identify(&self, headers: &HeaderMap) -> Option<String> {
+        headers.get(self.settings.header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    }
+
+    /// Checks quotas of the key and counts the request against them.
+    pub fn admit(&self, key: Option<&str>) -> Result<(), QuotaError> {
+        let key = match key {
+            Some(key) => key,
+            None if self.settings.require_key => return Err(QuotaError::MissingKey),
+            None => return Ok(()),
+        };
+        let limits = match self.limits(key) {
+            Some(limits) => limits,
+            None if self.settings.keys.is_empty() && !self.settings.require_key => return Ok(()),
+            None => return Err(QuotaError::UnknownKey),
+        };
+        self.update(key, |usage| {
+            if limits.requests_per_day.is_some_and(|limit| usage.requests >= limit) {
+                return Err(QuotaError::Exceeded("requests"));
+            }
+            if limits.transform_seconds_per_day.is_some_and(|limit| usage.transform_seconds >= limit) {
+                return Err(QuotaError::Exceeded("transform time"));
+            }
+            if limits.bytes_per_day.is_some_and(|limit| usage.bytes_served >= limit) {
+                return Err(QuotaError::Exceeded("bandwidth"));
+            }
+            usage.requests += 1;
+            Ok(())
+        })
+    }
+
+    /// Whether the key is listed in settings, regardless of its limits.
+    pub fn is_known(&self, key: &str) -> bool {
+        self.limits(key).is_some()
+    }
+
+    pub fn record_transform(&self, key: &str, duration: Duration) {
+        if self.limits(key).is_some() {
+            self.update(key, |usage| usage.transform_seconds += duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_served(&self, key: &str, bytes: u64) {
+        if self.limits(key).is_some() {
+            self.update(key, |usage| usage.bytes_served += bytes);
+        }
+    }
+
+    pub fn report(&self) -> BTreeMap<String, KeyReport> {
+        let today = today();
+        let usage = recover(self.usage.lock());
+        self.settings.keys.iter().map(|limits| {
+            let usage = usage.get(&limits.key)
+                .filter(|daily| daily.day == today)
+                .map(|daily| daily.usage.clone())
+                .unwrap_or_default();
+            let limits_report = KeyLimits {
+                requests: limits.requests_per_day,
+                transform_seconds: limits.transform_seconds_per_day,
+                bytes_served: limits.bytes_per_day,
+            };
+            (limits.key.clone(), KeyReport { limits: limits_report, usage })
+        }).collect()
+    }
+
+    fn limits(&self, key: &str) -> Option<&ApiKeyQuota> {
+        self.settings.keys.iter().find(|limits| limits.key == key)
+    }
+
+    fn update<T>(&self, key: &str, update: impl FnOnce(&mut KeyUsage) -> T) -> T {
+        let today = today();
+        let mut usage = recover(self.usage.lock());
+        let daily = usage.entry(key.to_string()).or_insert_with(|| DailyUsage { day: today, usage: KeyUsage::default() });
+        if daily.day != today {
+            *daily = DailyUsage { day: today, usage: KeyUsage::default() };
+        }
+        update(&mut daily.usage)
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+/// Seconds until quotas reset, sent as `Retry-After`.
+pub fn seconds_until_reset() -> u32 {
+    24 * 60 * 60 - Utc::now().num_seconds_from_midnight()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{ApiKeyQuota, QuotaSettings};
+    use crate::quotas::{QuotaError, Quotas};
+
+    fn quotas() -> Quotas {
+        Quotas::new(QuotaSettings {
+            keys: vec![ApiKeyQuota {
+                key: String::from("tenant"),
+                requests_per_day: Some(2),
+                transform_seconds_per_day: Some(1.0),
+                bytes_per_day: None,
+            }],
+            ..QuotaSettings::default()
+        })
+    }
+
+    #[test]
+    fn refuse_requests_over_quota() {
+        let quotas = quotas();
+        assert_eq!(quotas.admit(Some("tenant")), Ok(()));
+        assert_eq!(quotas.admit(Some("tenant")), Ok(()));
+        assert_eq!(quotas.admit(Some("tenant")), Err(QuotaError::Exceeded("requests")));
+        assert_eq!(quotas.report()["tenant"].usage.requests, 2);
+    }
+
+    #[test]
+    fn refuse_after_transform_time_is_used() {
+        let quotas = quotas();
+        assert_eq!(quotas.admit(Some("tenant")), Ok(()));
+        quotas.record_transform("tenant", Duration::from_millis(1500));
+        assert_eq!(quotas.admit(Some("tenant")), Err(QuotaError::Exceeded("transform time")));
+    }
+
+    #[test]
+    fn identify_only_configured_keys() {
+        let quotas = quotas();
+        assert_eq!(quotas.admit(Some("other")), Err(QuotaError::UnknownKey));
+        assert_eq!(quotas.admit(None), Ok(()));
+        assert_eq!(Quotas::new(QuotaSettings::default()).admit(Some("any")), Ok(()));
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Duration;
+
+use actix_web::web;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, recover};
+use crate::encoder::{EncodedImage, encoded_tag, OutputFormat, ParseError};
+use crate::fetcher::{FetchError, Resource, ResponseData};
+use crate::metrics::PREWARMED;
+use crate::mode::ServiceMode;
+use crate::operations::{FitMode, OperationChain, OperationParseError, OPERATIONS_QUERY_KEY};
+use crate::output_dimensions::OutputDimensions;
+use crate::quotas::QuotaError;
+use crate::routes::index::{origin, run_transform, TransformError};
+use crate::signing::Claims;
+use crate::trace::TraceContext;
+
+/// Rendition of a source described the way URL paths do. Zero width and height keep
+/// the original dimensions, empty format is the default output format, or the format of the source.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Rendition {
+    pub width: usize,
+    pub height: usize,
+    pub keep_ratio: bool,
+    pub format: String,
+    #[serde(alias = "ops")]
+    pub operations: String,
+}
+
+impl Rendition {
+    /// Rendition as requested, before fit and border operations adjust the dimensions.
+    pub fn canonical(dimensions: &OutputDimensions, output_format: &OutputFormat, operations: &OperationChain) -> Self {
+        let (width, height, keep_ratio) = match *dimensions {
+            OutputDimensions::Original => (0, 0, false),
+            OutputDimensions::ScaledExact(width, height) | OutputDimensions::SeamCarved(width, height) => (width, height, false),
+            OutputDimensions::ScaledWithRatio(width, height) => (width, height, true),
+        };
+        Rendition {
+            width,
+            height,
+            keep_ratio,
+            format: output_format.path_segment(),
+            operations: operations.to_string(),
+        }
+    }
+
+    /// Path of the rendition of the source, e.g. `/300_200/keep-ratio/webp80/{url}?ops=maxw:200`.
+    pub fn path(&self, source_url: &str) -> String {
+        let mut path = String::new();
+        if self.width != 0 || self.height != 0 {
+            path.push_str(&format!("/{}_{}", self.width, self.height));
+            if self.keep_ratio {
+                path.push_str("/keep-ratio");
+            }
+        }
+        if !self.format.is_empty() {
+            path.push_str(&format!("/{}", self.format));
+        }
+        path.push_str(&format!("/{}", urlencoding::encode(source_url)));
+        if !self.operations.is_empty() {
+            path.push_str(&format!("?{}={}", OPERATIONS_QUERY_KEY, self.operations));
+        }
+        path
+    }
+
+    pub fn output_dimensions(&self) -> OutputDimensions {
+        match (self.width, self.height, self.keep_ratio) {
+            (0, 0, _) => OutputDimensions::Original,
+            (width, height, true) => OutputDimensions::ScaledWithRatio(width, height),
+            (width, height, false) => OutputDimensions::ScaledExact(width, height),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenditionError {
+    Fetch(FetchError),
+    Format(ParseError),
+    Operations(OperationParseError),
+    Transform(TransformError),
+    Cancelled,
+    /// Origins are not contacted in the current mode.
+    Unavailable(ServiceMode),
+    /// Format left out of `allowedOutputFormats`.
+    NotAllowed(&'static str),
+    SeamCarvingDisabled,
+    Quota(QuotaError),
+    /// Renditions are only served to clients through signed image URLs.
+    SigningRequired,
+}
+
+/// Who a rendition is made for. Clients go through the same gates as on the image routes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Requester {
+    /// The instance itself, e.g. watch, prewarm and verify.
+    Instance,
+    /// A client with the API key it sent, its transforms are counted against the key.
+    Client(Option<String>),
+}
+
+impl Requester {
+    /// Lets the client through when URLs need no signature and its key has quota left, and counts the request.
+    /// Requests other than image URLs carry no signature, so they are refused while a signing key is set.
+    pub fn admit(&self, data: &AppState) -> Result<(), RenditionError> {
+        let api_key = match self {
+            Requester::Instance => return Ok(()),
+            Requester::Client(api_key) => api_key.as_deref(),
+        };
+        if !recover(data.config.lock()).signing.key.is_empty() {
+            return Err(RenditionError::SigningRequired);
+        }
+        data.quotas.admit(api_key).map_err(RenditionError::Quota)
+    }
+
+    fn record_transform(&self, data: &AppState, duration: Duration) {
+        if let Requester::Client(Some(api_key)) = self {
+            data.quotas.record_transform(api_key, duration);
+        }
+    }
+}
+
+pub async fn fetch(data: &web::Data<AppState>, url: &str) -> Result<Resource, RenditionError> {
+    match data.mode.get() {
+        ServiceMode::Normal => {}
+        mode => return Err(RenditionError::Unavailable(mode)),
+    }
+    let data = data.clone();
+    let url = url.to_string();
+    match web::block(move || recover(data.fetcher.lock()).fetch(&url, &TraceContext::default())).await {
+        Ok(result) => result.map_err(RenditionError::Fetch),
+        Err(e) => Err(RenditionError::Fetch(FetchError::Unknown(format!("{:?}", e)))),
+    }
+}
+
+/// Runs the source through the same pipeline as the image routes, so the result is cached
+/// under the same tags and served from the cache to the matching URL.
+pub async fn render(data: &web::Data<AppState>, url: &str, rendition: &Rendition, requester: &Requester) -> Result<EncodedImage, RenditionError> {
+    requester.admit(data)?;
+    let resource = fetch(data, url).await?;
+    let (encoded_image, duration) = transform(data, resource, rendition, requester).await?;
+    data.costs.record_transform(&origin(url), &encoded_image.content_type, duration);
+    Ok(encoded_image)
+}
+
+/// Transforms a source which is already at hand, for a requester the caller has admitted.
+/// Returns how long the transform took.
+pub async fn transform(data: &web::Data<AppState>, resource: Resource, rendition: &Rendition, requester: &Requester) -> Result<(EncodedImage, Duration), RenditionError> {
+    let (output_dimensions, operations, output_format) = resolve(data, rendition, &resource.response_data.content_type)?;
+    match run_transform(data, resource, None, output_dimensions, operations, output_format, Claims::default()).await {
+        Some((Ok(encoded_image), duration)) => {
+            requester.record_transform(data, duration);
+            Ok((encoded_image, duration))
+        }
+        Some((Err(e), _)) => Err(RenditionError::Transform(e)),
+        None => Err(RenditionError::Cancelled),
+    }
+}
+
+/// Keys the image routes may cache the rendition of the source under once encoded. Besides the one of the
+/// rendition as requested, Save-Data requests and signed links capping the quality get lower qualities.
+pub fn cache_keys(data: &AppState, rendition: &Rendition, source: &ResponseData) -> Result<Vec<String>, RenditionError> {
+    let (output_dimensions, operations, output_format) = resolve(data, rendition, &source.content_type)?;
+    let (save_data, signed, keep_color_profile) = {
+        let config = recover(data.config.lock());
+        (config.save_data.clone(), !config.signing.key.is_empty(), config.metadata.keep_color_profile)
+    };
+    let mut output_formats = vec![output_format.clone()];
+    if save_data.enabled {
+        output_formats.push(output_format.save_data(save_data.quality_delta));
+    }
+    if signed {
+        let capped: Vec<OutputFormat> = output_formats.iter()
+            .flat_map(|output_format| (1..=100).map(move |cap| output_format.clone().with_quality_cap(cap)))
+            .collect();
+        output_formats.extend(capped);
+    }
+    let tag = operations.tag(&source.id);
+    let mut keys: Vec<String> = vec![];
+    for output_format in output_formats {
+        let key = encoded_tag(&tag, &output_dimensions, &output_format, keep_color_profile);
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Dimensions, operations and format the rendition of a source of the content type is encoded with.
+fn resolve(data: &AppState, rendition: &Rendition, source_content_type: &str) -> Result<(OutputDimensions, OperationChain, OutputFormat), RenditionError> {
+    let default_output_format = recover(data.config.lock()).default_output_format.clone();
+    let requested_format = match (rendition.format.as_str(), &default_output_format) {
+        ("", Some(format)) => format.as_str(),
+        ("", None) => source_content_type,
+        (format, _) => format,
+    };
+    let quality_policy = recover(data.config.lock()).quality_policy.clone();
+    let (output_format, _) = OutputFormat::parse_with_policy(requested_format, &quality_policy)
+        .map_err(RenditionError::Format)?;
+    if !recover(data.config.lock()).allows_output_format(output_format.name()) {
+        return Err(RenditionError::NotAllowed(output_format.name()));
+    }
+    let output_format = output_format.with_defaults(&recover(data.config.lock()).encoding);
+    let operations: OperationChain = rendition.operations.parse().map_err(RenditionError::Operations)?;
+    operations.check_limits(&recover(data.config.lock()).operation_limits).map_err(RenditionError::Operations)?;
+    let mut output_dimensions = rendition.output_dimensions();
+    if let Some(fit) = operations.fit_mode() {
+        if *fit == FitMode::Seam && !recover(data.config.lock()).experimental.seam_carving {
+            return Err(RenditionError::SeamCarvingDisabled);
+        }
+        output_dimensions = output_dimensions.with_fit(fit);
+    }
+    let output_dimensions = output_dimensions.inset(operations.border_inset());
+    Ok((output_dimensions, operations, output_format))
+}
+
+/// Renders every rendition of the source ahead of the first request for it.
+/// Returns the number of renditions which failed.
+pub async fn prewarm(data: &web::Data<AppState>, url: &str, renditions: &[Rendition]) -> usize {
+    let mut failed = 0;
+    for rendition in renditions {
+        match render(data, url, rendition, &Requester::Instance).await {
+            Ok(_) => data.metrics.increment(PREWARMED, &[("result", "ok")]),
+            Err(e) => {
+                failed += 1;
+                data.metrics.increment(PREWARMED, &[("result", "error")]);
+                warn!("Unable to pre-generate {:?} of {}. Reason: {:?}", rendition, url, e);
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ApiKeyQuota, Config};
+    use crate::encoder::{JpegOptions, OutputFormat, PngCompression};
+    use crate::operations::OperationChain;
+    use crate::output_dimensions::OutputDimensions;
+    use crate::quotas::QuotaError;
+    use crate::rendition::{Rendition, RenditionError, Requester, resolve};
+    use crate::test_state;
+
+    #[test]
+    fn canonical_path() {
+        let operations: OperationChain = "autocontrast,maxw:200".parse().unwrap();
+        let rendition = Rendition::canonical(&OutputDimensions::ScaledWithRatio(300, 200), &OutputFormat::Jpeg(80, JpegOptions::default()), &operations);
+        assert_eq!(
+            rendition.path("https://example.com/a.jpg?v=2"),
+            "/300_200/keep-ratio/jpeg80/https%3A%2F%2Fexample.com%2Fa.jpg%3Fv%3D2?ops=autocontrast:luma,maxw:200"
+        );
+        let rendition = Rendition::canonical(&OutputDimensions::Original, &OutputFormat::Png(PngCompression::Default), &OperationChain::default());
+        assert_eq!(rendition.path("https://example.com/a.png"), "/png/https%3A%2F%2Fexample.com%2Fa.png");
+    }
+
+    #[test]
+    fn hold_clients_to_the_gates_of_the_image_routes() {
+        let mut config = Config::default();
+        config.quotas.require_key = true;
+        config.quotas.keys = vec![ApiKeyQuota { key: String::from("a"), requests_per_day: Some(1), transform_seconds_per_day: None, bytes_per_day: None }];
+        let data = test_state(config);
+        assert!(Requester::Instance.admit(&data).is_ok());
+        assert!(matches!(Requester::Client(None).admit(&data), Err(RenditionError::Quota(QuotaError::MissingKey))));
+        assert!(Requester::Client(Some(String::from("a"))).admit(&data).is_ok());
+        assert!(matches!(Requester::Client(Some(String::from("a"))).admit(&data), Err(RenditionError::Quota(QuotaError::Exceeded(_)))));
+
+        data.config.lock().unwrap().signing.key = String::from("secret");
+        assert!(matches!(Requester::Client(Some(String::from("a"))).admit(&data), Err(RenditionError::SigningRequired)));
+        assert!(Requester::Instance.admit(&data).is_ok());
+
+        let seam = Rendition { width: 100, height: 100, format: String::from("png"), operations: String::from("fit:seam"), ..Default::default() };
+        assert!(matches!(resolve(&data, &seam, "image/png"), Err(RenditionError::SeamCarvingDisabled)));
+        data.config.lock().unwrap().experimental.seam_carving = true;
+        assert!(resolve(&data, &seam, "image/png").is_ok());
+    }
+}
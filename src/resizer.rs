@@ -1,24 +1,42 @@
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::Instant;
 
-use image_crate::DynamicImage;
+use image_crate::{DynamicImage, GenericImageView};
 use image_crate::imageops::FilterType;
+use log::warn;
 
-use crate::cache::CacheEngine;
-use crate::config::Config;
+use crate::cache::SharedCache;
+use crate::cache::typed::Cache;
+use crate::config::{Config, CpuExtension, ResizerBackend, ResizerSettings};
 use crate::fetcher::generate_resource_tag;
 use crate::image::Image;
+use crate::metrics::{Metrics, observe_stage, record_cache_lookup};
 use crate::resizer::ResizeError::ResizeExceedsMaximumSize;
+#[cfg(feature = "libvips")]
+use crate::vips;
+
+#[cfg(feature = "wgpu")]
+mod gpu;
+mod seam_carving;
+#[cfg(feature = "simd")]
+mod simd;
 
 pub trait Resizer {
     fn resize(
         &self,
-        tag: &String,
+        tag: &str,
         resource: DynamicImage,
         dimensions: (usize, usize),
     ) -> Result<DynamicImage, ResizeError>;
     fn resize_exact(
         &self,
-        tag: &String,
+        tag: &str,
+        resource: DynamicImage,
+        dimensions: (usize, usize),
+    ) -> Result<DynamicImage, ResizeError>;
+    fn resize_seam_carved(
+        &self,
+        tag: &str,
         resource: DynamicImage,
         dimensions: (usize, usize),
     ) -> Result<DynamicImage, ResizeError>;
@@ -31,65 +49,170 @@ pub enum ResizeError {
 
 
 pub struct CachedResizer {
-    pub cache: Arc<RwLock<Box<dyn CacheEngine + Send + Sync>>>,
+    pub cache: SharedCache,
     pub config: Config,
+    pub metrics: Arc<dyn Metrics + Send + Sync>,
 }
 
-pub(self) fn resize(
+fn resize(
     resource: DynamicImage,
     dimensions: (usize, usize),
     maximum_size: usize,
-    exact: bool
+    exact: bool,
+    settings: &ResizerSettings,
 ) -> Result<DynamicImage, ResizeError> {
     let maximum_dimensions = dimensions.0 * dimensions.1;
     if maximum_dimensions > maximum_size {
         return Result::Err(ResizeExceedsMaximumSize(maximum_size, maximum_dimensions));
     }
-    let mut image = resource;
-    if exact {
-        image = image.resize_exact(dimensions.0 as u32, dimensions.1 as u32, FilterType::Lanczos3);
+    let (width, height) = if exact {
+        (dimensions.0 as u32, dimensions.1 as u32)
     } else {
-        image = image.resize(dimensions.0 as u32, dimensions.1 as u32, FilterType::Lanczos3);
+        fit_dimensions(resource.dimensions(), dimensions)
+    };
+    Result::Ok(resize_lanczos(resource, width, height, settings))
+}
+
+/// Biggest dimensions keeping the image ratio that fit in the box, same as `DynamicImage::resize` picks.
+fn fit_dimensions((width, height): (u32, u32), (box_width, box_height): (usize, usize)) -> (u32, u32) {
+    let ratio = f64::min(box_width as f64 / width as f64, box_height as f64 / height as f64);
+    (
+        u32::max((width as f64 * ratio).round() as u32, 1),
+        u32::max((height as f64 * ratio).round() as u32, 1),
+    )
+}
+
+/// Resizes with the configured backend, image resizes whatever the backend is unable to.
+fn resize_lanczos(image: DynamicImage, width: u32, height: u32, settings: &ResizerSettings) -> DynamicImage {
+    let resized = match settings.backend {
+        ResizerBackend::Image => None,
+        #[cfg(feature = "simd")]
+        ResizerBackend::FastImageResize => simd::resize_exact(&image, width, height, simd::cpu_extensions(&settings.cpu_extension)),
+        #[cfg(not(feature = "simd"))]
+        ResizerBackend::FastImageResize => None,
+        #[cfg(feature = "libvips")]
+        ResizerBackend::Libvips => vips::resize_exact(&image, width, height),
+        #[cfg(not(feature = "libvips"))]
+        ResizerBackend::Libvips => None,
+        #[cfg(feature = "wgpu")]
+        ResizerBackend::Wgpu => gpu::resize_exact(&image, width, height),
+        #[cfg(not(feature = "wgpu"))]
+        ResizerBackend::Wgpu => None,
+    };
+    resized.unwrap_or_else(|| image.resize_exact(width, height, FilterType::Lanczos3))
+}
+
+/// Describes the resize implementation used on this machine, so a deployment running without SIMD,
+/// or without the backend picked in the config, is visible on boot.
+pub fn describe_backend(settings: &ResizerSettings) -> String {
+    if settings.cpu_extension != CpuExtension::Auto && (settings.backend != ResizerBackend::FastImageResize || cfg!(not(feature = "simd"))) {
+        warn!("Resizer CPU extension {:?} is ignored, it only applies to fast_image_resize built with the simd feature.", settings.cpu_extension);
+    }
+    match settings.backend {
+        ResizerBackend::Image => String::from("image"),
+        #[cfg(feature = "simd")]
+        ResizerBackend::FastImageResize => simd::describe(&settings.cpu_extension),
+        #[cfg(not(feature = "simd"))]
+        ResizerBackend::FastImageResize => {
+            warn!("fast_image_resize is selected in the config, but this build does not include the simd feature. Images are resized with image.");
+            String::from("image (no SIMD)")
+        }
+        #[cfg(feature = "libvips")]
+        ResizerBackend::Libvips => vips::describe(),
+        #[cfg(not(feature = "libvips"))]
+        ResizerBackend::Libvips => {
+            warn!("libvips is selected in the config, but this build does not include the libvips feature. Images are resized with image.");
+            String::from("image")
+        }
+        #[cfg(feature = "wgpu")]
+        ResizerBackend::Wgpu => gpu::describe(),
+        #[cfg(not(feature = "wgpu"))]
+        ResizerBackend::Wgpu => {
+            warn!("wgpu is selected in the config, but this build does not include the wgpu feature. Images are resized with image.");
+            String::from("image")
+        }
+    }
+}
+
+fn resize_seam_carved(
+    resource: DynamicImage,
+    dimensions: (usize, usize),
+    maximum_size: usize,
+) -> Result<DynamicImage, ResizeError> {
+    let maximum_dimensions = dimensions.0 * dimensions.1;
+    if maximum_dimensions > maximum_size {
+        return Result::Err(ResizeExceedsMaximumSize(maximum_size, maximum_dimensions));
     }
-    return Result::Ok(image);
+    let (width, height) = (dimensions.0 as u32, dimensions.1 as u32);
+    let image = seam_carving::scale_to_cover(resource, width, height);
+    Result::Ok(seam_carving::seam_carve(image, width, height))
 }
 
 impl Resizer for CachedResizer {
-    fn resize(&self, tag: &String, resource: DynamicImage, dimensions: (usize, usize)) -> Result<DynamicImage, ResizeError> {
-        let cached_image: Option<Vec<u8>>;
+    fn resize(&self, tag: &str, resource: DynamicImage, dimensions: (usize, usize)) -> Result<DynamicImage, ResizeError> {
         let tag = generate_resource_tag(&format!("{} - {}x{}", tag, dimensions.0, dimensions.1));
-        {
-            cached_image = self.cache.read().unwrap().get(tag.as_str());
-        }
+        let cache = Cache::<Image>::new(&self.cache);
+        let cached_image = cache.get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "resize", cached_image.is_some());
         if let Some(cached_image) = cached_image {
-            let image: Image = bincode::deserialize(cached_image.as_slice()).unwrap();
-            return Ok(image.into());
-        }
-        let image = resize(resource, dimensions, self.config.maximum_image_size, false)?;
-        let binary_image = bincode::serialize::<Image>(&image.clone().into()).unwrap();
-        {
-            self.cache.write().unwrap().set(tag.as_str(), &binary_image).unwrap();
+            return Ok(cached_image.into());
         }
+        let started = Instant::now();
+        let image = resize(resource, dimensions, self.config.maximum_image_size, false, &self.config.resizer)?;
+        observe_stage(self.metrics.as_ref(), "resize", started);
+        cache.set_blocking(&tag, &image.clone().into());
         Ok(image)
     }
 
-    fn resize_exact(&self, tag: &String, resource: DynamicImage, dimensions: (usize, usize)) -> Result<DynamicImage, ResizeError> {
-        let cached_image: Option<Vec<u8>>;
+    fn resize_exact(&self, tag: &str, resource: DynamicImage, dimensions: (usize, usize)) -> Result<DynamicImage, ResizeError> {
         let tag = generate_resource_tag(&format!("{} - {}x{} exact", tag, dimensions.0, dimensions.1));
-        {
-            cached_image = self.cache.read().unwrap().get(tag.as_str());
-        }
+        let cache = Cache::<Image>::new(&self.cache);
+        let cached_image = cache.get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "resize", cached_image.is_some());
         if let Some(cached_image) = cached_image {
-            let image: Image = bincode::deserialize(cached_image.as_slice()).unwrap();
-            return Ok(image.into());
+            return Ok(cached_image.into());
         }
 
-        let image = resize(resource, dimensions, self.config.maximum_image_size, true)?;
-        let binary_image = bincode::serialize::<Image>(&image.clone().into()).unwrap();
-        {
-            self.cache.write().unwrap().set(tag.as_str(), &binary_image).unwrap();
+        let started = Instant::now();
+        let image = resize(resource, dimensions, self.config.maximum_image_size, true, &self.config.resizer)?;
+        observe_stage(self.metrics.as_ref(), "resize", started);
+        cache.set_blocking(&tag, &image.clone().into());
+        Ok(image)
+    }
+
+    fn resize_seam_carved(&self, tag: &str, resource: DynamicImage, dimensions: (usize, usize)) -> Result<DynamicImage, ResizeError> {
+        let tag = generate_resource_tag(&format!("{} - {}x{} seam", tag, dimensions.0, dimensions.1));
+        let cache = Cache::<Image>::new(&self.cache);
+        let cached_image = cache.get_blocking(&tag);
+        record_cache_lookup(self.metrics.as_ref(), "resize", cached_image.is_some());
+        if let Some(cached_image) = cached_image {
+            return Ok(cached_image.into());
         }
+
+        let started = Instant::now();
+        let image = resize_seam_carved(resource, dimensions, self.config.maximum_image_size)?;
+        observe_stage(self.metrics.as_ref(), "resize", started);
+        cache.set_blocking(&tag, &image.clone().into());
         Ok(image)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use image_crate::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    use crate::config::{ResizerBackend, ResizerSettings};
+    use crate::resizer::resize_lanczos;
+
+    #[test]
+    fn resize_with_every_backend() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 30, Rgba([200, 30, 30, 255])));
+        for backend in [ResizerBackend::Image, ResizerBackend::FastImageResize, ResizerBackend::Libvips, ResizerBackend::Wgpu] {
+            let settings = ResizerSettings { backend, ..ResizerSettings::default() };
+            let resized = resize_lanczos(image.clone(), 13, 7, &settings);
+            assert_eq!(resized.dimensions(), (13, 7), "{:?}", backend);
+            assert_eq!(resized.to_rgba8().get_pixel(6, 3), &Rgba([200, 30, 30, 255]), "{:?}", backend);
+        }
+        assert_eq!(serde_yaml::from_str::<ResizerSettings>("backend: fast_image_resize").unwrap().backend, ResizerBackend::FastImageResize);
+    }
+}
@@ -0,0 +1,236 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::sync::mpsc::channel;
+
+use futures_executor::block_on;
+use image_crate::{DynamicImage, RgbaImage};
+use log::{info, warn};
+use wgpu::util::DeviceExt;
+
+/// Lanczos3 in two passes, the same filter the CPU backends use: rows are resized into floats,
+/// then columns into RGBA8. Like image, colors are not premultiplied by alpha.
+const SHADER: &str = r#"
+struct Dimensions {
+    source_width: u32,
+    source_height: u32,
+    target_width: u32,
+    target_height: u32,
+}
+
+@group(0) @binding(0) var<uniform> dimensions: Dimensions;
+@group(0) @binding(1) var<storage, read> source: array<u32>;
+@group(0) @binding(2) var<storage, read_write> rows: array<vec4<f32>>;
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;
+
+const PI: f32 = 3.14159265;
+
+fn lanczos3(x: f32) -> f32 {
+    if abs(x) < 0.00001 {
+        return 1.0;
+    }
+    if abs(x) >= 3.0 {
+        return 0.0;
+    }
+    let px = PI * x;
+    return 3.0 * sin(px) * sin(px / 3.0) / (px * px);
+}
+
+@compute @workgroup_size(8, 8)
+fn resize_rows(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= dimensions.target_width || id.y >= dimensions.source_height {
+        return;
+    }
+    let ratio = f32(dimensions.source_width) / f32(dimensions.target_width);
+    let scale = max(ratio, 1.0);
+    let center = (f32(id.x) + 0.5) * ratio;
+    let first = max(i32(floor(center - 3.0 * scale)), 0);
+    let last = min(i32(ceil(center + 3.0 * scale)), i32(dimensions.source_width));
+    var sum = vec4<f32>(0.0);
+    var weights = 0.0;
+    for (var x = first; x < last; x++) {
+        let weight = lanczos3((f32(x) + 0.5 - center) / scale);
+        sum += unpack4x8unorm(source[u32(x) + id.y * dimensions.source_width]) * weight;
+        weights += weight;
+    }
+    rows[id.x + id.y * dimensions.target_width] = sum / max(weights, 0.00001);
+}
+
+@compute @workgroup_size(8, 8)
+fn resize_columns(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= dimensions.target_width || id.y >= dimensions.target_height {
+        return;
+    }
+    let ratio = f32(dimensions.source_height) / f32(dimensions.target_height);
+    let scale = max(ratio, 1.0);
+    let center = (f32(id.y) + 0.5) * ratio;
+    let first = max(i32(floor(center - 3.0 * scale)), 0);
+    let last = min(i32(ceil(center + 3.0 * scale)), i32(dimensions.source_height));
+    var sum = vec4<f32>(0.0);
+    var weights = 0.0;
+    for (var y = first; y < last; y++) {
+        let weight = lanczos3((f32(y) + 0.5 - center) / scale);
+        sum += rows[id.x + u32(y) * dimensions.target_width] * weight;
+        weights += weight;
+    }
+    output[id.x + id.y * dimensions.target_width] = pack4x8unorm(clamp(sum / max(weights, 0.00001), vec4<f32>(0.0), vec4<f32>(1.0)));
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 8;
+
+struct Gpu {
+    name: String,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    resize_rows: wgpu::ComputePipeline,
+    resize_columns: wgpu::ComputePipeline,
+}
+
+static GPU: OnceLock<Option<Gpu>> = OnceLock::new();
+
+/// Adapter picked once per process, none on machines without a usable GPU.
+fn gpu() -> Option<&'static Gpu> {
+    GPU.get_or_init(|| {
+        let gpu = block_on(request_gpu());
+        if gpu.is_none() {
+            warn!("No GPU adapter is available, images are resized on the CPU.");
+        }
+        gpu
+    }).as_ref()
+}
+
+async fn request_gpu() -> Option<Gpu> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        force_fallback_adapter: false,
+        compatible_surface: None,
+    }).await?;
+    let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+        label: Some("pixvert"),
+        required_features: wgpu::Features::empty(),
+        required_limits: adapter.limits(),
+    }, None).await.map_err(|e| warn!("Unable to open the GPU. Reason: {}", e)).ok()?;
+    // Without a scope, wgpu panics on invalid shaders instead of letting the CPU take over.
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("lanczos3"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER)),
+    });
+    let pipeline = |entry_point| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some(entry_point),
+        layout: None,
+        module: &module,
+        entry_point,
+    });
+    let (resize_rows, resize_columns) = (pipeline("resize_rows"), pipeline("resize_columns"));
+    if let Some(e) = device.pop_error_scope().await {
+        warn!("Unable to compile the resizing shader. Reason: {}", e);
+        return None;
+    }
+    let info = adapter.get_info();
+    info!("Resizing on {} ({:?}).", info.name, info.backend);
+    Some(Gpu { name: info.name, device, queue, resize_rows, resize_columns })
+}
+
+pub fn describe() -> String {
+    match gpu() {
+        Some(gpu) => format!("wgpu ({})", gpu.name),
+        None => String::from("image (no GPU)"),
+    }
+}
+
+/// Resized image of the same color type, none without a GPU or when the image exceeds its limits,
+/// so the caller can fall back to the CPU.
+pub fn resize_exact(image: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    let gpu = gpu()?;
+    let source = image.to_rgba8();
+    let pixels = resize_buffer(gpu, source.as_raw(), source.dimensions(), (width, height))?;
+    let resized = RgbaImage::from_raw(width, height, pixels)?;
+    Some(match image {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(DynamicImage::ImageRgba8(resized).to_luma8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(DynamicImage::ImageRgba8(resized).to_luma_alpha8()),
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(resized).to_rgb8()),
+        _ => DynamicImage::ImageRgba8(resized),
+    })
+}
+
+fn resize_buffer(gpu: &Gpu, source: &[u8], (source_width, source_height): (u32, u32), (width, height): (u32, u32)) -> Option<Vec<u8>> {
+    let limits = gpu.device.limits();
+    let rows_size = width as u64 * source_height as u64 * 16;
+    let target_size = width as u64 * height as u64 * 4;
+    let largest = rows_size.max(source.len() as u64).max(target_size);
+    let workgroups = |length: u32| length.div_ceil(WORKGROUP_SIZE);
+    if width == 0 || height == 0 || source.is_empty()
+        || largest > limits.max_storage_buffer_binding_size as u64
+        || largest > limits.max_buffer_size
+        || workgroups(width.max(height).max(source_height)) > limits.max_compute_workgroups_per_dimension {
+        return None;
+    }
+
+    let dimensions: Vec<u8> = [source_width, source_height, width, height].iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    let dimensions = gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("dimensions"),
+        contents: &dimensions,
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+    let source = gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("source"),
+        contents: source,
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let storage = |label, size| gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let (rows, output) = (storage("rows", rows_size), storage("output", target_size));
+    let readback = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback"),
+        size: target_size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    // Pipelines get their layouts from the shader, each only binds what its entry point uses.
+    let bind_group = |pipeline: &wgpu::ComputePipeline, entries: &[(u32, &wgpu::Buffer)]| {
+        let entries: Vec<wgpu::BindGroupEntry> = entries.iter()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry { binding: *binding, resource: buffer.as_entire_binding() })
+            .collect();
+        gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        })
+    };
+    let rows_group = bind_group(&gpu.resize_rows, &[(0, &dimensions), (1, &source), (2, &rows)]);
+    let columns_group = bind_group(&gpu.resize_columns, &[(0, &dimensions), (2, &rows), (3, &output)]);
+
+    let mut encoder = gpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    for (pipeline, group, workgroups_y) in [
+        (&gpu.resize_rows, &rows_group, workgroups(source_height)),
+        (&gpu.resize_columns, &columns_group, workgroups(height)),
+    ] {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, group, &[]);
+        pass.dispatch_workgroups(workgroups(width), workgroups_y, 1);
+    }
+    encoder.copy_buffer_to_buffer(&output, 0, &readback, 0, target_size);
+    gpu.queue.submit(Some(encoder.finish()));
+
+    let slice = readback.slice(..);
+    let (sender, receiver) = channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| { let _ = sender.send(result); });
+    gpu.device.poll(wgpu::Maintain::Wait);
+    if let Err(e) = receiver.recv().ok()? {
+        warn!("Unable to read the image resized on the GPU. Reason: {}", e);
+        return None;
+    }
+    let pixels = slice.get_mapped_range().to_vec();
+    readback.unmap();
+    Some(pixels)
+}
@@ -0,0 +1,131 @@
+use std::cmp::Ordering;
+
+use image_crate::{DynamicImage, GenericImageView, RgbaImage};
+use image_crate::imageops::{FilterType, rotate270, rotate90};
+
+/// Shrinks the image to the given dimensions by repeatedly removing the lowest energy seam.
+/// Image must be at least as big as the requested dimensions.
+pub fn seam_carve(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    if image.width() > width {
+        let seams = image.width() - width;
+        image = remove_vertical_seams(image, seams);
+    }
+    if image.height() > height {
+        let seams = image.height() - height;
+        image = rotate270(&remove_vertical_seams(rotate90(&image), seams));
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Scales the image keeping the ratio so it covers the box, leaving the excess for seam removal.
+pub fn scale_to_cover(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let scale = f64::max(
+        width as f64 / image.width() as f64,
+        height as f64 / image.height() as f64,
+    );
+    let cover_width = u32::max((image.width() as f64 * scale).round() as u32, width);
+    let cover_height = u32::max((image.height() as f64 * scale).round() as u32, height);
+    if (cover_width, cover_height) == image.dimensions() {
+        return image;
+    }
+    image.resize_exact(cover_width, cover_height, FilterType::Lanczos3)
+}
+
+fn remove_vertical_seams(mut image: RgbaImage, count: u32) -> RgbaImage {
+    for _ in 0..count {
+        match find_vertical_seam(&image) {
+            Some(seam) => image = remove_vertical_seam(&image, &seam),
+            None => break,
+        }
+    }
+    image
+}
+
+fn luminance(image: &RgbaImage) -> Vec<f32> {
+    image
+        .pixels()
+        .map(|p| 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32)
+        .collect()
+}
+
+/// `None` when the image is a single column or has no rows, so there is nothing to carve.
+fn find_vertical_seam(image: &RgbaImage) -> Option<Vec<usize>> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width <= 1 || height == 0 {
+        return None;
+    }
+    let luma = luminance(image);
+    let at = |x: usize, y: usize| luma[y * width + x];
+
+    let mut cost = vec![0f32; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let dx = at(usize::min(x + 1, width - 1), y) - at(x.saturating_sub(1), y);
+            let dy = at(x, usize::min(y + 1, height - 1)) - at(x, y.saturating_sub(1));
+            let energy = dx.abs() + dy.abs();
+            let previous = if y == 0 {
+                0.0
+            } else {
+                let row = (y - 1) * width;
+                (x.saturating_sub(1)..=usize::min(x + 1, width - 1))
+                    .map(|px| cost[row + px])
+                    .fold(f32::MAX, f32::min)
+            };
+            cost[y * width + x] = energy + previous;
+        }
+    }
+
+    let mut seam = vec![0usize; height];
+    let last_row = (height - 1) * width;
+    seam[height - 1] = (0..width)
+        .min_by(|a, b| cost[last_row + a].partial_cmp(&cost[last_row + b]).unwrap_or(Ordering::Equal))
+        .unwrap_or_default();
+    for y in (0..height - 1).rev() {
+        let x = seam[y + 1];
+        let row = y * width;
+        seam[y] = (x.saturating_sub(1)..=usize::min(x + 1, width - 1))
+            .min_by(|a, b| cost[row + a].partial_cmp(&cost[row + b]).unwrap_or(Ordering::Equal))
+            .unwrap_or(x);
+    }
+    Some(seam)
+}
+
+fn remove_vertical_seam(image: &RgbaImage, seam: &[usize]) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut carved = RgbaImage::new(width - 1, height);
+    for (y, seam_x) in seam.iter().enumerate() {
+        let mut target_x = 0;
+        for x in 0..width {
+            if x as usize == *seam_x {
+                continue;
+            }
+            carved.put_pixel(target_x, y as u32, *image.get_pixel(x, y as u32));
+            target_x += 1;
+        }
+    }
+    carved
+}
+
+#[cfg(test)]
+mod tests {
+    use image_crate::{DynamicImage, GenericImageView};
+
+    use crate::resizer::seam_carving::{scale_to_cover, seam_carve};
+
+    #[test]
+    fn seam_carve_to_exact_dimensions() {
+        let image = scale_to_cover(DynamicImage::new_rgb8(40, 30), 20, 20);
+        assert_eq!(image.dimensions(), (27, 20));
+
+        let carved = seam_carve(image, 20, 20);
+        assert_eq!(carved.dimensions(), (20, 20));
+    }
+
+    #[test]
+    fn seam_carve_single_pixel_rows_and_columns() {
+        assert_eq!(seam_carve(DynamicImage::new_rgb8(1, 30), 1, 20).dimensions(), (1, 20));
+        assert_eq!(seam_carve(DynamicImage::new_rgb8(30, 1), 20, 1).dimensions(), (20, 1));
+        assert_eq!(seam_carve(DynamicImage::new_rgb8(1, 1), 0, 0).dimensions(), (1, 1));
+    }
+}
@@ -0,0 +1,118 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fr;
+use image_crate::{DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+use log::warn;
+
+use crate::config::CpuExtension;
+
+fn requested_extensions(requested: &CpuExtension) -> Option<fr::CpuExtensions> {
+    match requested {
+        CpuExtension::None => Some(fr::CpuExtensions::None),
+        #[cfg(target_arch = "x86_64")]
+        CpuExtension::Sse4_1 => Some(fr::CpuExtensions::Sse4_1),
+        #[cfg(target_arch = "x86_64")]
+        CpuExtension::Avx2 => Some(fr::CpuExtensions::Avx2),
+        #[cfg(target_arch = "aarch64")]
+        CpuExtension::Neon => Some(fr::CpuExtensions::Neon),
+        #[cfg(target_arch = "wasm32")]
+        CpuExtension::Simd128 => Some(fr::CpuExtensions::Simd128),
+        _ => None,
+    }
+}
+
+/// Picks SIMD extension used for resizing. Requested extension is used only when the CPU supports it,
+/// otherwise the best one detected at runtime is used instead.
+pub fn cpu_extensions(requested: &CpuExtension) -> fr::CpuExtensions {
+    requested_extensions(requested)
+        .filter(fr::CpuExtensions::is_supported)
+        .unwrap_or_default()
+}
+
+/// Describes the resize backend picked for this machine and warns when it runs without SIMD.
+pub fn describe(requested: &CpuExtension) -> String {
+    let extensions = cpu_extensions(requested);
+    if *requested != CpuExtension::Auto && requested_extensions(requested) != Some(extensions) {
+        warn!("CPU extension {:?} is not available on this machine, using {:?} instead.", requested, extensions);
+    }
+    if extensions == fr::CpuExtensions::None && *requested != CpuExtension::None {
+        warn!("No SIMD extension detected, resizing will be slow.");
+    }
+    format!("fast_image_resize ({:?})", extensions)
+}
+
+pub fn resize_exact(image: &DynamicImage, width: u32, height: u32, extensions: fr::CpuExtensions) -> Option<DynamicImage> {
+    let (width, height) = (NonZeroU32::new(width)?, NonZeroU32::new(height)?);
+    match image {
+        DynamicImage::ImageLuma8(image) => {
+            let buffer = resize_buffer(image.as_raw().clone(), image.dimensions(), (width, height), fr::PixelType::U8, extensions)?;
+            GrayImage::from_raw(width.get(), height.get(), buffer).map(DynamicImage::ImageLuma8)
+        }
+        DynamicImage::ImageLumaA8(image) => {
+            let buffer = resize_buffer(image.as_raw().clone(), image.dimensions(), (width, height), fr::PixelType::U8x2, extensions)?;
+            GrayAlphaImage::from_raw(width.get(), height.get(), buffer).map(DynamicImage::ImageLumaA8)
+        }
+        DynamicImage::ImageRgb8(image) => {
+            let buffer = resize_buffer(image.as_raw().clone(), image.dimensions(), (width, height), fr::PixelType::U8x3, extensions)?;
+            RgbImage::from_raw(width.get(), height.get(), buffer).map(DynamicImage::ImageRgb8)
+        }
+        image => {
+            let image = image.to_rgba8();
+            let buffer = resize_buffer(image.as_raw().clone(), image.dimensions(), (width, height), fr::PixelType::U8x4, extensions)?;
+            RgbaImage::from_raw(width.get(), height.get(), buffer).map(DynamicImage::ImageRgba8)
+        }
+    }
+}
+
+fn resize_buffer(
+    buffer: Vec<u8>,
+    source_dimensions: (u32, u32),
+    (width, height): (NonZeroU32, NonZeroU32),
+    pixel_type: fr::PixelType,
+    extensions: fr::CpuExtensions,
+) -> Option<Vec<u8>> {
+    let mut source = fr::Image::from_vec_u8(
+        NonZeroU32::new(source_dimensions.0)?,
+        NonZeroU32::new(source_dimensions.1)?,
+        buffer,
+        pixel_type,
+    ).ok()?;
+    let mut target = fr::Image::new(width, height, pixel_type);
+    let has_alpha = matches!(pixel_type, fr::PixelType::U8x2 | fr::PixelType::U8x4);
+
+    let mut mul_div = fr::MulDiv::default();
+    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3));
+    // Extensions come from `cpu_extensions`, which only returns ones supported by the CPU.
+    unsafe {
+        mul_div.set_cpu_extensions(extensions);
+        resizer.set_cpu_extensions(extensions);
+    }
+    if has_alpha {
+        mul_div.multiply_alpha_inplace(&mut source.view_mut()).ok()?;
+    }
+    resizer.resize(&source.view(), &mut target.view_mut()).ok()?;
+    if has_alpha {
+        mul_div.divide_alpha_inplace(&mut target.view_mut()).ok()?;
+    }
+    Some(target.into_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use image_crate::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    use crate::config::CpuExtension;
+    use crate::resizer::simd::{cpu_extensions, resize_exact};
+
+    #[test]
+    fn resize_keeps_pixel_type_and_colors() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 30, Rgba([200, 100, 50, 128])));
+        let resized = resize_exact(&image, 10, 20, cpu_extensions(&CpuExtension::Auto)).unwrap();
+
+        assert_eq!(resized.dimensions(), (10, 20));
+        // Alpha is premultiplied for the resize, which may cost one step of precision.
+        let pixel = resized.as_rgba8().unwrap().get_pixel(5, 10);
+        assert!(pixel.0.iter().zip([200u8, 100, 50, 128]).all(|(a, b)| a.abs_diff(b) <= 1), "{:?}", pixel);
+        assert_eq!(resize_exact(&image.to_rgb8().into(), 4, 3, cpu_extensions(&CpuExtension::None)).unwrap().color(), image_crate::ColorType::Rgb8);
+    }
+}
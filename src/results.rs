@@ -0,0 +1,84 @@
+use std::io::Error;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::cache::{block_on, BlockingCache, SharedCache};
+use crate::cache::typed::Cache;
+use crate::encoder::EncodedImage;
+
+/// Stored results share the cache with the pipeline stages, their keys never collide with tags.
+const KEY_PREFIX: &str = "result-";
+
+/// Result kept in the cache until it expires or the cache engine evicts it.
+#[derive(Serialize, Deserialize)]
+pub struct StoredResult {
+    pub image: EncodedImage,
+    /// Unix timestamp in seconds.
+    pub expires_at: i64,
+}
+
+impl StoredResult {
+    /// Seconds until the result expires.
+    pub fn remaining(&self) -> i64 {
+        (self.expires_at - Utc::now().timestamp()).max(0)
+    }
+}
+
+/// Stores the result under a new ID. Blocks until the cache has written it, routes call it off the runtime.
+pub fn store(cache: &SharedCache, image: EncodedImage, retention: Duration) -> Result<String, Error> {
+    let id = Uuid::new_v4().to_simple().to_string();
+    let result = StoredResult { image, expires_at: Utc::now().timestamp() + retention.as_secs() as i64 };
+    block_on(Cache::new(cache).set(&key(&id), &result))?;
+    Ok(id)
+}
+
+/// Result stored under the ID, `None` when it is unknown or expired. Expired results are removed.
+/// Blocks like [`store`].
+pub fn load(cache: &SharedCache, id: &str) -> Option<StoredResult> {
+    if id.len() != 32 || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let result = Cache::<StoredResult>::new(cache).get_blocking(&key(id))?;
+    if result.expires_at <= Utc::now().timestamp() {
+        let _ = cache.remove_blocking(&key(id));
+        return None;
+    }
+    Some(result)
+}
+
+fn key(id: &str) -> String {
+    format!("{}{}", KEY_PREFIX, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::cache::{BlockingCache, HashMapCacheEngine, shared};
+    use crate::encoder::EncodedImage;
+    use crate::results::{load, store};
+
+    #[test]
+    fn forget_expired_results() {
+        let cache = shared(Box::new(HashMapCacheEngine::new()));
+        let image = EncodedImage {
+            content_type: String::from("image/png"),
+            image: vec![1, 2, 3],
+            etag: String::from("\"etag\""),
+            width: 1,
+            height: 1,
+        };
+        let kept = store(&cache, image.clone(), Duration::from_secs(60)).unwrap();
+        let expired = store(&cache, image, Duration::ZERO).unwrap();
+
+        let result = load(&cache, &kept).unwrap();
+        assert_eq!(result.image.image, vec![1, 2, 3]);
+        assert!(result.remaining() > 0);
+        assert!(load(&cache, &expired).is_none());
+        assert!(cache.get_blocking(&format!("result-{}", expired)).is_none());
+        assert!(load(&cache, "../../etc/passwd").is_none());
+    }
+}
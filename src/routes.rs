@@ -1,3 +1,8 @@
 pub mod index;
 pub mod health;
+pub mod metrics;
+pub mod admin;
+pub mod upload;
+pub mod contact_sheet;
+pub mod capabilities;
 mod cache;
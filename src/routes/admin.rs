@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::http::header::AUTHORIZATION;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, prewarm, purge, recover};
+use crate::cache::block_on;
+use crate::cache::layers::CacheLayer;
+use crate::mode::ServiceMode;
+use crate::prewarm::{PrewarmError, PrewarmRequest};
+use crate::purge::{PROPAGATED, PurgeRequest};
+use crate::selftest;
+use crate::verify::{self, VerifyRequest};
+
+#[derive(Debug, PartialEq)]
+pub enum AdminError {
+    /// There is no `admin.token`, so the routes are off.
+    Disabled,
+    MissingToken,
+    InvalidToken,
+    UnknownTenant(String),
+}
+
+impl From<AdminError> for HttpResponse {
+    fn from(e: AdminError) -> Self {
+        match e {
+            AdminError::Disabled => HttpResponse::Forbidden().body("Admin routes are disabled until admin.token is set."),
+            AdminError::MissingToken => HttpResponse::Unauthorized().body("Admin token is required."),
+            AdminError::InvalidToken => HttpResponse::Unauthorized().body("Invalid admin token."),
+            AdminError::UnknownTenant(name) => HttpResponse::NotFound().body(format!("Unknown tenant {}.", name)),
+        }
+    }
+}
+
+impl From<PrewarmError> for HttpResponse {
+    fn from(e: PrewarmError) -> Self {
+        match e {
+            PrewarmError::SourceNotAllowed => HttpResponse::Forbidden().body("Sitemap or manifest is not on an allowed host."),
+            PrewarmError::TooManyJobs => HttpResponse::TooManyRequests().body("Too many prewarm jobs are running."),
+        }
+    }
+}
+
+/// Lets requests to the admin routes which change the instance or list its sources through.
+fn authorize(req: &HttpRequest, data: &AppState) -> Result<(), AdminError> {
+    let token = recover(data.config.lock()).admin.token.clone();
+    check_token(req.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok()), &token)
+}
+
+/// Requests have to carry the token as a bearer token in `authorization`. Without a token nothing gets through,
+/// the peer address cannot tell local requests apart from ones passed on by a proxy or the HTTP/3 listener.
+pub fn check_token(authorization: Option<&str>, token: &str) -> Result<(), AdminError> {
+    if token.is_empty() {
+        return Err(AdminError::Disabled);
+    }
+    let bearer = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) if constant_time_eq(bearer.as_bytes(), token.as_bytes()) => Ok(()),
+        Some(_) => Err(AdminError::InvalidToken),
+        None => Err(AdminError::MissingToken),
+    }
+}
+
+/// Compares without stopping at the first difference, so the time taken does not tell how much of the token was guessed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// State of the tenant named in an admin request, the top-level one when none is named.
+fn tenant_state(data: &web::Data<AppState>, tenant: Option<&str>) -> Result<web::Data<AppState>, AdminError> {
+    match tenant {
+        None => Ok(data.clone()),
+        Some(name) => data.tenants.get(name).cloned().ok_or_else(|| AdminError::UnknownTenant(name.to_string())),
+    }
+}
+
+pub async fn self_test(data: web::Data<AppState>) -> HttpResponse {
+    let config = recover(data.config.lock()).clone();
+    let report = match web::block(move || selftest::run(&config)).await {
+        Ok(report) => report,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#?}", e)),
+    };
+    let mut response = if report.passed() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    *recover(data.self_test.write()) = report.clone();
+    response.json(report)
+}
+
+pub async fn costs(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.costs.report())
+}
+
+/// Most requested renditions, `?limit=` of them.
+pub async fn hot_renditions(req: HttpRequest, data: web::Data<AppState>, query: web::Query<HotRenditionsQuery>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    HttpResponse::Ok().json(data.hot_renditions.top(query.limit))
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HotRenditionsQuery {
+    pub limit: usize,
+}
+
+impl Default for HotRenditionsQuery {
+    fn default() -> Self {
+        HotRenditionsQuery { limit: 20 }
+    }
+}
+
+pub async fn quotas(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.quotas.report())
+}
+
+pub async fn jobs(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.jobs.list())
+}
+
+pub async fn job(data: web::Data<AppState>, id: web::Path<u64>) -> HttpResponse {
+    match data.jobs.get(id.into_inner()) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub async fn start_prewarm(req: HttpRequest, data: web::Data<AppState>, request: web::Json<PrewarmRequest>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    match prewarm::spawn(data, request.into_inner()) {
+        Ok(job) => HttpResponse::Accepted().json(job),
+        Err(e) => e.into(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModeChange {
+    pub mode: ServiceMode,
+}
+
+pub async fn mode(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(ModeChange { mode: data.mode.get() })
+}
+
+pub async fn switch_mode(req: HttpRequest, data: web::Data<AppState>, change: web::Json<ModeChange>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    warn!("Switching from {:?} to {:?} mode.", data.mode.get(), change.mode);
+    data.mode.set(change.mode);
+    HttpResponse::Ok().json(change.into_inner())
+}
+
+pub async fn start_purge(req: HttpRequest, data: web::Data<AppState>, request: web::Json<PurgeRequest>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    let propagate = !req.headers().contains_key(PROPAGATED);
+    let data = match tenant_state(&data, request.tenant.as_deref()) {
+        Ok(data) => data,
+        Err(e) => return e.into(),
+    };
+    match web::block(move || purge::purge(&data, &request.url, request.tenant.as_deref(), propagate)).await {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#?}", e)),
+    }
+}
+
+/// Compares the cached renditions of the source with the source the origin serves now, see `verify::verify`.
+pub async fn verify_source(req: HttpRequest, data: web::Data<AppState>, request: web::Json<VerifyRequest>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    let data = match tenant_state(&data, request.tenant.as_deref()) {
+        Ok(data) => data,
+        Err(e) => return e.into(),
+    };
+    match verify::verify(&data, &request).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => e.into(),
+    }
+}
+
+/// Takes the instance out of service and shuts it down, meant for `preStop` hooks. Answers once
+/// the transforms in flight finished or the timeout ran out and buffered cache writes are flushed.
+pub async fn drain(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    let timeout = Duration::from_secs(recover(data.config.lock()).drain.timeout_seconds);
+    warn!("Draining, waiting up to {:?} for {} transforms in flight.", timeout, data.drain.in_flight());
+    let report = data.drain.drain(timeout).await;
+    let cache = data.cache.clone();
+    match web::block(move || block_on(cache.flush())).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Unable to flush the cache while draining. Reason: {}", e),
+        Err(e) => error!("Unable to flush the cache while draining. Reason: {:?}", e),
+    }
+    data.drain.stop_server();
+    HttpResponse::Ok().json(report)
+}
+
+pub async fn cache_layers(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.cache_layers.report())
+}
+
+/// Switches the listed layers, e.g. `{"encode": false}`, the rest stay as they are.
+pub async fn switch_cache_layers(req: HttpRequest, data: web::Data<AppState>, changes: web::Json<BTreeMap<String, bool>>) -> HttpResponse {
+    if let Err(e) = authorize(&req, &data) {
+        return e.into();
+    }
+    let mut layers = vec![];
+    for (name, enabled) in changes.iter() {
+        match CacheLayer::from_name(name) {
+            Some(layer) => layers.push((layer, *enabled)),
+            None => return HttpResponse::BadRequest().body(format!("Unknown cache layer: {}", name)),
+        }
+    }
+    for (layer, enabled) in layers {
+        warn!("Cache layer {} is {}.", layer.name(), if enabled { "enabled" } else { "disabled" });
+        data.cache_layers.set(layer, enabled);
+    }
+    HttpResponse::Ok().json(data.cache_layers.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::admin::{AdminError, check_token};
+
+    #[test]
+    fn require_the_admin_token() {
+        assert_eq!(check_token(Some("Bearer secret"), ""), Err(AdminError::Disabled));
+        assert_eq!(check_token(None, "secret"), Err(AdminError::MissingToken));
+        assert_eq!(check_token(Some("Bearer secrets"), "secret"), Err(AdminError::InvalidToken));
+        assert_eq!(check_token(Some("Basic secret"), "secret"), Err(AdminError::MissingToken));
+        assert_eq!(check_token(Some("Bearer secret"), "secret"), Ok(()));
+    }
+}
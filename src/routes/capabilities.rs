@@ -0,0 +1,8 @@
+use actix_web::{HttpResponse, web};
+
+use crate::{AppState, capabilities, recover};
+
+pub async fn capabilities(data: web::Data<AppState>) -> HttpResponse {
+    let config = recover(data.config.lock());
+    HttpResponse::Ok().json(capabilities::capabilities(&config))
+}
@@ -0,0 +1,149 @@
+use std::io::Cursor;
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::http::header::{HeaderName, ETAG};
+use futures_util::{stream, StreamExt};
+use image_crate::DynamicImage;
+use image_crate::io::Reader as ImageReader;
+use log::warn;
+
+use crate::{AppState, recover};
+use crate::contact_sheet::{compose, ContactSheetRequest};
+use crate::encoder::{EncodedImage, OutputFormat};
+use crate::fetcher::generate_resource_tag;
+use crate::output_dimensions::OutputDimensions;
+use crate::rendition::{render, Rendition, Requester};
+use crate::routes::index::{IMAGE_HEIGHT, IMAGE_WIDTH};
+
+/// Number of sources which are missing from the sheet.
+const MISSING: &str = "x-contact-sheet-missing";
+/// Thumbnails rendered at once, so a single sheet does not take over the stage pools.
+const CONCURRENT_THUMBNAILS: usize = 4;
+
+/// Thumbnails go through the regular pipeline, so they are cached like any other rendition
+/// and the sheet is rebuilt from the cache when requested again.
+pub async fn contact_sheet(req: HttpRequest, data: web::Data<AppState>, request: web::Json<ContactSheetRequest>) -> HttpResponse {
+    let api_key = data.quotas.identify(req.headers());
+    let requester = Requester::Client(api_key.clone());
+    if let Err(e) = requester.admit(&data) {
+        return e.into();
+    }
+    let request = request.into_inner();
+    let background = match request.validate() {
+        Ok(background) => background,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let quality_policy = recover(data.config.lock()).quality_policy.clone();
+    let output_format = match OutputFormat::parse_with_policy(&request.format, &quality_policy) {
+        Ok((output_format, _)) => output_format.with_defaults(&recover(data.config.lock()).encoding),
+        Err(e) => return HttpResponse::UnprocessableEntity().body(format!("{:?}", e)),
+    };
+    if !recover(data.config.lock()).allows_output_format(output_format.name()) {
+        return HttpResponse::Forbidden().body(format!("Format {} is not allowed.", output_format.name()));
+    }
+
+    // Thumbnails are decoded again for the sheet, so they stay lossless unless PNG is not allowed.
+    let thumbnail_format = match recover(data.config.lock()).allows_output_format("png") {
+        true => String::from("png"),
+        false => request.format.clone(),
+    };
+    let thumbnail = Rendition {
+        width: request.tile_size as usize,
+        height: request.tile_size as usize,
+        keep_ratio: true,
+        format: thumbnail_format,
+        operations: String::new(),
+    };
+    // Every thumbnail counts against the quota of the key, like a request for it would.
+    let rendered: Vec<_> = stream::iter(&request.urls)
+        .map(|url| render(&data, url, &thumbnail, &requester))
+        .buffered(CONCURRENT_THUMBNAILS)
+        .collect()
+        .await;
+    let mut tags = vec![format!("{:?}", request)];
+    let thumbnails: Vec<Option<DynamicImage>> = request.urls.iter().zip(rendered).map(|(url, rendered)| match rendered {
+        Ok(encoded_image) => {
+            tags.push(encoded_image.etag.clone());
+            decode_thumbnail(&encoded_image)
+        }
+        Err(e) => {
+            warn!("Unable to add {} to a contact sheet. Reason: {:?}", url, e);
+            None
+        }
+    }).collect();
+    let missing = thumbnails.iter().filter(|thumbnail| thumbnail.is_none()).count();
+
+    let sheet = {
+        let data = data.clone();
+        move || {
+            let sheet = DynamicImage::ImageRgba8(compose(&request, &background, &thumbnails));
+            // Tagged by the thumbnails, so the sheet changes whenever one of the sources does.
+            let tag = generate_resource_tag(&tags.join(" "));
+            recover(data.encoder.lock()).encode(&tag, sheet, &OutputDimensions::Original, output_format, None)
+        }
+    };
+    let encoded_image: EncodedImage = match web::block(sheet).await {
+        Ok(Ok(encoded_image)) => encoded_image,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#?}", e)),
+    };
+    if let Some(api_key) = &api_key {
+        data.quotas.record_served(api_key, encoded_image.image.len() as u64);
+    }
+    HttpResponse::Ok()
+        .insert_header((ETAG, encoded_image.etag))
+        .insert_header((IMAGE_WIDTH, encoded_image.width.to_string()))
+        .insert_header((IMAGE_HEIGHT, encoded_image.height.to_string()))
+        .insert_header((HeaderName::from_static(MISSING), missing.to_string()))
+        .content_type(encoded_image.content_type)
+        .body(encoded_image.image)
+}
+
+fn decode_thumbnail(encoded_image: &EncodedImage) -> Option<DynamicImage> {
+    ImageReader::new(Cursor::new(&encoded_image.image))
+        .with_guessed_format().ok()?
+        .decode().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use actix_web::{App, test, web};
+    use actix_web::http::StatusCode;
+    use image_crate::{ImageFormat, RgbImage};
+    use serde_json::json;
+
+    use crate::config::{ApiKeyQuota, Config};
+    use crate::fetcher::fixtures::write_fixture;
+    use crate::routes::contact_sheet::{contact_sheet, MISSING};
+    use crate::test_state;
+
+    #[actix_web::test]
+    async fn count_thumbnails_against_the_quota() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let mut content = Cursor::new(Vec::new());
+        RgbImage::new(8, 8).write_to(&mut content, ImageFormat::Png).unwrap();
+        let urls: Vec<String> = (0..3).map(|i| format!("https://example.com/{}.png", i)).collect();
+        for url in &urls {
+            write_fixture(directory.path(), url, "image/png", content.get_ref());
+        }
+        let mut config = Config::default();
+        config.fixtures.directory = directory.path().to_string_lossy().to_string();
+        config.quotas.keys = vec![ApiKeyQuota { key: String::from("a"), requests_per_day: Some(3), transform_seconds_per_day: None, bytes_per_day: None }];
+        let data = test_state(config);
+        let app = test::init_service(App::new().app_data(data.clone()).route("/contact-sheet", web::post().to(contact_sheet))).await;
+
+        let request = test::TestRequest::post().uri("/contact-sheet")
+            .insert_header(("X-Api-Key", "a"))
+            .set_json(json!({"urls": urls, "tileSize": 16, "format": "png"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The sheet and two thumbnails use up the requests of the day, the last thumbnail is left out.
+        assert_eq!(response.headers().get(MISSING).unwrap(), "1");
+        let usage = &data.quotas.report()["a"].usage;
+        assert_eq!(usage.requests, 3);
+        assert!(usage.bytes_served > 0);
+    }
+}
@@ -1,10 +1,10 @@
 use actix_web::{HttpResponse, web};
 
-use crate::AppState;
+use crate::{AppState, recover};
 
 pub async fn health(data: web::Data<AppState>) -> HttpResponse {
-    if let Err(e) = data.cache.write() {
-        return HttpResponse::InternalServerError().body(format!("{:#?}", e));
+    if data.drain.is_draining() {
+        return HttpResponse::ServiceUnavailable().body("Draining.");
     }
     if let Err(e) = data.decoder.lock() {
         return HttpResponse::InternalServerError().body(format!("{:#?}", e));
@@ -15,11 +15,17 @@ pub async fn health(data: web::Data<AppState>) -> HttpResponse {
     if let Err(e) = data.resizer.lock() {
         return HttpResponse::InternalServerError().body(format!("{:#?}", e));
     }
+    if let Err(e) = data.filter.lock() {
+        return HttpResponse::InternalServerError().body(format!("{:#?}", e));
+    }
     if let Err(e) = data.config.lock() {
         return HttpResponse::InternalServerError().body(format!("{:#?}", e));
     }
     if let Err(e) = data.fetcher.lock() {
         return HttpResponse::InternalServerError().body(format!("{:#?}", e));
     }
+    if !recover(data.self_test.read()).passed() {
+        return HttpResponse::ServiceUnavailable().body("Self-test failed. See /admin/selftest.");
+    }
     return HttpResponse::Ok().body("ok");
 }
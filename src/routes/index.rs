@@ -1,20 +1,347 @@
+use std::io::Cursor;
 use std::mem::size_of_val;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::time::{Duration, Instant};
 
 use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, web};
-use log::{debug, info};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, HeaderName, HeaderValue, IF_NONE_MATCH, LINK, RETRY_AFTER, VARY};
+use image_crate::{DynamicImage, GenericImageView, ImageFormat};
+use image_crate::io::Reader as ImageReader;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::Serialize;
 
-use crate::AppState;
-use crate::encoder::OutputFormat;
-use crate::fetcher::FetchError;
+use crate::{AppState, metadata, recover, signing};
+use crate::client_hints::{ACCEPT_CH, ACCEPTED_HINTS, CONTENT_DPR, content_dpr, hinted_width, VARY_HINTS};
+use crate::config::{CacheControlPolicy, ResponseCacheControlSettings};
+#[cfg(feature = "libvips")]
+use crate::config::PipelineBackend;
+use crate::decoder::{DecodeError, is_apng, sniff_content_type};
+use crate::encoder::{EncodedImage, encoded_tag, EncodingError, OutputFormat, ParseError};
+#[cfg(feature = "libvips")]
+use crate::encoder::encoded_etag;
+use crate::events::{CacheStatus, TransformEvent};
+use crate::fetcher::{CanServeCache, FetchError, Resource, ResponseData};
+use crate::image::{Animation, AnimationFrame};
+use crate::metrics::{PANICS, RESPONSES};
+use crate::metadata::METADATA_QUERY_KEY;
+use crate::mode::ServiceMode;
+use crate::operations::{FitMode, Operation, OperationChain, OperationParseError, OPERATIONS_QUERY_KEY};
 use crate::output_dimensions::OutputDimensions;
+use crate::quotas::{QuotaError, seconds_until_reset};
+use crate::rendition::Rendition;
 use crate::resizer::ResizeError;
+use crate::signing::{Claims, SigningError};
+use crate::source_url::{SourceUrl, SourceUrlError};
+use crate::trace::TraceContext;
+#[cfg(feature = "libvips")]
+use crate::vips;
+#[cfg(feature = "libvips")]
+use crate::metrics::observe_stage;
+
+const SAVE_DATA: &str = "save-data";
+pub(crate) const IMAGE_WIDTH: &str = "x-image-width";
+pub(crate) const IMAGE_HEIGHT: &str = "x-image-height";
+const QUALITY_POLICY: &str = "x-quality-policy";
+const CACHE_KEY: &str = "x-cache-key";
+const CANONICAL_URL: &str = "x-canonical-url";
+const EXPLAIN_QUERY_KEY: &str = "explain";
+const REFRESH_QUERY_KEY: &str = "refresh";
+const PRELOAD_QUERY_KEY: &str = "preload";
+const INTEGRITY: &str = "x-source-integrity";
+/// Format segment which leaves the format to the `Accept` header, also the default without one.
+const AUTO_FORMAT: &str = "auto";
+
+/// Where dimensions of the output come from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sizing {
+    Path { keep_ratio: bool },
+    ClientHints,
+}
 
 pub async fn index(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
-    generate_image(req, data, false)
+    serve(req, data, Sizing::Path { keep_ratio: false }).await
 }
 
 pub async fn index_with_ratio(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
-    generate_image(req, data, true)
+    serve(req, data, Sizing::Path { keep_ratio: true }).await
+}
+
+pub async fn index_auto(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    serve(req, data, Sizing::ClientHints).await
+}
+
+async fn serve(req: HttpRequest, data: web::Data<AppState>, sizing: Sizing) -> HttpResponse {
+    let started = Instant::now();
+    let origin = request_origin(&req);
+    let trace = TraceContext::from_headers(req.headers());
+    let api_key = data.quotas.identify(req.headers());
+    let mut stages = Stages::default();
+    let mut response = match data.quotas.admit(api_key.as_deref()) {
+        Ok(()) => generate_image(req.clone(), data.clone(), sizing, trace.clone(), api_key.as_deref(), &mut stages).await,
+        Err(e) => e.into(),
+    };
+    if let Some(Ok(decision)) = quality_decision(&req, &data).map(|decision| HeaderValue::from_str(&decision)) {
+        response.headers_mut().insert(HeaderName::from_static(QUALITY_POLICY), decision);
+    }
+    if response.status().is_success() && recover(data.config.lock()).early_hints.enabled {
+        let signing_key = recover(data.config.lock()).signing.key.clone();
+        if let Some(Ok(link)) = preload_link(&req, &signing_key).map(|link| HeaderValue::from_str(&link)) {
+            response.headers_mut().insert(LINK, link);
+        }
+    }
+    let thumbnail = sizing == Sizing::ClientHints || req.match_info().get("width").is_some();
+    let cache_control_settings = recover(data.config.lock()).response_cache_control.clone();
+    if let Some(Ok(cache_control)) = response_cache_control(req.path(), thumbnail, &response, &cache_control_settings).map(|value| HeaderValue::from_str(&value)) {
+        response.headers_mut().insert(CACHE_CONTROL, cache_control);
+    }
+    // Single `Vary` value, CORS middleware merges its own into the first one only.
+    let mut vary = vec![];
+    if recover(data.config.lock()).save_data.enabled {
+        vary.push(SAVE_DATA);
+    }
+    if negotiates_format(&req, &data) {
+        vary.push(ACCEPT.as_str());
+    }
+    if sizing == Sizing::ClientHints {
+        response.headers_mut().insert(HeaderName::from_static(ACCEPT_CH), HeaderValue::from_static(ACCEPTED_HINTS));
+        vary.push(VARY_HINTS);
+        let served_width = response.headers().get(IMAGE_WIDTH)
+            .and_then(|width| width.to_str().ok())
+            .and_then(|width| width.parse().ok());
+        let dpr = served_width.and_then(|width| content_dpr(req.headers(), &recover(data.config.lock()).client_hints, width));
+        if let Some(Ok(dpr)) = dpr.map(|dpr| HeaderValue::from_str(&dpr.to_string())) {
+            response.headers_mut().insert(HeaderName::from_static(CONTENT_DPR), dpr);
+        }
+    }
+    if let Ok(vary) = HeaderValue::from_str(&vary.join(", ")) {
+        if !vary.is_empty() {
+            response.headers_mut().insert(VARY, vary);
+        }
+    }
+    // The image is described instead of served, so its bytes are neither recorded nor counted.
+    if explains(&req) {
+        return HttpResponse::Ok().json(transform_event(&req, &response, started, &trace));
+    }
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(served) = response.extensions().get::<ServedRendition>() {
+            data.hot_renditions.record(&served.url, &served.rendition);
+        }
+    }
+    let response = record_response(&data, &origin, api_key.as_deref(), response);
+    if data.events.is_enabled() {
+        data.events.publish(&transform_event(&req, &response, started, &trace));
+    }
+    if let Some(threshold_ms) = recover(data.config.lock()).slow_request_threshold_ms {
+        if started.elapsed() > Duration::from_millis(threshold_ms) {
+            log_slow_request(transform_event(&req, &response, started, &trace), stages, threshold_ms);
+        }
+    }
+    response
+}
+
+/// Cache-Control configured for the kind of the response, `None` keeps the header it has.
+fn response_cache_control(path: &str, thumbnail: bool, response: &HttpResponse, settings: &ResponseCacheControlSettings) -> Option<String> {
+    let configured = |policy: &CacheControlPolicy| {
+        if response.extensions().get::<Placeholder>().is_some() {
+            return policy.placeholder.clone();
+        }
+        if response.status().is_client_error() || response.status().is_server_error() {
+            return policy.error.clone();
+        }
+        match response.extensions().get::<CacheStatus>()? {
+            CacheStatus::Original => policy.original.clone(),
+            _ if thumbnail => policy.thumbnail.clone(),
+            _ => policy.rendition.clone(),
+        }
+    };
+    settings.routes.iter()
+        .find(|route| path.starts_with(&route.prefix))
+        .and_then(|route| configured(&route.policy))
+        .or_else(|| configured(&settings.policy))
+}
+
+/// `?explain` answers with a description of how the image was served instead of the image.
+fn explains(req: &HttpRequest) -> bool {
+    url::form_urlencoded::parse(req.query_string().as_bytes()).any(|(key, _)| key == EXPLAIN_QUERY_KEY)
+}
+
+/// `Link` preloading the full rendition of a placeholder requested with `?preload={width}_{height}`.
+/// It is the path of the placeholder with the size of the full rendition, and its query without
+/// `preload`, signed again when URLs are signed.
+fn preload_link(req: &HttpRequest, signing_key: &str) -> Option<String> {
+    let (width, height) = (req.match_info().get("width")?, req.match_info().get("height")?);
+    let (full_width, full_height) = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == PRELOAD_QUERY_KEY)
+        .and_then(|(_, size)| {
+            let (width, height) = size.split_once('_')?;
+            Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?))
+        })?;
+    let path = req.path().replacen(&format!("/{}_{}/", width, height), &format!("/{}_{}/", full_width, full_height), 1);
+    let parameters: Vec<&str> = req.query_string().split('&')
+        .filter(|parameter| {
+            let key = parameter.split('=').next().unwrap_or_default();
+            !key.is_empty() && ![PRELOAD_QUERY_KEY, EXPLAIN_QUERY_KEY, signing::SIGNATURE_QUERY_KEY].contains(&key)
+        })
+        .collect();
+    let path_and_query = match parameters.is_empty() {
+        true => path,
+        false => format!("{}?{}", path, parameters.join("&")),
+    };
+    let url = match signing_key {
+        "" => path_and_query,
+        key => {
+            let separator = if path_and_query.contains('?') { '&' } else { '?' };
+            format!("{}{}{}={}", path_and_query, separator, signing::SIGNATURE_QUERY_KEY, signing::sign(key, &path_and_query))
+        }
+    };
+    Some(format!("<{}>; rel=preload; as=image; fetchpriority=high", url))
+}
+
+/// `?refresh=1` fetches the source again even when the cached one is fresh. Only signed URLs
+/// may ask for it, so clients cannot send every request through to the origin.
+fn refreshes(req: &HttpRequest) -> bool {
+    url::form_urlencoded::parse(req.query_string().as_bytes()).any(|(key, value)| key == REFRESH_QUERY_KEY && value == "1")
+}
+
+fn transform_event(req: &HttpRequest, response: &HttpResponse, started: Instant, trace: &TraceContext) -> TransformEvent {
+    let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let source_url = SourceUrl::from_request(req).ok();
+    TransformEvent {
+        timestamp: Utc::now().to_rfc3339(),
+        source_url: source_url.as_ref().map(|source_url| source_url.url.clone()).unwrap_or_default(),
+        source_url_encoding: source_url.map(|source_url| source_url.encoding),
+        rendition: req.uri().path_and_query().map(|rendition| rendition.to_string()).unwrap_or_default(),
+        status: response.status().as_u16(),
+        content_type: header(CONTENT_TYPE.as_str()),
+        width: header(IMAGE_WIDTH).and_then(|width| width.parse().ok()),
+        height: header(IMAGE_HEIGHT).and_then(|height| height.parse().ok()),
+        bytes: match response.body().size() {
+            BodySize::Sized(bytes) => bytes,
+            _ => 0,
+        },
+        cache: response.extensions().get::<CacheStatus>().copied(),
+        cache_key: header(CACHE_KEY),
+        canonical_url: header(CANONICAL_URL),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        trace_id: trace.trace_id.clone(),
+        span_id: trace.span_id.clone(),
+        parent_span_id: trace.parent_span_id.clone(),
+    }
+}
+
+/// Time spent in the stages of an image request, logged when the request is slow.
+/// Stages the request never reached are left out.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Stages {
+    /// `fresh`, `stale` or `miss` for the cached source, `refresh` when it was fetched again on request.
+    pub source_cache: Option<&'static str>,
+    pub cache_probe_ms: Option<f64>,
+    /// Time spent waiting for the origin, it may have started answering during the cache probe.
+    pub fetch_ms: Option<f64>,
+    pub source_bytes: Option<usize>,
+    pub source_content_type: Option<String>,
+    pub output_format: Option<String>,
+    pub transform_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SlowRequest {
+    #[serde(flatten)]
+    event: TransformEvent,
+    threshold_ms: u64,
+    stages: Stages,
+}
+
+fn log_slow_request(event: TransformEvent, stages: Stages, threshold_ms: u64) {
+    match serde_json::to_string(&SlowRequest { event, threshold_ms, stages }) {
+        Ok(record) => warn!("Slow request: {}", record),
+        Err(e) => error!("Unable to describe slow request. Reason: {}", e),
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn record_response(data: &web::Data<AppState>, origin: &str, api_key: Option<&str>, response: HttpResponse) -> HttpResponse {
+    data.metrics.increment(RESPONSES, &[("status", response.status().as_str())]);
+    if let (true, BodySize::Sized(bytes)) = (response.status().is_success(), response.body().size()) {
+        let format = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("unknown");
+        data.costs.record_served(origin, format, bytes);
+        if let Some(api_key) = api_key {
+            data.quotas.record_served(api_key, bytes);
+        }
+    }
+    response
+}
+
+impl From<QuotaError> for HttpResponse {
+    fn from(e: QuotaError) -> Self {
+        match e {
+            QuotaError::MissingKey => HttpResponse::Unauthorized().body("API key is required."),
+            QuotaError::UnknownKey => HttpResponse::Unauthorized().body("Unknown API key."),
+            QuotaError::Exceeded(quota) => HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, seconds_until_reset().to_string()))
+                .body(format!("Daily {} quota exceeded.", quota)),
+        }
+    }
+}
+
+impl From<SigningError> for HttpResponse {
+    fn from(e: SigningError) -> Self {
+        match e {
+            SigningError::MissingSignature => HttpResponse::Forbidden().body("URL must be signed."),
+            SigningError::InvalidSignature => HttpResponse::Forbidden().body("Invalid signature."),
+            SigningError::InvalidClaims(reason) => HttpResponse::UnprocessableEntity().body(format!("Invalid claims. {}", reason)),
+            SigningError::InvalidExpiry(reason) => HttpResponse::UnprocessableEntity().body(format!("Invalid expiry. {}", reason)),
+            SigningError::Expired => HttpResponse::Gone().body("Link has expired."),
+        }
+    }
+}
+
+/// Format a request cannot be served in.
+#[derive(Debug)]
+pub enum OutputFormatError {
+    /// Requested format, as written, which does not parse.
+    Invalid(String, ParseError),
+    NotAllowed(&'static str),
+    NotAllowedByClaims(&'static str),
+}
+
+impl From<OutputFormatError> for HttpResponse {
+    fn from(e: OutputFormatError) -> Self {
+        match e {
+            OutputFormatError::Invalid(_, ParseError::QualityOutOfRange(range)) => HttpResponse::UnprocessableEntity().json(range),
+            OutputFormatError::Invalid(_, ParseError::FormatNotEnabled(format)) => HttpResponse::UnprocessableEntity().body(format!("Format {} is not enabled in this build.", format)),
+            OutputFormatError::Invalid(format, _) => HttpResponse::UnprocessableEntity().body(format!("Invalid format: {}", format)),
+            OutputFormatError::NotAllowed(format) => HttpResponse::Forbidden().body(format!("Format {} is not allowed.", format)),
+            OutputFormatError::NotAllowedByClaims(format) => HttpResponse::Forbidden().body(format!("Format {} is not allowed by the signed claims.", format)),
+        }
+    }
+}
+
+impl From<SourceUrlError> for HttpResponse {
+    fn from(e: SourceUrlError) -> Self {
+        match e {
+            SourceUrlError::InvalidEncoding(url) => HttpResponse::BadRequest().body(format!("Source URL {} is not percent-encoded UTF-8.", url)),
+        }
+    }
+}
+
+/// Domain of the source image, used to attribute cost.
+pub(crate) fn origin(resource_uri: &str) -> String {
+    url::Url::parse(resource_uri).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn request_origin(req: &HttpRequest) -> String {
+    origin(&SourceUrl::from_request(req).map(|source_url| source_url.url).unwrap_or_default())
 }
 
 impl From<FetchError> for HttpResponse {
@@ -23,93 +350,1029 @@ impl From<FetchError> for HttpResponse {
             FetchError::NotFound => HttpResponse::NotFound().body(format!("{:#?}", e)),
             FetchError::NoAccess => HttpResponse::Forbidden().body(format!("{:#?}", e)),
             FetchError::InvalidFormat => HttpResponse::UnprocessableEntity().body(format!("{:#?}", e)),
+            FetchError::ContentEncoding(_) | FetchError::Download(_) => HttpResponse::BadGateway().body(format!("{:#?}", e)),
+            FetchError::Integrity(_) => HttpResponse::BadGateway()
+                .insert_header((HeaderName::from_static(INTEGRITY), "mismatch"))
+                .body(format!("{:#?}", e)),
             _ => HttpResponse::InternalServerError().body(format!("{:#?}", e)),
         };
     }
 }
 
-pub fn generate_image(req: HttpRequest, data: web::Data<AppState>, keep_ratio: bool) -> HttpResponse {
-    let resource_url = &req.match_info().get("tail").unwrap().to_string();
-    let resource_uri = urlencoding::decode(resource_url).unwrap();
-    let width = req.match_info().get("width").unwrap_or("no-width");
-    let height = req.match_info().get("height").unwrap_or("no-height");
-    let output_dimensions: OutputDimensions = (width, height, keep_ratio).into();
-    if let Some(response_data) = data.fetcher.lock().unwrap().serve_cache(&resource_uri) {
-        let output_format = match req
-            .match_info()
-            .get("format")
-            .unwrap_or_else(|| response_data.content_type.as_str())
-            .parse::<OutputFormat>() {
+fn parse_operations(req: &HttpRequest) -> Result<OperationChain, OperationParseError> {
+    url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == OPERATIONS_QUERY_KEY)
+        .map(|(_, value)| value.parse())
+        .unwrap_or_else(|| Ok(OperationChain::default()))
+}
+
+/// Original bytes are served when the pipeline would not change anything: the requested format
+/// is the one of the source, no filters are applied and the source already has the requested dimensions.
+/// Bytes must be recognized as the format declared by the origin, so a lying content type never
+/// ends up served as a different format than requested.
+fn can_serve_original(
+    resource: &Resource,
+    output_format: &OutputFormat,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+) -> bool {
+    let content_type = resource.response_data.content_type.as_str();
+    // Compared as written in paths, which leave out the subsampling JPEGs get by default.
+    match content_type.parse::<OutputFormat>() {
+        Ok(source_format) if source_format.path_segment() == output_format.path_segment() => {}
+        Ok(OutputFormat::Png(_)) if *output_format == OutputFormat::Apng && is_apng(&resource.content) => {}
+        _ => return false,
+    }
+    if operations.operations().iter().any(Operation::is_filter) {
+        return false;
+    }
+    // Stripping metadata drops the orientation with it, such sources are decoded upright instead.
+    if metadata::orientation(&resource.content).is_some_and(|orientation| orientation != 1) {
+        return false;
+    }
+    let reader = match ImageReader::new(Cursor::new(&resource.content)).with_guessed_format() {
+        Ok(reader) => reader,
+        Err(_) => return false,
+    };
+    if reader.format().is_none() || reader.format() != ImageFormat::from_mime_type(content_type) {
+        return false;
+    }
+    match reader.into_dimensions() {
+        Ok(dimensions) => output_dimensions.matches(dimensions) && operations.maximum_dimensions(dimensions).is_none(),
+        Err(_) => false,
+    }
+}
+
+/// Format requested in the path, or the one of the source, adjusted for clients asking to save data.
+/// Animated PNG sources keep their frames in the format of the source where APNG is allowed.
+fn output_format(req: &HttpRequest, data: &AppState, source_content_type: &str, animated_png: bool, claims: &Claims) -> Result<OutputFormat, OutputFormatError> {
+    let default_output_format = recover(data.config.lock()).default_output_format.clone();
+    let configured = |output_format: &OutputFormat| recover(data.config.lock()).allows_output_format(output_format.name());
+    let path_format = path_format(req, data);
+    let requested_format = match (path_format.as_deref(), &default_output_format) {
+        (Some(format), _) if format != AUTO_FORMAT => format,
+        (None, Some(format)) => format.as_str(),
+        _ => match negotiate_format(req.headers().get(ACCEPT).and_then(|accept| accept.to_str().ok()), source_content_type, |content_type| {
+            content_type.parse::<OutputFormat>().is_ok_and(|output_format| configured(&output_format) && claims.allows(&output_format))
+        }) {
+            "image/png" if animated_png && configured(&OutputFormat::Apng) && claims.allows(&OutputFormat::Apng) => "apng",
+            format => format,
+        },
+    };
+    let quality_policy = recover(data.config.lock()).quality_policy.clone();
+    let output_format = match OutputFormat::parse_with_policy(requested_format, &quality_policy) {
+        Ok((f, _)) => f.with_defaults(&recover(data.config.lock()).encoding),
+        Err(e) => return Err(OutputFormatError::Invalid(requested_format.to_string(), e)),
+    };
+    if !configured(&output_format) {
+        return Err(OutputFormatError::NotAllowed(output_format.name()));
+    }
+    if !claims.allows(&output_format) {
+        return Err(OutputFormatError::NotAllowedByClaims(output_format.name()));
+    }
+    let save_data = recover(data.config.lock()).save_data.clone();
+    if save_data.enabled && saves_data(req) {
+        return Ok(claims.restrict(output_format.save_data(save_data.quality_delta)));
+    }
+    Ok(claims.restrict(output_format))
+}
+
+/// Format in the path, or the one of the preset in the path.
+fn path_format(req: &HttpRequest, data: &AppState) -> Option<String> {
+    match req.match_info().get("preset") {
+        Some(preset) => recover(data.config.lock()).presets.get(preset).cloned(),
+        None => req.match_info().get("format").map(str::to_string),
+    }
+}
+
+/// Paths without a format get the configured default one, if any, and `auto` is always negotiated.
+fn negotiates_format(req: &HttpRequest, data: &AppState) -> bool {
+    match path_format(req, data).as_deref() {
+        Some(format) => format == AUTO_FORMAT,
+        None => recover(data.config.lock()).default_output_format.is_none(),
+    }
+}
+
+/// Format picked by the `Accept` header: AVIF, then WebP, otherwise the format of the source when
+/// it is one every client decodes and PNG when it is not. Only formats listed explicitly count.
+/// GIF sources skip AVIF, which would keep only the first frame of an animation, and formats
+/// which are not allowed are skipped too.
+fn negotiate_format<'a>(accept: Option<&str>, source_content_type: &'a str, allowed: impl Fn(&str) -> bool) -> &'a str {
+    let accepts = |content_type: &str| accept.unwrap_or_default().split(',').any(|range| {
+        let mut parameters = range.split(';').map(str::trim);
+        parameters.next().is_some_and(|media_type| media_type.eq_ignore_ascii_case(content_type))
+            && parameters.all(|parameter| parameter.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) != Some(0.0))
+    });
+    if cfg!(feature = "avif") && source_content_type != "image/gif" && accepts("image/avif") && allowed("image/avif") {
+        return "image/avif";
+    }
+    if cfg!(feature = "webp") && accepts("image/webp") && allowed("image/webp") {
+        return "image/webp";
+    }
+    match source_content_type {
+        "image/jpeg" | "image/png" | "image/gif" => source_content_type,
+        _ => "image/png",
+    }
+}
+
+/// Outcome of the quality policy for a quality out of range in the path, `None` for qualities in range.
+fn quality_decision(req: &HttpRequest, data: &AppState) -> Option<String> {
+    let requested_format = path_format(req, data)?;
+    let quality_policy = recover(data.config.lock()).quality_policy.clone();
+    match OutputFormat::parse_with_policy(&requested_format, &quality_policy) {
+        Ok((_, Some(range))) => Some(format!("clamp; requested={}; applied={}", range.requested, range.clamped_quality())),
+        Err(ParseError::QualityOutOfRange(range)) => Some(format!("reject; requested={}", range.requested)),
+        _ => None,
+    }
+}
+
+/// Metadata is stripped as configured, unless the request asks otherwise.
+/// `None` when metadata is kept, otherwise whether the ICC profile is kept while stripping.
+fn strips_metadata(req: &HttpRequest, data: &AppState) -> Option<bool> {
+    let requested = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == METADATA_QUERY_KEY)
+        .map(|(_, value)| value.into_owned());
+    let settings = recover(data.config.lock()).metadata.clone();
+    let strip = match requested.as_deref() {
+        Some("keep") => false,
+        Some("strip") => true,
+        _ => settings.strip,
+    };
+    strip.then_some(settings.keep_color_profile)
+}
+
+fn saves_data(req: &HttpRequest) -> bool {
+    req.headers().get(SAVE_DATA)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// `api_key` is the key the request was admitted with, transforms are counted against it.
+pub async fn generate_image(req: HttpRequest, data: web::Data<AppState>, sizing: Sizing, trace: TraceContext, api_key: Option<&str>, stages: &mut Stages) -> HttpResponse {
+    let read_only = match data.mode.get() {
+        ServiceMode::Normal => false,
+        ServiceMode::ReadOnly => true,
+        ServiceMode::Maintenance => return placeholder_response(&data),
+    };
+    let signing_settings = recover(data.config.lock()).signing.clone();
+    let claims = match signing::verify(&signing_settings, req.uri().path(), req.query_string()) {
+        Ok(claims) => claims,
+        Err(e) => return e.into(),
+    };
+    let refresh = refreshes(&req);
+    if refresh && signing_settings.key.is_empty() {
+        return HttpResponse::Forbidden().body("Refreshing the source needs signed URLs.");
+    }
+    if let Some(preset) = req.match_info().get("preset") {
+        if !recover(data.config.lock()).presets.contains_key(preset) {
+            return HttpResponse::NotFound().body(format!("Unknown preset {}.", preset));
+        }
+    }
+    let resource_uri = match SourceUrl::from_request(&req) {
+        Ok(source_url) => source_url.url,
+        Err(e) => return e.into(),
+    };
+    let mut operations = match parse_operations(&req) {
+        Ok(operations) => operations,
+        Err(e) => return HttpResponse::UnprocessableEntity().body(e.to_string()),
+    };
+    if let Err(e) = operations.check_limits(&recover(data.config.lock()).operation_limits) {
+        return HttpResponse::UnprocessableEntity().body(e.to_string());
+    }
+    let mut output_dimensions: OutputDimensions = match sizing {
+        Sizing::Path { keep_ratio } => {
+            let width = req.match_info().get("width").unwrap_or("no-width");
+            let height = req.match_info().get("height").unwrap_or("no-height");
+            (width, height, keep_ratio).into()
+        }
+        Sizing::ClientHints => {
+            // Hinted width only ever shrinks the source, like `maxw`.
+            let width = hinted_width(req.headers(), &recover(data.config.lock()).client_hints);
+            operations = operations.with(Operation::MaxWidth(width));
+            OutputDimensions::Original
+        }
+    };
+    let requested_dimensions = output_dimensions.clone();
+    let strip_metadata = strips_metadata(&req, &data);
+    if let Some(fit) = operations.fit_mode() {
+        if *fit == FitMode::Seam && !recover(data.config.lock()).experimental.seam_carving {
+            return HttpResponse::Forbidden().body("Seam carving is disabled.");
+        }
+        output_dimensions = output_dimensions.with_fit(fit);
+    }
+    output_dimensions = output_dimensions.inset(operations.border_inset());
+    let cached_response = match refresh {
+        true => None,
+        false => recover(data.fetcher.lock()).serve_cache(&resource_uri),
+    };
+    stages.source_cache = Some(match (refresh, &cached_response) {
+        (true, _) => "refresh",
+        (false, Some((_, CanServeCache::Yes))) => "fresh",
+        (false, Some(_)) => "stale",
+        (false, None) => "miss",
+    });
+    let spawn_origin_fetch = || {
+        let data = data.clone();
+        let resource_uri = resource_uri.to_string();
+        let trace = trace.clone();
+        web::block(move || isolate(&data, || {
+            let fetcher = recover(data.fetcher.lock());
+            Ok(if refresh { fetcher.refetch(&resource_uri, &trace) } else { fetcher.fetch(&resource_uri, &trace) })
+        }))
+    };
+    // Revalidation with the origin only needs to wait for the cache probes when cached source is fresh,
+    // otherwise it runs alongside them.
+    let mut origin_fetch = match &cached_response {
+        Some((_, CanServeCache::Yes)) => None,
+        _ if read_only => None,
+        _ => Some(spawn_origin_fetch()),
+    };
+    let mut decoded_probe = None;
+    let mut probed_key = None;
+    if let Some((response_data, _)) = &cached_response {
+        // Whether the cached source is animated is not known without its content, renditions of
+        // animated PNGs in their own format are only found once the source is at hand.
+        let output_format = match output_format(&req, &data, &response_data.content_type, false, &claims) {
             Ok(f) => f,
-            Err(_) => return HttpResponse::UnprocessableEntity().body(format!("Invalid format: {}", req.match_info().get("format").unwrap_or_else(|| response_data.content_type.as_str()))),
+            Err(e) => return e.into(),
         };
         debug!("Fetcher allowed to serve cache {:?}", response_data);
-        if let Some(encoded_image) = data.encoder.lock().unwrap().serve_cache(
-            &response_data.id,
-            &output_dimensions,
-            output_format
-        ) {
-
-            let mut response: HttpResponseBuilder = response_data.into();
-            return response.content_type(encoded_image.content_type).body(encoded_image.image);
-        }
-    }
-    let resource = match data
-        .fetcher
-        .lock()
-        .unwrap()
-        .fetch(&resource_uri) {
-            Ok(r) => r,
-            Err(e) => return e.into(),
+        let key = RenditionKey::new(&data, &requested_dimensions, &output_dimensions, &operations, &output_format, &resource_uri, &response_data.id);
+        probed_key = Some(key.cache_key.clone());
+        stages.output_format = Some(output_format.to_string());
+        let encoded_probe = {
+            let data = data.clone();
+            let tag = operations.tag(&response_data.id);
+            let output_dimensions = output_dimensions.clone();
+            web::block(move || isolate(&data, || Ok(recover(data.encoder.lock()).serve_cache(&tag, &output_dimensions, output_format))))
+        };
+        decoded_probe = Some({
+            let data = data.clone();
+            let tag = response_data.id.clone();
+            web::block(move || isolate(&data, || Ok(recover(data.decoder.lock()).serve_cache(&tag))))
+        });
+        let probe_started = Instant::now();
+        let encoded_image = encoded_probe.await;
+        stages.cache_probe_ms = Some(milliseconds(probe_started.elapsed()));
+        if let Ok(Ok(Some(encoded_image))) = encoded_image {
+            if serves_cached(&data, &claims, &response_data.id, &encoded_image) {
+                return key.identify(encoded_response(&req, response_data.clone(), encoded_image, CacheStatus::Hit, strip_metadata));
+            }
+        }
+    }
+    if read_only {
+        return HttpResponse::ServiceUnavailable().body("Image is not cached and the service is read-only.");
+    }
+    let fetch_started = Instant::now();
+    let resource = origin_fetch.take().unwrap_or_else(spawn_origin_fetch).await;
+    stages.fetch_ms = Some(milliseconds(fetch_started.elapsed()));
+    let mut resource = match resource {
+        Ok(Ok(Ok(r))) => r,
+        Ok(Ok(Err(e))) => return e.into(),
+        Ok(Err(e)) => return e.into(),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#?}", e)),
     };
-
+    // Origins get the type wrong, e.g. WebP served as image/jpeg, so negotiation, serving the original
+    // and the animation checks all go by the format the content is in.
+    if let Some(sniffed) = sniff_content_type(&resource.content) {
+        resource.response_data.content_type = sniffed.to_string();
+    }
+    stages.source_bytes = Some(resource.content.len());
+    stages.source_content_type = Some(resource.response_data.content_type.clone());
 
     info!("Received image in format: {} - size: {}", &resource.response_data.content_type, size_of_val(&*resource.content.as_slice()));
-    let output_format = match req
-        .match_info()
-        .get("format")
-        .unwrap_or_else(|| resource.response_data.content_type.as_str())
-        .parse::<OutputFormat>() {
+    let output_format = match output_format(&req, &data, &resource.response_data.content_type, is_apng(&resource.content), &claims) {
         Ok(f) => f,
-        Err(_) => return HttpResponse::UnprocessableEntity().body(format!("Invalid format: {}", req.match_info().get("format").unwrap_or_else(|| resource.response_data.content_type.as_str()))),
+        Err(e) => return e.into(),
     };
+    stages.output_format = Some(output_format.to_string());
+
+    let key = RenditionKey::new(&data, &requested_dimensions, &output_dimensions, &operations, &output_format, &resource_uri, &resource.response_data.id);
+    if can_serve_original(&resource, &output_format, &output_dimensions, &operations) {
+        info!("Requested image is the same as the source, serving original bytes.");
+        let (width, height) = source_dimensions(&resource);
+        let original = EncodedImage {
+            content_type: resource.response_data.content_type.clone(),
+            etag: format!("\"{}\"", resource.response_data.id),
+            image: resource.content,
+            width,
+            height,
+        };
+        return key.identify(encoded_response(&req, resource.response_data, original, CacheStatus::Original, strip_metadata));
+    }
+
+    // Renditions of a source which was not cached, or changed, may have been encoded before all the same.
+    if probed_key.as_ref() != Some(&key.cache_key) {
+        let encoded_image = {
+            let data = data.clone();
+            let tag = operations.tag(&resource.response_data.id);
+            let (output_dimensions, output_format) = (output_dimensions.clone(), output_format.clone());
+            web::block(move || isolate(&data, || Ok(recover(data.encoder.lock()).serve_cache(&tag, &output_dimensions, output_format)))).await
+        };
+        if let Ok(Ok(Some(encoded_image))) = encoded_image {
+            if serves_cached(&data, &claims, &resource.response_data.id, &encoded_image) {
+                return key.identify(encoded_response(&req, resource.response_data, encoded_image, CacheStatus::Hit, strip_metadata));
+            }
+        }
+    }
 
     info!("Image will be converted to: {}", output_format);
 
-    let img = match data.decoder.lock().unwrap().decode(&resource.response_data.id, &resource) {
-        Ok(img) => img,
-        Err(err) => {
-            return HttpResponse::UnprocessableEntity().body(format!("{:#?}", err));
+    // Decoded layer probed for the cached source is only valid when the origin still serves the same resource.
+    let decoded_image = match (decoded_probe, &cached_response) {
+        (Some(probe), Some((response_data, _))) if response_data.id == resource.response_data.id => {
+            probe.await.ok().and_then(Result::ok).flatten()
+        }
+        _ => None,
+    };
+
+    let response_data = resource.response_data.clone();
+    let transformed = run_transform(&data, resource, decoded_image, output_dimensions, operations, output_format, claims).await;
+    stages.transform_ms = transformed.as_ref().map(|(_, duration)| milliseconds(*duration));
+    let encoded_image = match transformed {
+        Some((Ok(encoded_image), duration)) => {
+            data.costs.record_transform(&origin(&resource_uri), &encoded_image.content_type, duration);
+            if let Some(api_key) = api_key {
+                data.quotas.record_transform(api_key, duration);
+            }
+            encoded_image
+        }
+        Some((Err(e), _)) => return e.into(),
+        None => return HttpResponse::InternalServerError().body("Transform was cancelled."),
+    };
+
+    key.identify(encoded_response(&req, response_data, encoded_image, CacheStatus::Miss, strip_metadata))
+}
+
+/// Identity of the rendition for routers in front of instances, which can shard requests
+/// by it so equal renditions are always served by the same instance.
+struct RenditionKey {
+    /// Key the encoded image is cached under.
+    cache_key: String,
+    /// Path every request for the rendition is equivalent to, with dimensions, format and
+    /// operations resolved.
+    canonical_url: String,
+    served: ServedRendition,
+}
+
+/// Rendition a response carries, counted among the hot ones when it is served.
+struct ServedRendition {
+    url: String,
+    rendition: Rendition,
+}
+
+impl RenditionKey {
+    fn new(
+        data: &AppState,
+        requested_dimensions: &OutputDimensions,
+        output_dimensions: &OutputDimensions,
+        operations: &OperationChain,
+        output_format: &OutputFormat,
+        source_url: &str,
+        source_tag: &str,
+    ) -> Self {
+        let rendition = Rendition::canonical(requested_dimensions, output_format, operations);
+        let keep_color_profile = recover(data.config.lock()).metadata.keep_color_profile;
+        RenditionKey {
+            cache_key: encoded_tag(&operations.tag(source_tag), output_dimensions, output_format, keep_color_profile),
+            canonical_url: rendition.path(source_url),
+            served: ServedRendition { url: source_url.to_string(), rendition },
+        }
+    }
+
+    fn identify(self, mut response: HttpResponse) -> HttpResponse {
+        for (name, value) in [(CACHE_KEY, self.cache_key), (CANONICAL_URL, self.canonical_url)] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(HeaderName::from_static(name), value);
+            }
+        }
+        response.extensions_mut().insert(self.served);
+        response
+    }
+}
+
+/// A source cached in place of a larger rendition is only served where its format is allowed.
+fn serves_cached(data: &AppState, claims: &Claims, source_id: &str, encoded_image: &EncodedImage) -> bool {
+    encoded_image.etag != format!("\"{}\"", source_id) || allows_source(data, claims, &encoded_image.content_type)
+}
+
+/// Marks the response as the placeholder of the maintenance mode.
+struct Placeholder;
+
+/// Placeholder of the maintenance mode, never stored by clients and proxies so they
+/// request the image again once the maintenance is over.
+fn placeholder_response(data: &AppState) -> HttpResponse {
+    let placeholder = recover(data.config.lock()).mode.placeholder.clone();
+    let image = match data.mode.placeholder(&placeholder) {
+        Some(image) => image,
+        None => return HttpResponse::ServiceUnavailable().body("Service is under maintenance."),
+    };
+    let content_type = sniff_content_type(&image).unwrap_or("application/octet-stream");
+    let mut response = HttpResponse::Ok();
+    response.extensions_mut().insert(Placeholder);
+    response
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .content_type(content_type)
+        .body(image)
+}
+
+/// Responds with the image, or with `304 Not Modified` when the client already has it.
+fn encoded_response(req: &HttpRequest, response_data: ResponseData, mut encoded_image: EncodedImage, cache_status: CacheStatus, strip_metadata: Option<bool>) -> HttpResponse {
+    if let Some(keep_color_profile) = strip_metadata {
+        metadata::strip_encoded(&mut encoded_image, keep_color_profile);
+    }
+    let mut response: HttpResponseBuilder = response_data.into();
+    response.insert_header((ETAG, encoded_image.etag.clone()));
+    response.extensions_mut().insert(cache_status);
+    let if_none_match = req.headers().get(IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+    if if_none_match.is_some_and(|if_none_match| etag_matches(if_none_match, &encoded_image.etag)) {
+        return response.status(StatusCode::NOT_MODIFIED).finish();
+    }
+    response.insert_header((IMAGE_WIDTH, encoded_image.width.to_string()));
+    response.insert_header((IMAGE_HEIGHT, encoded_image.height.to_string()));
+    response.content_type(encoded_image.content_type).body(encoded_image.image)
+}
+
+/// Weak comparison of `If-None-Match` with the ETag, as required for `GET` requests.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Transforms the source in the transform pool, at the priority its estimated cost gets.
+/// Returns how long the transform took, `None` when it was cancelled.
+pub(crate) async fn run_transform(
+    data: &web::Data<AppState>,
+    resource: Resource,
+    decoded_image: Option<DynamicImage>,
+    output_dimensions: OutputDimensions,
+    operations: OperationChain,
+    output_format: OutputFormat,
+    claims: Claims,
+) -> Option<(Result<EncodedImage, TransformError>, Duration)> {
+    let _in_flight = data.drain.track();
+    let priority = data.pools.priority(estimate_cost(&resource, decoded_image.as_ref(), &output_format));
+    let transformation = {
+        let data = data.clone();
+        move || {
+            let started = Instant::now();
+            let result = isolate(&data, || transform(&data, &resource, decoded_image, &output_dimensions, &operations, output_format, &claims));
+            (result, started.elapsed())
+        }
+    };
+    data.pools.transform(priority, transformation).await
+}
+
+#[derive(Debug)]
+pub enum TransformError {
+    Decode(DecodeError),
+    Resize(ResizeError),
+    Encode(EncodingError),
+    Panic(String),
+}
+
+impl From<TransformError> for HttpResponse {
+    fn from(e: TransformError) -> Self {
+        match e {
+            TransformError::Decode(err) => HttpResponse::UnprocessableEntity().body(format!("{:#?}", err)),
+            TransformError::Resize(ResizeError::ResizeExceedsMaximumSize(maximum_size, maximum_dimensions)) => {
+                HttpResponse::BadRequest()
+                    .body(format!("Allowed maximum image size is: {}. Requested: {}.", maximum_size, maximum_dimensions))
+            }
+            TransformError::Encode(EncodingError::UnsupportedColorType(color)) => HttpResponse::UnprocessableEntity()
+                .body(format!("Images of color type {:?} cannot be written in the requested format.", color)),
+            TransformError::Encode(EncodingError::EncoderFailure(reason)) => HttpResponse::InternalServerError()
+                .body(format!("Encoding failed. {}", reason)),
+            TransformError::Encode(EncodingError::CacheWriteFailure(reason)) => HttpResponse::ServiceUnavailable()
+                .body(format!("Unable to store the encoded image. {}", reason)),
+            TransformError::Panic(_) => HttpResponse::InternalServerError().body("Image processing failed."),
+        }
+    }
+}
+
+/// Runs a part of the pipeline so a panic fails only the current request instead of the worker thread.
+fn isolate<T>(data: &AppState, f: impl FnOnce() -> Result<T, TransformError>) -> Result<T, TransformError> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("Unknown panic"));
+            error!("Image processing panicked: {}", message);
+            data.metrics.increment(PANICS, &[]);
+            Err(TransformError::Panic(message))
         }
+    }
+}
+
+/// Rough cost of a transform: pixels of the source multiplied by the weight of the output codec.
+fn estimate_cost(resource: &Resource, decoded_image: Option<&DynamicImage>, output_format: &OutputFormat) -> u64 {
+    let (width, height) = match decoded_image {
+        Some(image) => image.dimensions(),
+        None => source_dimensions(resource),
     };
+    width as u64 * height as u64 * output_format.cost_weight()
+}
+
+/// Dimensions read from the header of the source, without decoding it.
+pub(crate) fn source_dimensions(resource: &Resource) -> (u32, u32) {
+    ImageReader::new(Cursor::new(&resource.content))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .unwrap_or_default()
+}
 
-    let resized_image_result = match output_dimensions {
+fn transform(
+    data: &AppState,
+    resource: &Resource,
+    decoded_image: Option<DynamicImage>,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+    output_format: OutputFormat,
+    claims: &Claims,
+) -> Result<EncodedImage, TransformError> {
+    let encoded_image = match decoded_image {
+        None => transform_with_libvips(data, resource, output_dimensions, operations, &output_format),
+        Some(_) => None,
+    };
+    let encoded_image = match encoded_image {
+        Some(encoded_image) => encoded_image,
+        None => transform_source(data, resource, decoded_image, output_dimensions, operations, output_format.clone())?,
+    };
+    Ok(keep_smaller_original(data, resource, output_dimensions, operations, &output_format, claims, encoded_image))
+}
+
+/// Source streamed through libvips when the pipeline is configured so, cached like images of the
+/// native pipeline. `None` when the source, the operations or the format need the native pipeline.
+#[cfg(feature = "libvips")]
+fn transform_with_libvips(
+    data: &AppState,
+    resource: &Resource,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+    output_format: &OutputFormat,
+) -> Option<EncodedImage> {
+    let (backend, maximum_image_size, auto_orient, keep_color_profile) = {
+        let config = recover(data.config.lock());
+        (config.pipeline.backend, config.maximum_image_size, config.metadata.auto_orient, config.metadata.keep_color_profile)
+    };
+    if backend != PipelineBackend::Libvips || !operations.operations().is_empty() {
+        return None;
+    }
+    let started = Instant::now();
+    let transformed = vips::transform(&resource.content, output_dimensions, output_format, maximum_image_size, auto_orient, keep_color_profile)?;
+    observe_stage(data.metrics.as_ref(), "libvips", started);
+    let tag = operations.tag(&resource.response_data.id);
+    let encoded_image = EncodedImage {
+        content_type: transformed.content_type,
+        etag: encoded_etag(&encoded_tag(&tag, output_dimensions, output_format, keep_color_profile)),
+        image: transformed.image,
+        width: transformed.width,
+        height: transformed.height,
+    };
+    if let Err(e) = recover(data.encoder.lock()).store(&tag, output_dimensions, output_format, &encoded_image) {
+        warn!("Unable to cache the image transformed by libvips. Reason: {:?}", e);
+    }
+    Some(encoded_image)
+}
+
+#[cfg(not(feature = "libvips"))]
+fn transform_with_libvips(
+    _data: &AppState,
+    _resource: &Resource,
+    _output_dimensions: &OutputDimensions,
+    _operations: &OperationChain,
+    _output_format: &OutputFormat,
+) -> Option<EncodedImage> {
+    None
+}
+
+/// Source is served in place of a transformed image which shows the same thing but is larger,
+/// and replaces it in the cache.
+fn keep_smaller_original(
+    data: &AppState,
+    resource: &Resource,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+    output_format: &OutputFormat,
+    claims: &Claims,
+    encoded_image: EncodedImage,
+) -> EncodedImage {
+    if !recover(data.config.lock()).encoding.keep_smaller_original
+        || encoded_image.image.len() <= resource.content.len()
+        || operations.operations().iter().any(Operation::is_filter)
+        // Grayscale changes the pixels like a filter does.
+        || matches!(output_format, OutputFormat::Grayscale(_))
+        || metadata::orientation(&resource.content).is_some_and(|orientation| orientation != 1) {
+        return encoded_image;
+    }
+    let content_type = match sniff_content_type(&resource.content) {
+        Some(content_type) if stands_in_for(content_type, output_format) && allows_source(data, claims, content_type) => content_type,
+        _ => return encoded_image,
+    };
+    let (width, height) = source_dimensions(resource);
+    if (width, height) != (encoded_image.width, encoded_image.height) {
+        return encoded_image;
+    }
+    info!("Transformed image is larger than the source, serving original bytes.");
+    let original = EncodedImage {
+        content_type: content_type.to_string(),
+        etag: format!("\"{}\"", resource.response_data.id),
+        image: resource.content.clone(),
+        width,
+        height,
+    };
+    // The larger image stays cached, requests served from the cache just miss the saving.
+    if let Err(e) = recover(data.encoder.lock()).store(&operations.tag(&resource.response_data.id), output_dimensions, output_format, &original) {
+        warn!("Unable to replace the cached image with the source. Reason: {:?}", e);
+    }
+    original
+}
+
+/// Whether a source of the content type may be served in place of the format. TIFF, BMP and HEIC
+/// are only served when asked for by name, e.g. by print workflows, so nothing else stands in for them.
+/// ICOs hold several resolutions of the image, which no source has.
+fn stands_in_for(content_type: &str, output_format: &OutputFormat) -> bool {
+    match output_format.name() {
+        "ico" => false,
+        "tiff" | "bmp" | "heic" => content_type.parse::<OutputFormat>().is_ok_and(|source| source.name() == output_format.name()),
+        _ => true,
+    }
+}
+
+/// Whether the source, of the content type, may be served under `allowedOutputFormats` and the signed claims.
+fn allows_source(data: &AppState, claims: &Claims, content_type: &str) -> bool {
+    content_type.parse::<OutputFormat>()
+        .is_ok_and(|source| recover(data.config.lock()).allows_output_format(source.name()) && claims.allows(&source))
+}
+
+fn transform_source(
+    data: &AppState,
+    resource: &Resource,
+    decoded_image: Option<DynamicImage>,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+    output_format: OutputFormat,
+) -> Result<EncodedImage, TransformError> {
+    if output_format.is_animated() {
+        let animation = data.pools.decode(|| recover(data.decoder.lock()).decode_animation(&resource.response_data.id, resource))
+            .map_err(TransformError::Decode)?;
+        if let Some(animation) = animation {
+            return transform_animation(data, resource, animation, output_dimensions, operations, output_format);
+        }
+    }
+    let img = match decoded_image {
+        Some(img) => img,
+        None => data.pools.decode(|| recover(data.decoder.lock()).decode(&resource.response_data.id, resource))
+            .map_err(TransformError::Decode)?,
+    };
+    let image = process(data, &resource.response_data.id, img, output_dimensions, operations)?;
+    let image = match output_format {
+        OutputFormat::Png(_) => keep_grayscale(resource, operations, image),
+        _ => image,
+    };
+    // Decoded pixels keep the values of the source, its profile is needed to show them in its colors.
+    let color_profile = metadata::color_profile(&resource.content);
+    data.pools.encode(|| recover(data.encoder.lock()).encode(
+        &operations.tag(&resource.response_data.id),
+        image,
+        output_dimensions,
+        output_format,
+        color_profile.as_deref(),
+    )).map_err(TransformError::Encode)
+}
+
+/// Decoded images are always RGBA, grayscale PNG sources are written as grayscale again
+/// unless the operations bring in color.
+fn keep_grayscale(resource: &Resource, operations: &OperationChain, image: DynamicImage) -> DynamicImage {
+    if operations.operations().iter().any(Operation::adds_color) {
+        return image;
+    }
+    let color_type = png::Decoder::new(Cursor::new(&resource.content)).read_info()
+        .map(|reader| reader.info().color_type);
+    match color_type {
+        Ok(png::ColorType::Grayscale) => DynamicImage::ImageLuma8(image.to_luma8()),
+        Ok(png::ColorType::GrayscaleAlpha) => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        _ => image,
+    }
+}
+
+/// Every frame goes through the operations and the resize on its own, tagged by its position.
+fn transform_animation(
+    data: &AppState,
+    resource: &Resource,
+    animation: Animation,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+    output_format: OutputFormat,
+) -> Result<EncodedImage, TransformError> {
+    let frames = animation.frames.into_iter().enumerate().map(|(index, frame)| {
+        let id = format!("{} frame {}", resource.response_data.id, index);
+        let image = process(data, &id, frame.image.into(), output_dimensions, operations)?;
+        Ok(AnimationFrame { image: image.into(), delay_ms: frame.delay_ms })
+    }).collect::<Result<Vec<AnimationFrame>, TransformError>>()?;
+    data.pools.encode(|| recover(data.encoder.lock()).encode_animation(
+        &operations.tag(&resource.response_data.id),
+        Animation { frames },
+        output_dimensions,
+        output_format,
+    )).map_err(TransformError::Encode)
+}
+
+/// Filters and resizes a decoded image, ready to be encoded.
+fn process(
+    data: &AppState,
+    id: &str,
+    img: DynamicImage,
+    output_dimensions: &OutputDimensions,
+    operations: &OperationChain,
+) -> Result<DynamicImage, TransformError> {
+    let operations_before_resize = operations.before_resize();
+    let img = recover(data.filter.lock()).filter(id, img, &operations_before_resize);
+    let resize_tag = operations_before_resize.tag(id);
+
+    let image = match *output_dimensions {
         OutputDimensions::Original => {
-            Result::Ok(img)
+            img
         }
         OutputDimensions::ScaledExact(width, height) => {
-            data.resizer.lock().unwrap().resize_exact(&resource.response_data.id, img, (width, height))
+            recover(data.resizer.lock()).resize_exact(&resize_tag, img, (width, height)).map_err(TransformError::Resize)?
         }
         OutputDimensions::ScaledWithRatio(width, height) => {
-            data.resizer.lock().unwrap().resize(&resource.response_data.id, img, (width, height))
+            recover(data.resizer.lock()).resize(&resize_tag, img, (width, height)).map_err(TransformError::Resize)?
+        }
+        OutputDimensions::SeamCarved(width, height) => {
+            recover(data.resizer.lock()).resize_seam_carved(&resize_tag, img, (width, height)).map_err(TransformError::Resize)?
         }
     };
 
-    let encoded_image = match resized_image_result {
-        Ok(image) => {
-            data.encoder.lock().unwrap().encode(
-                &resource.response_data.id,
-                image,
-                &output_dimensions,
-                output_format,
-            ).unwrap()
-        }
-        Err(ResizeError::ResizeExceedsMaximumSize(maximum_size, maximum_dimensions)) => {
-            return HttpResponse::BadRequest()
-                .body(format!("Allowed maximum image size is: {}. Requested: {}.", maximum_size, maximum_dimensions));
-        }
+    let image = match operations.maximum_dimensions((image.width(), image.height())) {
+        Some(dimensions) => recover(data.resizer.lock()).resize(
+            &format!("{} - {}", resize_tag, output_dimensions),
+            image,
+            dimensions,
+        ).map_err(TransformError::Resize)?,
+        None => image,
     };
 
-    let mut response: HttpResponseBuilder = resource.response_data.into();
-    return response.content_type(encoded_image.content_type).body(encoded_image.image);
+    Ok(recover(data.filter.lock()).filter(
+        &format!("{} - {}", resize_tag, output_dimensions),
+        image,
+        &operations.after_resize(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use actix_web::{App, HttpResponse, web};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::http::StatusCode;
+    use actix_web::http::header::CONTENT_TYPE;
+
+    use image_crate::{ColorType, DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+
+    use crate::config::{ApiKeyQuota, CacheControlPolicy, Config, ResponseCacheControlSettings, RouteCacheControl};
+    use crate::cache::{NoCacheEngine, shared};
+    use crate::decoder::is_apng;
+    use crate::encoder::{AllInOneCachedImageEncoder, EncodingError, ImageEncoder, JpegEncoder, JpegOptions, OutputFormat, PngCompression, TiffCompression, WebpOptions};
+    use crate::events::CacheStatus;
+    use crate::fetcher::Resource;
+    use crate::fetcher::fixtures::write_fixture;
+    use crate::image::{Animation, AnimationFrame};
+    use crate::metrics::NoMetrics;
+    use crate::operations::OperationChain;
+    use crate::output_dimensions::OutputDimensions;
+    use crate::routes::index::{can_serve_original, etag_matches, index, keep_grayscale, negotiate_format, Placeholder, preload_link, response_cache_control, stands_in_for, TransformError};
+    use crate::signing::sign;
+    use crate::test_state;
+
+    fn resource(content_type: &str, format: ImageOutputFormat) -> Resource {
+        let mut resource = Resource::default();
+        DynamicImage::new_rgb8(4, 2).write_to(&mut Cursor::new(&mut resource.content), format).unwrap();
+        resource.response_data.content_type = content_type.to_string();
+        resource
+    }
+
+    #[test]
+    fn stand_in_for_formats_asked_for_by_name_only_with_the_same_format() {
+        assert!(stands_in_for("image/jpeg", &OutputFormat::Png(PngCompression::Default)));
+        assert!(!stands_in_for("image/jpeg", &OutputFormat::Tiff(TiffCompression::Lzw)));
+        assert!(stands_in_for("image/tiff", &OutputFormat::Tiff(TiffCompression::Lzw)));
+        assert!(!stands_in_for("image/png", &OutputFormat::Bmp));
+        assert!(!stands_in_for("image/x-icon", &OutputFormat::Ico));
+    }
+
+    #[test]
+    fn serve_original_when_nothing_changes() {
+        let png = resource("image/png", ImageOutputFormat::Png);
+        assert!(can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &OperationChain::default()));
+        assert!(can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::ScaledExact(4, 2), &OperationChain::default()));
+        assert!(can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &"maxw:10".parse().unwrap()));
+    }
+
+    #[test]
+    fn negotiate_format_with_accept() {
+        let browser = Some("image/avif,image/webp,image/apng,image/*,*/*;q=0.8");
+        #[cfg(feature = "avif")]
+        assert_eq!(negotiate_format(browser, "image/jpeg", |_| true), "image/avif");
+        #[cfg(feature = "webp")]
+        {
+            assert_eq!(negotiate_format(browser, "image/gif", |_| true), "image/webp");
+            assert_eq!(negotiate_format(Some("image/avif;q=0, image/webp"), "image/jpeg", |_| true), "image/webp");
+            assert_eq!(negotiate_format(browser, "image/jpeg", |content_type| content_type != "image/avif"), "image/webp");
+        }
+        assert_eq!(negotiate_format(browser, "image/jpeg", |content_type| content_type == "image/jpeg"), "image/jpeg");
+        assert_eq!(negotiate_format(Some("image/*"), "image/jpeg", |_| true), "image/jpeg");
+        assert_eq!(negotiate_format(None, "image/png", |_| true), "image/png");
+        assert_eq!(negotiate_format(Some("*/*"), "image/webp", |_| true), "image/png");
+    }
+
+    #[test]
+    fn do_not_serve_original_in_different_format() {
+        let png = resource("image/png", ImageOutputFormat::Png);
+        #[cfg(feature = "webp")]
+        assert!(!can_serve_original(&png, &OutputFormat::WebpLoseless(WebpOptions::default()), &OutputDimensions::Original, &OperationChain::default()));
+        assert!(!can_serve_original(&png, &OutputFormat::Bmp, &OutputDimensions::Original, &OperationChain::default()));
+
+        let jpeg = resource("image/jpeg", ImageOutputFormat::Jpeg(90));
+        assert!(!can_serve_original(&jpeg, &OutputFormat::Jpeg(80, JpegOptions::default()), &OutputDimensions::Original, &OperationChain::default()));
+    }
+
+    #[test]
+    fn do_not_serve_original_when_content_type_lies() {
+        let jpeg_as_png = resource("image/png", ImageOutputFormat::Jpeg(90));
+        assert!(!can_serve_original(&jpeg_as_png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &OperationChain::default()));
+
+        let octet_stream = resource("application/octet-stream", ImageOutputFormat::Png);
+        assert!(!can_serve_original(&octet_stream, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &OperationChain::default()));
+    }
+
+    #[test]
+    fn do_not_serve_original_when_image_changes() {
+        let png = resource("image/png", ImageOutputFormat::Png);
+        assert!(!can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::ScaledExact(2, 1), &OperationChain::default()));
+        assert!(!can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &"maxw:2".parse().unwrap()));
+        assert!(!can_serve_original(&png, &OutputFormat::Png(PngCompression::Default), &OutputDimensions::Original, &"autocontrast".parse().unwrap()));
+    }
+
+    #[test]
+    fn match_etags_weakly() {
+        assert!(etag_matches("\"a\"", "\"a\""));
+        assert!(etag_matches("\"b\", W/\"a\"", "\"a\""));
+        assert!(etag_matches("*", "\"a\""));
+        assert!(!etag_matches("\"b\"", "\"a\""));
+    }
+
+    #[test]
+    fn keep_grayscale_png_sources_gray() {
+        let mut gray = Resource::default();
+        DynamicImage::new_luma8(4, 2).write_to(&mut Cursor::new(&mut gray.content), ImageOutputFormat::Png).unwrap();
+        let decoded = DynamicImage::new_rgba8(4, 2);
+        assert!(matches!(keep_grayscale(&gray, &OperationChain::default(), decoded.clone()), DynamicImage::ImageLuma8(_)));
+        assert!(matches!(keep_grayscale(&gray, &"border:2:808080".parse().unwrap(), decoded.clone()), DynamicImage::ImageLuma8(_)));
+        assert!(matches!(keep_grayscale(&gray, &"border:2:ff0000".parse().unwrap(), decoded.clone()), DynamicImage::ImageRgba8(_)));
+        let color = resource("image/png", ImageOutputFormat::Png);
+        assert!(matches!(keep_grayscale(&color, &OperationChain::default(), decoded), DynamicImage::ImageRgba8(_)));
+    }
+
+    #[test]
+    fn set_cache_control_by_kind_of_response() {
+        let settings = ResponseCacheControlSettings {
+            policy: CacheControlPolicy {
+                original: Some(String::from("public, max-age=86400")),
+                thumbnail: Some(String::from("public, max-age=3600")),
+                error: Some(String::from("no-store")),
+                ..CacheControlPolicy::default()
+            },
+            routes: vec![RouteCacheControl {
+                prefix: String::from("/auto/"),
+                policy: CacheControlPolicy { thumbnail: Some(String::from("private, max-age=60")), ..CacheControlPolicy::default() },
+            }],
+        };
+        let served = |status| {
+            let mut response = HttpResponse::Ok();
+            response.extensions_mut().insert(status);
+            response.finish()
+        };
+        let policy = |path, thumbnail, response| response_cache_control(path, thumbnail, &response, &settings);
+
+        assert_eq!(policy("/png/a.png", false, served(CacheStatus::Original)).unwrap(), "public, max-age=86400");
+        assert_eq!(policy("/10_10/png/a.png", true, served(CacheStatus::Hit)).unwrap(), "public, max-age=3600");
+        assert_eq!(policy("/auto/a.png", true, served(CacheStatus::Miss)).unwrap(), "private, max-age=60");
+        // Routes fall back to the policy of every route for kinds they leave out.
+        assert_eq!(policy("/auto/a.png", false, served(CacheStatus::Original)).unwrap(), "public, max-age=86400");
+        assert_eq!(policy("/png/a.png", false, HttpResponse::NotFound().finish()).unwrap(), "no-store");
+        // Kinds left out keep the header of the origin.
+        assert!(policy("/png/a.png", false, served(CacheStatus::Miss)).is_none());
+        let mut placeholder = HttpResponse::Ok();
+        placeholder.extensions_mut().insert(Placeholder);
+        assert!(policy("/png/a.png", false, placeholder.finish()).is_none());
+    }
+
+    #[test]
+    fn answer_encoding_errors_instead_of_panicking() {
+        let status = |e| HttpResponse::from(TransformError::Encode(e)).status();
+        assert_eq!(status(EncodingError::UnsupportedColorType(ColorType::L16)), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(status(EncodingError::EncoderFailure(String::from("out of memory"))), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(status(EncodingError::CacheWriteFailure(String::from("disk full"))), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn link_placeholders_to_full_renditions() {
+        let request = |uri: &str| TestRequest::with_uri(uri)
+            .param("width", "20")
+            .param("height", "15")
+            .to_http_request();
+        let placeholder = request("/shop/20_15/keep-ratio/webp/https%3A%2F%2Fexample.com%2F20_15%2Fa.png?ops=autocontrast&preload=800_600&s=abc");
+        assert_eq!(
+            preload_link(&placeholder, "").as_deref(),
+            Some("</shop/800_600/keep-ratio/webp/https%3A%2F%2Fexample.com%2F20_15%2Fa.png?ops=autocontrast>; rel=preload; as=image; fetchpriority=high"),
+        );
+        let full = "/shop/800_600/keep-ratio/webp/https%3A%2F%2Fexample.com%2F20_15%2Fa.png?ops=autocontrast";
+        assert_eq!(
+            preload_link(&placeholder, "secret"),
+            Some(format!("<{}&s={}>; rel=preload; as=image; fetchpriority=high", full, sign("secret", full))),
+        );
+        let unsigned = request("/20_15/https%3A%2F%2Fexample.com%2Fa.png?preload=800_600&explain");
+        assert_eq!(
+            preload_link(&unsigned, "secret"),
+            Some(format!("</800_600/https%3A%2F%2Fexample.com%2Fa.png?s={}>; rel=preload; as=image; fetchpriority=high", sign("secret", "/800_600/https%3A%2F%2Fexample.com%2Fa.png"))),
+        );
+        assert_eq!(preload_link(&request("/20_15/https%3A%2F%2Fexample.com%2Fa.png"), ""), None);
+        assert_eq!(preload_link(&request("/20_15/https%3A%2F%2Fexample.com%2Fa.png?preload=large"), ""), None);
+    }
+
+    #[actix_web::test]
+    async fn go_by_the_sniffed_type_of_the_source() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let png = resource("image/png", ImageOutputFormat::Png);
+        write_fixture(directory.path(), "https://example.com/a.jpg", "image/jpeg", &png.content);
+        let mut config = Config::default();
+        config.fixtures.directory = directory.path().to_string_lossy().to_string();
+        let app = init_service(App::new().app_data(test_state(config)).route("/{tail:.*}", web::get().to(index))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/https%3A%2F%2Fexample.com%2Fa.jpg").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        assert_eq!(read_body(response).await, png.content);
+    }
+
+    #[actix_web::test]
+    async fn keep_the_frames_of_animated_png_sources() {
+        let frame = |color: [u8; 4]| AnimationFrame { image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba(color))).into(), delay_ms: 100 };
+        let animation = Animation { frames: vec![frame([255, 0, 0, 255]), frame([0, 0, 255, 255])] };
+        let encoder = AllInOneCachedImageEncoder { cache: shared(Box::new(NoCacheEngine {})), metrics: Arc::new(NoMetrics {}), jpeg_encoder: JpegEncoder::default(), keep_color_profile: true };
+        let apng = encoder.encode_animation(&String::from("animation"), animation, &OutputDimensions::Original, OutputFormat::Apng).unwrap();
+        let directory = tempfile::TempDir::new().unwrap();
+        write_fixture(directory.path(), "https://example.com/a.png", "image/png", &apng.image);
+        let mut config = Config::default();
+        config.fixtures.directory = directory.path().to_string_lossy().to_string();
+        let app = init_service(App::new().app_data(test_state(config)).route("/{width}_{height}/{tail:.*}", web::get().to(index))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/3_2/https%3A%2F%2Fexample.com%2Fa.png").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        assert!(is_apng(&read_body(response).await));
+    }
+
+    #[actix_web::test]
+    async fn report_encoded_renditions_as_hits() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba([255, 0, 0, 255]))).write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png).unwrap();
+        let directory = tempfile::TempDir::new().unwrap();
+        write_fixture(directory.path(), "https://example.com/a.png", "image/png", &png);
+        let mut config = Config::default();
+        config.fixtures.directory = directory.path().to_string_lossy().to_string();
+        let app = init_service(App::new().app_data(test_state(config)).route("/{width}_{height}/{tail:.*}", web::get().to(index))).await;
+
+        let first = call_service(&app, TestRequest::get().uri("/3_2/https%3A%2F%2Fexample.com%2Fa.png").to_request()).await;
+        assert_eq!(first.response().extensions().get::<CacheStatus>(), Some(&CacheStatus::Miss));
+        let second = call_service(&app, TestRequest::get().uri("/3_2/https%3A%2F%2Fexample.com%2Fa.png").to_request()).await;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.response().extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+    }
+
+    #[actix_web::test]
+    async fn explain_without_counting_the_image() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let png = resource("image/png", ImageOutputFormat::Png);
+        write_fixture(directory.path(), "https://example.com/a.png", "image/png", &png.content);
+        let mut config = Config::default();
+        config.fixtures.directory = directory.path().to_string_lossy().to_string();
+        config.quotas.keys = vec![ApiKeyQuota { key: String::from("key"), requests_per_day: None, transform_seconds_per_day: None, bytes_per_day: None }];
+        let state = test_state(config);
+        let app = init_service(App::new().app_data(state.clone()).route("/{tail:.*}", web::get().to(index))).await;
+        let request = |uri: &str| TestRequest::get().uri(uri).insert_header(("X-Api-Key", "key")).to_request();
+
+        let explained = call_service(&app, request("/https%3A%2F%2Fexample.com%2Fa.png?explain")).await;
+        assert_eq!(explained.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(state.quotas.report()["key"].usage.bytes_served, 0);
+        call_service(&app, request("/https%3A%2F%2Fexample.com%2Fa.png")).await;
+        assert_eq!(state.quotas.report()["key"].usage.bytes_served, png.content.len() as u64);
+    }
 }
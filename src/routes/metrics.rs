@@ -0,0 +1,10 @@
+use actix_web::{HttpResponse, web};
+
+use crate::AppState;
+
+pub async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    match data.metrics.render() {
+        Some(body) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body),
+        None => HttpResponse::NotFound().body("Configured metrics backend does not expose metrics."),
+    }
+}
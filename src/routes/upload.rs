@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use actix_multipart::Multipart;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, web};
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, LOCATION};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+
+use crate::{AppState, metadata, recover, results};
+use crate::decoder::sniff_content_type;
+use crate::encoder::{EncodedImage, ParseError};
+use crate::fetcher::{content_tag, Resource, ResponseData};
+use crate::mode::ServiceMode;
+use crate::quotas::QuotaError;
+use crate::rendition::{Rendition, RenditionError, Requester, transform};
+use crate::routes::index::{IMAGE_HEIGHT, IMAGE_WIDTH};
+
+/// Origin costs of uploaded images are attributed to.
+const UPLOAD_ORIGIN: &str = "upload";
+
+impl From<RenditionError> for HttpResponse {
+    fn from(e: RenditionError) -> Self {
+        match e {
+            RenditionError::Fetch(err) => err.into(),
+            RenditionError::Format(ParseError::QualityOutOfRange(range)) => HttpResponse::UnprocessableEntity().json(range),
+            RenditionError::Format(ParseError::FormatNotEnabled(format)) => HttpResponse::UnprocessableEntity().body(format!("Format {} is not enabled in this build.", format)),
+            RenditionError::Format(err) => HttpResponse::UnprocessableEntity().body(format!("{:?}", err)),
+            RenditionError::Operations(err) => HttpResponse::UnprocessableEntity().body(err.to_string()),
+            RenditionError::Transform(err) => err.into(),
+            RenditionError::Cancelled => HttpResponse::InternalServerError().body("Transform was cancelled."),
+            RenditionError::Unavailable(mode) => HttpResponse::ServiceUnavailable().body(format!("Service is in {:?} mode.", mode)),
+            RenditionError::NotAllowed(format) => HttpResponse::Forbidden().body(format!("Format {} is not allowed.", format)),
+            RenditionError::SeamCarvingDisabled => HttpResponse::Forbidden().body("Seam carving is disabled."),
+            RenditionError::Quota(err) => err.into(),
+            RenditionError::SigningRequired => HttpResponse::Forbidden().body("Only signed image URLs are served."),
+        }
+    }
+}
+
+/// Transforms an image sent in the body, either raw or as the first file of a multipart form.
+/// The rendition is read from the query: `width`, `height`, `keepRatio`, `format` and `ops`.
+pub async fn upload(req: HttpRequest, data: web::Data<AppState>, rendition: web::Query<Rendition>, payload: web::Payload) -> HttpResponse {
+    let settings = recover(data.config.lock()).upload.clone();
+    if !settings.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    let api_key = match data.quotas.identify(req.headers()) {
+        Some(api_key) => api_key,
+        None => return QuotaError::MissingKey.into(),
+    };
+    if !data.quotas.is_known(&api_key) {
+        return QuotaError::UnknownKey.into();
+    }
+    let requester = Requester::Client(Some(api_key.clone()));
+    if let Err(e) = requester.admit(&data) {
+        return e.into();
+    }
+    match data.mode.get() {
+        ServiceMode::Normal => {}
+        mode => return RenditionError::Unavailable(mode).into(),
+    }
+
+    let (content, declared_type) = match read_upload(&req, payload, settings.maximum_size).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let declared_type = declared_type.filter(|content_type| content_type.starts_with("image/"));
+    let content_type = match sniff_content_type(&content).map(str::to_string).or(declared_type) {
+        Some(content_type) => content_type,
+        None => return HttpResponse::UnsupportedMediaType().body("Unable to recognize the uploaded image."),
+    };
+    let resource = Resource {
+        response_data: ResponseData {
+            id: content_tag(&content),
+            content_type,
+            additional_data: HashMap::default(),
+        },
+        content,
+    };
+
+    let (mut encoded_image, duration) = match transform(&data, resource, &rendition, &requester).await {
+        Ok(transformed) => transformed,
+        Err(e) => return e.into(),
+    };
+    let metadata_settings = recover(data.config.lock()).metadata.clone();
+    if metadata_settings.strip {
+        metadata::strip_encoded(&mut encoded_image, metadata_settings.keep_color_profile);
+    }
+    data.costs.record_transform(UPLOAD_ORIGIN, &encoded_image.content_type, duration);
+    data.costs.record_served(UPLOAD_ORIGIN, &encoded_image.content_type, encoded_image.image.len() as u64);
+    data.quotas.record_served(&api_key, encoded_image.image.len() as u64);
+
+    let mut response = if settings.store_results {
+        let stored = {
+            let data = data.clone();
+            let encoded_image = encoded_image.clone();
+            web::block(move || results::store(&data.cache, encoded_image, Duration::from_secs(settings.retention_seconds))).await
+        };
+        match stored {
+            Ok(Ok(id)) => {
+                let mut response = HttpResponse::Created();
+                response.insert_header((LOCATION, format!("/r/{}", id)));
+                response
+            }
+            Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Unable to store the result. Reason: {}", e)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("{:#?}", e)),
+        }
+    } else {
+        HttpResponse::Ok()
+    };
+    image_response(&mut response, encoded_image)
+}
+
+/// Result stored by an earlier upload, cacheable by clients until it expires.
+pub async fn stored_result(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let id = req.match_info().get("id").unwrap_or_default().to_string();
+    match web::block(move || results::load(&data.cache, &id)).await {
+        Ok(Some(result)) => {
+            let mut response = HttpResponse::Ok();
+            response.insert_header((CACHE_CONTROL, format!("public, max-age={}", result.remaining())));
+            image_response(&mut response, result.image)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Result is unknown or expired."),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#?}", e)),
+    }
+}
+
+fn image_response(response: &mut HttpResponseBuilder, encoded_image: EncodedImage) -> HttpResponse {
+    response
+        .insert_header((ETAG, encoded_image.etag))
+        .insert_header((IMAGE_WIDTH, encoded_image.width.to_string()))
+        .insert_header((IMAGE_HEIGHT, encoded_image.height.to_string()))
+        .content_type(encoded_image.content_type)
+        .body(encoded_image.image)
+}
+
+/// Bytes of the uploaded image with the content type declared for them.
+async fn read_upload(req: &HttpRequest, payload: web::Payload, maximum_size: usize) -> Result<(Vec<u8>, Option<String>), HttpResponse> {
+    let content_type = req.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if !content_type.as_deref().unwrap_or_default().starts_with("multipart/form-data") {
+        return Ok((read_limited(payload, maximum_size).await?, content_type));
+    }
+    let mut form = Multipart::new(req.headers(), payload);
+    while let Some(field) = form.next().await {
+        let field = field.map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+        if field.content_disposition().get_filename().is_none() {
+            continue;
+        }
+        let content_type = field.content_type().essence_str().to_string();
+        return Ok((read_limited(field, maximum_size).await?, Some(content_type)));
+    }
+    Err(HttpResponse::BadRequest().body("Form has no file."))
+}
+
+/// Collects the body, refusing it as soon as it grows over the limit.
+async fn read_limited<S, E>(mut stream: S, maximum_size: usize) -> Result<Vec<u8>, HttpResponse>
+    where S: Stream<Item=Result<Bytes, E>> + Unpin, E: Debug {
+    let mut content = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| HttpResponse::BadRequest().body(format!("{:?}", e)))?;
+        if content.len() + chunk.len() > maximum_size {
+            return Err(HttpResponse::PayloadTooLarge().body(format!("Allowed maximum upload size is: {} bytes.", maximum_size)));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    if content.is_empty() {
+        return Err(HttpResponse::BadRequest().body("Upload is empty."));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use actix_web::{App, test, web};
+    use actix_web::http::StatusCode;
+    use actix_web::http::header::CONTENT_TYPE;
+    use image_crate::{ImageFormat, RgbImage};
+
+    use crate::config::{ApiKeyQuota, Config};
+    use crate::routes::upload::upload;
+    use crate::test_state;
+
+    fn png() -> Vec<u8> {
+        let mut content = Cursor::new(Vec::new());
+        RgbImage::new(8, 8).write_to(&mut content, ImageFormat::Png).unwrap();
+        content.into_inner()
+    }
+
+    #[actix_web::test]
+    async fn hold_uploads_to_the_gates_of_the_image_routes() {
+        let mut config = Config::default();
+        config.upload.enabled = true;
+        config.quotas.keys = vec![ApiKeyQuota { key: String::from("a"), requests_per_day: None, transform_seconds_per_day: None, bytes_per_day: None }];
+        let data = test_state(config);
+        let app = test::init_service(App::new().app_data(data.clone()).route("/upload", web::post().to(upload))).await;
+        let request = |uri: &str| test::TestRequest::post().uri(uri)
+            .insert_header(("X-Api-Key", "a"))
+            .insert_header((CONTENT_TYPE, "image/png"))
+            .set_payload(png())
+            .to_request();
+
+        let response = test::call_service(&app, request("/upload?width=4&height=4&format=png&ops=fit:seam")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = test::call_service(&app, request("/upload?width=4&height=4&format=png")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let usage = &data.quotas.report()["a"].usage;
+        assert_eq!(usage.requests, 2);
+        assert!(usage.transform_seconds > 0.0);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Arc;
+
+use image_crate::GenericImageView;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::cache::{NoCacheEngine, shared, SharedCache};
+use crate::config::Config;
+use crate::decoder::{CachedImageDecoder, ImageDecoder};
+use crate::encoder::{AllInOneCachedImageEncoder, ImageEncoder, JpegOptions, OutputFormat, PngCompression, TiffCompression};
+use crate::fetcher::{Resource, ResponseData};
+use crate::metrics::NoMetrics;
+use crate::output_dimensions::OutputDimensions;
+use crate::resizer::{CachedResizer, Resizer};
+
+const SAMPLE: &[u8] = include_bytes!("selftest.png");
+const SAMPLE_TAG: &str = "Self Test";
+const OUTPUT_SIZE: (usize, usize) = (8, 6);
+
+#[derive(Serialize, Clone)]
+pub struct FormatCheck {
+    pub format: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct SelfTestReport {
+    pub checks: Vec<FormatCheck>,
+}
+
+impl SelfTestReport {
+    /// Report of a self-test that did not run yet counts as failed.
+    pub fn passed(&self) -> bool {
+        !self.checks.is_empty() && self.checks.iter().all(|check| check.error.is_none())
+    }
+}
+
+/// Output formats enabled in this build.
+pub fn output_formats() -> Vec<OutputFormat> {
+    let formats = vec![OutputFormat::Jpeg(90, JpegOptions::default()), OutputFormat::Png(PngCompression::Default), OutputFormat::Bmp, OutputFormat::Gif, OutputFormat::Tiff(TiffCompression::Lzw), OutputFormat::Ico];
+    #[cfg(feature = "webp")]
+    let formats = [formats, vec![OutputFormat::Webp(80.0, crate::encoder::WebpOptions::default()), OutputFormat::WebpLoseless(crate::encoder::WebpOptions::default())]].concat();
+    #[cfg(feature = "avif")]
+    let formats = [formats, vec![OutputFormat::Avif(80, 10)]].concat();
+    #[cfg(feature = "heic")]
+    let formats = [formats, vec![OutputFormat::Heic(80, 10)]].concat();
+    formats
+}
+
+/// Runs the embedded sample through decode, resize and encode for every output format,
+/// then decodes the result back. Stages work on a cache that stores nothing, so a broken
+/// codec cannot hide behind an earlier cached result.
+pub fn run(config: &Config) -> SelfTestReport {
+    let cache: SharedCache = shared(Box::new(NoCacheEngine {}));
+    let metrics = Arc::new(NoMetrics {});
+    let decoder = CachedImageDecoder { cache: cache.clone(), metrics: metrics.clone(), auto_orient: config.metadata.auto_orient, maximum_image_size: config.maximum_image_size };
+    let resizer = CachedResizer { cache: cache.clone(), config: config.clone(), metrics: metrics.clone() };
+    let encoder = AllInOneCachedImageEncoder { cache, metrics, jpeg_encoder: config.encoding.jpeg_encoder, keep_color_profile: config.metadata.keep_color_profile };
+
+    let checks = output_formats().into_iter().map(|output_format| {
+        let format = output_format.to_string();
+        let error = match catch_unwind(AssertUnwindSafe(|| check(&decoder, &resizer, &encoder, output_format))) {
+            Ok(result) => result.err(),
+            Err(_) => Some(String::from("Codec panicked.")),
+        };
+        match &error {
+            Some(e) => error!("Self-test of {} failed: {}", format, e),
+            None => info!("Self-test of {} passed.", format),
+        }
+        FormatCheck { format, error }
+    }).collect();
+    SelfTestReport { checks }
+}
+
+fn check(
+    decoder: &dyn ImageDecoder,
+    resizer: &dyn Resizer,
+    encoder: &dyn ImageEncoder,
+    output_format: OutputFormat,
+) -> Result<(), String> {
+    let image = decoder.decode(SAMPLE_TAG, &sample_resource(SAMPLE.to_vec(), "image/png"))
+        .map_err(|e| format!("Decoding sample failed: {:?}", e))?;
+    let image = resizer.resize_exact(SAMPLE_TAG, image, OUTPUT_SIZE)
+        .map_err(|e| format!("Resizing sample failed: {:?}", e))?;
+    // ICO holds icons of fixed sizes whatever the image was resized to, the largest one is decoded.
+    let expected = match output_format {
+        OutputFormat::Ico => (64, 64),
+        _ => (OUTPUT_SIZE.0 as u32, OUTPUT_SIZE.1 as u32),
+    };
+    let encoded_image = encoder.encode(
+        &String::from(SAMPLE_TAG),
+        image,
+        &OutputDimensions::ScaledExact(OUTPUT_SIZE.0, OUTPUT_SIZE.1),
+        output_format,
+        None,
+    ).map_err(|e| format!("Encoding sample failed: {:?}", e))?;
+    if (encoded_image.width, encoded_image.height) != expected {
+        return Err(format!("Encoder reported dimensions {:?}.", (encoded_image.width, encoded_image.height)));
+    }
+
+    // AVIF is decoded only with the avif-decoder feature, otherwise the brand of its container is checked instead.
+    if cfg!(not(feature = "avif-decoder")) && encoded_image.content_type == "image/avif" {
+        return match encoded_image.image.get(4..12) {
+            Some(b"ftypavif") => Ok(()),
+            _ => Err(String::from("Encoded sample is not an AVIF file.")),
+        };
+    }
+    let decoded_image = decoder.decode(SAMPLE_TAG, &sample_resource(encoded_image.image, &encoded_image.content_type))
+        .map_err(|e| format!("Decoding encoded sample failed: {:?}", e))?;
+    if decoded_image.dimensions() != expected {
+        return Err(format!("Encoded sample has dimensions {:?}.", decoded_image.dimensions()));
+    }
+    Ok(())
+}
+
+fn sample_resource(content: Vec<u8>, content_type: &str) -> Resource {
+    Resource {
+        response_data: ResponseData {
+            id: String::from(SAMPLE_TAG),
+            content_type: content_type.to_string(),
+            additional_data: HashMap::default(),
+        },
+        content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::selftest::run;
+
+    #[test]
+    fn all_formats_pass_self_test() {
+        let report = run(&Config::default());
+        assert!(report.passed(), "{:?}", report.checks.iter().map(|check| &check.error).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SigningSettings;
+use crate::encoder::OutputFormat;
+
+/// Signed the same way as the URLs clients build with `pixvert-url`.
+pub use pixvert_url::{CLAIMS_QUERY_KEY, EXPIRES_QUERY_KEY, sign, SIGNATURE_QUERY_KEY};
+
+/// Restrictions a backend puts on a signed URL, so links it hands out cannot be changed into
+/// more expensive renditions or used forever.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Claims {
+    /// Lossy formats are encoded at this quality at most.
+    pub max_quality: Option<u8>,
+    /// Names of the formats the image may be served in, see `OutputFormat::name`. Empty allows all.
+    pub formats: Vec<String>,
+    /// Unix time in seconds the link stops working at.
+    pub expires: Option<i64>,
+}
+
+impl Claims {
+    pub fn allows(&self, output_format: &OutputFormat) -> bool {
+        self.formats.is_empty() || self.formats.iter().any(|format| format == output_format.name())
+    }
+
+    pub fn restrict(&self, output_format: OutputFormat) -> OutputFormat {
+        match self.max_quality {
+            Some(max_quality) => output_format.with_quality_cap(max_quality),
+            None => output_format,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SigningError {
+    MissingSignature,
+    InvalidSignature,
+    InvalidClaims(String),
+    InvalidExpiry(String),
+    Expired,
+}
+
+/// Checks the signature of the request and returns its claims. Nothing is checked without a key.
+/// Links expire at the earlier of the `expires` parameter and claim, give or take the clock skew.
+pub fn verify(settings: &SigningSettings, path: &str, query: &str) -> Result<Claims, SigningError> {
+    let key = settings.key.as_str();
+    if key.is_empty() {
+        return Ok(Claims::default());
+    }
+    let signature_prefix = format!("{}=", SIGNATURE_QUERY_KEY);
+    let (signatures, parameters): (Vec<&str>, Vec<&str>) = query.split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .partition(|parameter| parameter.starts_with(&signature_prefix));
+    let signature = match signatures[..] {
+        [signature] => &signature[signature_prefix.len()..],
+        _ => return Err(SigningError::MissingSignature),
+    };
+    let signed = match parameters.join("&").as_str() {
+        "" => path.to_string(),
+        query => format!("{}?{}", path, query),
+    };
+    if !pixvert_url::verify_signature(key, &signed, signature) {
+        return Err(SigningError::InvalidSignature);
+    }
+
+    let value = |name: &str| {
+        let prefix = format!("{}=", name);
+        parameters.iter().find_map(|parameter| parameter.strip_prefix(&prefix))
+    };
+    let claims: Claims = match value(CLAIMS_QUERY_KEY) {
+        Some(claims) => {
+            let json = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).map_err(|e| SigningError::InvalidClaims(e.to_string()))?;
+            serde_json::from_slice(&json).map_err(|e| SigningError::InvalidClaims(e.to_string()))?
+        }
+        None => Claims::default(),
+    };
+    let expires = match value(EXPIRES_QUERY_KEY) {
+        Some(expires) => Some(expires.parse::<i64>().map_err(|e| SigningError::InvalidExpiry(e.to_string()))?),
+        None => None,
+    };
+    let expires = expires.into_iter().chain(claims.expires).min();
+    if expires.is_some_and(|expires| expires.saturating_add(settings.clock_skew_seconds) <= Utc::now().timestamp()) {
+        return Err(SigningError::Expired);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use pixvert_url::{Format, ImageUrl, Operation, Size, Subsampling};
+
+    use crate::config::SigningSettings;
+    use crate::encoder::{JpegOptions, OutputFormat};
+    use crate::operations::OperationChain;
+    use crate::signing::{Claims, sign, SigningError, verify};
+
+    fn claims(json: &str) -> String {
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn verify_signature_and_claims() {
+        let settings = SigningSettings { key: String::from("secret"), clock_skew_seconds: 60 };
+        let path = "/100_100/jpeg90/https%3A%2F%2Fexample.com%2Fa.png";
+        let query = format!("ops=blur:2&claims={}", claims(r#"{"maxQuality":70,"formats":["jpeg","webp"]}"#));
+        let signature = sign("secret", &format!("{}?{}", path, query));
+
+        let verified = verify(&settings, path, &format!("{}&s={}", query, signature)).unwrap();
+        assert!(verified.allows(&OutputFormat::Jpeg(90, JpegOptions::default())));
+        assert!(!verified.allows(&OutputFormat::Bmp));
+        assert_eq!(verified.restrict(OutputFormat::Jpeg(90, JpegOptions::default())), OutputFormat::Jpeg(70, JpegOptions::default()));
+        assert_eq!(verify(&settings, path, &format!("s={}&{}", signature, query)), Ok(verified));
+
+        assert_eq!(verify(&settings, path, &query), Err(SigningError::MissingSignature));
+        assert_eq!(verify(&settings, path, &format!("ops=blur:3&s={}", signature)), Err(SigningError::InvalidSignature));
+        let other = SigningSettings { key: String::from("other"), ..settings.clone() };
+        assert_eq!(verify(&other, path, &format!("{}&s={}", query, signature)), Err(SigningError::InvalidSignature));
+        assert_eq!(verify(&SigningSettings::default(), path, ""), Ok(Claims::default()));
+    }
+
+    #[test]
+    fn expire_links_after_clock_skew() {
+        let settings = SigningSettings { key: String::from("secret"), clock_skew_seconds: 60 };
+        let path = "/webp/https%3A%2F%2Fexample.com%2Fa.png";
+        let signed = |query: String| format!("{}&s={}", query, sign("secret", &format!("{}?{}", path, query)));
+        let now = Utc::now().timestamp();
+
+        assert!(verify(&settings, path, &signed(format!("expires={}", now - 30))).is_ok());
+        assert_eq!(verify(&settings, path, &signed(format!("expires={}", now - 90))), Err(SigningError::Expired));
+        let expired_claim = format!("expires={}&claims={}", now + 3600, claims(&format!(r#"{{"expires":{}}}"#, now - 90)));
+        assert_eq!(verify(&settings, path, &signed(expired_claim)), Err(SigningError::Expired));
+        assert!(matches!(verify(&settings, path, &signed(String::from("expires=soon"))), Err(SigningError::InvalidExpiry(_))));
+    }
+
+    #[test]
+    fn accept_urls_built_by_the_client() {
+        let settings = SigningSettings { key: String::from("secret"), clock_skew_seconds: 60 };
+        let jpeg = Format::Jpeg { quality: Some(80), progressive: true, subsampling: Some(Subsampling::Chroma420) };
+        let url = ImageUrl::new("https://images.example.com/shop", "https://example.com/a.png?v=1")
+            .size(Size::KeepRatio(100, 50))
+            .format(Format::Grayscale(Box::new(Format::MaxSize(Box::new(jpeg), 50_000))))
+            .operation(Operation::Denoise(Some(30)))
+            .operation(Operation::Border { width: 4, color: pixvert_url::Color { red: 255, green: 0, blue: 0, alpha: 128 }, inside: true })
+            .claims(pixvert_url::Claims { max_quality: Some(70), formats: vec![String::from("jpeg")], expires: None })
+            .signed("secret");
+        let path_and_query = url.strip_prefix("https://images.example.com").unwrap();
+        let (path, query) = path_and_query.split_once('?').unwrap();
+        let claims = verify(&settings, path, query).unwrap();
+        assert_eq!(claims.max_quality, Some(70));
+
+        let segments: Vec<&str> = path.split('/').collect();
+        assert_eq!(segments[..4], ["", "shop", "100_50", "keep-ratio"]);
+        let output_format: OutputFormat = segments[4].parse().unwrap();
+        assert_eq!(output_format.path_segment(), segments[4]);
+        assert_eq!(urlencoding::decode(segments[5]).unwrap(), "https://example.com/a.png?v=1");
+        let operations = query.split('&').find_map(|parameter| parameter.strip_prefix("ops=")).unwrap();
+        let chain: OperationChain = operations.parse().unwrap();
+        assert_eq!(chain.to_string(), operations);
+    }
+}
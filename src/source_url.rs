@@ -0,0 +1,93 @@
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+/// How the source URL was written in the path of the request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceUrlEncoding {
+    /// Percent-encoded as a single segment, e.g. with `encodeURIComponent`, and decoded once.
+    Percent,
+    /// Written as it is, recognized by the slashes it keeps, and taken without decoding.
+    Verbatim,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SourceUrlError {
+    /// Percent-encoding which does not decode to UTF-8.
+    InvalidEncoding(String),
+}
+
+/// URL of the source image, decoded the same way wherever it is read from the request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceUrl {
+    pub url: String,
+    pub encoding: SourceUrlEncoding,
+}
+
+impl SourceUrl {
+    /// Source URL of the `tail` of an image route.
+    pub fn from_request(req: &HttpRequest) -> Result<SourceUrl, SourceUrlError> {
+        let tail = req.match_info().get("tail").unwrap_or_default();
+        SourceUrl::parse(raw_tail(req.path(), req.match_info().as_str(), tail))
+    }
+
+    /// Source URL of the tail as it was sent, before any decoding.
+    ///
+    /// Encoded URLs are decoded exactly once, so `%252F` in the path is the `%2F` of the source and
+    /// `+` stays a plus. Verbatim URLs are fetched as they are, their `%2F` is never turned into
+    /// a slash.
+    pub fn parse(raw_tail: &str) -> Result<SourceUrl, SourceUrlError> {
+        if raw_tail.contains('/') {
+            return Ok(SourceUrl { url: raw_tail.to_string(), encoding: SourceUrlEncoding::Verbatim });
+        }
+        match urlencoding::decode(raw_tail) {
+            Ok(url) => Ok(SourceUrl { url: url.into_owned(), encoding: SourceUrlEncoding::Percent }),
+            Err(_) => Err(SourceUrlError::InvalidEncoding(raw_tail.to_string())),
+        }
+    }
+}
+
+/// Tail as it was sent. actix decodes the matched path except for `%25`, `%2F` and `%2B`, which
+/// would decode the source URL once already. Slashes stay as they were, so the tail starts after
+/// as many of them in the raw path as there are before it in the matched one.
+fn raw_tail<'a>(raw_path: &'a str, matched_path: &str, tail: &str) -> &'a str {
+    let slashes = matched_path[..matched_path.len().saturating_sub(tail.len())].matches('/').count();
+    match raw_path.match_indices('/').nth(slashes.saturating_sub(1)) {
+        Some((position, _)) if slashes > 0 => &raw_path[position + 1..],
+        _ => raw_path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source_url::{raw_tail, SourceUrl, SourceUrlEncoding, SourceUrlError};
+
+    fn percent(url: &str) -> Result<SourceUrl, SourceUrlError> {
+        Ok(SourceUrl { url: url.to_string(), encoding: SourceUrlEncoding::Percent })
+    }
+
+    #[test]
+    fn decode_encoded_urls_once() {
+        assert_eq!(SourceUrl::parse("https%3A%2F%2Fexample.com%2Fa.png"), percent("https://example.com/a.png"));
+        assert_eq!(SourceUrl::parse("https%3A%2F%2Fexample.com%2Fa%252Fb.png%3Fv%3D1%26w%3D2"), percent("https://example.com/a%2Fb.png?v=1&w=2"));
+        assert_eq!(SourceUrl::parse("https%3A%2F%2Fexample.com%2Fa+b%2Bc.png"), percent("https://example.com/a+b+c.png"));
+        assert_eq!(SourceUrl::parse("https%3A%2F%2Fexample.com%2F%25E2%2582%25AC%20%C3%A9.png"), percent("https://example.com/%E2%82%AC é.png"));
+        assert_eq!(SourceUrl::parse("https%3A%2F%2Fexample.com%2F%FF.png"), Err(SourceUrlError::InvalidEncoding(String::from("https%3A%2F%2Fexample.com%2F%FF.png"))));
+    }
+
+    #[test]
+    fn keep_verbatim_urls() {
+        let verbatim = SourceUrl::parse("https://example.com/a%2Fb+c%20d.png").unwrap();
+        assert_eq!(verbatim, SourceUrl { url: String::from("https://example.com/a%2Fb+c%20d.png"), encoding: SourceUrlEncoding::Verbatim });
+    }
+
+    #[test]
+    fn find_raw_tail() {
+        // actix decoded `%3A` and `%3F`, slashes and `%25` stay encoded.
+        let raw = "/100_100/webp/https%3A%2F%2Fexample.com%2Fa%252Fb%3Fv.png";
+        let matched = "/100_100/webp/https:%2F%2Fexample.com%2Fa%252Fb?v.png";
+        assert_eq!(raw_tail(raw, matched, "https:%2F%2Fexample.com%2Fa%252Fb?v.png"), "https%3A%2F%2Fexample.com%2Fa%252Fb%3Fv.png");
+        assert_eq!(raw_tail("/shop/https://example.com/a%20b.png", "/shop/https://example.com/a b.png", "https://example.com/a b.png"), "https://example.com/a%20b.png");
+        assert_eq!(raw_tail("/https%3A%2F%2Fexample.com", "/https:%2F%2Fexample.com", "https:%2F%2Fexample.com"), "https%3A%2F%2Fexample.com");
+    }
+}
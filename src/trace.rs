@@ -0,0 +1,134 @@
+use actix_web::http::header::HeaderMap;
+
+const TRACEPARENT: &str = "traceparent";
+const B3: &str = "b3";
+const B3_TRACE_ID: &str = "x-b3-traceid";
+const B3_SPAN_ID: &str = "x-b3-spanid";
+const B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+/// Headers Istio asks services to pass on to the requests they make, W3C Trace Context, B3 in
+/// its single and multiple header forms, and the request id of Envoy.
+const PROPAGATED_HEADERS: [&str; 10] = [
+    TRACEPARENT, "tracestate", B3, B3_TRACE_ID, B3_SPAN_ID, B3_PARENT_SPAN_ID, "x-b3-sampled", "x-b3-flags",
+    "x-request-id", "x-ot-span-context",
+];
+
+/// Trace a request belongs to, taken from its headers. Pixvert handles the request in a span of
+/// its own, child of the caller's, and origins are fetched as its children, so traces connect
+/// the browser, pixvert and the origin.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceContext {
+    /// `None` when the request is not traced, headers such as `x-request-id` are still passed on.
+    pub trace_id: Option<String>,
+    pub parent_span_id: Option<String>,
+    pub span_id: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl TraceContext {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let headers: Vec<(String, String)> = PROPAGATED_HEADERS.iter()
+            .filter_map(|name| Some((name.to_string(), headers.get(*name)?.to_str().ok()?.to_string())))
+            .collect();
+        let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+        let (trace_id, parent_span_id) = match (header(TRACEPARENT), header(B3), header(B3_TRACE_ID)) {
+            // version-trace-parent-flags
+            (Some(traceparent), _, _) => match traceparent.split('-').collect::<Vec<&str>>()[..] {
+                [_, trace_id, parent_span_id, _] => (Some(trace_id), Some(parent_span_id)),
+                _ => (None, None),
+            },
+            // trace-span[-sampled[-parent]], or the sampling decision alone.
+            (None, Some(b3), _) => {
+                let mut fields = b3.split('-');
+                match (fields.next(), fields.next()) {
+                    (Some(trace_id), Some(span_id)) => (Some(trace_id), Some(span_id)),
+                    _ => (None, None),
+                }
+            }
+            (None, None, Some(trace_id)) => (Some(trace_id), header(B3_SPAN_ID)),
+            _ => (None, None),
+        };
+        let trace_id = trace_id.map(str::to_string);
+        TraceContext {
+            span_id: trace_id.as_ref().map(|_| format!("{:016x}", rand::random::<u64>())),
+            trace_id,
+            parent_span_id: parent_span_id.map(str::to_string),
+            headers,
+        }
+    }
+
+    /// Headers of requests made while handling the traced one, with pixvert's span as their parent.
+    pub fn propagated_headers(&self) -> Vec<(String, String)> {
+        let (span_id, parent_span_id) = match (&self.span_id, &self.parent_span_id) {
+            (Some(span_id), Some(parent_span_id)) => (span_id, parent_span_id),
+            _ => return self.headers.clone(),
+        };
+        let mut headers: Vec<(String, String)> = self.headers.iter()
+            .map(|(name, value)| {
+                let fields = value.split('-').collect::<Vec<&str>>();
+                let value = match (name.as_str(), &fields[..]) {
+                    (TRACEPARENT, [version, trace_id, _, flags]) => format!("{}-{}-{}-{}", version, trace_id, span_id, flags),
+                    (B3, [trace_id, _]) => format!("{}-{}", trace_id, span_id),
+                    (B3, [trace_id, _, sampled, ..]) => format!("{}-{}-{}-{}", trace_id, span_id, sampled, parent_span_id),
+                    (B3_SPAN_ID, _) => span_id.clone(),
+                    (B3_PARENT_SPAN_ID, _) => parent_span_id.clone(),
+                    _ => value.clone(),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        if self.has_header(B3_SPAN_ID) && !self.has_header(B3_PARENT_SPAN_ID) {
+            headers.push((B3_PARENT_SPAN_ID.to_string(), parent_span_id.clone()));
+        }
+        headers
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(header, _)| header == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use crate::trace::TraceContext;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn continue_trace_of_caller() {
+        let trace = TraceContext::from_headers(&headers(&[
+            ("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            ("x-b3-traceid", "4bf92f3577b34da6a3ce929d0e0e4736"),
+            ("x-b3-spanid", "00f067aa0ba902b7"),
+            ("x-request-id", "abc"),
+            ("cookie", "secret"),
+        ]));
+        assert_eq!(trace.trace_id.as_deref(), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert_eq!(trace.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        let span_id = trace.span_id.clone().unwrap();
+        assert_eq!(trace.propagated_headers(), vec![
+            (String::from("traceparent"), format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", span_id)),
+            (String::from("x-b3-traceid"), String::from("4bf92f3577b34da6a3ce929d0e0e4736")),
+            (String::from("x-b3-spanid"), span_id.clone()),
+            (String::from("x-request-id"), String::from("abc")),
+            (String::from("x-b3-parentspanid"), String::from("00f067aa0ba902b7")),
+        ]);
+
+        let b3 = TraceContext::from_headers(&headers(&[("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1")]));
+        let span_id = b3.span_id.clone().unwrap();
+        assert_eq!(b3.propagated_headers(), vec![
+            (String::from("b3"), format!("80f198ee56343ba864fe8b2a57d3eff7-{}-1-e457b5a2e4d86bd1", span_id)),
+        ]);
+
+        let untraced = TraceContext::from_headers(&headers(&[("b3", "0"), ("x-request-id", "abc")]));
+        assert_eq!((&untraced.trace_id, &untraced.span_id), (&None, &None));
+        assert_eq!(untraced.propagated_headers(), vec![(String::from("b3"), String::from("0")), (String::from("x-request-id"), String::from("abc"))]);
+    }
+}
@@ -0,0 +1,264 @@
+use actix_web::web;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, purge, recover};
+use crate::cache::BlockingCache;
+use crate::fetcher::{FetchError, Resource, ResponseData};
+use crate::mode::ServiceMode;
+use crate::rendition::{cache_keys, Rendition, RenditionError, Requester, transform};
+use crate::trace::TraceContext;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyRequest {
+    pub url: String,
+    /// Renditions to check, the hot ones of the source when empty.
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+    #[serde(default)]
+    pub repair: bool,
+    /// Name of the tenant whose cache is verified, the top-level cache when none.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RenditionState {
+    /// Cached for the source the origin serves now.
+    Current,
+    /// Cached only for the source cached before, which the origin no longer serves.
+    Stale,
+    /// Cached for a source the origin no longer has.
+    Orphaned,
+    /// Not cached, it is rendered on the next request.
+    Missing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RenditionIntegrity {
+    pub path: String,
+    pub state: Option<RenditionState>,
+    pub repaired: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub url: String,
+    /// Content hash of the source cached before the check.
+    pub cached_source_id: Option<String>,
+    /// Content hash of the source the origin serves, none when it is gone.
+    pub origin_source_id: Option<String>,
+    pub source_purged: bool,
+    pub renditions: Vec<RenditionIntegrity>,
+}
+
+/// Refetches the source and compares the cached renditions of the previously cached source
+/// with the ones of the source the origin serves now. Repairing renders stale renditions
+/// again and removes the stale and orphaned entries, together with the source of orphaned ones.
+pub async fn verify(data: &web::Data<AppState>, request: &VerifyRequest) -> Result<VerifyReport, RenditionError> {
+    match data.mode.get() {
+        ServiceMode::Normal => {}
+        mode => return Err(RenditionError::Unavailable(mode)),
+    }
+    if !recover(data.config.lock()).allows_source(&request.url) {
+        return Err(RenditionError::Fetch(FetchError::NoAccess));
+    }
+    let (cached, origin) = {
+        let data = data.clone();
+        let url = request.url.clone();
+        match web::block(move || {
+            let fetcher = recover(data.fetcher.lock());
+            let cached = fetcher.serve_cache(&url).map(|(response_data, _)| response_data);
+            (cached, fetcher.refetch(&url, &TraceContext::default()))
+        }).await {
+            Ok(result) => result,
+            Err(e) => return Err(RenditionError::Fetch(FetchError::Unknown(format!("{:?}", e)))),
+        }
+    };
+    let origin = match origin {
+        Ok(resource) => Some(resource),
+        Err(FetchError::NotFound) => None,
+        Err(e) => return Err(RenditionError::Fetch(e)),
+    };
+    let renditions = match request.renditions.is_empty() {
+        true => data.hot_renditions.top(usize::MAX).into_iter()
+            .filter(|hot| hot.url == request.url)
+            .map(|hot| hot.rendition)
+            .collect(),
+        false => request.renditions.clone(),
+    };
+
+    let mut report = VerifyReport {
+        url: request.url.clone(),
+        cached_source_id: cached.as_ref().map(|source| source.id.clone()),
+        origin_source_id: origin.as_ref().map(|source| source.response_data.id.clone()),
+        source_purged: false,
+        renditions: vec![],
+    };
+    for rendition in renditions {
+        let mut integrity = RenditionIntegrity {
+            path: rendition.path(&request.url),
+            state: None,
+            repaired: false,
+            error: None,
+        };
+        match verify_rendition(data, cached.as_ref(), origin.as_ref(), &rendition, request.repair).await {
+            Ok((state, repaired)) => {
+                integrity.state = Some(state);
+                integrity.repaired = repaired;
+            }
+            Err(e) => integrity.error = Some(format!("{:?}", e)),
+        }
+        report.renditions.push(integrity);
+    }
+    if request.repair && origin.is_none() && cached.is_some() {
+        let data = data.clone();
+        let url = request.url.clone();
+        report.source_purged = match web::block(move || purge::purge_local(&data, &url)).await {
+            Ok(Ok(purged)) => purged,
+            Ok(Err(e)) => return Err(RenditionError::Fetch(FetchError::Unknown(e))),
+            Err(e) => return Err(RenditionError::Fetch(FetchError::Unknown(format!("{:?}", e)))),
+        };
+    }
+    Ok(report)
+}
+
+/// State of the rendition and whether it was repaired.
+async fn verify_rendition(
+    data: &web::Data<AppState>,
+    cached: Option<&ResponseData>,
+    origin: Option<&Resource>,
+    rendition: &Rendition,
+    repair: bool,
+) -> Result<(RenditionState, bool), RenditionError> {
+    let origin_keys = origin.map(|source| cache_keys(data, rendition, &source.response_data)).transpose()?;
+    let previous_keys: Vec<String> = match cached {
+        Some(source) => cache_keys(data, rendition, source)?.into_iter()
+            .filter(|key| !origin_keys.as_ref().is_some_and(|origin_keys| origin_keys.contains(key)))
+            .collect(),
+        None => vec![],
+    };
+    let probe = {
+        let data = data.clone();
+        let is_cached = move |key: &String| data.cache.get_blocking(key).is_some();
+        web::block(move || {
+            let origin_cached = origin_keys.map(|keys| keys.iter().any(&is_cached));
+            let previous_cached: Vec<String> = previous_keys.into_iter().filter(&is_cached).collect();
+            (origin_cached, previous_cached)
+        }).await
+    };
+    let (origin_cached, previous_cached) = probe
+        .map_err(|e| RenditionError::Fetch(FetchError::Unknown(format!("{:?}", e))))?;
+    let state = classify(origin_cached, !previous_cached.is_empty());
+    if !repair || !matches!(state, RenditionState::Stale | RenditionState::Orphaned) {
+        return Ok((state, false));
+    }
+    if let (RenditionState::Stale, Some(origin)) = (state, origin) {
+        transform(data, origin.clone(), rendition, &Requester::Instance).await?;
+    }
+    let data = data.clone();
+    let removed = web::block(move || {
+        let mut failure = None;
+        for key in &previous_cached {
+            if let Err(e) = data.cache.remove_blocking(key) {
+                failure = Some(e);
+            }
+        }
+        failure
+    }).await;
+    match removed {
+        Ok(Some(e)) => warn!("Unable to remove the {:?} rendition {:?}. Reason: {}", state, rendition, e),
+        Err(e) => warn!("Unable to remove the {:?} rendition {:?}. Reason: {:?}", state, rendition, e),
+        _ => {}
+    }
+    Ok((state, true))
+}
+
+/// `origin_cached` is none when the origin no longer has the source, `previous_cached` only
+/// counts renditions of a previously cached source which differs from the one of the origin.
+fn classify(origin_cached: Option<bool>, previous_cached: bool) -> RenditionState {
+    match (origin_cached, previous_cached) {
+        (Some(true), _) => RenditionState::Current,
+        (Some(false), true) => RenditionState::Stale,
+        (None, true) => RenditionState::Orphaned,
+        (_, false) => RenditionState::Missing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use httpmock::Method::GET;
+    use httpmock::MockServer;
+    use image_crate::{ImageFormat, Rgb, RgbImage};
+
+    use crate::cache::BlockingCache;
+    use crate::config::Config;
+    use crate::fetcher::{FetchError, HttpImageFetcher};
+    use crate::metrics::NoMetrics;
+    use crate::rendition::{cache_keys, render, Rendition, RenditionError, Requester};
+    use crate::test_state;
+    use crate::verify::{classify, RenditionState, verify, VerifyRequest};
+
+    fn png(value: u8) -> Vec<u8> {
+        let mut content = Cursor::new(Vec::new());
+        RgbImage::from_pixel(8, 8, Rgb([value; 3])).write_to(&mut content, ImageFormat::Png).unwrap();
+        content.into_inner()
+    }
+
+    #[test]
+    fn classify_renditions() {
+        assert_eq!(classify(Some(true), true), RenditionState::Current);
+        assert_eq!(classify(Some(true), false), RenditionState::Current);
+        assert_eq!(classify(Some(false), true), RenditionState::Stale);
+        assert_eq!(classify(Some(false), false), RenditionState::Missing);
+        assert_eq!(classify(None, true), RenditionState::Orphaned);
+        assert_eq!(classify(None, false), RenditionState::Missing);
+    }
+
+    #[actix_web::test]
+    async fn repair_stale_renditions() {
+        let origin = MockServer::start();
+        let mut v1 = origin.mock(|when, then| {
+            when.method(GET).path("/a.png");
+            then.status(200).header("content-type", "image/png").header("cache-control", "max-age=3600").body(png(0));
+        });
+        let config = Config { allow_from: vec![String::from("127.0.0.1")], ..Config::default() };
+        let data = test_state(config.clone());
+        *data.fetcher.lock().unwrap() = Box::new(HttpImageFetcher { cache: data.cache.clone(), config, metrics: Arc::new(NoMetrics {}) });
+        let url = origin.url("/a.png");
+        let rendition = Rendition { width: 4, height: 4, format: String::from("webp80"), ..Default::default() };
+
+        render(&data, &url, &rendition, &Requester::Instance).await.unwrap();
+        let (cached, _) = data.fetcher.lock().unwrap().serve_cache(&url).unwrap();
+        let stale_keys = cache_keys(&data, &rendition, &cached).unwrap();
+        // Save-Data requests left their own rendition of the same source.
+        assert_eq!(stale_keys.len(), 2);
+        assert!(data.cache.get_blocking(&stale_keys[0]).is_some());
+        data.cache.set_blocking(&stale_keys[1], b"save-data").unwrap();
+        v1.delete();
+        origin.mock(|when, then| {
+            when.method(GET).path("/a.png");
+            then.status(200).header("content-type", "image/png").header("cache-control", "max-age=3600").body(png(255));
+        });
+
+        let request = VerifyRequest { url: url.clone(), renditions: vec![rendition.clone()], repair: true, tenant: None };
+        let report = verify(&data, &request).await.unwrap();
+        assert_ne!(report.cached_source_id, report.origin_source_id);
+        assert_eq!(report.renditions[0].state, Some(RenditionState::Stale));
+        assert!(report.renditions[0].repaired);
+        assert!(stale_keys.iter().all(|key| data.cache.get_blocking(key).is_none()));
+        let (refreshed, _) = data.fetcher.lock().unwrap().serve_cache(&url).unwrap();
+        assert!(data.cache.get_blocking(&cache_keys(&data, &rendition, &refreshed).unwrap()[0]).is_some());
+
+        let request = VerifyRequest { url: String::from("https://example.com/a.png"), renditions: vec![], repair: true, tenant: None };
+        assert!(matches!(verify(&data, &request).await, Err(RenditionError::Fetch(FetchError::NoAccess))));
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use image_crate::io::Reader as ImageReader;
+use image_crate::{DynamicImage, RgbaImage};
+use libvips::{ops, VipsApp, VipsImage};
+use log::error;
+
+use crate::animated_webp;
+use crate::decoder::is_apng;
+use crate::encoder::{ChromaSubsampling, OutputFormat, PngCompression};
+use crate::output_dimensions::OutputDimensions;
+
+/// Largest width and height of libvips images.
+const VIPS_MAX_COORD: usize = 10_000_000;
+
+static START: Once = Once::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts libvips once per process. It is never shut down, workers use it until the process exits.
+fn start() -> bool {
+    START.call_once(|| match VipsApp::new("pixvert", false) {
+        Ok(app) => {
+            std::mem::forget(app);
+            STARTED.store(true, Ordering::SeqCst);
+        }
+        Err(e) => error!("Unable to start libvips, images are processed with image. Reason: {}", e),
+    });
+    STARTED.load(Ordering::SeqCst)
+}
+
+pub fn describe() -> String {
+    match start() {
+        true => String::from("libvips"),
+        false => String::from("image (libvips did not start)"),
+    }
+}
+
+/// Resized RGBA image, none when libvips is unavailable or fails, so the caller can fall back.
+pub fn resize_exact(image: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    if !start() {
+        return None;
+    }
+    let rgba = image.to_rgba8();
+    let source = VipsImage::new_from_memory(rgba.as_raw(), rgba.width() as i32, rgba.height() as i32, 4, ops::BandFormat::Uchar).ok()?;
+    let options = ops::ResizeOptions {
+        vscale: height as f64 / rgba.height() as f64,
+        kernel: ops::Kernel::Lanczos3,
+        ..ops::ResizeOptions::default()
+    };
+    let resized = ops::resize_with_opts(&source, width as f64 / rgba.width() as f64, &options).ok()?;
+    // Scales are rounded to whole pixels by libvips, images off by one are resized by image instead.
+    if (resized.get_width(), resized.get_height()) != (width as i32, height as i32) {
+        return None;
+    }
+    RgbaImage::from_raw(width, height, resized.image_write_to_memory()).map(DynamicImage::ImageRgba8)
+}
+
+/// Source decoded, resized and encoded in one go, with the content type and dimensions of the result.
+pub struct Transformed {
+    pub image: Vec<u8>,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes, resizes and encodes still JPEG, PNG and WebP sources into JPEG, PNG and WebP without
+/// holding the whole decoded source in memory: JPEGs shrink while they load and the rest is
+/// processed in strips. Colors are converted to sRGB and metadata is dropped, unless the color
+/// profile is kept, then the colors are left as they are with the profile of the source. `None` for
+/// anything else, for images past the maximum size, and when libvips fails, to be transformed by
+/// the native pipeline instead.
+pub fn transform(
+    content: &[u8],
+    dimensions: &OutputDimensions,
+    output_format: &OutputFormat,
+    maximum_size: usize,
+    auto_orient: bool,
+    keep_color_profile: bool,
+) -> Option<Transformed> {
+    let still_source = content.starts_with(&[0xFF, 0xD8])
+        || (content.starts_with(b"\x89PNG") && !is_apng(content))
+        || (content.get(8..12) == Some(b"WEBP") && animated_webp::frames(content).is_none());
+    if !still_source || !start() {
+        return None;
+    }
+    let (width, height, size) = match *dimensions {
+        OutputDimensions::ScaledWithRatio(width, height) => (width, height, ops::Size::Both),
+        OutputDimensions::ScaledExact(width, height) => (width, height, ops::Size::Force),
+        // Largest dimensions libvips takes, the source is only turned upright.
+        OutputDimensions::Original => (VIPS_MAX_COORD, VIPS_MAX_COORD, ops::Size::Down),
+        OutputDimensions::SeamCarved(_, _) => return None,
+    };
+    // Checked before libvips allocates anything, scaled images never get larger than requested.
+    if !fits(content, dimensions, maximum_size) {
+        return None;
+    }
+    let options = ops::ThumbnailBufferOptions {
+        height: height as i32,
+        size,
+        no_rotate: !auto_orient,
+        export_profile: if keep_color_profile { String::new() } else { String::from("srgb") },
+        ..ops::ThumbnailBufferOptions::default()
+    };
+    let image = ops::thumbnail_buffer_with_opts(content, width as i32, &options).ok()?;
+    let (width, height) = (image.get_width() as u32, image.get_height() as u32);
+    if width as usize * height as usize > maximum_size {
+        return None;
+    }
+    // Kept metadata is stripped with the rest of the responses, down to the color profile.
+    let (image, content_type) = save(&image, output_format, !keep_color_profile)?;
+    Some(Transformed { image, content_type: content_type.to_string(), width, height })
+}
+
+/// Whether the requested dimensions, or those of the source kept at its size, are within the maximum size.
+fn fits(content: &[u8], dimensions: &OutputDimensions, maximum_size: usize) -> bool {
+    let (width, height) = match *dimensions {
+        OutputDimensions::ScaledWithRatio(width, height) | OutputDimensions::ScaledExact(width, height) => (width, height),
+        _ => match ImageReader::new(Cursor::new(content)).with_guessed_format().ok().and_then(|reader| reader.into_dimensions().ok()) {
+            Some((width, height)) => (width as usize, height as usize),
+            None => return false,
+        },
+    };
+    width.saturating_mul(height) <= maximum_size
+}
+
+fn save(image: &VipsImage, output_format: &OutputFormat, strip: bool) -> Option<(Vec<u8>, &'static str)> {
+    match output_format {
+        OutputFormat::Jpeg(quality, options) => {
+            let subsample_mode = match options.subsampling.unwrap_or_default() {
+                ChromaSubsampling::Chroma444 => ops::ForeignSubsample::Off,
+                ChromaSubsampling::Chroma420 => ops::ForeignSubsample::On,
+                // libvips subsamples both directions or none.
+                ChromaSubsampling::Chroma422 => return None,
+            };
+            let options = ops::JpegsaveBufferOptions {
+                q: *quality as i32,
+                interlace: options.progressive,
+                subsample_mode,
+                strip,
+                ..ops::JpegsaveBufferOptions::default()
+            };
+            ops::jpegsave_buffer_with_opts(image, &options).ok().map(|image| (image, "image/jpeg"))
+        }
+        OutputFormat::Png(compression) => {
+            let options = ops::PngsaveBufferOptions {
+                compression: match compression {
+                    PngCompression::Default => 6,
+                    PngCompression::Level(level) => *level as i32,
+                    PngCompression::Fast => 1,
+                },
+                strip,
+                ..ops::PngsaveBufferOptions::default()
+            };
+            ops::pngsave_buffer_with_opts(image, &options).ok().map(|image| (image, "image/png"))
+        }
+        #[cfg(feature = "webp")]
+        OutputFormat::Webp(quality, options) => save_webp(image, *quality as i32, false, options.method, strip),
+        #[cfg(feature = "webp")]
+        OutputFormat::WebpLoseless(options) => save_webp(image, 100, true, options.method, strip),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "webp")]
+fn save_webp(image: &VipsImage, quality: i32, lossless: bool, method: Option<u8>, strip: bool) -> Option<(Vec<u8>, &'static str)> {
+    let options = ops::WebpsaveBufferOptions {
+        q: quality,
+        lossless,
+        effort: method.unwrap_or(4) as i32,
+        strip,
+        ..ops::WebpsaveBufferOptions::default()
+    };
+    ops::webpsave_buffer_with_opts(image, &options).ok().map(|image| (image, "image/webp"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image_crate::{DynamicImage, ImageOutputFormat};
+
+    use crate::encoder::{OutputFormat, PngCompression};
+    use crate::output_dimensions::OutputDimensions;
+    use crate::vips::{fits, transform};
+
+    #[test]
+    fn refuse_images_past_the_maximum_size_before_libvips() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(8, 4).write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png).unwrap();
+        assert!(fits(&png, &OutputDimensions::ScaledExact(10, 10), 100));
+        assert!(!fits(&png, &OutputDimensions::ScaledWithRatio(10, 11), 100));
+        assert!(!fits(&png, &OutputDimensions::ScaledExact(usize::MAX, 2), usize::MAX - 1));
+        assert!(fits(&png, &OutputDimensions::Original, 32));
+        assert!(!fits(&png, &OutputDimensions::Original, 31));
+        assert!(!fits(b"not an image", &OutputDimensions::Original, usize::MAX));
+
+        let output_format = OutputFormat::Png(PngCompression::Default);
+        assert!(transform(&png, &OutputDimensions::ScaledExact(10, 11), &output_format, 100, true, false).is_none());
+        assert!(transform(&png, &OutputDimensions::Original, &output_format, 31, true, false).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+use log::{error, info, warn};
+
+use crate::AppState;
+use crate::config::WatchSettings;
+use crate::purge::purge_local;
+use crate::rendition::prewarm;
+
+type Snapshot = HashMap<PathBuf, (u64, SystemTime)>;
+
+/// Lists files below `root` with their size and modification time. Hidden files and
+/// directories are skipped, uploads are often written to them before they are renamed.
+fn scan(root: &Path) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                directories.push(entry.path());
+            } else if metadata.is_file() {
+                let path = entry.path().strip_prefix(root).unwrap().to_path_buf();
+                snapshot.insert(path, (metadata.len(), metadata.modified()?));
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+#[derive(Debug, PartialEq)]
+struct Settled {
+    path: PathBuf,
+    /// The file existed before with different content.
+    replaced: bool,
+}
+
+/// Tracks files between scans. A file is settled once it is seen unchanged by two scans
+/// in a row, so files still being written are not rendered half way.
+#[derive(Default)]
+struct Changes {
+    known: Snapshot,
+    pending: Snapshot,
+}
+
+impl Changes {
+    fn new(known: Snapshot) -> Self {
+        Changes { known, pending: Snapshot::new() }
+    }
+
+    fn update(&mut self, current: Snapshot) -> Vec<Settled> {
+        let mut settled = vec![];
+        self.known.retain(|path, _| current.contains_key(path));
+        self.pending.retain(|path, _| current.contains_key(path));
+        for (path, state) in current {
+            if self.known.get(&path) == Some(&state) {
+                continue;
+            }
+            if self.pending.get(&path) == Some(&state) {
+                self.pending.remove(&path);
+                let replaced = self.known.insert(path.clone(), state).is_some();
+                settled.push(Settled { path, replaced });
+            } else {
+                self.pending.insert(path, state);
+            }
+        }
+        settled
+    }
+}
+
+fn source_url(base_url: &str, path: &Path) -> String {
+    let segments: Vec<String> = path.iter()
+        .map(|segment| urlencoding::encode(&segment.to_string_lossy()).into_owned())
+        .collect();
+    format!("{}/{}", base_url.trim_end_matches('/'), segments.join("/"))
+}
+
+async fn prewarm_file(data: &web::Data<AppState>, settings: &WatchSettings, file: Settled) {
+    let url = source_url(&settings.base_url, &file.path);
+    if file.replaced {
+        if let Err(e) = purge_local(data, &url) {
+            warn!("Unable to forget previous version of {}. Reason: {}", url, e);
+        }
+    }
+    let failed = prewarm(data, &url, &settings.renditions).await;
+    info!("Pre-generated {} of {} renditions of {}.", settings.renditions.len() - failed, settings.renditions.len(), url);
+}
+
+/// Watches the configured directory on its own thread and pre-generates renditions of
+/// files which appear in it. Files present at start are left alone.
+pub fn spawn(settings: WatchSettings, data: web::Data<AppState>) {
+    thread::Builder::new()
+        .name(String::from("pixvert-watch"))
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Unable to start watch runtime. Reason: {}", e);
+                    return;
+                }
+            };
+            let root = PathBuf::from(&settings.directory);
+            let mut changes = match scan(&root) {
+                Ok(snapshot) => Changes::new(snapshot),
+                Err(e) => {
+                    error!("Unable to watch {}. Reason: {}", settings.directory, e);
+                    return;
+                }
+            };
+            info!("Watching {} for new sources.", settings.directory);
+            loop {
+                thread::sleep(Duration::from_millis(settings.interval_ms));
+                let snapshot = match scan(&root) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        warn!("Unable to scan {}. Reason: {}", settings.directory, e);
+                        continue;
+                    }
+                };
+                for file in changes.update(snapshot) {
+                    runtime.block_on(prewarm_file(&data, &settings, file));
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use crate::watch::{Changes, Settled, Snapshot, source_url};
+
+    fn snapshot(files: &[(&str, u64, u64)]) -> Snapshot {
+        files.iter()
+            .map(|(path, size, modified)| (PathBuf::from(path), (*size, SystemTime::UNIX_EPOCH + Duration::from_secs(*modified))))
+            .collect()
+    }
+
+    #[test]
+    fn settle_files_unchanged_between_scans() {
+        let mut changes = Changes::new(snapshot(&[("old.jpg", 10, 1)]));
+        assert!(changes.update(snapshot(&[("old.jpg", 10, 1), ("new.jpg", 5, 2)])).is_empty());
+        assert!(changes.update(snapshot(&[("old.jpg", 10, 1), ("new.jpg", 8, 3)])).is_empty());
+        assert_eq!(
+            changes.update(snapshot(&[("old.jpg", 10, 1), ("new.jpg", 8, 3)])),
+            vec![Settled { path: PathBuf::from("new.jpg"), replaced: false }]
+        );
+        assert!(changes.update(snapshot(&[("old.jpg", 12, 4), ("new.jpg", 8, 3)])).is_empty());
+        assert_eq!(
+            changes.update(snapshot(&[("old.jpg", 12, 4), ("new.jpg", 8, 3)])),
+            vec![Settled { path: PathBuf::from("old.jpg"), replaced: true }]
+        );
+        assert!(changes.update(snapshot(&[("new.jpg", 8, 3)])).is_empty());
+        assert!(changes.update(snapshot(&[("old.jpg", 12, 4), ("new.jpg", 8, 3)])).is_empty());
+        assert_eq!(changes.update(snapshot(&[("old.jpg", 12, 4), ("new.jpg", 8, 3)])).len(), 1);
+    }
+
+    #[test]
+    fn encode_path_segments() {
+        assert_eq!(
+            source_url("http://static.example.com/images/", Path::new("summer 2021/beach.jpg")),
+            "http://static.example.com/images/summer%202021/beach.jpg"
+        );
+    }
+}